@@ -0,0 +1,381 @@
+//! Summing numeric fields of high-frequency events over a time window, writing one summary
+//! record per window instead of a record per event
+//!
+//! Events are selected by name, as set with `event!(name: "chunk.sent", ...)`. The summary
+//! record has the name and target of the first aggregated event of the window, the name as
+//! message, the number of aggregated events as `aggregate.count`, the length of the window as
+//! `aggregate.window_ms`, and the sum of each aggregated field under its own name.
+//!
+//! With [`TenantQuotas::with_summary_records`](crate::quota::TenantQuotas::with_summary_records),
+//! the events of each tenant are aggregated separately, and each summary has the tenant field
+//! of its events and counts towards their quotas.
+//!
+//! Windows are checked when events are logged, and by a thread started when the layer is
+//! registered with a subscriber, so a summary is written when its window ends, at most a second
+//! late. The summaries written by the thread are dispatched to the subscriber as root events, so
+//! other layers see them too. Call [`Aggregation::flush`], with the handle from
+//! [`Layer::aggregation`](crate::Layer::aggregation), to write the windows that have not ended,
+//! such as before exiting; the [`Guard`](crate::builder::Guard) of the
+//! [`builder`](crate::builder()) does when dropped. Windows not flushed before the subscriber is
+//! dropped are lost.
+//!
+//! # Example
+//! ```
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::aggregate::Aggregation;
+//! #
+//! let logger = tracing_logstash::Layer::default().with_aggregation(
+//!     Aggregation::new(Duration::from_secs(10)).with_event("chunk.sent", &["bytes_sent"]),
+//! );
+//! let aggregation = logger.aggregation().unwrap();
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! # let _default = tracing::subscriber::set_default(collector);
+//!
+//! // Written as one record with the total every 10 seconds
+//! tracing::trace!(name: "chunk.sent", bytes_sent = 4096);
+//!
+//! // Before exiting
+//! aggregation.flush();
+//! ```
+
+use crate::logstash::has_generated_name;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing_core::callsite::{Callsite, Identifier};
+use tracing_core::dispatcher::WeakDispatch;
+use tracing_core::field::{Field, FieldSet, Value, Visit};
+use tracing_core::metadata::Kind;
+use tracing_core::subscriber::Interest;
+use tracing_core::{Dispatch, Event, Metadata};
+
+/// Maximum number of aggregated fields per event name
+pub const MAX_FIELDS: usize = 28;
+
+/// Number of fields of a summary record: the message, count, window, tenant and the aggregated
+/// fields
+const SUMMARY_FIELDS: usize = MAX_FIELDS + 4;
+
+/// Longest time between checks of the windows by the thread
+const MAX_TICK: Duration = Duration::from_secs(1);
+
+/// Events aggregated by the layer, see the [module](self) documentation
+pub struct Aggregation {
+    window: Duration,
+    rules: Vec<Rule>,
+    /// The subscriber the layer was first registered with, that summaries are written through
+    /// outside of events
+    dispatch: OnceLock<WeakDispatch>,
+}
+
+struct Rule {
+    name: &'static str,
+    fields: Vec<&'static str>,
+    metadata: OnceLock<&'static Metadata<'static>>,
+    /// The window of each tenant, or of all events when they are not aggregated by tenant
+    windows: Mutex<HashMap<Option<String>, Window>>,
+}
+
+struct Window {
+    start: Instant,
+    count: u64,
+    sums: Vec<Option<Sum>>,
+}
+
+impl Aggregation {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            rules: Vec::new(),
+            dispatch: OnceLock::new(),
+        }
+    }
+
+    /// Aggregate the events named `name`, summing `fields`
+    ///
+    /// # Panics
+    /// If more than [`MAX_FIELDS`] fields are given
+    pub fn with_event(mut self, name: &'static str, fields: &[&'static str]) -> Self {
+        assert!(
+            fields.len() <= MAX_FIELDS,
+            "at most {} fields can be aggregated",
+            MAX_FIELDS
+        );
+        self.rules.push(Rule {
+            name,
+            fields: fields.to_vec(),
+            metadata: OnceLock::new(),
+            windows: Default::default(),
+        });
+        self
+    }
+
+    /// Adds the event to its window, returning whether it was aggregated. With `tenant_field`,
+    /// the events of each tenant, as returned by `tenant`, are aggregated separately.
+    pub(crate) fn add(
+        &self,
+        event: &Event<'_>,
+        now: Instant,
+        tenant_field: Option<&'static str>,
+        tenant: impl FnOnce() -> Option<String>,
+    ) -> bool {
+        let event_metadata = event.metadata();
+        if has_generated_name(event_metadata) {
+            return false;
+        }
+        let Some(rule) = self
+            .rules
+            .iter()
+            .find(|rule| rule.name == event_metadata.name())
+        else {
+            return false;
+        };
+        rule.metadata
+            .get_or_init(|| summary_metadata(rule, event_metadata, tenant_field));
+
+        let tenant = tenant_field.and_then(|_| tenant());
+        let mut windows = rule.windows.lock().unwrap_or_else(|e| e.into_inner());
+        let window = windows.entry(tenant).or_insert_with(|| Window {
+            start: now,
+            count: 0,
+            sums: vec![None; rule.fields.len()],
+        });
+        window.count += 1;
+        event.record(&mut SumVisitor {
+            fields: &rule.fields,
+            sums: &mut window.sums,
+        });
+        true
+    }
+
+    /// Writes the summaries of all windows, including those that have not ended, through the
+    /// subscriber the layer is registered with. Does nothing before the layer is registered or
+    /// after the subscriber is dropped.
+    pub fn flush(&self) {
+        self.write_summaries(None);
+    }
+
+    /// Starts the thread writing the summaries of the windows that have ended through
+    /// `dispatch`, the first time the layer is registered with a subscriber. The thread stops
+    /// when the subscriber or the aggregation is dropped.
+    pub(crate) fn register(self: &Arc<Self>, dispatch: &Dispatch) {
+        if self.dispatch.set(dispatch.downgrade()).is_err() {
+            return;
+        }
+        let aggregation = Arc::downgrade(self);
+        let tick = self.window.min(MAX_TICK);
+        // Without the thread, the windows are still written by later events and `flush`
+        let _ = std::thread::Builder::new()
+            .name("tracing-logstash-aggregate".to_owned())
+            .spawn(move || loop {
+                std::thread::sleep(tick);
+                let Some(aggregation) = aggregation.upgrade() else {
+                    break;
+                };
+                if !aggregation.write_summaries(Some(Instant::now())) {
+                    break;
+                }
+            });
+    }
+
+    /// Writes the summaries of the windows that have ended at `now`, or of all windows, through
+    /// the registered subscriber, returning false when there is none
+    fn write_summaries(&self, now: Option<Instant>) -> bool {
+        let Some(dispatch) = self.dispatch.get().and_then(WeakDispatch::upgrade) else {
+            return false;
+        };
+        for summary in self.take(now) {
+            summary.with_event(|event| dispatch.event(event));
+        }
+        true
+    }
+
+    /// Whether `metadata` is of the summary records, as written by [`flush`](Self::flush) and
+    /// the thread
+    pub(crate) fn is_summary(&self, metadata: &Metadata<'_>) -> bool {
+        self.rules.iter().any(|rule| {
+            rule.metadata
+                .get()
+                .is_some_and(|summary| std::ptr::eq(*summary, metadata))
+        })
+    }
+
+    /// Takes the windows that have ended
+    pub(crate) fn take_due(&self, now: Instant) -> Vec<Summary> {
+        self.take(Some(now))
+    }
+
+    /// Takes the windows that have ended at `now`, or all windows
+    fn take(&self, now: Option<Instant>) -> Vec<Summary> {
+        let mut summaries = Vec::new();
+        for rule in &self.rules {
+            let Some(metadata) = rule.metadata.get() else {
+                continue;
+            };
+            let mut windows = rule.windows.lock().unwrap_or_else(|e| e.into_inner());
+            windows.retain(|tenant, window| {
+                if now.is_some_and(|now| now.duration_since(window.start) < self.window) {
+                    return true;
+                }
+                summaries.push(Summary {
+                    metadata,
+                    tenant: tenant.clone(),
+                    count: window.count,
+                    window_ms: self.window.as_millis() as u64,
+                    sums: std::mem::take(&mut window.sums),
+                });
+                false
+            });
+        }
+        summaries
+    }
+}
+
+struct SummaryCallsite(OnceLock<Metadata<'static>>);
+
+impl Callsite for SummaryCallsite {
+    fn set_interest(&self, _interest: Interest) {}
+
+    fn metadata(&self) -> &Metadata<'_> {
+        self.0.get().expect("summary metadata is set when created")
+    }
+}
+
+/// Metadata for the summary records of `rule`, created once per rule and kept for the lifetime
+/// of the program
+fn summary_metadata(
+    rule: &Rule,
+    event_metadata: &Metadata<'static>,
+    tenant_field: Option<&'static str>,
+) -> &'static Metadata<'static> {
+    let mut names = vec!["message", "aggregate.count", "aggregate.window_ms"];
+    names.extend(tenant_field);
+    names.extend(&rule.fields);
+    let names: &'static [&'static str] = Vec::leak(names);
+
+    let callsite: &'static SummaryCallsite = Box::leak(Box::new(SummaryCallsite(OnceLock::new())));
+    let metadata = Metadata::new(
+        rule.name,
+        event_metadata.target(),
+        *event_metadata.level(),
+        None,
+        None,
+        event_metadata.module_path(),
+        FieldSet::new(names, Identifier(callsite)),
+        Kind::EVENT,
+    );
+    callsite.0.get_or_init(|| metadata)
+}
+
+/// The aggregate of a window, see [`Summary::with_event`]
+pub(crate) struct Summary {
+    metadata: &'static Metadata<'static>,
+    tenant: Option<String>,
+    count: u64,
+    window_ms: u64,
+    sums: Vec<Option<Sum>>,
+}
+
+impl Summary {
+    /// Calls `f` with the summary as a root event
+    pub(crate) fn with_event<R>(&self, f: impl FnOnce(&Event<'_>) -> R) -> R {
+        let fields = self.metadata.fields().iter().collect::<Vec<_>>();
+        let message = self.metadata.name();
+        let tenant = self.tenant.as_deref();
+        let mut values: Vec<Option<&dyn Value>> = vec![
+            Some(&message as &dyn Value),
+            Some(&self.count as &dyn Value),
+            Some(&self.window_ms as &dyn Value),
+        ];
+        // The tenant field follows the window when the events are aggregated by tenant
+        if fields.len() > 3 + self.sums.len() {
+            values.push(tenant.as_ref().map(|tenant| tenant as &dyn Value));
+        }
+        values.extend(self.sums.iter().map(|sum| match sum {
+            Some(Sum::Int(sum)) => Some(sum as &dyn Value),
+            Some(Sum::Float(sum)) => Some(sum as &dyn Value),
+            None => None,
+        }));
+
+        // Unused entries repeat the last field without a value
+        let entries: [(&Field, Option<&dyn Value>); SUMMARY_FIELDS] = std::array::from_fn(|i| {
+            (
+                &fields[i.min(fields.len() - 1)],
+                values.get(i).copied().flatten(),
+            )
+        });
+        let value_set = self.metadata.fields().value_set(&entries);
+        f(&Event::new_child_of(None, self.metadata, &value_set))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Sum {
+    Int(i64),
+    Float(f64),
+}
+
+impl Sum {
+    fn add_int(sum: Option<Self>, value: i64) -> Self {
+        match sum {
+            None => Sum::Int(value),
+            Some(Sum::Int(sum)) => Sum::Int(sum.saturating_add(value)),
+            Some(Sum::Float(sum)) => Sum::Float(sum + value as f64),
+        }
+    }
+
+    fn add_float(sum: Option<Self>, value: f64) -> Self {
+        match sum {
+            None => Sum::Float(value),
+            Some(Sum::Int(sum)) => Sum::Float(sum as f64 + value),
+            Some(Sum::Float(sum)) => Sum::Float(sum + value),
+        }
+    }
+}
+
+struct SumVisitor<'a> {
+    fields: &'a [&'static str],
+    sums: &'a mut [Option<Sum>],
+}
+
+impl SumVisitor<'_> {
+    fn sum(&mut self, field: &Field) -> Option<&mut Option<Sum>> {
+        let index = self.fields.iter().position(|name| *name == field.name())?;
+        self.sums.get_mut(index)
+    }
+}
+
+impl Visit for SumVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if let Some(sum) = self.sum(field) {
+            *sum = Some(Sum::add_float(*sum, value));
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if let Some(sum) = self.sum(field) {
+            *sum = Some(Sum::add_int(*sum, value));
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if let Some(sum) = self.sum(field) {
+            *sum = Some(Sum::add_int(*sum, i64::try_from(value).unwrap_or(i64::MAX)));
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::Sum;
+
+    #[test]
+    fn test_sum() {
+        let sum = Sum::add_int(None, 2);
+        assert_eq!(Sum::add_int(Some(sum), i64::MAX), Sum::Int(i64::MAX));
+        assert_eq!(Sum::add_float(Some(sum), 0.5), Sum::Float(2.5));
+    }
+}
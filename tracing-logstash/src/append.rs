@@ -0,0 +1,206 @@
+//! Appending records to a file shared with other processes, such as preforked workers logging
+//! to the same file
+//!
+//! The file is opened in append mode (`O_APPEND`, or `FILE_APPEND_DATA` on Windows), so the
+//! operating system moves to the end of the file and writes as one step, and each record,
+//! including its separator, is written with a single write. Records of all the processes
+//! therefore end up whole and one after the other, within these limits:
+//!
+//! - On Linux and macOS, a write to a regular file on a local file system is not interleaved
+//!   with other writes to the same file, whatever its size, but it may write less than the
+//!   whole record when the disk is full or the process is interrupted by a signal. POSIX only
+//!   requires writes of up to `PIPE_BUF` bytes, 4096 on Linux and 512 on macOS, to be atomic for
+//!   pipes and FIFOs, so keep records below it when the file is a FIFO.
+//! - On Windows, a single write to a file opened for appending is atomic for local files.
+//! - On network file systems such as NFS, appends from several hosts are not atomic and may
+//!   overwrite each other.
+//!
+//! A record written only partly is completed with further writes, which other processes may
+//! interleave with, and counted as torn. Records that could not be written are dropped and
+//! counted.
+//!
+//! Records are left to the operating system to write to disk. To keep the most important ones
+//! through a power loss, the file can be synced after writing records at a
+//! [level](AppendFileWriter::with_sync_on), such as `ERROR`, and after the first record written
+//! once an [interval](AppendFileWriter::with_sync_interval) has passed since the last sync.
+//! Records that could not be synced are counted as dropped, as they may be lost.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing::Level;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::append::AppendFileWriter;
+//! #
+//! let writer = AppendFileWriter::new("/var/log/checkout/app.json")
+//!     .unwrap()
+//!     .with_sync_on(Level::ERROR);
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::record::{RecordWriter, WriteRecord};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer appending records to a file, see the [module](self) documentation
+///
+/// Clones share the same file.
+#[derive(Clone)]
+pub struct AppendFileWriter {
+    file: Arc<File>,
+    sync: FileSync,
+    torn: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl AppendFileWriter {
+    /// A writer appending to the file at `path`, creating it if needed
+    pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Arc::new(file),
+            sync: FileSync::default(),
+            torn: Default::default(),
+            dropped: Default::default(),
+        })
+    }
+
+    /// Sync the file to disk after writing a record at this level or a more severe one, defaults
+    /// to never
+    pub fn with_sync_on(mut self, level: Level) -> Self {
+        self.sync.level = Some(level);
+        self
+    }
+
+    /// Sync the file to disk after the first record written once this long has passed since the
+    /// last sync, defaults to never
+    pub fn with_sync_interval(mut self, interval: Option<Duration>) -> Self {
+        self.sync.interval = interval;
+        self
+    }
+
+    /// Number of records that could not be written with a single write
+    pub fn torn(&self) -> u64 {
+        self.torn.load(Ordering::Relaxed)
+    }
+
+    /// Number of records dropped because they could not be written
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn append(&self, record: &[u8], level: Level) -> io::Result<()> {
+        let mut file = &*self.file;
+        let written = loop {
+            match file.write(record) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+        if written < record.len() {
+            self.torn.fetch_add(1, Ordering::Relaxed);
+            file.write_all(&record[written..])?;
+        }
+        self.sync.after_write(file, level)
+    }
+}
+
+/// When the file writers sync their file to disk
+#[derive(Clone, Default)]
+pub(crate) struct FileSync {
+    pub(crate) level: Option<Level>,
+    pub(crate) interval: Option<Duration>,
+    /// When the file was last synced, shared by the clones of the writer
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl FileSync {
+    /// Syncs the data of the file after writing a record at `level`, if it is due
+    pub(crate) fn after_write(&self, file: &File, level: Level) -> io::Result<()> {
+        if self.level.is_none() && self.interval.is_none() {
+            return Ok(());
+        }
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let last = last.get_or_insert(now);
+        let due = self.level.is_some_and(|sync_on| level <= sync_on)
+            || self
+                .interval
+                .is_some_and(|interval| now.duration_since(*last) >= interval);
+        if due {
+            file.sync_data()?;
+            *last = now;
+        }
+        Ok(())
+    }
+}
+
+impl WriteRecord for AppendFileWriter {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.append(record, level).inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for AppendFileWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FileSync;
+    use std::fs::File;
+    use std::time::Duration;
+    use tracing_core::Level;
+
+    #[test]
+    fn test_file_sync() {
+        let path = std::env::temp_dir().join(format!("file-sync-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let last = |sync: &FileSync| sync.last.lock().unwrap().unwrap();
+
+        let sync = FileSync {
+            level: Some(Level::WARN),
+            ..Default::default()
+        };
+        sync.after_write(&file, Level::INFO).unwrap();
+        let first = last(&sync);
+        sync.after_write(&file, Level::INFO).unwrap();
+        assert_eq!(last(&sync), first);
+        std::thread::sleep(Duration::from_millis(1));
+        sync.after_write(&file, Level::ERROR).unwrap();
+        assert!(last(&sync) > first);
+
+        let sync = FileSync {
+            interval: Some(Duration::from_millis(1)),
+            ..Default::default()
+        };
+        sync.after_write(&file, Level::INFO).unwrap();
+        let first = last(&sync);
+        std::thread::sleep(Duration::from_millis(2));
+        sync.after_write(&file, Level::INFO).unwrap();
+        assert!(last(&sync) > first);
+
+        // Not synced without a level or an interval
+        let sync = FileSync::default();
+        sync.after_write(&file, Level::ERROR).unwrap();
+        assert!(sync.last.lock().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+//! Writing records from a background thread, so that slow writers don't delay the threads
+//! logging
+//!
+//! Records are queued, up to a capacity, and written in order by a thread started with the
+//! first record, the way the network sinks send theirs. What happens to a record when the queue
+//! is full is chosen with a [`BackpressurePolicy`], which the sinks take too; dropped records
+//! are counted.
+//!
+//! With an [inline budget](BackgroundWriter::with_inline_budget), records are instead written on
+//! the threads logging them while the queue is empty, saving the hand-off for occasional records.
+//! A thread that has written the budgeted number of records in a row hands its next record off to
+//! the background thread, and its later ones too until the queue has been written, so an async
+//! task logging in a tight loop does not spend its executor thread writing. The records written
+//! inline, the hand-offs and the time spent writing inline are counted.
+//!
+//! Call [`BackgroundWriter::flush`] before exiting to wait for the queued records to be written.
+//! When the last clone of the writer is dropped, the queued records are written and the thread
+//! is stopped.
+//!
+//! # Example
+//! ```
+//! # use tracing_core::Level;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::background::{BackgroundWriter, BackpressurePolicy};
+//! #
+//! let writer = BackgroundWriter::new(std::io::stdout)
+//!     .unwrap()
+//!     .with_capacity(10_000)
+//!     .with_policy(BackpressurePolicy::DropBelow(Level::WARN));
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! writer.flush();
+//! ```
+
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::record::{RecordWriter, WriteRecord};
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// What to do with a record when the queue of a writer or sink is full
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BackpressurePolicy {
+    /// Wait for room in the queue, delaying the thread logging
+    Block,
+    /// Drop the record
+    DropNewest,
+    /// Drop the oldest queued record to make room for the record
+    DropOldest,
+    /// Drop the record if it is less severe than the level, otherwise wait for room in the
+    /// queue
+    DropBelow(Level),
+}
+
+/// Writes a record with the writer given to [`BackgroundWriter::new`]
+type WriteFn = Box<dyn FnMut(&[u8]) -> io::Result<()> + Send>;
+
+thread_local! {
+    /// The writer the thread last wrote a record inline with, and the number of records it has
+    /// written inline in a row
+    static INLINE_RUN: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+/// A writer queueing records for a background thread, see the [module](self) documentation
+///
+/// Clones share the same queue and thread.
+#[derive(Clone)]
+pub struct BackgroundWriter {
+    batching: Batching,
+    /// Used by the background thread, and by the threads logging within their inline budget
+    write: Arc<Mutex<WriteFn>>,
+    /// Number of records a thread writes inline in a row, none by default
+    inline_budget: usize,
+    inline: Arc<InlineStats>,
+    delivery: Delivery<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct InlineStats {
+    records: AtomicU64,
+    handoffs: AtomicU64,
+    nanos: AtomicU64,
+}
+
+/// The writer, used from the background thread
+struct Output {
+    write: Arc<Mutex<WriteFn>>,
+}
+
+impl Transport for Output {
+    type Record = Vec<u8>;
+
+    /// Writes the record with a writer of its own, counting it if it could not be written
+    fn send(&mut self, batch: &[Vec<u8>], _seq: u64) -> io::Result<u64> {
+        let failed = batch
+            .iter()
+            .filter(|record| write(&self.write, record).is_err())
+            .count();
+        Ok(failed as u64)
+    }
+
+    fn bytes(record: &Vec<u8>) -> &[u8] {
+        record
+    }
+}
+
+impl BackgroundWriter {
+    /// A writer writing the records with `make_writer` from a background thread
+    pub fn new<W>(make_writer: W) -> io::Result<Self>
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + 'static,
+    {
+        let write: WriteFn = Box::new(move |record| make_writer.make_writer().write_all(record));
+        Ok(Self {
+            batching: Batching {
+                batch_size: 1,
+                batch_bytes: usize::MAX,
+                // The capacity, and the record being written
+                max_pending: 1024 + 1,
+                max_attempts: 1,
+                // Records are written as soon as they are queued
+                flush_interval: Duration::ZERO,
+                connections: 1,
+                policy: BackpressurePolicy::Block,
+            },
+            write: Arc::new(Mutex::new(write)),
+            inline_budget: 0,
+            inline: Default::default(),
+            delivery: Delivery::new(),
+        })
+    }
+
+    /// Maximum number of queued records, defaults to 1024
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        // Not counting the record being written
+        self.batching.max_pending = capacity.max(1) + 1;
+        self
+    }
+
+    /// Defaults to [`BackpressurePolicy::Block`]
+    pub fn with_policy(mut self, policy: BackpressurePolicy) -> Self {
+        self.batching.policy = policy;
+        self
+    }
+
+    /// Write up to `records` records in a row on the thread logging them while the queue is
+    /// empty, see the [module](self) documentation, defaults to 0
+    pub fn with_inline_budget(self, records: usize) -> Self {
+        Self {
+            inline_budget: records,
+            ..self
+        }
+    }
+
+    /// Number of records dropped because the queue was full or they could not be written
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Number of records written on the threads logging them
+    pub fn inline_records(&self) -> u64 {
+        self.inline.records.load(Ordering::Relaxed)
+    }
+
+    /// Number of times a thread used up its inline budget and handed its records off to the
+    /// background thread
+    pub fn handoffs(&self) -> u64 {
+        self.inline.handoffs.load(Ordering::Relaxed)
+    }
+
+    /// Time the threads logging spent writing records inline
+    pub fn inline_time(&self) -> Duration {
+        Duration::from_nanos(self.inline.nanos.load(Ordering::Relaxed))
+    }
+
+    /// Writes the record on the calling thread if it is within its inline budget and no records
+    /// are queued, so the records of the thread stay in order, returning `None` otherwise
+    fn write_inline(&self, record: &[u8]) -> Option<io::Result<()>> {
+        if self.inline_budget == 0 || !self.delivery.is_idle() {
+            return None;
+        }
+        let writer = Arc::as_ptr(&self.write) as *const () as usize;
+        let run = match INLINE_RUN.get() {
+            (last, run) if last == writer => run,
+            _ => 0,
+        };
+        if run >= self.inline_budget {
+            INLINE_RUN.set((writer, 0));
+            self.inline.handoffs.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        INLINE_RUN.set((writer, run + 1));
+
+        let start = Instant::now();
+        let result = write(&self.write, record);
+        let nanos = u64::try_from(start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.inline.nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.inline.records.fetch_add(1, Ordering::Relaxed);
+        if result.is_err() {
+            self.delivery.count_dropped();
+        }
+        Some(result)
+    }
+
+    /// Wait for the queued records to be written
+    pub fn flush(&self) {
+        // Records that could not be written are counted rather than kept, so flushing can't fail
+        let _ = self.delivery.flush();
+    }
+}
+
+/// Writes a record with the shared writer
+fn write(write: &Mutex<WriteFn>, record: &[u8]) -> io::Result<()> {
+    (write.lock().unwrap_or_else(|e| e.into_inner()))(record)
+}
+
+impl WriteRecord for BackgroundWriter {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if let Some(result) = self.write_inline(record) {
+            return result;
+        }
+        self.delivery
+            .push(record.to_vec(), level, self.batching, || Output {
+                write: self.write.clone(),
+            })
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for BackgroundWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    /// A writer with the level `INFO`, as the level of the event is unknown
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
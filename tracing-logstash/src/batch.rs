@@ -0,0 +1,266 @@
+//! Batching of records for writers that cost a system call per write, such as a `TcpStream` to
+//! a Logstash `tcp` input
+//!
+//! Records are queued by the threads writing them and written from a background thread, started
+//! with the first record, in batches of up to `max_records` records or `max_bytes` bytes, each with
+//! a single write. A partial batch is written when its oldest record is older than the flush
+//! interval, also when no further records are written. A batch that could not be written is kept
+//! and written again after the flush interval. Up to `max_pending` records are queued, and the
+//! oldest records beyond that are dropped and counted, or the records chosen by another
+//! [`BackpressurePolicy`]. With a [fallback writer](BatchWriter::with_fallback_writer), records are
+//! written to it instead of being dropped or kept.
+//!
+//! Batches are written as the records written by the layer, one after the other, or as a JSON
+//! array of the records with [`BatchFraming::JsonArray`], for endpoints taking a single JSON
+//! array per request.
+//!
+//! Constants shared by all records, such as the service name, can be written
+//! [once per batch](BatchWriter::with_batch_constants) instead of in every record, for receivers
+//! adding them back to the records of the batch. They are written as an object with a single
+//! `@batch` field holding the constants, in front of the records of each batch, and should then
+//! not be set on the format.
+//!
+//! Call [`BatchWriter::flush`] before exiting to wait for the queued records to be written. When
+//! the last clone of the writer is dropped, the queued records are written once more, and
+//! dropped if that fails, and the thread is stopped.
+//!
+//! # Example
+//! ```no_run
+//! # use std::net::TcpStream;
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::batch::BatchWriter;
+//! #
+//! let writer = BatchWriter::new(TcpStream::connect("logstash:5000").unwrap())
+//!     .with_max_records(256)
+//!     .with_flush_interval(Duration::from_millis(200));
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! writer.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use serde_json::{Map, Value};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_core::Level;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// How the records of a batch are written
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BatchFraming {
+    /// The records as written by the layer, each followed by the record separator
+    Separated,
+    /// The records without their separators, separated by commas and enclosed in `[` and `]`
+    JsonArray,
+}
+
+/// A writer batching records, see the [module](self) documentation
+///
+/// Clones share the same writer and queued records.
+pub struct BatchWriter<W> {
+    batching: Batching,
+    framing: BatchFraming,
+    /// The `@batch` object written in front of the records of each batch
+    constants: Option<Arc<[u8]>>,
+    /// Moved to the background thread when it is started
+    writer: Arc<Mutex<Option<W>>>,
+    delivery: Delivery<Vec<u8>>,
+}
+
+impl<W> Clone for BatchWriter<W> {
+    fn clone(&self) -> Self {
+        Self {
+            batching: self.batching,
+            framing: self.framing,
+            constants: self.constants.clone(),
+            writer: self.writer.clone(),
+            delivery: self.delivery.clone(),
+        }
+    }
+}
+
+/// The writer, used from the background thread
+struct Batches<W> {
+    writer: W,
+    framing: BatchFraming,
+    constants: Option<Arc<[u8]>>,
+}
+
+impl<W: Write + Send + 'static> Transport for Batches<W> {
+    type Record = Vec<u8>;
+
+    fn send(&mut self, batch: &[Vec<u8>], _seq: u64) -> io::Result<u64> {
+        let constants = self.constants.as_deref();
+        let batch = match self.framing {
+            BatchFraming::Separated => {
+                let mut records = Vec::new();
+                if let (Some(constants), Some(first)) = (constants, batch.first()) {
+                    records.extend_from_slice(constants);
+                    records.extend_from_slice(&first[trim_separator(first).len()..]);
+                }
+                for record in batch {
+                    records.extend_from_slice(record);
+                }
+                records
+            }
+            BatchFraming::JsonArray => {
+                let mut array = b"[".to_vec();
+                for (n, record) in constants
+                    .into_iter()
+                    .chain(batch.iter().map(|record| trim_separator(record)))
+                    .enumerate()
+                {
+                    if n > 0 {
+                        array.push(b',');
+                    }
+                    array.extend_from_slice(record);
+                }
+                array.push(b']');
+                array
+            }
+        };
+        self.writer.write_all(&batch)?;
+        self.writer.flush()?;
+        Ok(0)
+    }
+
+    fn bytes(record: &Vec<u8>) -> &[u8] {
+        record
+    }
+}
+
+impl<W: Write + Send + 'static> BatchWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            batching: Batching {
+                batch_size: 64,
+                batch_bytes: 64 * 1024,
+                max_pending: 10_000,
+                max_attempts: 1,
+                flush_interval: Duration::from_millis(100),
+                connections: 1,
+                policy: BackpressurePolicy::DropOldest,
+            },
+            framing: BatchFraming::Separated,
+            constants: None,
+            writer: Arc::new(Mutex::new(Some(writer))),
+            delivery: Delivery::new(),
+        }
+    }
+
+    /// Number of records per batch, defaults to 64
+    pub fn with_max_records(mut self, max_records: usize) -> Self {
+        self.batching.batch_size = max_records.max(1);
+        self
+    }
+
+    /// Write the batch when it holds this many bytes, defaults to 64 KiB
+    pub fn with_max_bytes(mut self, max_bytes: usize) -> Self {
+        self.batching.batch_bytes = max_bytes;
+        self
+    }
+
+    /// Write a partial batch when its oldest record is older than this, defaults to 100
+    /// milliseconds
+    pub fn with_flush_interval(mut self, flush_interval: Duration) -> Self {
+        self.batching.flush_interval = flush_interval;
+        self
+    }
+
+    /// Defaults to [`BatchFraming::Separated`]
+    pub fn with_framing(self, framing: BatchFraming) -> Self {
+        Self { framing, ..self }
+    }
+
+    /// Write `constants` once in front of the records of each batch, as `{"@batch":{..}}`, see the
+    /// [module](self) documentation
+    pub fn with_batch_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        let constants = constants
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), Value::String(value)))
+            .collect::<Map<_, _>>();
+        let object = serde_json::json!({ "@batch": constants });
+        Self {
+            constants: Some(object.to_string().into_bytes().into()),
+            ..self
+        }
+    }
+
+    /// Maximum number of records queued, such as while the writer fails, defaults to 10000
+    pub fn with_max_pending(mut self, max_pending: usize) -> Self {
+        self.batching.max_pending = max_pending.max(1);
+        self
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(mut self, policy: BackpressurePolicy) -> Self {
+        self.batching.policy = policy;
+        self
+    }
+
+    /// Number of records dropped because too many records were queued, or because they could
+    /// not be written when the writer was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of batches that could
+    /// not be written instead of keeping them, see [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be written, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+}
+
+impl<W: Write + Send + 'static> WriteRecord for BatchWriter<W> {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .push(record.to_vec(), level, self.batching, || Batches {
+                framing: self.framing,
+                constants: self.constants.clone(),
+                writer: self
+                    .writer
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .take()
+                    .expect("the thread is started once"),
+            })
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a, W: Write + Send + 'static> MakeWriter<'a> for BatchWriter<W> {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+}
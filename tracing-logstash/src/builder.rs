@@ -0,0 +1,184 @@
+//! Installing a layer as the global default subscriber in one call, like
+//! `tracing_subscriber::fmt()`
+//!
+//! The [`Builder`] configures a [`Layer`] over a [`Registry`] with an optional filter. The
+//! [`Guard`] returned when it is installed flushes the [`Aggregation`] windows of the layer and
+//! the [`BackgroundWriter`] set with
+//! [`with_background_writer`](Builder::with_background_writer), if any, when dropped, so keep it
+//! until the program exits.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_core::Level;
+//! # use tracing_logstash::logstash::LogstashFormat;
+//! #
+//! let _guard = tracing_logstash::builder()
+//!     .with_max_level(Level::INFO)
+//!     .event_format(LogstashFormat::default().with_constants(vec![
+//!         ("service.name", "checkout".to_owned()),
+//!     ]))
+//!     .init();
+//! ```
+
+use crate::aggregate::Aggregation;
+use crate::background::BackgroundWriter;
+use crate::format::{FormatEvent, Json, MakeSerializer};
+use crate::logstash::LogstashFormat;
+use crate::Layer;
+use std::sync::Arc;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::{SubscriberInitExt, TryInitError};
+use tracing_subscriber::Registry;
+
+/// The filter of the subscriber
+pub type Filter = Option<Box<dyn tracing_subscriber::Layer<Registry> + Send + Sync>>;
+
+/// The registry with the filter, that the layer is installed in
+pub type Filtered = Layered<Filter, Registry>;
+
+/// The subscriber built by [`Builder::finish`]
+pub type Subscriber<E, W, M> = Layered<Layer<Filtered, E, W, M>, Filtered>;
+
+/// Builds a subscriber writing records with a [`Layer`], see the [module](self) documentation
+pub struct Builder<E = LogstashFormat, W = fn() -> std::io::StdoutLock<'static>, M = Json> {
+    layer: Layer<Filtered, E, W, M>,
+    filter: Filter,
+    background_writer: Option<BackgroundWriter>,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self {
+            layer: Layer::default(),
+            filter: None,
+            background_writer: None,
+        }
+    }
+}
+
+impl<E, W, M> Builder<E, W, M>
+where
+    E: FormatEvent + 'static,
+    W: for<'writer> MakeWriter<'writer> + 'static,
+    M: MakeSerializer + 'static,
+{
+    /// Only enable events and spans at `level` or more severe
+    pub fn with_max_level(self, level: impl Into<LevelFilter>) -> Self {
+        Self {
+            filter: Some(Box::new(level.into())),
+            ..self
+        }
+    }
+
+    /// Filter events and spans with an [`EnvFilter`](tracing_subscriber::EnvFilter), such as
+    /// `EnvFilter::from_default_env()` or `"info,h2=warn"`, instead of a maximum level
+    #[cfg(feature = "env-filter")]
+    pub fn with_env_filter(self, filter: impl Into<tracing_subscriber::EnvFilter>) -> Self {
+        Self {
+            filter: Some(Box::new(filter.into())),
+            ..self
+        }
+    }
+
+    pub fn event_format<E2>(self, event_format: E2) -> Builder<E2, W, M>
+    where
+        E2: FormatEvent + 'static,
+    {
+        Builder {
+            layer: self.layer.event_format(event_format),
+            filter: self.filter,
+            background_writer: self.background_writer,
+        }
+    }
+
+    pub fn with_writer<W2>(self, make_writer: W2) -> Builder<E, W2, M>
+    where
+        W2: for<'writer> MakeWriter<'writer> + 'static,
+    {
+        Builder {
+            layer: self.layer.with_writer(make_writer),
+            filter: self.filter,
+            background_writer: None,
+        }
+    }
+
+    /// Write records with `writer`, flushing it when the [`Guard`] is dropped
+    pub fn with_background_writer(
+        self,
+        writer: BackgroundWriter,
+    ) -> Builder<E, BackgroundWriter, M> {
+        Builder {
+            layer: self.layer.with_writer(writer.clone()),
+            filter: self.filter,
+            background_writer: Some(writer),
+        }
+    }
+
+    /// Configure the layer further, such as with
+    /// [`with_record_checksum`](Layer::with_record_checksum)
+    pub fn map_layer(
+        self,
+        f: impl FnOnce(Layer<Filtered, E, W, M>) -> Layer<Filtered, E, W, M>,
+    ) -> Self {
+        Self {
+            layer: f(self.layer),
+            ..self
+        }
+    }
+
+    /// The subscriber, to install in some other way than as the global default
+    pub fn finish(self) -> Subscriber<E, W, M> {
+        Registry::default().with(self.filter).with(self.layer)
+    }
+}
+
+impl<E, W, M> Builder<E, W, M>
+where
+    E: FormatEvent + Send + Sync + 'static,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+    M: MakeSerializer + Send + Sync + 'static,
+{
+    /// Install the subscriber as the global default, returning an error if one is already
+    /// installed, like `tracing_subscriber::fmt().try_init()`
+    pub fn try_init(mut self) -> Result<Guard, TryInitError> {
+        let background_writer = self.background_writer.take();
+        let aggregation = self.layer.aggregation();
+        self.finish().try_init()?;
+        Ok(Guard {
+            aggregation,
+            background_writer,
+        })
+    }
+
+    /// Install the subscriber as the global default, see [`try_init`](Self::try_init) to handle
+    /// failure instead
+    ///
+    /// # Panics
+    /// If a global default subscriber is already installed
+    pub fn init(self) -> Guard {
+        self.try_init()
+            .expect("failed to set the global default subscriber")
+    }
+}
+
+/// Flushes the aggregation windows and the background writer of the installed subscriber when
+/// dropped
+#[must_use = "dropping the guard flushes the background writer immediately"]
+pub struct Guard {
+    aggregation: Option<Arc<Aggregation>>,
+    background_writer: Option<BackgroundWriter>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        // The summaries are written before the writer is flushed
+        if let Some(aggregation) = &self.aggregation {
+            aggregation.flush();
+        }
+        if let Some(writer) = &self.background_writer {
+            writer.flush();
+        }
+    }
+}
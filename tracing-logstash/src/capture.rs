@@ -0,0 +1,131 @@
+//! Capturing what the process itself prints to stdout or stderr, such as the `println!` output
+//! of dependencies, as records
+//!
+//! [`Capture`] redirects the stream into a pipe, and writes each line read from it with a
+//! [`RawLogWriter`] from a background thread. Records written while the stream is captured must
+//! not go to the captured stream, so write them through an [`OriginalStream`], created before
+//! the capture. Dropping the [`Capture`] restores the stream and writes the remaining lines,
+//! waiting for child processes that inherited the captured stream to close it.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::capture::{Capture, OriginalStream};
+//! # use tracing_logstash::raw::RawLogWriter;
+//! #
+//! let stdout = OriginalStream::stdout().unwrap();
+//! let _capture = Capture::stdout(RawLogWriter::new(stdout.clone()).with_stream("stdout")).unwrap();
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(stdout);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Written as a record with `stream: "stdout"`
+//! println!("hello");
+//! ```
+
+use crate::raw::RawLogWriter;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer to stdout or stderr as they were before being captured
+///
+/// Clones share the same file descriptor.
+#[derive(Clone)]
+pub struct OriginalStream(Arc<File>);
+
+impl OriginalStream {
+    pub fn stdout() -> io::Result<Self> {
+        Ok(Self(Arc::new(
+            io::stdout().as_fd().try_clone_to_owned()?.into(),
+        )))
+    }
+
+    pub fn stderr() -> io::Result<Self> {
+        Ok(Self(Arc::new(
+            io::stderr().as_fd().try_clone_to_owned()?.into(),
+        )))
+    }
+}
+
+impl<'a> MakeWriter<'a> for OriginalStream {
+    type Writer = &'a File;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        &self.0
+    }
+}
+
+/// A captured stream, see the [module](self) documentation
+pub struct Capture {
+    fd: RawFd,
+    original: OwnedFd,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Capture {
+    /// Captures stdout, writing each line with `writer`
+    pub fn stdout<W>(writer: RawLogWriter<W>) -> io::Result<Self>
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + 'static,
+    {
+        Self::start(io::stdout().as_raw_fd(), writer)
+    }
+
+    /// Captures stderr, writing each line with `writer`
+    pub fn stderr<W>(writer: RawLogWriter<W>) -> io::Result<Self>
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + 'static,
+    {
+        Self::start(io::stderr().as_raw_fd(), writer)
+    }
+
+    fn start<W>(fd: RawFd, writer: RawLogWriter<W>) -> io::Result<Self>
+    where
+        W: for<'writer> MakeWriter<'writer> + Send + 'static,
+    {
+        let original = dup(fd)?;
+        let (reader, pipe_writer) = io::pipe()?;
+        dup2(pipe_writer.as_raw_fd(), fd)?;
+        // The stream is now the only write end of the pipe, so the reader ends when it is
+        // restored
+        drop(pipe_writer);
+
+        let thread = std::thread::Builder::new()
+            .name("tracing-logstash-capture".to_owned())
+            .spawn(move || writer.copy_lines(BufReader::new(reader)))?;
+        Ok(Self {
+            fd,
+            original,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        if dup2(self.original.as_raw_fd(), self.fd).is_ok() {
+            if let Some(thread) = self.thread.take() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn dup(fd: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: `fd` is only borrowed for the duration of the call
+    let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+    fd.try_clone_to_owned()
+}
+
+fn dup2(from: RawFd, to: RawFd) -> io::Result<()> {
+    // SAFETY: dup2 has no memory safety requirements, and both descriptors stay open
+    if unsafe { libc::dup2(from, to) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,472 @@
+//! [CBOR](https://www.rfc-editor.org/rfc/rfc8949) output, for links where bandwidth is scarce
+//!
+//! [`Cbor`] serializes the records of any JSON format as CBOR, which is usually about half the
+//! size. CBOR items are self-delimiting, so records are written back to back as a CBOR sequence
+//! by default; use an empty record separator with it.
+//!
+//! # Example
+//! ```
+//! # use tracing_subscriber::prelude::*;
+//! use tracing_logstash::cbor::{Cbor, Framing};
+//! use tracing_logstash::RecordSeparator;
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .with_serializer(Cbor::default().with_framing(Framing::LengthPrefixed))
+//!     .record_separator(RecordSeparator::NONE);
+//! #
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::format::MakeSerializer;
+use serde::ser::{self, Serialize};
+use std::fmt::Display;
+use std::io;
+
+/// How records are delimited
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Records are written back to back, as an [RFC 8742](https://www.rfc-editor.org/rfc/rfc8742)
+    /// CBOR sequence
+    #[default]
+    None,
+    /// Each record is preceded by its length in bytes, as a big-endian `u32`
+    LengthPrefixed,
+}
+
+/// Serializes records as CBOR, see the [module documentation](self)
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Cbor {
+    framing: Framing,
+}
+
+impl Cbor {
+    pub fn with_framing(self, framing: Framing) -> Self {
+        Self { framing }
+    }
+}
+
+impl MakeSerializer for Cbor {
+    fn serialize<T: Serialize + ?Sized>(&self, buffer: &mut Vec<u8>, value: &T) -> io::Result<()> {
+        let to_io_error = |e: Error| io::Error::new(io::ErrorKind::InvalidData, e.0);
+        match self.framing {
+            Framing::None => value.serialize(&mut Encoder(buffer)).map_err(to_io_error),
+            Framing::LengthPrefixed => {
+                let start = buffer.len();
+                buffer.extend_from_slice(&[0; 4]);
+                value.serialize(&mut Encoder(buffer)).map_err(to_io_error)?;
+                let length = u32::try_from(buffer.len() - start - 4)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "record too large"))?;
+                buffer[start..start + 4].copy_from_slice(&length.to_be_bytes());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const FLOAT32: u8 = 0xfa;
+const FLOAT64: u8 = 0xfb;
+const INDEFINITE: u8 = 31;
+const BREAK: u8 = 0xff;
+
+struct Encoder<'a>(&'a mut Vec<u8>);
+
+impl Encoder<'_> {
+    fn header(&mut self, major: u8, value: u64) {
+        let major = major << 5;
+        if value < 24 {
+            self.0.push(major | value as u8);
+        } else if let Ok(value) = u8::try_from(value) {
+            self.0.extend_from_slice(&[major | 24, value]);
+        } else if let Ok(value) = u16::try_from(value) {
+            self.0.push(major | 25);
+            self.0.extend_from_slice(&value.to_be_bytes());
+        } else if let Ok(value) = u32::try_from(value) {
+            self.0.push(major | 26);
+            self.0.extend_from_slice(&value.to_be_bytes());
+        } else {
+            self.0.push(major | 27);
+            self.0.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+
+    fn text(&mut self, value: &str) {
+        self.header(TEXT, value.len() as u64);
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+struct Compound<'a, 'b> {
+    encoder: &'a mut Encoder<'b>,
+    indefinite: bool,
+}
+
+impl<'a, 'b> Compound<'a, 'b> {
+    /// Writes a definite length header, or the start of an indefinite length item
+    fn start(encoder: &'a mut Encoder<'b>, major: u8, length: Option<usize>) -> Self {
+        match length {
+            Some(length) => encoder.header(major, length as u64),
+            None => encoder.0.push(major << 5 | INDEFINITE),
+        }
+        Compound {
+            encoder,
+            indefinite: length.is_none(),
+        }
+    }
+
+    fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.encoder)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        if self.indefinite {
+            self.encoder.0.push(BREAK);
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Encoder<'b> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, 'b>;
+    type SerializeTuple = Compound<'a, 'b>;
+    type SerializeTupleStruct = Compound<'a, 'b>;
+    type SerializeTupleVariant = Compound<'a, 'b>;
+    type SerializeMap = Compound<'a, 'b>;
+    type SerializeStruct = Compound<'a, 'b>;
+    type SerializeStructVariant = Compound<'a, 'b>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.0.push(if v { TRUE } else { FALSE });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        if v >= 0 {
+            self.header(UNSIGNED, v as u64);
+        } else {
+            self.header(NEGATIVE, !v as u64);
+        }
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.header(UNSIGNED, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.0.push(FLOAT32);
+        self.0.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.0.push(FLOAT64);
+        self.0.extend_from_slice(&v.to_be_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.text(v.encode_utf8(&mut [0; 4]));
+        Ok(())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.text(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.header(BYTES, v.len() as u64);
+        self.0.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.0.push(NULL);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.header(MAP, 1);
+        self.text(variant);
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a, 'b>, Error> {
+        Ok(Compound::start(self, ARRAY, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, 'b>, Error> {
+        Ok(Compound::start(self, ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, 'b>, Error> {
+        Ok(Compound::start(self, ARRAY, Some(len)))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, 'b>, Error> {
+        self.header(MAP, 1);
+        self.text(variant);
+        Ok(Compound::start(self, ARRAY, Some(len)))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, 'b>, Error> {
+        Ok(Compound::start(self, MAP, len))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, 'b>, Error> {
+        Ok(Compound::start(self, MAP, Some(len)))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, 'b>, Error> {
+        self.header(MAP, 1);
+        self.text(variant);
+        Ok(Compound::start(self, MAP, Some(len)))
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.element(key)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.encoder.text(key);
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.encoder.text(key);
+        self.element(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        Compound::end(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cbor, Framing};
+    use crate::format::MakeSerializer;
+
+    fn encode(framing: Framing, value: &serde_json::Value) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        Cbor::default()
+            .with_framing(framing)
+            .serialize(&mut buffer, value)
+            .unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_encoding() {
+        let value = serde_json::json!({ "a": -500, "b": [true, null, 1.5], "c": 1000 });
+        let expected = [
+            &[0xa3, 0x61, b'a', 0x39, 0x01, 0xf3][..],
+            &[
+                0x61, b'b', 0x83, 0xf5, 0xf6, 0xfb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0,
+            ],
+            &[0x61, b'c', 0x19, 0x03, 0xe8],
+        ]
+        .concat();
+        assert_eq!(encode(Framing::None, &value), expected);
+
+        let mut prefixed = (expected.len() as u32).to_be_bytes().to_vec();
+        prefixed.extend_from_slice(&expected);
+        assert_eq!(encode(Framing::LengthPrefixed, &value), prefixed);
+    }
+}
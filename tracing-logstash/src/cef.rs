@@ -0,0 +1,230 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, MakeSerializer, RecordKeys};
+use crate::logstash::has_generated_name;
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::text::TextFields;
+use serde::Serializer;
+use std::io::Write as _;
+use std::sync::Arc;
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for the ArcSight Common Event Format (CEF)
+///
+/// The signature id is the name of the event if it was set explicitly, as in
+/// `event!(name: "login.failed", ...)`, and its target otherwise. The message is used as the
+/// event name. The receipt time, constants, event fields and recorded span fields are written as
+/// extensions; characters other than ASCII letters and digits are removed from their keys.
+///
+/// When used with a JSON serializer, as when wrapped by other formats, the record is serialized
+/// as a string.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::cef::CefFormat::new("Acme", "Checkout", env!("CARGO_PKG_VERSION")),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct CefFormat {
+    device_vendor: String,
+    device_product: String,
+    device_version: String,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+}
+
+impl CefFormat {
+    pub fn new(
+        device_vendor: impl Into<String>,
+        device_product: impl Into<String>,
+        device_version: impl Into<String>,
+    ) -> Self {
+        Self {
+            device_vendor: device_vendor.into(),
+            device_product: device_product.into(),
+            device_version: device_version.into(),
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+
+    fn format<SS>(&self, event: &Event<'_>, level: Level, ctx: &Context<'_, SS>) -> String
+    where
+        SS: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let event_metadata = event.metadata();
+        let mut fields = TextFields::excluding(&self.excluded_fields);
+        let receipt_time = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+        fields.add("rt", receipt_time.to_string());
+        for (key, value) in &self.constants {
+            fields.add(*key, value);
+        }
+        fields.add_event(event, ctx);
+
+        let signature_id = if has_generated_name(event_metadata) {
+            event_metadata.target()
+        } else {
+            event_metadata.name()
+        };
+        let name = fields.message.as_deref().unwrap_or(event_metadata.name());
+
+        let mut record = String::with_capacity(256);
+        record.push_str("CEF:0");
+        for header in [
+            &self.device_vendor,
+            &self.device_product,
+            &self.device_version,
+            signature_id,
+            name,
+        ] {
+            record.push('|');
+            escape_header(&mut record, header);
+        }
+        record.push('|');
+        record.push_str(severity(&level));
+        record.push('|');
+
+        let mut first = true;
+        for (key, value) in &fields.fields {
+            let key = extension_key(key);
+            if key.is_empty() {
+                continue;
+            }
+            if !first {
+                record.push(' ');
+            }
+            first = false;
+            record.push_str(&key);
+            record.push('=');
+            escape_extension_value(&mut record, value);
+        }
+        record
+    }
+}
+
+const fn severity(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "8",
+        Level::WARN => "6",
+        Level::INFO => "3",
+        Level::DEBUG => "1",
+        Level::TRACE => "0",
+    }
+}
+
+fn escape_header(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' | '|' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' | '\n' => out.push(' '),
+            c => out.push(c),
+        }
+    }
+}
+
+fn extension_key(key: &str) -> String {
+    key.chars().filter(|c| c.is_ascii_alphanumeric()).collect()
+}
+
+fn escape_extension_value(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\\' | '=' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\r' => out.push_str("\\r"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+impl FormatEvent for CefFormat {
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.format(event, level, &ctx))
+    }
+
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        _make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> std::io::Result<()> {
+        buffer.write_all(self.format(event, level, &ctx).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape_extension_value, escape_header, extension_key};
+
+    #[test]
+    fn test_escaping() {
+        let mut escaped = String::new();
+        escape_header(&mut escaped, "a|b\\c=d\ne");
+        assert_eq!(escaped, "a\\|b\\\\c=d e");
+
+        let mut escaped = String::new();
+        escape_extension_value(&mut escaped, "a|b\\c=d\ne");
+        assert_eq!(escaped, "a|b\\\\c\\=d\\ne");
+
+        assert_eq!(extension_key("src.ip_addr"), "srcipaddr");
+    }
+}
@@ -0,0 +1,65 @@
+//! CRC-32 checksums of records, for detecting records truncated or corrupted on their way to
+//! storage
+//!
+//! The checksum is written as `log.crc32`, 8 lowercase hex digits, at the end of records
+//! serialized as JSON objects. It is computed over the record with the digits of the field
+//! replaced by `00000000`, excluding the record separator, so a record is verified by making
+//! the same replacement and computing the CRC-32 (IEEE) of the result.
+
+pub(crate) const FIELD: &str = "log.crc32";
+
+const PLACEHOLDER: &[u8] = b"\"00000000\"";
+
+/// Inserts the checksum field with a placeholder value, returning the position of its digits
+pub(crate) fn insert_placeholder(record: &mut Vec<u8>) -> Option<usize> {
+    let mut field = crate::json_field_prefix(record, FIELD)?;
+    let position = record.len() - 1 + field.len() + 1;
+    field.extend_from_slice(PLACEHOLDER);
+    crate::insert_json_fields(record, field);
+    Some(position)
+}
+
+/// Writes the checksum of `record`, excluding the trailing `separator_len` bytes, over the
+/// placeholder at `position`
+pub(crate) fn fill(record: &mut [u8], position: usize, separator_len: usize) {
+    let checksum = crc32(&record[..record.len() - separator_len]);
+    record[position..position + 8].copy_from_slice(format!("{:08x}", checksum).as_bytes());
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, fill, insert_placeholder};
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut record = br#"{"message":"hello"}"#.to_vec();
+        let position = insert_placeholder(&mut record).unwrap();
+        assert_eq!(record, br#"{"message":"hello","log.crc32":"00000000"}"#);
+        record.push(b'\n');
+        fill(&mut record, position, 1);
+
+        let checksum = u32::from_str_radix(
+            std::str::from_utf8(&record[position..position + 8]).unwrap(),
+            16,
+        )
+        .unwrap();
+        let mut verified = record[..record.len() - 1].to_vec();
+        verified[position..position + 8].copy_from_slice(b"00000000");
+        assert_eq!(crc32(&verified), checksum);
+
+        assert_eq!(insert_placeholder(&mut b"[]".to_vec()), None);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, RecordKeys};
+use crate::logstash::{
+    LogFieldContributor, LogFieldReceiver, LogTimestamp, SerializingFieldVisitor,
+};
+use crate::span_recorder::DefaultSpanRecorder;
+use serde::ser::SerializeMap;
+use serde::Serializer;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt::Write as _;
+use std::sync::Arc;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for the [Compact Log Event Format](https://clef-json.org/) (CLEF), for sending
+/// events to Seq
+///
+/// The message is written as `@m`, or as the message template `@mt` for Seq to render from the
+/// properties when message templates are enabled. The first error field is written as the
+/// exception `@x`, including its sources. Constants, contributed fields, event fields and
+/// recorded span fields are written as properties.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::clef::ClefFormat::default()
+///         .with_message_templates(true)
+///         .with_constants(vec![("Application", "checkout".to_owned())]),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct ClefFormat<FC = ()> {
+    message_templates: bool,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    field_contributor: FC,
+}
+
+impl Default for ClefFormat {
+    fn default() -> Self {
+        Self {
+            message_templates: false,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> ClefFormat<FC> {
+    /// Write the message as the message template `@mt` instead of as `@m`, so
+    /// `info!(order_id, "order {{order_id}} shipped")` is rendered by Seq using the `order_id`
+    /// property
+    pub fn with_message_templates(self, message_templates: bool) -> Self {
+        Self {
+            message_templates,
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> ClefFormat<FC2> {
+        ClefFormat {
+            message_templates: self.message_templates,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            field_contributor,
+        }
+    }
+}
+
+/// Serilog level names
+const fn level_name(level: &Level) -> &'static str {
+    match *level {
+        Level::TRACE => "Verbose",
+        Level::DEBUG => "Debug",
+        Level::INFO => "Information",
+        Level::WARN => "Warning",
+        Level::ERROR => "Error",
+    }
+}
+
+impl<FC> FormatEvent for ClefFormat<FC>
+where
+    FC: LogFieldContributor,
+{
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: if self.message_templates { "@mt" } else { "@m" },
+            kept: &["@t", "@l"],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let mut message_and_exception = MessageAndException::default();
+        event.record(&mut message_and_exception);
+
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("@t", &LogTimestamp::default())?;
+        if let Some(message) = &message_and_exception.message {
+            let key = if self.message_templates { "@mt" } else { "@m" };
+            s.serialize_entry(key, message)?;
+        }
+        s.serialize_entry("@l", level_name(&level))?;
+        if let Some(exception) = &message_and_exception.exception {
+            s.serialize_entry("@x", exception)?;
+        }
+
+        // The message is not a property, and properties must not start with `@`
+        let mut seen = HashSet::from([Cow::Borrowed("message")]);
+        seen.extend(
+            self.excluded_fields
+                .iter()
+                .map(|field| Cow::Borrowed(*field)),
+        );
+        let mut field_visitor = SerializingFieldVisitor::new(&mut s, |name: Cow<'static, str>| {
+            !name.starts_with('@') && seen.insert(name)
+        });
+
+        for (key, value) in &self.constants {
+            field_visitor.add_field(key, value);
+        }
+        self.field_contributor.add_fields(&mut field_visitor);
+        event.record(&mut field_visitor);
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    field_visitor.add_extension_fields(span_fields);
+                }
+            }
+        }
+        field_visitor.finish()?;
+
+        s.end()
+    }
+}
+
+#[derive(Default)]
+struct MessageAndException {
+    message: Option<String>,
+    exception: Option<String>,
+}
+
+impl Visit for MessageAndException {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.get_or_insert_with(|| value.to_owned());
+        }
+    }
+
+    fn record_error(&mut self, _field: &Field, value: &(dyn std::error::Error + 'static)) {
+        if self.exception.is_none() {
+            let mut exception = value.to_string();
+            let mut source = value.source();
+            while let Some(error) = source {
+                let _ = write!(exception, "\n ---> {}", error);
+                source = error.source();
+            }
+            self.exception = Some(exception);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message.get_or_insert_with(|| format!("{:?}", value));
+        }
+    }
+}
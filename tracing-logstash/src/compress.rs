@@ -0,0 +1,332 @@
+//! Deflate compression for the network sinks and rolled files, in zlib and gzip containers
+//!
+//! Data is compressed into a single block with the fixed Huffman codes, finding repeated
+//! strings with hash chains. This compresses records well, as their keys repeat, without the
+//! cost of building dynamic codes for each batch.
+
+use crate::checksum::crc32;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compression wrapping the data in a zlib stream, as used by Lumberjack compressed frames
+#[cfg_attr(not(feature = "lumberjack"), allow(dead_code))]
+pub(crate) fn zlib(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, default compression level
+    let mut out = vec![0x78, 0x9c];
+    deflate(data, &mut out);
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Compression wrapping the data in a gzip member, as used by Fluentd compressed messages and
+/// compressed rolled files
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    // No name or modification time, unknown operating system
+    let mut out = vec![0x1f, 0x8b, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xff];
+    deflate(data, &mut out);
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, len: u32) {
+        self.bits |= (value as u64) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting with their most significant bit
+    fn write_code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn write_literal(&mut self, symbol: u32) {
+        match symbol {
+            0..=143 => self.write_code(0x30 + symbol, 8),
+            144..=255 => self.write_code(0x190 + symbol - 144, 9),
+            256..=279 => self.write_code(symbol - 256, 7),
+            _ => self.write_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn write_match(&mut self, length: usize, distance: usize) {
+        let i = LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1;
+        self.write_literal(257 + i as u32);
+        self.write(
+            (length - LENGTH_BASE[i] as usize) as u32,
+            LENGTH_EXTRA[i] as u32,
+        );
+        let i = DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1;
+        self.write_code(i as u32, 5);
+        self.write(
+            (distance - DISTANCE_BASE[i] as usize) as u32,
+            DISTANCE_EXTRA[i] as u32,
+        );
+    }
+
+    fn finish(self) {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+fn insert(data: &[u8], pos: usize, head: &mut [usize], prev: &mut [usize]) {
+    if pos + MIN_MATCH <= data.len() {
+        let h = hash(&data[pos..]);
+        prev[pos % WINDOW_SIZE] = head[h];
+        head[h] = pos;
+    }
+}
+
+/// Appends the data compressed as a single final block with the fixed codes
+fn deflate(data: &[u8], out: &mut Vec<u8>) {
+    let mut writer = BitWriter {
+        out,
+        bits: 0,
+        len: 0,
+    };
+    // BFINAL, then BTYPE 01 for the fixed codes
+    writer.write(1, 1);
+    writer.write(1, 2);
+
+    // Most recent position with each hash, and the previous position with the same hash
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut best = (0, 0);
+        if pos + MIN_MATCH <= data.len() {
+            let max_length = (data.len() - pos).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[pos..])];
+            let mut chain = 0;
+            while candidate != usize::MAX && pos - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..]
+                    .iter()
+                    .zip(&data[pos..pos + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best.0 {
+                    best = (length, pos - candidate);
+                    if length == max_length {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            writer.write_match(best.0, best.1);
+            for p in pos..pos + best.0 {
+                insert(data, p, &mut head, &mut prev);
+            }
+            pos += best.0;
+        } else {
+            writer.write_literal(data[pos] as u32);
+            insert(data, pos, &mut head, &mut prev);
+            pos += 1;
+        }
+    }
+    writer.write_literal(256);
+    writer.finish();
+}
+
+#[cfg_attr(not(feature = "lumberjack"), allow(dead_code))]
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::{adler32, gzip, zlib, DISTANCE_BASE, DISTANCE_EXTRA};
+    use super::{LENGTH_BASE, LENGTH_EXTRA};
+
+    /// Decompresses a single block with the fixed codes
+    fn inflate_fixed(data: &[u8]) -> Vec<u8> {
+        let mut pos = 0;
+        let mut bit = |n: u32| {
+            let mut value = 0;
+            for i in 0..n {
+                value |= (((data[pos / 8] >> (pos % 8)) & 1) as u32) << i;
+                pos += 1;
+            }
+            value
+        };
+        assert_eq!(bit(3), 0b011);
+
+        let mut out = Vec::new();
+        loop {
+            let mut code = 0;
+            let mut len = 0;
+            let symbol = loop {
+                code = (code << 1) | bit(1);
+                len += 1;
+                match (len, code) {
+                    (7, 0..=23) => break code + 256,
+                    (8, 0x30..=0xbf) => break code - 0x30,
+                    (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                    (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                    _ => assert!(len < 9),
+                }
+            };
+            match symbol {
+                0..=255 => out.push(symbol as u8),
+                256 => return out,
+                _ => {
+                    let i = symbol as usize - 257;
+                    let length = LENGTH_BASE[i] as usize + bit(LENGTH_EXTRA[i] as u32) as usize;
+                    let i = (0..5).fold(0, |code, _| (code << 1) | bit(1)) as usize;
+                    let distance =
+                        DISTANCE_BASE[i] as usize + bit(DISTANCE_EXTRA[i] as u32) as usize;
+                    for _ in 0..length {
+                        out.push(out[out.len() - distance]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_zlib() {
+        let data = br#"{"message":"hello","level":"INFO"}{"message":"hello again","level":"INFO"}"#
+            .repeat(50);
+        let compressed = zlib(&data);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(compressed[..2], [0x78, 0x9c]);
+        assert_eq!(inflate_fixed(&compressed[2..compressed.len() - 4]), data);
+        assert_eq!(
+            compressed[compressed.len() - 4..],
+            adler32(&data).to_be_bytes()
+        );
+
+        let data = (0..=255u8).cycle().take(70_000).collect::<Vec<_>>();
+        assert_eq!(inflate_fixed(&zlib(&data)[2..]), data);
+        assert_eq!(inflate_fixed(&zlib(b"")[2..]), b"");
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    /// Streams written by the encoder, each checked to decompress to its input with zlib 1.2.13:
+    /// `python3 -c 'import zlib, gzip; print(zlib.decompress(bytes.fromhex(...)))'`, and
+    /// `gzip.decompress` for the gzip members
+    const KNOWN_ANSWERS: [(&[u8], &str, &str); 4] = [
+        (
+            b"",
+            "789c030000000001",
+            "1f8b08000000000000ff03000000000000000000",
+        ),
+        (
+            b"a",
+            "789c4b040000620062",
+            "1f8b08000000000000ff4b040043beb7e801000000",
+        ),
+        (
+            b"hello hello hello",
+            "789ccb48cdc9c9574022013a2e067d",
+            "1f8b08000000000000ffcb48cdc9c9574022018088f9e511000000",
+        ),
+        (
+            br#"{"message":"hello","level":"INFO"}{"message":"hello again","level":"INFO"}"#,
+            "789cab56ca4d2d2e4e4c4f55b252ca48cdc9c957d251ca492d4bcd01f23dfddcfc956a31542824a62766e66\
+             1a8030093d317eb",
+            "1f8b08000000000000ffab56ca4d2d2e4e4c4f55b252ca48cdc9c957d251ca492d4bcd01f23dfddcfc956a\
+             31542824a62766e661a80300d69b21fd4a000000",
+        ),
+    ];
+
+    #[test]
+    fn test_known_answers() {
+        for (data, zlib_hex, gzip_hex) in KNOWN_ANSWERS {
+            assert_eq!(zlib(data), unhex(zlib_hex));
+            assert_eq!(gzip(data), unhex(gzip_hex));
+        }
+    }
+
+    /// The test decoder reads streams written with the fixed codes by zlib itself, with
+    /// `zlib.compressobj(9, zlib.DEFLATED, -15, 9, zlib.Z_FIXED)`
+    #[test]
+    fn test_inflate_zlib_streams() {
+        assert_eq!(
+            inflate_fixed(&unhex("cb48cdc9c957c8409000")),
+            b"hello hello hello"
+        );
+        let stream = [
+            "4b4c4a4e44450c8c4ccc2cac6cec1c9c5cdc3cbc7cfc028242c222a262e2129252d232b272f20a8a4aca2aaa",
+            "6aea1a9a5ada3aba7afa068646c626a666e6169656d636b676f60e8e4ece2eae6eee1e9e5ede3ebe7efe0181",
+            "41c121a161e1119151d131b171f109403b5252d3d23332b3b27372f3f20b0a8b8a4b4acbca2b2aabaa6b6aeb",
+            "ea1b1a9b9a5b5adbda3b3abbba7b7afbfa274c9c3479cad469d367cc9c357bcedc79f3172c5cb478c9d265cb",
+            "57ac5cb57acdda75eb376cdcb479cbd66ddb77ecdcb57bcfde7dfb0f1c3c74f8c8d163c74f9c3c75faccd973",
+            "e72f5cbc74f9cad56bd76fdcbc75fbcedd7bf71f3c7cf4f8c9d367cf5fbc7cf5facddb77ef3f7cfcf4f9cbd7",
+            "6fdf7ffcfcf5fbcfdf7fff01",
+        ]
+        .concat();
+        let mut data = b"abcabcabcabcabcabc".to_vec();
+        data.extend(0..=255u8);
+        assert_eq!(inflate_fixed(&unhex(&stream)), data);
+    }
+
+    #[test]
+    fn test_gzip() {
+        let compressed = gzip(b"hello hello hello");
+        assert_eq!(compressed[..3], [0x1f, 0x8b, 0x08]);
+        assert_eq!(inflate_fixed(&compressed[10..]), b"hello hello hello");
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+}
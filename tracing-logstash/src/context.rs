@@ -0,0 +1,103 @@
+//! Carrying the correlation fields of a process to the child processes it starts, so that the
+//! records of short-lived subprocesses have the identifiers of the request or job that started
+//! them
+//!
+//! [`to_env`] returns an environment variable holding the recorded fields of the current span
+//! and its parents, as configured with `with_span_fields`, and the context the process was
+//! started with, as a JSON object. Fields of inner spans take precedence. Fields excluded with
+//! `with_excluded_fields` are not recorded, so they are not passed on. [`from_env`] reads the
+//! variable in the child, returning the fields as constants for the format.
+//!
+//! # Example
+//! ```no_run
+//! # use std::process::Command;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::context;
+//! #
+//! // In the parent
+//! let _span = tracing::info_span!("job", job_id = "j-42").entered();
+//! Command::new("worker").envs(context::to_env()).spawn().unwrap();
+//!
+//! // At the start of the child
+//! let logger = tracing_logstash::Layer::default().event_format(
+//!     tracing_logstash::logstash::LogstashFormat::default().with_constants(context::from_env()),
+//! );
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::fields::TryForEachField;
+use crate::span_recorder::DefaultSpanRecorder;
+use serde_json::{Map, Value};
+use std::sync::OnceLock;
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+/// Name of the environment variable holding the context
+pub const ENV_VAR: &str = "TRACING_LOGSTASH_CONTEXT";
+
+/// The context read by [`from_env`], passed on to the children of this process
+static IMPORTED: OnceLock<Vec<(&'static str, String)>> = OnceLock::new();
+
+/// The environment variable to set for a child process, or `None` if there is no context to
+/// pass on
+///
+/// Spans are only found when the current subscriber is built on a
+/// [`Registry`].
+pub fn to_env() -> Option<(&'static str, String)> {
+    let mut context = Map::new();
+    tracing::Span::current().with_subscriber(|(id, dispatch)| {
+        let Some(span) = dispatch
+            .downcast_ref::<Registry>()
+            .and_then(|registry| registry.span(id))
+        else {
+            return;
+        };
+        for span in span.scope() {
+            if let Some(fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                let _ = fields.try_for_each::<(), _>(|name, value| {
+                    if !value.is_unset() && !context.contains_key(name.as_ref()) {
+                        if let Ok(value) = serde_json::to_value(value) {
+                            context.insert(name.to_string(), value);
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+    });
+    for (name, value) in IMPORTED.get().into_iter().flatten() {
+        if !context.contains_key(*name) {
+            context.insert(name.to_string(), Value::String(value.clone()));
+        }
+    }
+    if context.is_empty() {
+        return None;
+    }
+    Some((ENV_VAR, Value::Object(context).to_string()))
+}
+
+/// The context this process was started with, as constants for the format
+///
+/// The context is read once, the first time this is called. Its field names are kept for the
+/// lifetime of the program.
+pub fn from_env() -> Vec<(&'static str, String)> {
+    IMPORTED
+        .get_or_init(|| {
+            let Some(Ok(Value::Object(context))) = std::env::var(ENV_VAR)
+                .ok()
+                .map(|json| serde_json::from_str(&json))
+            else {
+                return Vec::new();
+            };
+            context
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = match value {
+                        Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    (&*Box::leak(name.into_boxed_str()), value)
+                })
+                .collect()
+        })
+        .clone()
+}
@@ -0,0 +1,303 @@
+//! Composing several field contributors, with namespaces and conflict detection
+//!
+//! [`Contributors`] combines field contributors into one, checking when each is added that none
+//! of its [declared fields](LogFieldContributor::field_names) is already added by another, so
+//! enrichment modules can't silently shadow each other's fields. A contributor added with a
+//! namespace has its fields prefixed with the namespace and a dot.
+//!
+//! A contributor wrapped in [`Budgeted`] is skipped for a number of events after it takes longer
+//! than its time budget, so a slow enrichment callback can't hold up logging.
+//!
+//! # Example
+//! ```
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::contributors::Contributors;
+//! # use tracing_logstash::logstash::{LogFieldContributor, LogFieldReceiver};
+//! #
+//! struct BuildInfo;
+//! impl LogFieldContributor for BuildInfo {
+//!     fn add_fields<F: LogFieldReceiver>(&self, serializer: &mut F) {
+//!         serializer.add_field("version", env!("CARGO_PKG_VERSION"));
+//!     }
+//!     fn field_names(&self) -> Vec<&'static str> {
+//!         vec!["version"]
+//!     }
+//! }
+//!
+//! let contributors = Contributors::new()
+//!     .with_namespace("build", BuildInfo)
+//!     .unwrap()
+//!     .with_namespace("service", BuildInfo)
+//!     .unwrap();
+//! assert_eq!(contributors.field_names(), ["build.version", "service.version"]);
+//!
+//! let logger = tracing_logstash::Layer::default().event_format(
+//!     tracing_logstash::logstash::LogstashFormat::default().with_field_contributor(contributors),
+//! );
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::logstash::{LogFieldContributor, LogFieldReceiver};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Field contributors combined into one, see the [module](self) documentation
+pub struct Contributors<C = ()> {
+    contributor: C,
+    field_names: Vec<&'static str>,
+}
+
+impl Contributors {
+    pub fn new() -> Self {
+        Self {
+            contributor: (),
+            field_names: Vec::new(),
+        }
+    }
+}
+
+impl Default for Contributors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Contributors<C> {
+    /// Adds `contributor`, failing if it declares a field already declared by another
+    pub fn with<C2>(self, contributor: C2) -> Result<Contributors<(C, C2)>, FieldConflict>
+    where
+        C2: LogFieldContributor,
+    {
+        let mut field_names = self.field_names;
+        for field in contributor.field_names() {
+            if field_names.contains(&field) {
+                return Err(FieldConflict { field });
+            }
+            field_names.push(field);
+        }
+        Ok(Contributors {
+            contributor: (self.contributor, contributor),
+            field_names,
+        })
+    }
+
+    /// Adds `contributor` with its fields prefixed by `namespace` and a dot
+    pub fn with_namespace<C2>(
+        self,
+        namespace: &'static str,
+        contributor: C2,
+    ) -> Result<Contributors<(C, Namespaced<C2>)>, FieldConflict>
+    where
+        C2: LogFieldContributor,
+    {
+        self.with(Namespaced::new(namespace, contributor))
+    }
+}
+
+impl<C: LogFieldContributor> LogFieldContributor for Contributors<C> {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        self.contributor.add_fields(serializer);
+    }
+
+    fn field_names(&self) -> Vec<&'static str> {
+        self.field_names.clone()
+    }
+}
+
+/// Two contributors declaring the same field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldConflict {
+    pub field: &'static str,
+}
+
+impl Display for FieldConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "field {} is added by more than one contributor",
+            self.field
+        )
+    }
+}
+
+impl std::error::Error for FieldConflict {}
+
+/// A field contributor with its fields prefixed by a namespace and a dot
+pub struct Namespaced<C> {
+    namespace: &'static str,
+    contributor: C,
+    names: RwLock<HashMap<&'static str, &'static str>>,
+}
+
+impl<C> Namespaced<C> {
+    pub fn new(namespace: &'static str, contributor: C) -> Self {
+        Self {
+            namespace,
+            contributor,
+            names: Default::default(),
+        }
+    }
+
+    /// The namespaced name of `field`. Names are created once per field and kept for the
+    /// lifetime of the program.
+    fn name(&self, field: &'static str) -> &'static str {
+        if let Some(name) = self
+            .names
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(field)
+        {
+            return name;
+        }
+        self.names
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(field)
+            .or_insert_with(|| Box::leak(format!("{}.{}", self.namespace, field).into_boxed_str()))
+    }
+}
+
+impl<C: LogFieldContributor> LogFieldContributor for Namespaced<C> {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        self.contributor.add_fields(&mut NamespacedReceiver {
+            namespaced: self,
+            receiver: serializer,
+        });
+    }
+
+    fn field_names(&self) -> Vec<&'static str> {
+        self.contributor
+            .field_names()
+            .into_iter()
+            .map(|field| self.name(field))
+            .collect()
+    }
+}
+
+struct NamespacedReceiver<'a, C, F> {
+    namespaced: &'a Namespaced<C>,
+    receiver: &'a mut F,
+}
+
+impl<C, F: LogFieldReceiver> LogFieldReceiver for NamespacedReceiver<'_, C, F> {
+    fn add_field<V: ?Sized + Serialize>(&mut self, field: &'static str, value: &V) {
+        self.receiver.add_field(self.namespaced.name(field), value);
+    }
+}
+
+/// A field contributor that is skipped for the next events after it exceeds its time budget
+///
+/// Fields added by the run exceeding the budget are kept. Overruns and skipped events are
+/// counted in the [`BudgetCounters`].
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use tracing_logstash::contributors::{Budgeted, Contributors};
+/// # use tracing_logstash::logstash::{LogFieldContributor, LogFieldReceiver};
+/// #
+/// # struct Enrichment;
+/// # impl LogFieldContributor for Enrichment {
+/// #     fn add_fields<F: LogFieldReceiver>(&self, _serializer: &mut F) {}
+/// # }
+/// let budgeted = Budgeted::new(Enrichment, Duration::from_micros(50)).with_skip_events(1000);
+/// let counters = budgeted.counters();
+///
+/// let contributors = Contributors::new().with(budgeted).unwrap();
+/// # let _ = contributors;
+/// // Periodically
+/// if counters.overruns() > 0 {
+///     // Alert
+/// }
+/// ```
+pub struct Budgeted<C> {
+    contributor: C,
+    budget: Duration,
+    skip_events: u64,
+    remaining_skips: AtomicU64,
+    counters: Arc<BudgetCounters>,
+}
+
+/// Counters of a [`Budgeted`] contributor
+#[derive(Default, Debug)]
+pub struct BudgetCounters {
+    overruns: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl BudgetCounters {
+    /// Times the contributor took longer than its budget
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Events the contributor was skipped for
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+}
+
+impl<C> Budgeted<C> {
+    /// `contributor`, skipped for the next 100 events after it takes longer than `budget`
+    pub fn new(contributor: C, budget: Duration) -> Self {
+        Self {
+            contributor,
+            budget,
+            skip_events: 100,
+            remaining_skips: AtomicU64::new(0),
+            counters: Default::default(),
+        }
+    }
+
+    /// Number of events to skip the contributor for after an overrun, defaults to 100
+    pub fn with_skip_events(self, skip_events: u64) -> Self {
+        Self {
+            skip_events,
+            ..self
+        }
+    }
+
+    pub fn counters(&self) -> Arc<BudgetCounters> {
+        self.counters.clone()
+    }
+
+    /// Takes one of the remaining skips, returning whether there was one
+    fn take_skip(&self) -> bool {
+        self.remaining_skips
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+    }
+}
+
+impl<C: LogFieldContributor> LogFieldContributor for Budgeted<C> {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        if self.take_skip() {
+            self.counters.skipped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let start = Instant::now();
+        self.contributor.add_fields(serializer);
+        if start.elapsed() > self.budget {
+            self.counters.overruns.fetch_add(1, Ordering::Relaxed);
+            self.remaining_skips
+                .store(self.skip_events, Ordering::Relaxed);
+        }
+    }
+
+    fn field_names(&self) -> Vec<&'static str> {
+        self.contributor.field_names()
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Attribution of the bytes written to the targets writing them
+///
+/// Each record written can be stamped with its own size in bytes, including the record
+/// separator and the size field itself, by inserting the field at the end of the record after
+/// it has been serialized. Only records serialized as JSON objects are stamped.
+///
+/// The records and bytes written are also counted per target. Clones share the same statistics.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::cost::CostAttribution;
+/// #
+/// let cost = CostAttribution::new();
+///
+/// let logger = tracing_logstash::Layer::default().with_cost_attribution(Some(cost.clone()));
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+///
+/// // Later
+/// for (target, stats) in cost.stats() {
+///     println!("{}: {} bytes", target, stats.bytes_written);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct CostAttribution {
+    size_field: Option<&'static str>,
+    targets: Arc<Mutex<HashMap<&'static str, TargetStats>>>,
+}
+
+/// Statistics for a single target
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TargetStats {
+    pub records_written: u64,
+    pub bytes_written: u64,
+}
+
+impl Default for CostAttribution {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CostAttribution {
+    pub fn new() -> Self {
+        Self {
+            size_field: Some("log.size_bytes"),
+            targets: Default::default(),
+        }
+    }
+
+    /// Name of the field holding the size of the record, defaults to `log.size_bytes`. `None`
+    /// only counts the bytes per target.
+    pub fn with_size_field(self, size_field: Option<&'static str>) -> Self {
+        Self { size_field, ..self }
+    }
+
+    /// Statistics per target seen so far
+    pub fn stats(&self) -> HashMap<String, TargetStats> {
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        targets
+            .iter()
+            .map(|(target, stats)| (target.to_string(), stats.clone()))
+            .collect()
+    }
+
+    /// Inserts the size field into a serialized JSON object, followed by `separator_len` bytes
+    pub(crate) fn stamp(&self, record: &mut Vec<u8>, separator_len: usize) {
+        let Some(size_field) = self.size_field else {
+            return;
+        };
+        let Some(mut field) = crate::json_field_prefix(record, size_field) else {
+            return;
+        };
+
+        // The size includes its own digits
+        let fixed = record.len() + field.len() + separator_len;
+        let mut digits = 1;
+        let size = loop {
+            let size = fixed + digits;
+            if size.to_string().len() == digits {
+                break size;
+            }
+            digits += 1;
+        };
+        field.extend_from_slice(size.to_string().as_bytes());
+        crate::insert_json_fields(record, field);
+    }
+
+    pub(crate) fn record(&self, target: &'static str, bytes: usize) {
+        let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        let stats = targets.entry(target).or_default();
+        stats.records_written += 1;
+        stats.bytes_written += bytes as u64;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CostAttribution;
+
+    #[test]
+    fn test_stamp() {
+        let cost = CostAttribution::new();
+        let mut record = br#"{"message":"hello"}"#.to_vec();
+        cost.stamp(&mut record, 1);
+        assert_eq!(record, br#"{"message":"hello","log.size_bytes":40}"#);
+        assert_eq!(record.len() + 1, 40);
+
+        let mut record = b"{}".to_vec();
+        cost.stamp(&mut record, 0);
+        assert_eq!(record, br#"{"log.size_bytes":21}"#);
+    }
+}
@@ -0,0 +1,279 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, RecordKeys};
+use crate::logstash::{
+    LogFieldContributor, LogFieldReceiver, LogTimestamp, SerializingFieldVisitor,
+};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::trace_context::TraceContextProvider;
+use serde::ser::SerializeMap;
+use serde::Serializer;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format using the [Datadog reserved attributes](https://docs.datadoghq.com/logs/log_configuration/attributes_naming_convention/)
+///
+/// Trace and span ids from the trace context provider are hex encoded W3C/OpenTelemetry ids;
+/// they are written as `dd.trace_id` and `dd.span_id` in Datadog's decimal form, which uses the
+/// lower 64 bits of the trace id.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::trace_context::TraceContext;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::datadog::DatadogFormat::new("checkout")
+///         .with_env("production")
+///         .with_trace_context(|| None::<TraceContext>),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct DatadogFormat<FC = ()> {
+    service: String,
+    source: String,
+    env: Option<String>,
+    version: Option<String>,
+    display_logger_name: bool,
+    display_thread_name: bool,
+    trace_context: Option<Arc<dyn TraceContextProvider>>,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    field_contributor: FC,
+}
+
+impl DatadogFormat {
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            source: "rust".to_owned(),
+            env: None,
+            version: None,
+            display_logger_name: true,
+            display_thread_name: true,
+            trace_context: None,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> DatadogFormat<FC> {
+    /// Value of `ddsource`, defaults to `rust`
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            ..self
+        }
+    }
+    pub fn with_env(self, env: impl Into<String>) -> Self {
+        Self {
+            env: Some(env.into()),
+            ..self
+        }
+    }
+    pub fn with_version(self, version: impl Into<String>) -> Self {
+        Self {
+            version: Some(version.into()),
+            ..self
+        }
+    }
+    pub fn with_logger_name(self, display_logger_name: bool) -> Self {
+        Self {
+            display_logger_name,
+            ..self
+        }
+    }
+    pub fn with_thread_name(self, display_thread_name: bool) -> Self {
+        Self {
+            display_thread_name,
+            ..self
+        }
+    }
+    /// Correlate logs with traces, using the ids from `provider`
+    pub fn with_trace_context(self, provider: impl TraceContextProvider + 'static) -> Self {
+        Self {
+            trace_context: Some(Arc::new(provider)),
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> DatadogFormat<FC2> {
+        DatadogFormat {
+            service: self.service,
+            source: self.source,
+            env: self.env,
+            version: self.version,
+            display_logger_name: self.display_logger_name,
+            display_thread_name: self.display_thread_name,
+            trace_context: self.trace_context,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            field_contributor,
+        }
+    }
+}
+
+const fn status(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "error",
+        Level::WARN => "warning",
+        Level::INFO => "info",
+        Level::DEBUG => "debug",
+        Level::TRACE => "trace",
+    }
+}
+
+/// Converts a hex encoded id to Datadog's decimal form, the lower 64 bits as an unsigned integer
+fn decimal_id(hex_id: &str) -> Option<u64> {
+    let lower = hex_id.get(hex_id.len().saturating_sub(16)..)?;
+    u64::from_str_radix(lower, 16).ok()
+}
+
+impl<FC> FormatEvent for DatadogFormat<FC>
+where
+    FC: LogFieldContributor,
+{
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: "message",
+            kept: &[
+                "timestamp",
+                "status",
+                "service",
+                "ddsource",
+                "env",
+                "version",
+                "dd.trace_id",
+                "dd.span_id",
+                "logger.name",
+            ],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let event_metadata = event.metadata();
+
+        let mut s = serializer.serialize_map(None)?;
+
+        // Excluded fields are skipped as if already written
+        let mut seen = self
+            .excluded_fields
+            .iter()
+            .map(|field| Cow::Borrowed(*field))
+            .collect::<HashSet<_>>();
+        let mut field_visitor = SerializingFieldVisitor::new(&mut s, |name| seen.insert(name));
+
+        field_visitor.add_field("timestamp", &LogTimestamp::default());
+        field_visitor.add_field("status", status(&level));
+        field_visitor.add_field("service", &self.service);
+        field_visitor.add_field("ddsource", &self.source);
+        if let Some(env) = &self.env {
+            field_visitor.add_field("env", env);
+        }
+        if let Some(version) = &self.version {
+            field_visitor.add_field("version", version);
+        }
+
+        if let Some(trace_context) = self.trace_context.as_ref().and_then(|p| p.trace_context()) {
+            if let Some(trace_id) = decimal_id(&trace_context.trace_id) {
+                field_visitor.add_field("dd.trace_id", &trace_id.to_string());
+            }
+            if let Some(span_id) = trace_context.span_id.as_deref().and_then(decimal_id) {
+                field_visitor.add_field("dd.span_id", &span_id.to_string());
+            }
+        }
+
+        if self.display_logger_name {
+            field_visitor.add_field("logger.name", event_metadata.target());
+        }
+
+        if self.display_thread_name {
+            if let Some(name) = std::thread::current().name() {
+                field_visitor.add_field("logger.thread_name", name);
+            }
+        }
+
+        for (key, value) in &self.constants {
+            field_visitor.add_field(key, value);
+        }
+
+        self.field_contributor.add_fields(&mut field_visitor);
+
+        event.record(&mut field_visitor);
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    field_visitor.add_extension_fields(span_fields);
+                }
+            }
+        }
+
+        field_visitor.finish()?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::decimal_id;
+
+    #[test]
+    fn test_decimal_id() {
+        assert_eq!(
+            decimal_id("0af7651916cd43dd8448eb211c80319c"),
+            Some(0x8448eb211c80319c)
+        );
+        assert_eq!(decimal_id("b7ad6b7169203331"), Some(13235353014750950193));
+        assert_eq!(decimal_id("2a"), Some(42));
+        assert_eq!(decimal_id("not hex"), None);
+    }
+}
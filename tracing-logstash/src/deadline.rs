@@ -0,0 +1,119 @@
+//! Request deadlines, written as `request.deadline_ms_remaining` on the records logged while
+//! handling a request
+//!
+//! A middleware sets the deadline for the code handling a request with [`scope`], or for a future
+//! with [`with_deadline`], which sets it each time the future is polled. The [`RequestDeadline`]
+//! field contributor writes the milliseconds left until the deadline, negative once it has
+//! passed.
+//!
+//! # Example
+//! ```
+//! # use std::time::{Duration, Instant};
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::deadline::{self, RequestDeadline};
+//! #
+//! let logger = tracing_logstash::Layer::default().event_format(
+//!     tracing_logstash::logstash::LogstashFormat::default()
+//!         .with_field_contributor(RequestDeadline),
+//! );
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // In a middleware
+//! deadline::scope(Instant::now() + Duration::from_millis(500), || {
+//!     tracing::info!("handling request");
+//! });
+//! ```
+
+use crate::logstash::{LogFieldContributor, LogFieldReceiver};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+thread_local! {
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Restores the previous deadline when dropped, also when unwinding
+struct Restore(Option<Instant>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        DEADLINE.with(|deadline| deadline.set(self.0));
+    }
+}
+
+/// Runs `f` with `deadline` as the current deadline
+pub fn scope<R>(deadline: Instant, f: impl FnOnce() -> R) -> R {
+    let _restore = Restore(DEADLINE.with(|current| current.replace(Some(deadline))));
+    f()
+}
+
+/// The current deadline, if any
+pub fn current() -> Option<Instant> {
+    DEADLINE.with(Cell::get)
+}
+
+/// Makes `deadline` the current deadline whenever `future` is polled
+pub fn with_deadline<F: Future>(deadline: Instant, future: F) -> WithDeadline<F> {
+    WithDeadline {
+        deadline,
+        future: Box::pin(future),
+    }
+}
+
+/// A future with a deadline, see [`with_deadline`]
+pub struct WithDeadline<F> {
+    deadline: Instant,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithDeadline<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let deadline = self.deadline;
+        scope(deadline, || self.future.as_mut().poll(cx))
+    }
+}
+
+/// Field contributor writing the time left until the current deadline as
+/// `request.deadline_ms_remaining`
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RequestDeadline;
+
+impl LogFieldContributor for RequestDeadline {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        if let Some(deadline) = current() {
+            let now = Instant::now();
+            let remaining_ms = if deadline >= now {
+                deadline.duration_since(now).as_millis() as i64
+            } else {
+                -(now.duration_since(deadline).as_millis() as i64)
+            };
+            serializer.add_field("request.deadline_ms_remaining", &remaining_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{current, scope};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_scope_restores_deadline() {
+        let outer = Instant::now() + Duration::from_secs(10);
+        let inner = Instant::now() + Duration::from_secs(1);
+        assert_eq!(current(), None);
+        scope(outer, || {
+            scope(inner, || assert_eq!(current(), Some(inner)));
+            assert_eq!(current(), Some(outer));
+        });
+        assert_eq!(current(), None);
+    }
+}
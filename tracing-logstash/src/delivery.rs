@@ -0,0 +1,429 @@
+//! Sending records from a background thread, for the sinks delivering records over the network
+//! and the [`BatchWriter`](crate::batch::BatchWriter)
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records including the
+//! ones being sent. Beyond that, the [`BackpressurePolicy`] of the sink chooses between waiting
+//! for room, dropping the record, and dropping the oldest record. They are sent in batches by a thread
+//! started with the first record, when a batch has `batch_size` records or `batch_bytes` bytes,
+//! when its oldest record is older than the flush interval, or when flushing. A batch that could
+//! not be sent in the number of attempts is put back in front of the queue and resent after the
+//! flush interval.
+//!
+//! With more than one connection, a thread with its own transport is started per connection,
+//! each sending one batch at a time, taken from the front of the shared queue. Batches are then
+//! sent concurrently, and may be received out of order.
+//!
+//! When the last clone of a sink is dropped, the queued records are sent once more, and dropped
+//! if that fails, and the threads are stopped.
+//!
+//! With a fallback writer, records are written to it instead of being dropped, and a batch that
+//! could not be sent in the number of attempts is written to it instead of being resent.
+
+use crate::background::BackpressurePolicy;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing_core::Level;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Sends batches of records from the background thread
+pub(crate) trait Transport: Send + 'static {
+    type Record: Send + 'static;
+
+    /// Sends a batch, returning the number of records rejected by the receiver. Each attempt to
+    /// send a batch has the same sequence number, starting at 1 and unique across connections.
+    fn send(&mut self, batch: &[Self::Record], seq: u64) -> io::Result<u64>;
+
+    /// The record as written by the layer, for counting towards `batch_bytes` and writing to the
+    /// fallback writer
+    fn bytes(record: &Self::Record) -> &[u8];
+}
+
+/// How records are batched, taken from the configuration of the sink
+#[derive(Copy, Clone)]
+pub(crate) struct Batching {
+    pub(crate) batch_size: usize,
+    pub(crate) batch_bytes: usize,
+    pub(crate) max_pending: usize,
+    pub(crate) max_attempts: usize,
+    pub(crate) flush_interval: Duration,
+    /// Number of connections, each with a thread sending one batch at a time
+    pub(crate) connections: usize,
+    /// What to do with a record when `max_pending` records are queued
+    pub(crate) policy: BackpressurePolicy,
+}
+
+/// The queue of a sink and the thread sending its records, shared by the clones of the sink
+pub(crate) struct Delivery<R> {
+    shared: Arc<Shared<R>>,
+    /// Started with the first record, and stopped when the last clone is dropped
+    workers: Arc<OnceLock<Workers<R>>>,
+    fallback: Option<Arc<BoxMakeWriter>>,
+}
+
+impl<R> Clone for Delivery<R> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            workers: self.workers.clone(),
+            fallback: self.fallback.clone(),
+        }
+    }
+}
+
+struct Shared<R> {
+    queue: Mutex<Queue<R>>,
+    /// Signalled when a record is queued, a flush is requested or the sink is closed
+    queued: Condvar,
+    /// Signalled when a flush is done
+    flushed: Condvar,
+    /// Signalled when records have been sent, making room in the queue
+    room: Condvar,
+    dropped: AtomicU64,
+    fallbacks: AtomicU64,
+    /// The sequence number of the last batch sent
+    seq: AtomicU64,
+}
+
+struct Queue<R> {
+    /// The records, with the time they were queued
+    records: VecDeque<(Instant, R)>,
+    /// Number of records taken from the queue and being sent, by all connections
+    sending: usize,
+    flush_requested: u64,
+    flushed: u64,
+    /// The error of the last flush, if some records could not be sent
+    flush_error: Option<(io::ErrorKind, String)>,
+    closed: bool,
+}
+
+/// The threads sending the records, one per connection
+struct Workers<R> {
+    shared: Arc<Shared<R>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl<R> Drop for Workers<R> {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.queued.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<R> Shared<R> {
+    fn lock(&self) -> MutexGuard<'_, Queue<R>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes records that won't be sent to the fallback writer, counting the ones that could not
+    /// be written as dropped, and returns whether all of them were written
+    fn give_up<T: Transport<Record = R>>(
+        &self,
+        records: impl IntoIterator<Item = R>,
+        fallback: Option<&BoxMakeWriter>,
+    ) -> bool {
+        let mut written = true;
+        for record in records {
+            let fallen_back = fallback.is_some_and(|fallback| {
+                let mut writer = fallback.make_writer();
+                writer
+                    .write_all(T::bytes(&record))
+                    .and_then(|_| writer.flush())
+                    .is_ok()
+            });
+            if fallen_back {
+                self.fallbacks.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                written = false;
+            }
+        }
+        written
+    }
+
+    fn run<T: Transport<Record = R>>(
+        &self,
+        mut transport: T,
+        batching: Batching,
+        fallback: Option<Arc<BoxMakeWriter>>,
+    ) {
+        let fallback = fallback.as_deref();
+        // Set when a batch could not be sent, to wait before resending it
+        let mut retry_at = None;
+        let mut queue = self.lock();
+        loop {
+            let flushing = queue.flushed < queue.flush_requested;
+            let Some(&(oldest, _)) = queue.records.front() else {
+                // Done when the other connections have sent their batches too
+                if flushing && queue.sending == 0 {
+                    queue.flushed = queue.flush_requested;
+                    queue.flush_error = None;
+                    self.flushed.notify_all();
+                    continue;
+                }
+                if queue.closed {
+                    return;
+                }
+                queue = self.queued.wait(queue).unwrap_or_else(|e| e.into_inner());
+                continue;
+            };
+
+            let now = Instant::now();
+            let deadline = retry_at.unwrap_or(oldest + batching.flush_interval);
+            let (len, full) = next_batch::<T>(&queue.records, batching);
+            let full = retry_at.is_none() && full;
+            if !full && now < deadline && !flushing && !queue.closed {
+                queue = self
+                    .queued
+                    .wait_timeout(queue, deadline - now)
+                    .unwrap_or_else(|e| e.into_inner())
+                    .0;
+                continue;
+            }
+
+            let (times, records): (Vec<_>, Vec<_>) = queue.records.drain(..len).unzip();
+            queue.sending += len;
+            drop(queue);
+
+            let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+            let mut result = transport.send(&records, seq);
+            for _ in 1..batching.max_attempts {
+                if result.is_ok() {
+                    break;
+                }
+                result = transport.send(&records, seq);
+            }
+
+            queue = self.lock();
+            queue.sending -= len;
+            self.room.notify_all();
+            match result {
+                Ok(rejected) => {
+                    self.dropped.fetch_add(rejected, Ordering::Relaxed);
+                    retry_at = None;
+                }
+                Err(_) if queue.closed || fallback.is_some() => {
+                    let remaining = if queue.closed {
+                        queue.records.drain(..).map(|(_, record)| record).collect()
+                    } else {
+                        Vec::new()
+                    };
+                    drop(queue);
+                    self.give_up::<T>(records.into_iter().chain(remaining), fallback);
+                    queue = self.lock();
+                    retry_at = None;
+                }
+                Err(e) => {
+                    for record in times.into_iter().zip(records).rev() {
+                        queue.records.push_front(record);
+                    }
+                    let excess = queue.records.len().saturating_sub(batching.max_pending);
+                    queue.records.drain(..excess);
+                    self.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+                    retry_at = Some(Instant::now() + batching.flush_interval);
+                    // The other connections may send the batch before this one retries
+                    self.queued.notify_all();
+                    if flushing {
+                        queue.flushed = queue.flush_requested;
+                        queue.flush_error = Some((e.kind(), e.to_string()));
+                        self.flushed.notify_all();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The number of records in the next batch, and whether the batch is full
+fn next_batch<T: Transport>(
+    records: &VecDeque<(Instant, T::Record)>,
+    batching: Batching,
+) -> (usize, bool) {
+    let mut bytes = 0;
+    for (n, (_, record)) in records.iter().take(batching.batch_size).enumerate() {
+        bytes += T::bytes(record).len();
+        if bytes >= batching.batch_bytes {
+            return (n + 1, true);
+        }
+    }
+    let len = records.len().min(batching.batch_size);
+    (len, len == batching.batch_size)
+}
+
+impl<R: Send + 'static> Delivery<R> {
+    pub(crate) fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                queue: Mutex::new(Queue {
+                    records: VecDeque::new(),
+                    sending: 0,
+                    flush_requested: 0,
+                    flushed: 0,
+                    flush_error: None,
+                    closed: false,
+                }),
+                queued: Condvar::new(),
+                flushed: Condvar::new(),
+                room: Condvar::new(),
+                dropped: Default::default(),
+                fallbacks: Default::default(),
+                seq: Default::default(),
+            }),
+            workers: Default::default(),
+            fallback: None,
+        }
+    }
+
+    /// Write records to `fallback` instead of dropping them or resending them, taking effect if
+    /// set before the first record is queued
+    pub(crate) fn with_fallback<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            fallback: Some(Arc::new(BoxMakeWriter::new(fallback))),
+            ..self
+        }
+    }
+
+    pub(crate) fn fallbacks(&self) -> u64 {
+        self.shared.fallbacks.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Counts a record dropped by the sink before it was queued
+    pub(crate) fn count_dropped(&self) {
+        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Queues a record written at `level`, starting the threads with a transport made by
+    /// `transport` per connection for the first record. Records are dropped, failing, if no
+    /// thread could be started.
+    pub(crate) fn push<T>(
+        &self,
+        record: R,
+        level: Level,
+        batching: Batching,
+        transport: impl Fn() -> T,
+    ) -> io::Result<()>
+    where
+        T: Transport<Record = R>,
+    {
+        let workers = self.workers.get_or_init(|| {
+            let threads = (0..batching.connections.max(1))
+                .filter_map(|_| {
+                    let shared = self.shared.clone();
+                    let transport = transport();
+                    let fallback = self.fallback.clone();
+                    std::thread::Builder::new()
+                        .name("tracing-logstash-sink".to_owned())
+                        .spawn(move || shared.run(transport, batching, fallback))
+                        .ok()
+                })
+                .collect();
+            Workers {
+                shared: self.shared.clone(),
+                threads,
+            }
+        });
+        let fallback = self.fallback.as_deref();
+        if workers.threads.is_empty() {
+            return match self.shared.give_up::<T>([record], fallback) {
+                true => Ok(()),
+                false => Err(io::Error::other("sink thread could not be started")),
+            };
+        }
+
+        let mut queue = self.shared.lock();
+        let mut oldest = None;
+        while queue.records.len() + queue.sending >= batching.max_pending {
+            let block = match batching.policy {
+                BackpressurePolicy::Block => true,
+                BackpressurePolicy::DropNewest => false,
+                BackpressurePolicy::DropOldest => match queue.records.pop_front() {
+                    Some((_, popped)) => {
+                        oldest = Some(popped);
+                        break;
+                    }
+                    // All of them are being sent
+                    None => false,
+                },
+                // More verbose levels compare greater
+                BackpressurePolicy::DropBelow(threshold) => level <= threshold,
+            };
+            if !block {
+                drop(queue);
+                return match self.shared.give_up::<T>([record], fallback) {
+                    true => Ok(()),
+                    false => Err(io::Error::new(io::ErrorKind::WouldBlock, "queue full")),
+                };
+            }
+            queue = self
+                .shared
+                .room
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        queue.records.push_back((Instant::now(), record));
+        self.shared.queued.notify_one();
+        drop(queue);
+        self.shared.give_up::<T>(oldest, fallback);
+        Ok(())
+    }
+
+    /// Queues a record with `push` and waits for it to be sent, failing if it could not be, or if
+    /// records were dropped or written to the fallback writer meanwhile
+    pub(crate) fn acknowledged(&self, push: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        let undelivered = || self.dropped() + self.fallbacks();
+        let before = undelivered();
+        push()?;
+        self.flush()?;
+        if undelivered() > before {
+            return Err(io::Error::other("record not delivered"));
+        }
+        Ok(())
+    }
+
+    /// Whether no records are queued or being sent
+    pub(crate) fn is_idle(&self) -> bool {
+        let queue = self.shared.lock();
+        queue.records.is_empty() && queue.sending == 0
+    }
+
+    /// Waits for the queued records to be sent, failing with the error of the last attempt if
+    /// some could not be
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        if self
+            .workers
+            .get()
+            .is_none_or(|workers| workers.threads.is_empty())
+        {
+            return Ok(());
+        }
+        let mut queue = self.shared.lock();
+        queue.flush_requested += 1;
+        let flush = queue.flush_requested;
+        self.shared.queued.notify_all();
+        while queue.flushed < flush {
+            queue = self
+                .shared
+                .flushed
+                .wait(queue)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+        match &queue.flush_error {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone())),
+            None => Ok(()),
+        }
+    }
+}
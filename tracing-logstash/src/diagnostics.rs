@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters for unexpected conditions the layer recovered from
+///
+/// Obtained from [`Layer::diagnostics`](crate::Layer::diagnostics).
+#[derive(Default, Debug)]
+pub struct Diagnostics {
+    missing_spans: AtomicU64,
+    write_errors: AtomicU64,
+    oversized_records: AtomicU64,
+    unshrinkable_records: AtomicU64,
+}
+
+impl Diagnostics {
+    /// Times a span the layer was notified about could not be found in the registry
+    pub fn missing_spans(&self) -> u64 {
+        self.missing_spans.load(Ordering::Relaxed)
+    }
+
+    /// Records dropped because they could not be serialized or written, counted with
+    /// [`WriteErrorPolicy::Count`](crate::WriteErrorPolicy::Count)
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// Records shrunk because they were larger than the
+    /// [maximum record size](crate::Layer::with_max_record_bytes)
+    pub fn oversized_records(&self) -> u64 {
+        self.oversized_records.load(Ordering::Relaxed)
+    }
+
+    /// Records larger than the [maximum record size](crate::Layer::with_max_record_bytes) that
+    /// were written as they were, because they are not JSON objects the format can shrink
+    pub fn unshrinkable_records(&self) -> u64 {
+        self.unshrinkable_records.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_missing_span(&self) {
+        self.missing_spans.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_oversized_record(&self) {
+        self.oversized_records.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_unshrinkable_record(&self) {
+        self.unshrinkable_records.fetch_add(1, Ordering::Relaxed);
+    }
+}
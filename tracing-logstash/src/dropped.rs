@@ -0,0 +1,128 @@
+//! Writing a record about the records dropped by the writers, so that data loss shows in the log
+//! stream itself
+//!
+//! The writers dropping records, such as a [`BackgroundWriter`](crate::background) with a full
+//! queue or a network sink that could not deliver, count them. The counters are added as
+//! sources of a [`DroppedSummary`], and when records were dropped since the last summary, the
+//! layer writes a record like
+//! `{"message":"dropped 132 log events","logger_name":"tracing_logstash","dropped":132}` at
+//! level `WARN`.
+//!
+//! The counters are checked when events are logged, at most once per interval, so a summary is
+//! written before the first event logged after the interval.
+//!
+//! # Example
+//! ```
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::background::BackgroundWriter;
+//! # use tracing_logstash::dropped::DroppedSummary;
+//! #
+//! let writer = BackgroundWriter::new(std::io::stdout).unwrap();
+//! let summary = DroppedSummary::new(Duration::from_secs(60)).with_source({
+//!     let writer = writer.clone();
+//!     move || writer.dropped()
+//! });
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .with_writer(writer)
+//!     .with_dropped_summary(summary);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing_core::callsite::{Callsite, Identifier};
+use tracing_core::field::{FieldSet, Value};
+use tracing_core::metadata::Kind;
+use tracing_core::subscriber::Interest;
+use tracing_core::{Event, Level, Metadata};
+
+/// Target of the summary records
+pub const TARGET: &str = "tracing_logstash";
+
+struct DroppedCallsite;
+
+static CALLSITE: DroppedCallsite = DroppedCallsite;
+
+static METADATA: Metadata<'static> = Metadata::new(
+    "tracing_logstash.dropped",
+    TARGET,
+    Level::WARN,
+    None,
+    None,
+    None,
+    FieldSet::new(&["message", "dropped"], Identifier(&CALLSITE)),
+    Kind::EVENT,
+);
+
+impl Callsite for DroppedCallsite {
+    fn set_interest(&self, _interest: Interest) {}
+
+    fn metadata(&self) -> &Metadata<'_> {
+        &METADATA
+    }
+}
+
+/// Counters of dropped records summarized by the layer, see the [module](self) documentation
+pub struct DroppedSummary {
+    interval: Duration,
+    sources: Vec<Box<dyn Fn() -> u64 + Send + Sync>>,
+    state: Mutex<State>,
+}
+
+struct State {
+    checked: Instant,
+    reported: u64,
+}
+
+impl DroppedSummary {
+    /// Check the counters at most once per `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            sources: Vec::new(),
+            state: Mutex::new(State {
+                checked: Instant::now(),
+                reported: 0,
+            }),
+        }
+    }
+
+    /// Add a counter of dropped records, returning the total dropped so far, such as
+    /// [`BackgroundWriter::dropped`](crate::background::BackgroundWriter::dropped)
+    pub fn with_source(mut self, source: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// The number of records dropped since the last summary, if the interval has passed and any
+    /// were dropped
+    pub(crate) fn take_due(&self, now: Instant) -> Option<u64> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.duration_since(state.checked) < self.interval {
+            return None;
+        }
+        state.checked = now;
+        let total = self.sources.iter().map(|source| source()).sum::<u64>();
+        let dropped = total.saturating_sub(state.reported);
+        state.reported = total;
+        (dropped > 0).then_some(dropped)
+    }
+}
+
+/// Calls `f` with the summary of `dropped` records as a root event
+pub(crate) fn with_event<R>(dropped: u64, f: impl FnOnce(&Event<'_>) -> R) -> R {
+    let message = format!("dropped {} log events", dropped);
+    let fields = METADATA.fields();
+    let field = |name| fields.field(name).expect("summary fields are static");
+    let values: [(_, Option<&dyn Value>); 2] = [
+        (&field("message"), Some(&message.as_str() as &dyn Value)),
+        (&field("dropped"), Some(&dropped as &dyn Value)),
+    ];
+    f(&Event::new_child_of(
+        None,
+        &METADATA,
+        &fields.value_set(&values),
+    ))
+}
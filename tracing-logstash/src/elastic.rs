@@ -0,0 +1,154 @@
+use crate::format::{FormatEvent, MakeSerializer, RecordKeys};
+use crate::logstash::LogstashFormat;
+use crate::template::Template;
+use serde::Serializer;
+use std::io::Write;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format writing the records of another format as entries of an Elasticsearch
+/// [`_bulk`](https://www.elastic.co/guide/en/elasticsearch/reference/current/docs-bulk.html)
+/// request body
+///
+/// Each record is an action line naming the index, followed by the record of the wrapped format
+/// on the next line. With the default newline record separator, concatenated records are a valid
+/// NDJSON `_bulk` body, so batches can be posted as is by the writer, such as the
+/// `elasticsearch::ElasticsearchSink` of the `elasticsearch` feature. The index name is a
+/// [`Template`] rendered with the time and the event fields, like `logs-{date:%Y.%m.%d}`.
+///
+/// When used with [`FormatEvent::format_event`] directly, only the record of the wrapped format
+/// is written.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::template::Template;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::elastic::BulkFormat::new(
+///         Template::parse("logs-{field:tenant|default:shared}-{date:%Y.%m.%d}").unwrap(),
+///         tracing_logstash::logstash::LogstashFormat::default(),
+///     )
+///     .with_action(tracing_logstash::elastic::BulkAction::Create),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct BulkFormat<E = LogstashFormat> {
+    index: Template,
+    action: BulkAction,
+    event_format: E,
+}
+
+/// The bulk action of each record
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum BulkAction {
+    /// Adds or replaces the document, for regular indices
+    #[default]
+    Index,
+    /// Adds the document, as required for data streams
+    Create,
+}
+
+impl BulkAction {
+    const fn name(self) -> &'static str {
+        match self {
+            BulkAction::Index => "index",
+            BulkAction::Create => "create",
+        }
+    }
+}
+
+impl<E> BulkFormat<E> {
+    /// Records of `event_format` written to the index named by rendering `index`
+    pub fn new(index: Template, event_format: E) -> Self {
+        Self {
+            index,
+            action: BulkAction::Index,
+            event_format,
+        }
+    }
+
+    pub fn with_action(self, action: BulkAction) -> Self {
+        Self { action, ..self }
+    }
+
+    fn index_name(&self, event: &Event<'_>) -> String {
+        let mut fields = IndexFields(Vec::new());
+        if self.index.uses_fields() {
+            event.record(&mut fields);
+        }
+        self.index
+            .render(time::OffsetDateTime::now_utc(), &fields.0[..])
+    }
+}
+
+/// String representations of the event fields, for rendering the index name
+struct IndexFields(Vec<(&'static str, String)>);
+
+impl Visit for IndexFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+impl<E: FormatEvent> FormatEvent for BulkFormat<E> {
+    type R = E::R;
+
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        self.event_format.event_level(event)
+    }
+
+    fn span_recorder(&self) -> Self::R {
+        self.event_format.span_recorder()
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        // Records are an action line followed by the document
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.event_format.format_event(serializer, event, ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.event_format
+            .format_event_at(serializer, event, level, ctx)
+    }
+
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> std::io::Result<()> {
+        let action = serde_json::json!({
+            self.action.name(): { "_index": self.index_name(event) }
+        });
+        serde_json::to_writer(&mut *buffer, &action)?;
+        buffer.write_all(b"\n")?;
+        self.event_format
+            .write_event(make_serializer, buffer, event, level, ctx)
+    }
+}
@@ -0,0 +1,395 @@
+//! Delivery of records to Elasticsearch with the
+//! [`_bulk`](https://www.elastic.co/guide/en/elasticsearch/reference/current/docs-bulk.html) API,
+//! for deployments without Logstash
+//!
+//! The records are the bulk entries written by [`BulkFormat`](crate::elastic::BulkFormat), an
+//! action line naming the index followed by the document, and are posted in `_bulk` requests of
+//! up to `batch_size` records over plain HTTP/1.1; TLS is not supported. A request is resent,
+//! after reconnecting, until Elasticsearch answers it or the number of attempts runs out, so
+//! records may be indexed more than once without document ids. Requests answered with
+//! `429 Too Many Requests` or a server error, and requests that could not be sent, are kept and
+//! resent after the flush interval. Records Elasticsearch rejects, in a request it answers with
+//! another client error or in the items of a successful response, are dropped and counted.
+//!
+//! With document ids, each action gets an `_id` made of an id of the sink, differing between
+//! sinks and runs, and the number of the record, the same for each attempt to send it. A record
+//! resent after it was indexed then does not add a second document: with the `create` action,
+//! Elasticsearch rejects it with `409 Conflict`, counted as a duplicate rather than as dropped, and
+//! with the `index` action, it replaces the document.
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records; beyond that the
+//! oldest records are dropped and counted, or the records chosen by another [`BackpressurePolicy`].
+//! They are sent from a background thread, started with the first record, when a batch is full or
+//! the oldest queued record is older than the flush interval. Call [`ElasticsearchSink::flush`]
+//! before exiting to wait for the queued records to be sent. When the last clone of the sink is
+//! dropped, the queued records are sent once more and the thread is stopped.
+//!
+//! # Example
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::elastic::BulkFormat;
+//! # use tracing_logstash::elasticsearch::ElasticsearchSink;
+//! # use tracing_logstash::logstash::LogstashFormat;
+//! # use tracing_logstash::template::Template;
+//! #
+//! let sink = ElasticsearchSink::new("http://elasticsearch:9200")
+//!     .unwrap()
+//!     .with_header("Authorization", "ApiKey c2VjcmV0")
+//!     .with_document_ids(true)
+//!     .with_batch_size(256)
+//!     .with_flush_interval(Duration::from_secs(1));
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .event_format(BulkFormat::new(
+//!         Template::parse("logs-{date:%Y.%m.%d}").unwrap(),
+//!         LogstashFormat::default(),
+//!     ))
+//!     .with_writer(sink.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! sink.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::http::{Connection, Url};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use serde_json::Value;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer posting records to Elasticsearch, see the [module](self) documentation
+///
+/// Clones share the same connection and queued records.
+#[derive(Clone)]
+pub struct ElasticsearchSink {
+    config: Arc<Config>,
+    delivery: Delivery<Entry>,
+    /// The number of the next record
+    records: Arc<AtomicU64>,
+    duplicates: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+struct Config {
+    url: Url,
+    /// The path of the `_bulk` endpoint
+    path: String,
+    headers: Vec<(String, String)>,
+    /// The id of the sink, in the document ids
+    id: u64,
+    document_ids: bool,
+    batch_size: usize,
+    max_pending: usize,
+    max_attempts: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    policy: BackpressurePolicy,
+}
+
+impl Config {
+    fn batching(&self) -> Batching {
+        Batching {
+            batch_size: self.batch_size,
+            batch_bytes: usize::MAX,
+            max_pending: self.max_pending,
+            max_attempts: self.max_attempts,
+            flush_interval: self.flush_interval,
+            connections: 1,
+            policy: self.policy,
+        }
+    }
+}
+
+/// The connection to Elasticsearch, used from the background thread
+struct Bulk {
+    config: Arc<Config>,
+    connection: Connection,
+    duplicates: Arc<AtomicU64>,
+}
+
+/// A bulk entry as written by the layer, with its number
+struct Entry {
+    number: u64,
+    record: Vec<u8>,
+}
+
+impl ElasticsearchSink {
+    /// A sink for the cluster at `url`, such as `http://localhost:9200`, which is resolved once.
+    /// A path in the URL is prefixed to `/_bulk`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let url = Url::parse(url, 9200)?;
+        Ok(Self {
+            config: Arc::new(Config {
+                path: format!("{}/_bulk", url.path),
+                url,
+                headers: Vec::new(),
+                id: sink_id(),
+                document_ids: false,
+                batch_size: 64,
+                max_pending: 10_000,
+                max_attempts: 3,
+                flush_interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                policy: BackpressurePolicy::DropOldest,
+            }),
+            delivery: Delivery::new(),
+            records: Default::default(),
+            duplicates: Default::default(),
+        })
+    }
+
+    fn with_config(self, f: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Arc::unwrap_or_clone(self.config);
+        f(&mut config);
+        Self {
+            config: Arc::new(config),
+            ..self
+        }
+    }
+
+    /// A header sent with each request, such as `Authorization`
+    pub fn with_header(self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let header = (name.into(), value.into());
+        self.with_config(|config| config.headers.push(header))
+    }
+
+    /// Give each action an `_id`, so records resent after they were indexed do not add documents,
+    /// defaults to false
+    pub fn with_document_ids(self, document_ids: bool) -> Self {
+        self.with_config(|config| config.document_ids = document_ids)
+    }
+
+    /// Number of records per request, defaults to 64
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        self.with_config(|config| config.batch_size = batch_size.max(1))
+    }
+
+    /// Maximum number of records queued, such as while the cluster is unreachable, defaults to
+    /// 10000
+    pub fn with_max_pending(self, max_pending: usize) -> Self {
+        self.with_config(|config| config.max_pending = max_pending.max(1))
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(self, policy: BackpressurePolicy) -> Self {
+        self.with_config(|config| config.policy = policy)
+    }
+
+    /// Number of connection attempts per request, defaults to 3
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        self.with_config(|config| config.max_attempts = max_attempts.max(1))
+    }
+
+    /// Send a partial batch when its oldest record is older than this, defaults to one second
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        self.with_config(|config| config.flush_interval = flush_interval)
+    }
+
+    /// Timeout for connecting, writing and waiting for responses, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_config(|config| config.timeout = timeout)
+    }
+
+    /// Number of records dropped because too many records were queued, because Elasticsearch
+    /// rejected them, or because they could not be sent when the sink was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Number of records Elasticsearch rejected as duplicates of documents with the same id,
+    /// already indexed by an earlier attempt, see [`with_document_ids`](Self::with_document_ids)
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of requests that
+    /// could not be sent in the number of attempts instead of keeping them, see
+    /// [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be sent, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+
+    fn push(&self, record: Vec<u8>, level: Level) -> io::Result<()> {
+        let entry = Entry {
+            number: self.records.fetch_add(1, Ordering::Relaxed),
+            record,
+        };
+        self.delivery
+            .push(entry, level, self.config.batching(), || Bulk {
+                connection: Connection::new(&self.config.url, self.config.timeout),
+                config: self.config.clone(),
+                duplicates: self.duplicates.clone(),
+            })
+    }
+}
+
+impl Transport for Bulk {
+    type Record = Entry;
+
+    fn send(&mut self, batch: &[Entry], _seq: u64) -> io::Result<u64> {
+        let mut body = Vec::new();
+        for entry in batch {
+            let record = trim_separator(&entry.record);
+            if self.config.document_ids {
+                let id = format!("{:016x}-{:x}", self.config.id, entry.number);
+                push_with_id(&mut body, record, &id);
+            } else {
+                body.extend_from_slice(record);
+            }
+            body.push(b'\n');
+        }
+        let mut headers = vec![("Content-Type", "application/x-ndjson")];
+        headers.extend(
+            self.config
+                .headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        let (status, body) = self.connection.post(&self.config.path, &headers, &body)?;
+        match status {
+            200..=299 => {
+                let (rejected, duplicates) = rejected_items(&body);
+                self.duplicates.fetch_add(duplicates, Ordering::Relaxed);
+                Ok(rejected)
+            }
+            429 | 500..=599 => Err(io::Error::other(format!(
+                "elasticsearch responded {}",
+                status
+            ))),
+            _ => Ok(batch.len() as u64),
+        }
+    }
+
+    fn bytes(entry: &Entry) -> &[u8] {
+        &entry.record
+    }
+}
+
+/// An id for the documents of a sink, differing between sinks and runs
+fn sink_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    std::process::id().hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Appends the bulk entry `record` to `body`, with `id` as the `_id` of its action
+fn push_with_id(body: &mut Vec<u8>, record: &[u8], id: &str) {
+    let action_len = record
+        .iter()
+        .position(|b| *b == b'\n')
+        .unwrap_or(record.len());
+    let (action, document) = record.split_at(action_len);
+    match action.strip_suffix(b"}}") {
+        Some(action) => {
+            body.extend_from_slice(action);
+            body.extend_from_slice(format!(",\"_id\":\"{}\"}}}}", id).as_bytes());
+            body.extend_from_slice(document);
+        }
+        None => body.extend_from_slice(record),
+    }
+}
+
+/// Number of items of a `_bulk` response that were not indexed, apart from the duplicates of
+/// already indexed documents, and the number of duplicates
+fn rejected_items(body: &[u8]) -> (u64, u64) {
+    let Ok(response) = serde_json::from_slice::<Value>(body) else {
+        return (0, 0);
+    };
+    if response["errors"] != Value::Bool(true) {
+        return (0, 0);
+    }
+    let Some(items) = response["items"].as_array() else {
+        return (0, 0);
+    };
+    let statuses = items
+        .iter()
+        .filter_map(|item| item.as_object()?.values().next()?["status"].as_u64())
+        .filter(|status| *status >= 300)
+        .collect::<Vec<_>>();
+    let duplicates = statuses.iter().filter(|status| **status == 409).count() as u64;
+    (statuses.len() as u64 - duplicates, duplicates)
+}
+
+impl WriteRecord for ElasticsearchSink {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if trim_separator(record).is_empty() {
+            return Ok(());
+        }
+        self.push(record.to_vec(), level)
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for ElasticsearchSink {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{push_with_id, rejected_items};
+
+    #[test]
+    fn test_rejected_items() {
+        let body =
+            br#"{"errors":true,"items":[{"create":{"status":201}},{"create":{"status":400}}]}"#;
+        assert_eq!(rejected_items(body), (1, 0));
+        let body =
+            br#"{"errors":true,"items":[{"create":{"status":409}},{"create":{"status":400}}]}"#;
+        assert_eq!(rejected_items(body), (1, 1));
+        assert_eq!(rejected_items(br#"{"errors":false,"items":[]}"#), (0, 0));
+    }
+
+    #[test]
+    fn test_push_with_id() {
+        let mut body = Vec::new();
+        push_with_id(
+            &mut body,
+            b"{\"create\":{\"_index\":\"logs\"}}\n{\"message\":\"one\"}",
+            "1f-0",
+        );
+        assert_eq!(
+            body,
+            b"{\"create\":{\"_index\":\"logs\",\"_id\":\"1f-0\"}}\n{\"message\":\"one\"}"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Writing a last record when the process is crashing, from signal handlers and the panic hook,
+//! where the layer can't be used
+//!
+//! An [`EmergencyWriter`] writes to a file descriptor opened up front, such as a duplicate of
+//! stderr, with the start of the record formatted up front. Writing a record does not allocate
+//! or take locks: it is formatted in a buffer on the stack and written with a single `write`,
+//! like
+//! `{"@version":"1","service.name":"checkout","level":"FATAL","@timestamp":"2024-05-01T12:00:00Z","pid":4211,"reason":"SIGSEGV"}`.
+//!
+//! Records are at most 4096 bytes, with the constants limited to about half of that and the
+//! reason truncated to fit.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_logstash::emergency::{self, EmergencyWriter};
+//! #
+//! EmergencyWriter::stderr()
+//!     .unwrap()
+//!     .with_constants(vec![("service.name", "checkout".to_owned())])
+//!     .install()
+//!     .ok();
+//! emergency::install_panic_hook();
+//!
+//! // In a signal handler
+//! emergency::log("SIGSEGV");
+//! ```
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::sync::OnceLock;
+use time::OffsetDateTime;
+
+const RECORD_CAPACITY: usize = 4096;
+
+/// Maximum size of the start of the record, leaving room for the reason
+const MAX_PREFIX: usize = RECORD_CAPACITY / 2;
+
+const SUFFIX: &[u8] = b"\"}\n";
+
+static INSTALLED: OnceLock<EmergencyWriter> = OnceLock::new();
+
+/// Writes last-gasp records to a file descriptor, see the [module](self) documentation
+pub struct EmergencyWriter {
+    file: File,
+    prefix: Vec<u8>,
+}
+
+impl EmergencyWriter {
+    /// Write records to `fd`
+    pub fn new(fd: OwnedFd) -> Self {
+        let mut writer = Self {
+            file: File::from(fd),
+            prefix: Vec::new(),
+        };
+        writer.format_prefix(&[]);
+        writer
+    }
+
+    /// Write records to a duplicate of stderr
+    pub fn stderr() -> io::Result<Self> {
+        Ok(Self::new(io::stderr().as_fd().try_clone_to_owned()?))
+    }
+
+    /// Fields written in every record. Constants that don't fit the prefix are left out.
+    pub fn with_constants(mut self, constants: Vec<(&'static str, String)>) -> Self {
+        self.format_prefix(&constants);
+        self
+    }
+
+    fn format_prefix(&mut self, constants: &[(&'static str, String)]) {
+        let end = b"\"level\":\"FATAL\",";
+        let mut prefix = b"{\"@version\":\"1\",".to_vec();
+        for (key, value) in constants {
+            let mut field = serde_json::to_vec(key).unwrap_or_default();
+            field.push(b':');
+            field.extend(serde_json::to_vec(value).unwrap_or_default());
+            field.push(b',');
+            if prefix.len() + field.len() + end.len() <= MAX_PREFIX {
+                prefix.extend(field);
+            }
+        }
+        prefix.extend_from_slice(end);
+        self.prefix = prefix;
+    }
+
+    /// Install as the writer used by [`log`], failing if one is already installed
+    pub fn install(self) -> Result<(), Self> {
+        INSTALLED.set(self)
+    }
+
+    /// Write a record with `reason`, without allocating
+    pub fn write(&self, reason: &str) -> io::Result<()> {
+        let mut record = StackRecord {
+            buf: [0; RECORD_CAPACITY],
+            len: 0,
+        };
+        record.push(&self.prefix);
+
+        record.push(b"\"@timestamp\":\"");
+        let mut timestamp = [0u8; 64];
+        let mut cursor = &mut timestamp[..];
+        if OffsetDateTime::now_utc()
+            .format_into(&mut cursor, &time::format_description::well_known::Rfc3339)
+            .is_ok()
+        {
+            let written = 64 - cursor.len();
+            record.push(&timestamp[..written]);
+        }
+
+        record.push(b"\",\"pid\":");
+        let mut digits = [0u8; 10];
+        let mut pid = std::process::id();
+        let mut start = digits.len();
+        loop {
+            start -= 1;
+            digits[start] = b'0' + (pid % 10) as u8;
+            pid /= 10;
+            if pid == 0 {
+                break;
+            }
+        }
+        record.push(&digits[start..]);
+
+        record.push(b",\"reason\":\"");
+        let mut escaped = [0u8; 6];
+        for c in reason.chars() {
+            let bytes: &[u8] = match c {
+                '"' => b"\\\"",
+                '\\' => b"\\\\",
+                c if (c as u32) < 0x20 => {
+                    const HEX: &[u8; 16] = b"0123456789abcdef";
+                    escaped = *b"\\u0000";
+                    escaped[4] = HEX[(c as usize) >> 4];
+                    escaped[5] = HEX[(c as usize) & 0xf];
+                    &escaped
+                }
+                c => c.encode_utf8(&mut escaped).as_bytes(),
+            };
+            if record.len + bytes.len() + SUFFIX.len() > RECORD_CAPACITY {
+                break;
+            }
+            record.push(bytes);
+        }
+        record.push(SUFFIX);
+
+        (&self.file).write_all(&record.buf[..record.len])
+    }
+}
+
+/// A record formatted on the stack
+struct StackRecord {
+    buf: [u8; RECORD_CAPACITY],
+    len: usize,
+}
+
+impl StackRecord {
+    /// Appends `bytes` if they fit
+    fn push(&mut self, bytes: &[u8]) {
+        if let Some(buf) = self.buf.get_mut(self.len..self.len + bytes.len()) {
+            buf.copy_from_slice(bytes);
+            self.len += bytes.len();
+        }
+    }
+}
+
+/// Write a record with `reason` with the [installed](EmergencyWriter::install) writer, if any.
+/// Safe to call from signal handlers.
+pub fn log(reason: &str) {
+    if let Some(writer) = INSTALLED.get() {
+        let _ = writer.write(reason);
+    }
+}
+
+/// Write a record with the panic message with the installed writer when a thread panics,
+/// before calling the previous panic hook
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let reason = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("panic");
+        log(reason);
+        previous(info);
+    }));
+}
@@ -0,0 +1,164 @@
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::fmt::Debug;
+use tracing_core::field::{Field, Visit};
+use tracing_core::Event;
+
+/// Promotes numeric event fields to CloudWatch metrics, using the
+/// [Embedded Metric Format](https://docs.aws.amazon.com/AmazonCloudWatch/latest/monitoring/CloudWatch_Embedded_Metric_Format_Specification.html)
+///
+/// Events with at least one numeric metric field get an `_aws` metadata block referencing the
+/// metric fields they have. The fields themselves, and the dimension fields, are written as
+/// usual.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::emf::EmfMetrics;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default()
+///         .with_constants(vec![("service", "checkout".to_owned())])
+///         .with_emf_metrics(Some(
+///             EmfMetrics::new("Checkout")
+///                 .with_metric("latency_ms", "Milliseconds")
+///                 .with_metric("items", "Count")
+///                 .with_dimensions(vec!["service"]),
+///         )),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone, Debug)]
+pub struct EmfMetrics {
+    namespace: String,
+    metrics: Vec<(&'static str, &'static str)>,
+    dimensions: Vec<&'static str>,
+}
+
+impl EmfMetrics {
+    pub fn new(namespace: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            metrics: Vec::new(),
+            dimensions: Vec::new(),
+        }
+    }
+
+    /// Promote the field `name` to a metric with a CloudWatch unit, such as `Count`,
+    /// `Milliseconds` or `Bytes`
+    pub fn with_metric(mut self, name: &'static str, unit: &'static str) -> Self {
+        self.metrics.push((name, unit));
+        self
+    }
+
+    /// Names of the fields to use as the dimensions of the metrics
+    pub fn with_dimensions(self, dimensions: Vec<&'static str>) -> Self {
+        Self { dimensions, ..self }
+    }
+
+    /// The `_aws` metadata block for an event, or `None` if it has no metric fields
+    pub(crate) fn metadata(&self, event: &Event<'_>) -> Option<EmfMetadata<'_>> {
+        let mut visitor = MetricVisitor(self, Vec::new());
+        event.record(&mut visitor);
+        if visitor.1.is_empty() {
+            return None;
+        }
+        Some(EmfMetadata {
+            config: self,
+            metrics: visitor.1,
+            timestamp: (time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000) as i64,
+        })
+    }
+}
+
+pub(crate) struct EmfMetadata<'a> {
+    config: &'a EmfMetrics,
+    metrics: Vec<(&'static str, &'static str)>,
+    timestamp: i64,
+}
+
+impl Serialize for EmfMetadata<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(2))?;
+        s.serialize_entry("Timestamp", &self.timestamp)?;
+        s.serialize_entry("CloudWatchMetrics", &[MetricDirective(self)])?;
+        s.end()
+    }
+}
+
+struct MetricDirective<'a>(&'a EmfMetadata<'a>);
+
+impl Serialize for MetricDirective<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(3))?;
+        s.serialize_entry("Namespace", &self.0.config.namespace)?;
+        s.serialize_entry("Dimensions", &[&self.0.config.dimensions])?;
+        s.serialize_entry(
+            "Metrics",
+            &self
+                .0
+                .metrics
+                .iter()
+                .map(|(name, unit)| MetricDefinition { name, unit })
+                .collect::<Vec<_>>(),
+        )?;
+        s.end()
+    }
+}
+
+struct MetricDefinition<'a> {
+    name: &'a str,
+    unit: &'a str,
+}
+
+impl Serialize for MetricDefinition<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(2))?;
+        s.serialize_entry("Name", self.name)?;
+        s.serialize_entry("Unit", self.unit)?;
+        s.end()
+    }
+}
+
+struct MetricVisitor<'a>(&'a EmfMetrics, Vec<(&'static str, &'static str)>);
+
+impl MetricVisitor<'_> {
+    fn record_metric(&mut self, field: &Field) {
+        if let Some(metric) = self
+            .0
+            .metrics
+            .iter()
+            .find(|(name, _)| *name == field.name())
+        {
+            if !self.1.contains(metric) {
+                self.1.push(*metric);
+            }
+        }
+    }
+}
+
+impl Visit for MetricVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, _value: f64) {
+        self.record_metric(field);
+    }
+
+    fn record_i64(&mut self, field: &Field, _value: i64) {
+        self.record_metric(field);
+    }
+
+    fn record_u64(&mut self, field: &Field, _value: u64) {
+        self.record_metric(field);
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn Debug) {}
+}
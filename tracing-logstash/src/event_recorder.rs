@@ -1,20 +1,17 @@
 use crate::fields::{FieldConfig, FieldRecorder, FieldVisitor, RecordedValue, TryForEachField};
+use std::borrow::Cow;
 use std::sync::Arc;
 use tracing_core::field::Field;
 use tracing_core::Event;
 
-pub trait EventRecorder {
-    fn record_event(&mut self, event: &Event<'_>);
-}
-
 pub struct DefaultEventRecorder {
     config: Arc<FieldConfig>,
     fields: Vec<RecordedValue>,
 }
 
 impl TryForEachField for DefaultEventRecorder {
-    fn try_for_each<E, F: FnMut(&'static str, &RecordedValue) -> Result<(), E>>(
-        &self,
+    fn try_for_each<'a, E, F: FnMut(&'a Cow<'static, str>, &'a RecordedValue) -> Result<(), E>>(
+        &'a self,
         mut f: F,
     ) -> Result<(), E> {
         for (name, value) in self.config.event_field_names.iter().zip(self.fields.iter()) {
@@ -24,12 +21,6 @@ impl TryForEachField for DefaultEventRecorder {
     }
 }
 
-impl EventRecorder for DefaultEventRecorder {
-    fn record_event(&mut self, event: &Event<'_>) {
-        event.record(&mut FieldVisitor::new(self))
-    }
-}
-
 #[allow(dead_code)]
 impl DefaultEventRecorder {
     pub fn from_config(config: Arc<FieldConfig>) -> Self {
@@ -39,12 +30,21 @@ impl DefaultEventRecorder {
             fields: vec![RecordedValue::Unset; n],
         }
     }
+
+    pub fn record_event(&mut self, event: &Event<'_>) {
+        event.record(&mut FieldVisitor::new(self))
+    }
 }
 
 impl FieldRecorder for DefaultEventRecorder {
     fn record_field(&mut self, field: &Field, value: impl Into<RecordedValue>) {
         if let Some(i) = self.config.event_field_index(field) {
-            self.fields[i] = value.into();
+            let value = self.config.event_value(i, value.into());
+            if self.config.is_event_array(i) {
+                self.fields[i].push(value);
+            } else {
+                self.fields[i] = value;
+            }
         }
     }
 }
@@ -0,0 +1,114 @@
+//! Writing records to a secondary writer when the primary writer fails, such as to stderr or a
+//! local spool file while a collector is unreachable
+//!
+//! Each record is written and flushed to the primary writer, and written whole to the fallback
+//! writer if that fails. Only errors returned by the primary writer are seen, so records that
+//! writers queueing records fail to send later never fall back. The network sinks and the
+//! [`BatchWriter`](crate::batch::BatchWriter) take a fallback writer of their own for those, with
+//! `with_fallback_writer`.
+//!
+//! # Example
+//! ```no_run
+//! # use std::net::TcpStream;
+//! # use std::sync::Mutex;
+//! # use tracing_subscriber::prelude::*;
+//! #
+//! let stream = Mutex::new(TcpStream::connect("logstash:5000").unwrap());
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .with_writer(stream)
+//!     .with_fallback_writer(std::io::stderr);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing_core::Metadata;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer falling back to a secondary writer, see the [module](self) documentation
+///
+/// Clones share the same counters.
+#[derive(Clone)]
+pub struct FallbackWriter<P, F> {
+    primary: P,
+    fallback: F,
+    fallbacks: Arc<AtomicU64>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<P, F> FallbackWriter<P, F> {
+    pub fn new(primary: P, fallback: F) -> Self {
+        Self {
+            primary,
+            fallback,
+            fallbacks: Default::default(),
+            dropped: Default::default(),
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.fallbacks.load(Ordering::Relaxed)
+    }
+
+    /// Number of records dropped because neither writer could write them
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// The writer made for a single event, writing each write to the primary writer as a whole
+/// record, and to the fallback writer if that fails
+pub struct FallbackRecord<'a, P: MakeWriter<'a> + 'a, F: MakeWriter<'a> + 'a> {
+    writer: &'a FallbackWriter<P, F>,
+    primary: P::Writer,
+}
+
+impl<'a, P: MakeWriter<'a> + 'a, F: MakeWriter<'a> + 'a> Write for FallbackRecord<'a, P, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty()
+            || self
+                .primary
+                .write_all(buf)
+                .and_then(|_| self.primary.flush())
+                .is_ok()
+        {
+            return Ok(buf.len());
+        }
+        self.writer.fallbacks.fetch_add(1, Ordering::Relaxed);
+        let mut fallback = self.writer.fallback.make_writer();
+        fallback
+            .write_all(buf)
+            .and_then(|_| fallback.flush())
+            .inspect_err(|_| {
+                self.writer.dropped.fetch_add(1, Ordering::Relaxed);
+            })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a, P: MakeWriter<'a> + 'a, F: MakeWriter<'a> + 'a> MakeWriter<'a> for FallbackWriter<P, F> {
+    type Writer = FallbackRecord<'a, P, F>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        FallbackRecord {
+            writer: self,
+            primary: self.primary.make_writer(),
+        }
+    }
+
+    /// The primary writer is made for the event, the fallback writer with
+    /// [`make_writer`](MakeWriter::make_writer)
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        FallbackRecord {
+            writer: self,
+            primary: self.primary.make_writer_for(meta),
+        }
+    }
+}
@@ -1,23 +1,51 @@
 use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing_core::callsite::Identifier;
 use tracing_core::field::{Field, Visit};
+use tracing_core::Metadata;
+
+thread_local! {
+    /// Whether the record being serialized is serialized with serde_json, see
+    /// [`MakeSerializer::SPLICES_JSON`](crate::format::MakeSerializer::SPLICES_JSON)
+    static SPLICE_JSON: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Restores the previous setting when dropped, also when unwinding
+struct RestoreSplice(bool);
+
+impl Drop for RestoreSplice {
+    fn drop(&mut self) {
+        SPLICE_JSON.set(self.0);
+    }
+}
+
+/// Runs `f`, serializing recorded JSON values as their text if `splice`, or parsed otherwise
+pub(crate) fn splicing_json<R>(splice: bool, f: impl FnOnce() -> R) -> R {
+    let _restore = RestoreSplice(SPLICE_JSON.replace(splice));
+    f()
+}
 
 #[allow(dead_code)]
+#[derive(Clone)]
 enum FieldSourceFilter {
     SpanOrEvent,
     Event,
 }
 
-#[allow(dead_code)]
+#[derive(Clone)]
 enum FieldSource {
     Copy(FieldSourceFilter, &'static str),
     Translate(
         FieldSourceFilter,
         &'static str,
-        Box<dyn Fn(RecordedValue) -> RecordedValue>,
+        Arc<dyn Fn(RecordedValue) -> RecordedValue + Send + Sync>,
     ),
     Static(RecordedValue),
-    Dynamic(Box<dyn Fn() -> RecordedValue>),
+    Dynamic(Arc<dyn Fn() -> RecordedValue + Send + Sync>),
 }
 
 impl FieldSource {
@@ -26,6 +54,8 @@ impl FieldSource {
             self,
             FieldSource::Copy(FieldSourceFilter::SpanOrEvent, _)
                 | FieldSource::Translate(FieldSourceFilter::SpanOrEvent, _, _)
+                | FieldSource::Static(_)
+                | FieldSource::Dynamic(_)
         )
     }
     fn records_event(&self) -> bool {
@@ -37,30 +67,199 @@ impl FieldSource {
                 | FieldSource::Translate(FieldSourceFilter::SpanOrEvent, _, _)
         )
     }
+    fn source_name(&self) -> Option<&'static str> {
+        match self {
+            FieldSource::Copy(_, name) | FieldSource::Translate(_, name, _) => Some(name),
+            FieldSource::Static(_) | FieldSource::Dynamic(_) => None,
+        }
+    }
 }
 
-pub struct FieldSpec(&'static str, FieldSource);
+pub struct FieldSpec(Cow<'static, str>, FieldSource, Option<Unit>, FieldOptions);
+
+/// How the values of a field are recorded
+#[derive(Copy, Clone, Default)]
+struct FieldOptions {
+    array: bool,
+    json: bool,
+}
+
+impl FieldSpec {
+    /// The field `name`, recorded as is
+    pub fn new(name: &'static str) -> Self {
+        name.into()
+    }
+
+    /// The field `name` with a constant value, written with the span fields of each span
+    pub fn static_value(name: &'static str, value: impl Into<RecordedValue>) -> Self {
+        FieldSpec(
+            Cow::Borrowed(name),
+            FieldSource::Static(value.into()),
+            None,
+            FieldOptions::default(),
+        )
+    }
+
+    /// The field `name` with the value returned by `value`, called when each span is created
+    /// and written with its span fields
+    pub fn dynamic<V: Into<RecordedValue>>(
+        name: &'static str,
+        value: impl Fn() -> V + Send + Sync + 'static,
+    ) -> Self {
+        FieldSpec(
+            Cow::Borrowed(name),
+            FieldSource::Dynamic(Arc::new(move || value().into())),
+            None,
+            FieldOptions::default(),
+        )
+    }
+
+    /// The field `from`, recorded as `to` with its values rewritten by `translate`, such as to
+    /// map status codes to classes
+    pub fn translate(
+        to: &'static str,
+        from: &'static str,
+        translate: impl Fn(RecordedValue) -> RecordedValue + Send + Sync + 'static,
+    ) -> Self {
+        FieldSpec(
+            Cow::Borrowed(to),
+            FieldSource::Translate(FieldSourceFilter::SpanOrEvent, from, Arc::new(translate)),
+            None,
+            FieldOptions::default(),
+        )
+    }
+
+    /// Numeric values of the field are in `unit`, and are written converted to its canonical
+    /// unit, with the canonical unit appended to the name: `payload_size` in
+    /// [`Unit::Kilobytes`] is written as `payload_size.bytes`
+    pub fn unit(self, unit: Unit) -> Self {
+        let name = format!("{}.{}", self.0, unit.canonical_name());
+        FieldSpec(Cow::Owned(name), self.1, Some(unit), self.3)
+    }
+
+    /// Keep every value recorded for the field, written as an array, rather than the last one
+    pub fn array(self) -> Self {
+        let options = FieldOptions {
+            array: true,
+            ..self.3
+        };
+        FieldSpec(self.0, self.1, self.2, options)
+    }
+
+    /// Values of the field are JSON text, such as values recorded as
+    /// [`Structured`], and are written as the JSON values they hold. Values that are not valid
+    /// JSON are written as strings.
+    pub fn json(self) -> Self {
+        let options = FieldOptions {
+            json: true,
+            ..self.3
+        };
+        FieldSpec(self.0, self.1, self.2, options)
+    }
+}
 
 impl From<&'static str> for FieldSpec {
     fn from(name: &'static str) -> Self {
         FieldSpec(
-            name,
+            Cow::Borrowed(name),
             FieldSource::Copy(FieldSourceFilter::SpanOrEvent, name),
+            None,
+            FieldOptions::default(),
         )
     }
 }
 
 impl From<(&'static str, &'static str)> for FieldSpec {
     fn from((to, from): (&'static str, &'static str)) -> Self {
-        FieldSpec(to, FieldSource::Copy(FieldSourceFilter::SpanOrEvent, from))
+        FieldSpec(
+            Cow::Borrowed(to),
+            FieldSource::Copy(FieldSourceFilter::SpanOrEvent, from),
+            None,
+            FieldOptions::default(),
+        )
+    }
+}
+
+/// Unit of the numeric values of a field, see [`FieldSpec::unit`]
+///
+/// Sizes are converted to bytes and rates to bytes per second. Decimal units are multiples of
+/// 1000 and binary units multiples of 1024.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Bytes,
+    Kilobytes,
+    Kibibytes,
+    Megabytes,
+    Mebibytes,
+    Gigabytes,
+    Gibibytes,
+    BytesPerSecond,
+    KilobytesPerSecond,
+    KibibytesPerSecond,
+    MegabytesPerSecond,
+    MebibytesPerSecond,
+}
+
+impl Unit {
+    /// Name of the canonical unit, appended to the field name
+    pub const fn canonical_name(self) -> &'static str {
+        match self {
+            Unit::Bytes
+            | Unit::Kilobytes
+            | Unit::Kibibytes
+            | Unit::Megabytes
+            | Unit::Mebibytes
+            | Unit::Gigabytes
+            | Unit::Gibibytes => "bytes",
+            Unit::BytesPerSecond
+            | Unit::KilobytesPerSecond
+            | Unit::KibibytesPerSecond
+            | Unit::MegabytesPerSecond
+            | Unit::MebibytesPerSecond => "bytes_per_second",
+        }
+    }
+
+    /// Number of canonical units in one unit
+    const fn factor(self) -> u64 {
+        match self {
+            Unit::Bytes | Unit::BytesPerSecond => 1,
+            Unit::Kilobytes | Unit::KilobytesPerSecond => 1000,
+            Unit::Kibibytes | Unit::KibibytesPerSecond => 1 << 10,
+            Unit::Megabytes | Unit::MegabytesPerSecond => 1000 * 1000,
+            Unit::Mebibytes | Unit::MebibytesPerSecond => 1 << 20,
+            Unit::Gigabytes => 1000 * 1000 * 1000,
+            Unit::Gibibytes => 1 << 30,
+        }
+    }
+
+    /// Converts a numeric value to the canonical unit, saturating integers. Other values are
+    /// kept as is.
+    fn convert(self, value: RecordedValue) -> RecordedValue {
+        let factor = self.factor();
+        match value {
+            RecordedValue::U64(v) => RecordedValue::U64(v.saturating_mul(factor)),
+            RecordedValue::I64(v) => RecordedValue::I64(v.saturating_mul(factor as i64)),
+            RecordedValue::F64(v) => RecordedValue::F64(v * factor as f64),
+            value => value,
+        }
     }
 }
 
 pub struct FieldConfig {
-    pub span_field_index: HashMap<&'static str, usize>,
-    pub span_field_names: Vec<&'static str>,
-    pub event_field_index: HashMap<&'static str, usize>,
-    pub event_field_names: Vec<&'static str>,
+    pub span_field_index: HashMap<Cow<'static, str>, usize>,
+    pub span_field_names: Vec<Cow<'static, str>>,
+    pub event_field_index: HashMap<Cow<'static, str>, usize>,
+    pub event_field_names: Vec<Cow<'static, str>>,
+    span_units: Vec<Option<Unit>>,
+    event_units: Vec<Option<Unit>>,
+    span_options: Vec<FieldOptions>,
+    event_options: Vec<FieldOptions>,
+    span_sources: Vec<FieldSource>,
+    event_sources: Vec<FieldSource>,
+    span_source_index: HashMap<&'static str, usize>,
+    event_source_index: HashMap<&'static str, usize>,
+    /// The span field indices of the fields of each callsite, see [`FieldConfig::span_field_indices`]
+    span_callsites: RwLock<HashMap<Identifier, Arc<[Option<usize>]>>>,
 }
 
 impl Default for FieldConfig {
@@ -71,29 +270,58 @@ impl Default for FieldConfig {
 
 impl FieldConfig {
     pub fn new(fields: Vec<FieldSpec>) -> Self {
-        let span_field_index = fields
+        let span_fields = fields
             .iter()
             .filter(|f| f.1.records_span())
+            .collect::<Vec<_>>();
+        let span_field_index = span_fields
+            .iter()
             .enumerate()
-            .map(|(i, f)| (f.0, i))
-            .collect::<HashMap<&'static str, usize>>();
-        let mut span_field_names = vec![""; span_field_index.len()];
+            .map(|(i, f)| (f.0.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let mut span_field_names = vec![Cow::Borrowed(""); span_field_index.len()];
         for (name, i) in &span_field_index {
-            span_field_names[*i] = name;
+            span_field_names[*i] = name.clone();
         }
 
-        let event_field_index = fields
+        let event_fields = fields
             .iter()
             .filter(|f| f.1.records_event())
+            .collect::<Vec<_>>();
+        let event_field_index = event_fields
+            .iter()
             .enumerate()
-            .map(|(i, f)| (f.0, i))
-            .collect::<HashMap<&'static str, usize>>();
-        let mut event_field_names = vec![""; event_field_index.len()];
+            .map(|(i, f)| (f.0.clone(), i))
+            .collect::<HashMap<_, _>>();
+        let mut event_field_names = vec![Cow::Borrowed(""); event_field_index.len()];
         for (name, i) in &event_field_index {
-            event_field_names[*i] = name;
+            event_field_names[*i] = name.clone();
         }
 
+        let span_units = span_fields.iter().map(|f| f.2).collect();
+        let event_units = event_fields.iter().map(|f| f.2).collect();
+        let span_options = span_fields.iter().map(|f| f.3).collect();
+        let event_options = event_fields.iter().map(|f| f.3).collect();
+        let span_source_index = source_index(&span_fields, &span_field_index);
+        let event_source_index = source_index(&event_fields, &event_field_index);
         Self {
+            span_units,
+            event_units,
+            span_options,
+            event_options,
+            span_sources: fields
+                .iter()
+                .filter(|f| f.1.records_span())
+                .map(|f| f.1.clone())
+                .collect(),
+            event_sources: fields
+                .into_iter()
+                .filter(|f| f.1.records_event())
+                .map(|f| f.1)
+                .collect(),
+            span_source_index,
+            event_source_index,
+            span_callsites: Default::default(),
             span_field_index,
             span_field_names,
             event_field_index,
@@ -101,18 +329,118 @@ impl FieldConfig {
         }
     }
 
+    /// The index of the span field recording `field`, looked up by the source name of the specs,
+    /// so that a spec renaming `tenant_id` to `tenant` records `tenant_id` and not `tenant`
     pub fn field_index(&self, field: &Field) -> Option<usize> {
-        self.span_field_index.get(field.name()).copied()
+        self.span_source_index.get(field.name()).copied()
+    }
+
+    /// The index of the span field recording each field of the callsite of `metadata`, by the
+    /// index of the field in its field set. The names of the fields of a callsite are looked up
+    /// once, the first time one of its spans is recorded, so recording a span costs a single
+    /// lookup by callsite.
+    pub fn span_field_indices(&self, metadata: &Metadata<'_>) -> Arc<[Option<usize>]> {
+        let callsite = metadata.callsite();
+        if let Some(indices) = self
+            .span_callsites
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&callsite)
+        {
+            return indices.clone();
+        }
+        let indices = metadata
+            .fields()
+            .iter()
+            .map(|field| self.field_index(&field))
+            .collect::<Arc<[_]>>();
+        self.span_callsites
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(callsite)
+            .or_insert(indices)
+            .clone()
     }
 
+    /// The index of the event field recording `field`, looked up by source name
     pub fn event_field_index(&self, field: &Field) -> Option<usize> {
-        self.event_field_index.get(field.name()).copied()
+        self.event_source_index.get(field.name()).copied()
+    }
+
+    /// Whether every value recorded for the span field at `index` is kept
+    pub fn is_span_array(&self, index: usize) -> bool {
+        self.span_options
+            .get(index)
+            .is_some_and(|options| options.array)
+    }
+
+    /// Whether every value recorded for the event field at `index` is kept
+    pub fn is_event_array(&self, index: usize) -> bool {
+        self.event_options
+            .get(index)
+            .is_some_and(|options| options.array)
+    }
+
+    /// The values of the span fields of a new span, before its fields are recorded
+    pub fn initial_span_values(&self) -> Vec<RecordedValue> {
+        (0..self.span_field_index.len())
+            .map(|i| match self.span_sources.get(i) {
+                Some(FieldSource::Static(value)) => self.span_value(i, value.clone()),
+                Some(FieldSource::Dynamic(value)) => self.span_value(i, value()),
+                _ => RecordedValue::Unset,
+            })
+            .collect()
+    }
+
+    /// The value recorded for the span field at `index`, parsed, translated and converted to
+    /// its canonical unit
+    pub fn span_value(&self, index: usize, value: RecordedValue) -> RecordedValue {
+        let value = match self.span_options.get(index) {
+            Some(options) if options.json => value.parse_json(),
+            _ => value,
+        };
+        let value = match self.span_sources.get(index) {
+            Some(FieldSource::Translate(_, _, translate)) => translate(value),
+            _ => value,
+        };
+        match self.span_units.get(index).copied().flatten() {
+            Some(unit) => unit.convert(value),
+            None => value,
+        }
+    }
+
+    /// The value recorded for the event field at `index`, parsed, translated and converted to
+    /// its canonical unit
+    pub fn event_value(&self, index: usize, value: RecordedValue) -> RecordedValue {
+        let value = match self.event_options.get(index) {
+            Some(options) if options.json => value.parse_json(),
+            _ => value,
+        };
+        let value = match self.event_sources.get(index) {
+            Some(FieldSource::Translate(_, _, translate)) => translate(value),
+            _ => value,
+        };
+        match self.event_units.get(index).copied().flatten() {
+            Some(unit) => unit.convert(value),
+            None => value,
+        }
     }
 }
 
+/// Maps the source name of each field to the index of the field it is recorded as
+fn source_index(
+    fields: &[&FieldSpec],
+    index: &HashMap<Cow<'static, str>, usize>,
+) -> HashMap<&'static str, usize> {
+    fields
+        .iter()
+        .filter_map(|f| Some((f.1.source_name()?, *index.get(&f.0)?)))
+        .collect()
+}
+
 pub trait TryForEachField {
-    fn try_for_each<E, F: FnMut(&'static str, &RecordedValue) -> Result<(), E>>(
-        &self,
+    fn try_for_each<'a, E, F: FnMut(&'a Cow<'static, str>, &'a RecordedValue) -> Result<(), E>>(
+        &'a self,
         f: F,
     ) -> Result<(), E>;
 }
@@ -161,7 +489,7 @@ impl<'a, R: FieldRecorder> Visit for FieldVisitor<'a, R> {
     }
 }
 
-#[allow(dead_code)]
+/// A value of a recorded field
 #[derive(Clone, Debug)]
 pub enum RecordedValue {
     Unset,
@@ -171,12 +499,74 @@ pub enum RecordedValue {
     U64(u64),
     Bool(bool),
     String(String),
+    /// A value serialized as JSON when recorded
+    Serialized(Box<RawValue>),
+    /// The values recorded for a field keeping every value, see [`FieldSpec::array`]
+    Array(Vec<RecordedValue>),
+    /// A structured value, see [`FieldSpec::json`]
+    Json(serde_json::Value),
 }
 
 impl RecordedValue {
     pub fn is_unset(&self) -> bool {
         matches!(self, RecordedValue::Unset)
     }
+
+    /// The JSON value held by a string, or the value itself if it isn't one
+    fn parse_json(self) -> Self {
+        match self {
+            RecordedValue::String(s) => match serde_json::from_str(&s) {
+                Ok(value) => RecordedValue::Json(value),
+                Err(_) => RecordedValue::String(s),
+            },
+            value => value,
+        }
+    }
+
+    /// Appends `value`, making this an array if it isn't one
+    pub fn push(&mut self, value: RecordedValue) {
+        match self {
+            RecordedValue::Array(values) => values.push(value),
+            RecordedValue::Unset => *self = RecordedValue::Array(vec![value]),
+            _ => {
+                let first = std::mem::replace(self, RecordedValue::Array(Vec::new()));
+                *self = RecordedValue::Array(vec![first, value]);
+            }
+        }
+    }
+
+    /// The value as plain text, for text formats
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            RecordedValue::None | RecordedValue::Unset => None,
+            RecordedValue::F64(v) => Some(v.to_string()),
+            RecordedValue::I64(v) => Some(v.to_string()),
+            RecordedValue::U64(v) => Some(v.to_string()),
+            RecordedValue::Bool(v) => Some(v.to_string()),
+            RecordedValue::String(v) => Some(v.clone()),
+            RecordedValue::Serialized(v) => match serde_json::from_str(v.get()) {
+                Ok(serde_json::Value::String(s)) => Some(s),
+                Ok(serde_json::Value::Null) | Err(_) => None,
+                Ok(value) => Some(value.to_string()),
+            },
+            RecordedValue::Array(_) => serde_json::to_string(self).ok(),
+            RecordedValue::Json(serde_json::Value::String(s)) => Some(s.clone()),
+            RecordedValue::Json(serde_json::Value::Null) => None,
+            RecordedValue::Json(value) => Some(value.to_string()),
+        }
+    }
+
+    /// The value serialized as JSON, or the value itself if it can't be
+    pub fn into_serialized(self) -> Self {
+        match self {
+            // Kept as is so later values can be appended
+            RecordedValue::Unset | RecordedValue::Serialized(_) | RecordedValue::Array(_) => self,
+            value => match serde_json::value::to_raw_value(&value) {
+                Ok(raw) => RecordedValue::Serialized(raw),
+                Err(_) => value,
+            },
+        }
+    }
 }
 
 impl Serialize for RecordedValue {
@@ -191,10 +581,42 @@ impl Serialize for RecordedValue {
             RecordedValue::U64(v) => serializer.serialize_u64(*v),
             RecordedValue::Bool(v) => serializer.serialize_bool(*v),
             RecordedValue::String(v) => serializer.serialize_str(v),
+            // Spliced as is when serde_json writes the record, other serializers need the parsed
+            // value
+            RecordedValue::Serialized(v) if SPLICE_JSON.get() => v.serialize(serializer),
+            RecordedValue::Serialized(v) => {
+                match serde_json::from_str::<serde_json::Value>(v.get()) {
+                    Ok(value) => value.serialize(serializer),
+                    Err(e) => Err(serde::ser::Error::custom(e)),
+                }
+            }
+            RecordedValue::Array(values) => values.serialize(serializer),
+            RecordedValue::Json(value) => value.serialize(serializer),
         }
     }
 }
 
+/// A value recorded as its JSON text, for [fields holding JSON](FieldSpec::json), as in
+/// `tracing::info_span!("request", body = %Structured(&body))`
+///
+/// Values that fail to serialize are written as `null`.
+pub struct Structured<T>(pub T);
+
+impl<T: Serialize> std::fmt::Display for Structured<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match serde_json::to_string(&self.0) {
+            Ok(json) => f.write_str(&json),
+            Err(_) => f.write_str("null"),
+        }
+    }
+}
+
+impl<T: Serialize> std::fmt::Debug for Structured<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
 impl From<f64> for RecordedValue {
     fn from(v: f64) -> Self {
         Self::F64(v)
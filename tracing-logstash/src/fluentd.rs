@@ -0,0 +1,497 @@
+//! Delivery of records to a Fluentd or Fluent Bit `forward` input, using the Forward protocol
+//!
+//! Records are sent in Forward mode messages of up to `batch_size` entries, converted from JSON
+//! to MessagePack, with the time they were written. With acknowledgements enabled, a message is
+//! resent, after reconnecting, until the input acknowledges it or the number of attempts runs
+//! out; without them a message is considered delivered once written. Messages that could not be
+//! delivered are kept and resent after the flush interval.
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records; beyond that the
+//! oldest records are dropped and counted, or the records chosen by another [`BackpressurePolicy`].
+//! They are sent from a background thread, started with the first record, when a batch is full or
+//! the oldest queued record is older than the flush interval. Call [`FluentdSink::flush`] before
+//! exiting to wait for the queued records to be sent. When the last clone of the sink is dropped,
+//! the queued records are sent once more and the thread is stopped.
+//!
+//! With compression enabled, the entries of each message are sent gzip compressed, in
+//! CompressedPackedForward mode.
+//!
+//! Records that are not JSON and shared key authentication are not supported.
+//!
+//! # Example
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::fluentd::FluentdSink;
+//! #
+//! let sink = FluentdSink::new("fluent-bit:24224", "app.checkout")
+//!     .unwrap()
+//!     .with_ack(true)
+//!     .with_batch_size(64);
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! sink.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::compress::gzip;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use serde::de::IgnoredAny;
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer sending records to a Fluentd `forward` input, see the [module](self) documentation
+///
+/// Clones share the same connection and queued records.
+#[derive(Clone)]
+pub struct FluentdSink {
+    config: Arc<Config>,
+    delivery: Delivery<Entry>,
+}
+
+#[derive(Clone)]
+struct Config {
+    addrs: Vec<SocketAddr>,
+    tag: String,
+    ack: bool,
+    compression: bool,
+    batch_size: usize,
+    max_pending: usize,
+    max_attempts: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    policy: BackpressurePolicy,
+}
+
+impl Config {
+    fn batching(&self) -> Batching {
+        Batching {
+            batch_size: self.batch_size,
+            batch_bytes: usize::MAX,
+            max_pending: self.max_pending,
+            max_attempts: self.max_attempts,
+            flush_interval: self.flush_interval,
+            connections: 1,
+            policy: self.policy,
+        }
+    }
+}
+
+/// The connection to the input, used from the background thread
+struct Connection {
+    config: Arc<Config>,
+    stream: Option<TcpStream>,
+}
+
+/// A record as written by the layer, with the time it was written
+struct Entry {
+    time: Duration,
+    record: Vec<u8>,
+}
+
+impl FluentdSink {
+    /// A sink for the input at `addr`, which is resolved once, tagging records with `tag`
+    pub fn new(addr: impl ToSocketAddrs, tag: impl Into<String>) -> io::Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address resolved to nothing",
+            ));
+        }
+        Ok(Self {
+            config: Arc::new(Config {
+                addrs,
+                tag: tag.into(),
+                ack: false,
+                compression: false,
+                batch_size: 1,
+                max_pending: 10_000,
+                max_attempts: 3,
+                flush_interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                policy: BackpressurePolicy::DropOldest,
+            }),
+            delivery: Delivery::new(),
+        })
+    }
+
+    fn with_config(self, f: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Arc::unwrap_or_clone(self.config);
+        f(&mut config);
+        Self {
+            config: Arc::new(config),
+            ..self
+        }
+    }
+
+    /// Wait for the input to acknowledge each message, defaults to false
+    pub fn with_ack(self, ack: bool) -> Self {
+        self.with_config(|config| config.ack = ack)
+    }
+
+    /// Compress the entries of each message, defaults to false
+    pub fn with_compression(self, compression: bool) -> Self {
+        self.with_config(|config| config.compression = compression)
+    }
+
+    /// Number of records per message, defaults to 1
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        self.with_config(|config| config.batch_size = batch_size.max(1))
+    }
+
+    /// Maximum number of records queued, such as while the input is unreachable, defaults to
+    /// 10000
+    pub fn with_max_pending(self, max_pending: usize) -> Self {
+        self.with_config(|config| config.max_pending = max_pending.max(1))
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(self, policy: BackpressurePolicy) -> Self {
+        self.with_config(|config| config.policy = policy)
+    }
+
+    /// Number of connection attempts per message, defaults to 3
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        self.with_config(|config| config.max_attempts = max_attempts.max(1))
+    }
+
+    /// Send a partial batch when its oldest record is older than this, defaults to one second
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        self.with_config(|config| config.flush_interval = flush_interval)
+    }
+
+    /// Timeout for connecting, writing and waiting for acknowledgements, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_config(|config| config.timeout = timeout)
+    }
+
+    /// Number of records dropped because too many records were queued, because they were not
+    /// JSON, or because they could not be sent when the sink was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of messages that could
+    /// not be sent in the number of attempts instead of keeping them, see
+    /// [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be sent, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+
+    fn push(&self, record: &[u8], level: Level) -> io::Result<()> {
+        serde_json::from_slice::<IgnoredAny>(trim_separator(record)).map_err(|e| {
+            self.delivery.count_dropped();
+            io::Error::new(io::ErrorKind::InvalidData, e)
+        })?;
+        let entry = Entry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            record: record.to_vec(),
+        };
+
+        self.delivery
+            .push(entry, level, self.config.batching(), || Connection {
+                config: self.config.clone(),
+                stream: None,
+            })
+    }
+}
+
+impl Transport for Connection {
+    type Record = Entry;
+
+    fn send(&mut self, batch: &[Entry], seq: u64) -> io::Result<u64> {
+        let chunk = format!("{:x}-{:x}", std::process::id(), seq);
+        let result = self.try_send_batch(batch, &chunk);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result.map(|()| 0)
+    }
+
+    fn bytes(entry: &Entry) -> &[u8] {
+        &entry.record
+    }
+}
+
+impl Connection {
+    fn try_send_batch(&mut self, batch: &[Entry], chunk: &str) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let stream = self.stream.as_mut().expect("connected");
+
+        let mut entries = Vec::new();
+        for entry in batch {
+            // Checked to be JSON when queued
+            let value = serde_json::from_slice::<Value>(trim_separator(&entry.record))
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            encode_array_len(&mut entries, 2);
+            encode_event_time(&mut entries, entry.time);
+            encode_value(&mut entries, &value);
+        }
+
+        // [tag, [[time, record], ...], {"chunk": id}], or with compression
+        // [tag, gzip([time, record]...), {"compressed": "gzip", "chunk": id}]
+        let options = self.config.ack as usize + self.config.compression as usize;
+        let mut message = Vec::new();
+        encode_array_len(&mut message, if options > 0 { 3 } else { 2 });
+        encode_str(&mut message, &self.config.tag);
+        if self.config.compression {
+            encode_bin(&mut message, &gzip(&entries));
+        } else {
+            encode_array_len(&mut message, batch.len());
+            message.extend_from_slice(&entries);
+        }
+        if options > 0 {
+            encode_map_len(&mut message, options);
+        }
+        if self.config.compression {
+            encode_str(&mut message, "compressed");
+            encode_str(&mut message, "gzip");
+        }
+        if self.config.ack {
+            encode_str(&mut message, "chunk");
+            encode_str(&mut message, chunk);
+        }
+        stream.write_all(&message)?;
+        stream.flush()?;
+
+        if self.config.ack && read_ack(stream)? != chunk {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "acknowledgement for another chunk",
+            ));
+        }
+        Ok(())
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = None;
+        for addr in &self.config.addrs {
+            match TcpStream::connect_timeout(addr, self.config.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.config.timeout))?;
+                    stream.set_write_timeout(Some(self.config.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("at least one address"))
+    }
+}
+
+fn encode_len(buffer: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, long: [u8; 2]) {
+    if len <= fix_max {
+        buffer.push(fix | len as u8);
+    } else if len <= u16::MAX as usize {
+        buffer.push(long[0]);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(long[1]);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn encode_array_len(buffer: &mut Vec<u8>, len: usize) {
+    encode_len(buffer, len, 0x90, 15, [0xdc, 0xdd]);
+}
+
+fn encode_map_len(buffer: &mut Vec<u8>, len: usize) {
+    encode_len(buffer, len, 0x80, 15, [0xde, 0xdf]);
+}
+
+fn encode_str(buffer: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len <= 31 {
+        buffer.push(0xa0 | len as u8);
+    } else if len <= u8::MAX as usize {
+        buffer.extend_from_slice(&[0xd9, len as u8]);
+    } else if len <= u16::MAX as usize {
+        buffer.push(0xda);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(0xdb);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn encode_bin(buffer: &mut Vec<u8>, bin: &[u8]) {
+    let len = bin.len();
+    if len <= u8::MAX as usize {
+        buffer.extend_from_slice(&[0xc4, len as u8]);
+    } else if len <= u16::MAX as usize {
+        buffer.push(0xc5);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(0xc6);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buffer.extend_from_slice(bin);
+}
+
+/// The EventTime extension type, seconds and nanoseconds since the epoch
+fn encode_event_time(buffer: &mut Vec<u8>, time: Duration) {
+    buffer.extend_from_slice(&[0xd7, 0x00]);
+    buffer.extend_from_slice(&(time.as_secs() as u32).to_be_bytes());
+    buffer.extend_from_slice(&time.subsec_nanos().to_be_bytes());
+}
+
+/// Encodes a JSON value as MessagePack
+fn encode_value(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buffer.push(0xc0),
+        Value::Bool(false) => buffer.push(0xc2),
+        Value::Bool(true) => buffer.push(0xc3),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                buffer.push(0xcf);
+                buffer.extend_from_slice(&n.to_be_bytes());
+            } else if let Some(n) = n.as_i64() {
+                buffer.push(0xd3);
+                buffer.extend_from_slice(&n.to_be_bytes());
+            } else {
+                buffer.push(0xcb);
+                buffer.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => encode_str(buffer, s),
+        Value::Array(values) => {
+            encode_array_len(buffer, values.len());
+            for value in values {
+                encode_value(buffer, value);
+            }
+        }
+        Value::Object(map) => {
+            encode_map_len(buffer, map.len());
+            for (key, value) in map {
+                encode_str(buffer, key);
+                encode_value(buffer, value);
+            }
+        }
+    }
+}
+
+/// Reads an acknowledgement, `{"ack": id}`, returning the chunk id
+fn read_ack<R: Read>(reader: &mut R) -> io::Result<String> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid acknowledgement");
+    let mut marker = [0u8; 1];
+    reader.read_exact(&mut marker)?;
+    if marker[0] != 0x81 || read_str(reader)? != "ack" {
+        return Err(invalid());
+    }
+    read_str(reader)
+}
+
+fn read_str<R: Read>(reader: &mut R) -> io::Result<String> {
+    let mut marker = [0u8; 1];
+    reader.read_exact(&mut marker)?;
+    let len = match marker[0] {
+        m @ 0xa0..=0xbf => (m & 0x1f) as usize,
+        0xd9 => {
+            let mut len = [0u8; 1];
+            reader.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        0xda => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as usize
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "expected a string",
+            ))
+        }
+    };
+    let mut s = vec![0u8; len];
+    reader.read_exact(&mut s)?;
+    String::from_utf8(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+impl WriteRecord for FluentdSink {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if trim_separator(record).is_empty() {
+            return Ok(());
+        }
+        self.push(record, level)
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for FluentdSink {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_value, read_ack};
+
+    #[test]
+    fn test_encode_value() {
+        let mut buffer = Vec::new();
+        encode_value(
+            &mut buffer,
+            &serde_json::json!({ "a": [1, -1, 0.5, null, true], "b": "x" }),
+        );
+        let mut expected = vec![0x82, 0xa1, b'a', 0x95, 0xcf];
+        expected.extend_from_slice(&1u64.to_be_bytes());
+        expected.push(0xd3);
+        expected.extend_from_slice(&(-1i64).to_be_bytes());
+        expected.push(0xcb);
+        expected.extend_from_slice(&0.5f64.to_be_bytes());
+        expected.extend_from_slice(&[0xc0, 0xc3, 0xa1, b'b', 0xa1, b'x']);
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn test_read_ack() {
+        let mut ack = &[0x81, 0xa3, b'a', b'c', b'k', 0xa2, b'i', b'd'][..];
+        assert_eq!(read_ack(&mut ack).unwrap(), "id");
+    }
+}
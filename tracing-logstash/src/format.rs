@@ -1,10 +1,10 @@
 use crate::fields::TryForEachField;
 use crate::span_recorder::{DefaultSpanRecorder, SpanRecorder};
-use crate::DisplayLevelFilter;
+use crate::{target_matches, DisplayLevelFilter};
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{Serialize, Serializer};
 use std::collections::HashSet;
-use tracing_core::{Event, Subscriber};
+use tracing_core::{Event, Level, Subscriber};
 use tracing_subscriber::layer::Context;
 use tracing_subscriber::registry::{LookupSpan, SpanRef};
 
@@ -17,18 +17,144 @@ pub trait FormatSpan {
 
 pub trait FormatEvent {
     type R: SpanRecorder + Send + Sync;
+
+    /// The level the event is written with, the level of its metadata unless the format changes
+    /// it
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        *event.metadata().level()
+    }
+
     fn span_recorder(&self) -> Self::R;
+
+    /// The keys of the records used to shrink
+    /// [oversized records](crate::Layer::with_max_record_bytes), or `None` if the records are
+    /// not JSON objects that can be shrunk. Defaults to the keys of [`LogstashFormat`](crate::logstash::LogstashFormat).
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys::LOGSTASH)
+    }
+
     fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
         &self,
         serializer: S,
         event: &Event<'_>,
         ctx: Context<'_, SS>,
     ) -> Result<S::Ok, S::Error>;
+
+    /// Formats the event as written at `level`, the [`event_level`](Self::event_level) after the
+    /// [`SeverityRemap`](crate::SeverityRemap) of the layer. The default ignores the level and
+    /// calls [`format_event`](Self::format_event).
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let _ = level;
+        self.format_event(serializer, event, ctx)
+    }
+
+    /// Appends the record of the event written at `level` to `buffer`. The default writes the
+    /// output of [`format_event_at`](Self::format_event_at) using `make_serializer`; text formats
+    /// override this.
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> std::io::Result<()>
+    where
+        Self: Sized,
+    {
+        crate::fields::splicing_json(M::SPLICES_JSON, || {
+            make_serializer.serialize(buffer, &SerializeEvent(self, event, level, ctx))
+        })
+    }
+}
+
+/// The keys of the top-level fields of records serialized as JSON objects that are handled by
+/// the [`OversizeStrategy`](crate::OversizeStrategy)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RecordKeys {
+    /// The message, truncated by the strategy and replaced by a note in stubs
+    pub message: &'static str,
+    /// Fields never dropped and kept in stubs, such as the timestamp and level
+    pub kept: &'static [&'static str],
+}
+
+impl RecordKeys {
+    /// The keys of [`LogstashFormat`](crate::logstash::LogstashFormat)
+    pub const LOGSTASH: RecordKeys = RecordKeys {
+        message: "message",
+        kept: &[
+            "@version",
+            "@timestamp",
+            "thread_name",
+            "logger_name",
+            "level",
+        ],
+    };
+}
+
+/// Serializes records into a buffer using a serde data format
+pub trait MakeSerializer {
+    /// Whether the records are serialized with serde_json, which writes the JSON text of
+    /// [recorded JSON values](crate::RecordedValue::Serialized) as is; other serializers
+    /// are given the parsed values. Defaults to false.
+    const SPLICES_JSON: bool = false;
+
+    fn serialize<T: Serialize + ?Sized>(
+        &self,
+        buffer: &mut Vec<u8>,
+        value: &T,
+    ) -> std::io::Result<()>;
 }
 
-#[derive(Default)]
+/// Serializes records as JSON, the default
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Json;
+
+impl MakeSerializer for Json {
+    const SPLICES_JSON: bool = true;
+
+    fn serialize<T: Serialize + ?Sized>(
+        &self,
+        buffer: &mut Vec<u8>,
+        value: &T,
+    ) -> std::io::Result<()> {
+        serde_json::to_writer(buffer, value)?;
+        Ok(())
+    }
+}
+
+/// Serializes an event using [`FormatEvent::format_event_at`]
+pub(crate) struct SerializeEvent<'a, E, SS>(
+    pub &'a E,
+    pub &'a Event<'a>,
+    pub Level,
+    pub Context<'a, SS>,
+);
+
+impl<E, SS> Serialize for SerializeEvent<'_, E, SS>
+where
+    E: FormatEvent,
+    SS: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0
+            .format_event_at(serializer, self.1, self.2, self.3.clone())
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct DefaultSpanFormat {
     display_location: bool,
+    location_targets: Vec<&'static str>,
     display_fields: bool,
 }
 
@@ -39,12 +165,29 @@ impl DefaultSpanFormat {
             ..self
         }
     }
+    /// Display the location of spans with one of these targets or their descendants, even if
+    /// locations are not displayed for all spans. `my_app` matches `my_app` and `my_app::db`,
+    /// but not `my_app_macros`.
+    pub fn with_location_targets(self, location_targets: Vec<&'static str>) -> Self {
+        Self {
+            location_targets,
+            ..self
+        }
+    }
     pub fn with_fields(self, display_fields: bool) -> Self {
         Self {
             display_fields,
             ..self
         }
     }
+
+    fn display_location(&self, target: &str) -> bool {
+        self.display_location
+            || self
+                .location_targets
+                .iter()
+                .any(|prefix| target_matches(target, prefix))
+    }
 }
 
 const RESERVED_SPAN_FIELDS: [&str; 5] = ["name", "target", "level", "file", "line"];
@@ -60,7 +203,7 @@ impl FormatSpan for DefaultSpanFormat {
         s.serialize_entry("name", span.name())?;
         s.serialize_entry("target", metadata.target())?;
         s.serialize_entry("level", metadata.level().as_str())?;
-        if self.display_location {
+        if self.display_location(metadata.target()) {
             if let Some(file) = metadata.file() {
                 s.serialize_entry("file", file)?;
             }
@@ -77,13 +220,13 @@ impl FormatSpan for DefaultSpanFormat {
     }
 }
 
-pub(crate) fn write_extension_fields<S: SerializeMap, R: TryForEachField>(
-    seen: &mut HashSet<&str>,
+pub(crate) fn write_extension_fields<'a, S: SerializeMap, R: TryForEachField>(
+    seen: &mut HashSet<&'a str>,
     serialize_map: &mut S,
-    recorded: &R,
+    recorded: &'a R,
 ) -> Result<(), S::Error> {
     recorded.try_for_each(|name, value| {
-        if !value.is_unset() && seen.insert(name) {
+        if !value.is_unset() && seen.insert(name.as_ref()) {
             serialize_map.serialize_entry(name, value)?;
         }
         Ok(())
@@ -0,0 +1,281 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, RecordKeys};
+use crate::logstash::{
+    LogFieldContributor, LogFieldReceiver, LogTimestamp, SerializingFieldVisitor,
+};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::trace_context::TraceContextProvider;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing_core::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for [Cloud Logging structured logs](https://cloud.google.com/logging/docs/structured-logging),
+/// as read from the standard output of containers on GKE and Cloud Run
+///
+/// `severity`, `timestamp`, `message` and the `logging.googleapis.com/*` fields are picked up
+/// by the logging agent; all other fields, such as event fields and recorded span fields, end
+/// up in the entry's `jsonPayload`.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::trace_context::TraceContext;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::gcp::GcpFormat::default()
+///         .with_trace_context("my-project", || None::<TraceContext>)
+///         .with_labels(vec![("service", "checkout".to_owned())]),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct GcpFormat<FC = ()> {
+    display_source_location: bool,
+    display_logger_name: bool,
+    display_thread_name: bool,
+    trace_context: Option<(String, Arc<dyn TraceContextProvider>)>,
+    labels: Vec<(&'static str, String)>,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    field_contributor: FC,
+}
+
+impl Default for GcpFormat {
+    fn default() -> Self {
+        Self {
+            display_source_location: true,
+            display_logger_name: true,
+            display_thread_name: true,
+            trace_context: None,
+            labels: Default::default(),
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> GcpFormat<FC> {
+    /// Write `logging.googleapis.com/sourceLocation` for events that know their file and line
+    pub fn with_source_location(self, display_source_location: bool) -> Self {
+        Self {
+            display_source_location,
+            ..self
+        }
+    }
+    pub fn with_logger_name(self, display_logger_name: bool) -> Self {
+        Self {
+            display_logger_name,
+            ..self
+        }
+    }
+    pub fn with_thread_name(self, display_thread_name: bool) -> Self {
+        Self {
+            display_thread_name,
+            ..self
+        }
+    }
+    /// Link entries to Cloud Trace, using the ids from `provider` and the id of the project the
+    /// traces are stored in
+    pub fn with_trace_context(
+        self,
+        project_id: impl Into<String>,
+        provider: impl TraceContextProvider + 'static,
+    ) -> Self {
+        Self {
+            trace_context: Some((project_id.into(), Arc::new(provider))),
+            ..self
+        }
+    }
+    /// Entry labels, written as `logging.googleapis.com/labels`
+    pub fn with_labels(self, labels: Vec<(&'static str, String)>) -> Self {
+        Self { labels, ..self }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> GcpFormat<FC2> {
+        GcpFormat {
+            display_source_location: self.display_source_location,
+            display_logger_name: self.display_logger_name,
+            display_thread_name: self.display_thread_name,
+            trace_context: self.trace_context,
+            labels: self.labels,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            field_contributor,
+        }
+    }
+}
+
+const fn severity(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "ERROR",
+        Level::WARN => "WARNING",
+        Level::INFO => "INFO",
+        Level::DEBUG | Level::TRACE => "DEBUG",
+    }
+}
+
+impl<FC> FormatEvent for GcpFormat<FC>
+where
+    FC: LogFieldContributor,
+{
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: "message",
+            kept: &[
+                "severity",
+                "timestamp",
+                "logging.googleapis.com/trace",
+                "logging.googleapis.com/spanId",
+                "logger_name",
+            ],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let event_metadata = event.metadata();
+
+        let mut s = serializer.serialize_map(None)?;
+
+        // Excluded fields are skipped as if already written
+        let mut seen = self
+            .excluded_fields
+            .iter()
+            .map(|field| Cow::Borrowed(*field))
+            .collect::<HashSet<_>>();
+        let mut field_visitor = SerializingFieldVisitor::new(&mut s, |name| seen.insert(name));
+
+        field_visitor.add_field("severity", severity(&level));
+        field_visitor.add_field("timestamp", &LogTimestamp::default());
+
+        if self.display_source_location && event_metadata.file().is_some() {
+            field_visitor.add_field(
+                "logging.googleapis.com/sourceLocation",
+                &SourceLocation(event_metadata),
+            );
+        }
+
+        if let Some((project_id, provider)) = &self.trace_context {
+            if let Some(trace_context) = provider.trace_context() {
+                field_visitor.add_field(
+                    "logging.googleapis.com/trace",
+                    &format!("projects/{}/traces/{}", project_id, trace_context.trace_id),
+                );
+                if let Some(span_id) = &trace_context.span_id {
+                    field_visitor.add_field("logging.googleapis.com/spanId", span_id);
+                }
+            }
+        }
+
+        if !self.labels.is_empty() {
+            field_visitor.add_field("logging.googleapis.com/labels", &Labels(&self.labels));
+        }
+
+        if self.display_logger_name {
+            field_visitor.add_field("logger_name", event_metadata.target());
+        }
+
+        if self.display_thread_name {
+            if let Some(name) = std::thread::current().name() {
+                field_visitor.add_field("thread_name", name);
+            }
+        }
+
+        for (key, value) in &self.constants {
+            field_visitor.add_field(key, value);
+        }
+
+        self.field_contributor.add_fields(&mut field_visitor);
+
+        event.record(&mut field_visitor);
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    field_visitor.add_extension_fields(span_fields);
+                }
+            }
+        }
+
+        field_visitor.finish()?;
+        s.end()
+    }
+}
+
+struct SourceLocation<'a>(&'a Metadata<'a>);
+
+impl Serialize for SourceLocation<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(None)?;
+        if let Some(file) = self.0.file() {
+            s.serialize_entry("file", file)?;
+        }
+        if let Some(line) = self.0.line() {
+            // The LogEntrySourceLocation line is an int64, which is a string in JSON
+            s.serialize_entry("line", &line.to_string())?;
+        }
+        s.serialize_entry("function", self.0.module_path().unwrap_or(self.0.target()))?;
+        s.end()
+    }
+}
+
+struct Labels<'a>(&'a [(&'static str, String)]);
+
+impl Serialize for Labels<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
@@ -0,0 +1,323 @@
+use crate::fields::{FieldConfig, FieldSpec, TryForEachField};
+use crate::format::{FormatEvent, RecordKeys};
+use crate::logstash::{LogFieldContributor, LogFieldReceiver};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::syslog::syslog_severity;
+use serde::ser::{Error, SerializeMap};
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for [GELF 1.1](https://go2docs.graylog.org/current/getting_in_log_data/gelf.html)
+/// payloads, for sending events to Graylog
+///
+/// The first line of the event message is written as `short_message`, and the whole message as
+/// `full_message` if it spans several lines. Event fields, recorded span fields, constants and
+/// contributed fields are written as additional fields, prefixed with an underscore.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default()
+///     .record_separator(tracing_logstash::RecordSeparator::NUL)
+///     .event_format(
+///         tracing_logstash::gelf::GelfFormat::default()
+///             .with_host("web-1")
+///             .with_constants(vec![("service", "checkout".to_owned())]),
+///     );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct GelfFormat<FC = ()> {
+    host: String,
+    display_logger_name: bool,
+    display_thread_name: bool,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    field_contributor: FC,
+}
+
+impl Default for GelfFormat {
+    fn default() -> Self {
+        Self {
+            host: crate::host::hostname(),
+            display_logger_name: true,
+            display_thread_name: true,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> GelfFormat<FC> {
+    /// Name of the host sending the message, defaults to the name of this host
+    pub fn with_host(self, host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            ..self
+        }
+    }
+    pub fn with_logger_name(self, display_logger_name: bool) -> Self {
+        Self {
+            display_logger_name,
+            ..self
+        }
+    }
+    pub fn with_thread_name(self, display_thread_name: bool) -> Self {
+        Self {
+            display_thread_name,
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> GelfFormat<FC2> {
+        GelfFormat {
+            host: self.host,
+            display_logger_name: self.display_logger_name,
+            display_thread_name: self.display_thread_name,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            field_contributor,
+        }
+    }
+}
+
+impl<FC> FormatEvent for GelfFormat<FC>
+where
+    FC: LogFieldContributor,
+{
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: "short_message",
+            kept: &["version", "host", "timestamp", "level"],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let event_metadata = event.metadata();
+        let timestamp = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000;
+
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("version", "1.1")?;
+        s.serialize_entry("host", &self.host)?;
+        s.serialize_entry("timestamp", &(timestamp as f64 / 1_000_000.0))?;
+        s.serialize_entry("level", &syslog_severity(&level))?;
+
+        let mut field_visitor = GelfFieldVisitor {
+            serializer: &mut s,
+            // Excluded fields are skipped as if already written
+            seen: self
+                .excluded_fields
+                .iter()
+                .map(|field| additional_field_name(field))
+                .collect(),
+            has_message: false,
+            status: Ok(()),
+        };
+
+        if self.display_logger_name {
+            field_visitor.add_field("logger_name", event_metadata.target());
+        }
+        if self.display_thread_name {
+            if let Some(name) = std::thread::current().name() {
+                field_visitor.add_field("thread_name", name);
+            }
+        }
+        for (key, value) in &self.constants {
+            field_visitor.add_field(key, value);
+        }
+        self.field_contributor.add_fields(&mut field_visitor);
+
+        event.record(&mut field_visitor);
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    let _ = span_fields.try_for_each::<(), _>(|name, value| {
+                        if !value.is_unset() {
+                            field_visitor.add_named_field(name, value);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+        if !field_visitor.has_message {
+            field_visitor.record_message(event_metadata.name());
+        }
+
+        field_visitor.status?;
+        s.end()
+    }
+}
+
+struct GelfFieldVisitor<'a, S: SerializeMap> {
+    serializer: &'a mut S,
+    seen: HashSet<String>,
+    has_message: bool,
+    status: Result<(), S::Error>,
+}
+
+impl<'a, S: SerializeMap> GelfFieldVisitor<'a, S> {
+    fn record_message(&mut self, message: &str) {
+        if self.status.is_err() || self.has_message {
+            return;
+        }
+        self.has_message = true;
+        let (short_message, multiline) = match message.split_once('\n') {
+            Some((first, _)) => (first, true),
+            None => (message, false),
+        };
+        self.status = self
+            .serializer
+            .serialize_entry("short_message", short_message);
+        if multiline && self.status.is_ok() {
+            self.status = self.serializer.serialize_entry("full_message", message);
+        }
+    }
+
+    fn add_named_field<V: ?Sized + Serialize>(&mut self, field: &str, value: &V) {
+        if self.status.is_err() {
+            return;
+        }
+        // Different names may sanitize to the same additional field name, keep the first
+        let name = additional_field_name(field);
+        if self.seen.contains(&name) {
+            return;
+        }
+        let value = match serde_json::to_value(value) {
+            Ok(Value::Null) => return,
+            Ok(Value::Bool(b)) => Value::String(b.to_string()),
+            Ok(value @ (Value::Array(_) | Value::Object(_))) => Value::String(value.to_string()),
+            Ok(value) => value,
+            Err(e) => {
+                self.status = Err(S::Error::custom(e));
+                return;
+            }
+        };
+        self.status = self.serializer.serialize_entry(&name, &value);
+        self.seen.insert(name);
+    }
+}
+
+impl<'a, S: SerializeMap> LogFieldReceiver for GelfFieldVisitor<'a, S> {
+    fn add_field<V: ?Sized + Serialize>(&mut self, field: &'static str, value: &V) {
+        self.add_named_field(field, value)
+    }
+}
+
+/// GELF additional field names are prefixed with `_` and may only contain word characters,
+/// `.` and `-`. `_id` is reserved.
+fn additional_field_name(name: &str) -> String {
+    let mut additional = String::with_capacity(name.len() + 1);
+    additional.push('_');
+    additional.extend(name.chars().map(|c| {
+        if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') {
+            c
+        } else {
+            '_'
+        }
+    }));
+    if additional == "_id" {
+        additional.push('_');
+    }
+    additional
+}
+
+impl<'a, S: SerializeMap> Visit for GelfFieldVisitor<'a, S> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.add_field(field.name(), &value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add_field(field.name(), &value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.add_field(field.name(), &value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add_field(field.name(), &value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.record_message(value);
+        } else {
+            self.add_field(field.name(), value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.add_field(field.name(), &value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.record_message(&format!("{:?}", value));
+        } else {
+            self.add_field(field.name(), &format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::additional_field_name;
+
+    #[test]
+    fn test_additional_field_name() {
+        assert_eq!(additional_field_name("http.status"), "_http.status");
+        assert_eq!(additional_field_name("a b/c"), "_a_b_c");
+        assert_eq!(additional_field_name("id"), "_id_");
+    }
+}
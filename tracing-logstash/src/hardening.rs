@@ -0,0 +1,179 @@
+use serde_json::Value;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Sanitization applied to every field name and value before it is written, for consumers that cannot
+/// cope with control characters, deeply nested values or very long strings.
+///
+/// Tab, carriage return and NUL characters are replaced with a space, strings longer than the
+/// configured length are truncated and values nested deeper than the configured depth are
+/// replaced with their JSON text.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::hardening::HardeningProfile;
+/// #
+/// let profile = HardeningProfile::default()
+///     .with_max_depth(4)
+///     .with_max_string_length(1024);
+/// let stats = profile.stats();
+///
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default().with_hardening(Some(profile)),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// # assert_eq!(stats.strings_truncated(), 0);
+/// ```
+#[derive(Clone)]
+pub struct HardeningProfile {
+    max_depth: usize,
+    max_string_length: usize,
+    stats: Arc<HardeningStats>,
+}
+
+impl Default for HardeningProfile {
+    fn default() -> Self {
+        Self {
+            max_depth: 16,
+            max_string_length: 32 * 1024,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl HardeningProfile {
+    /// Maximum number of nested arrays/objects within a single field value
+    pub fn with_max_depth(self, max_depth: usize) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    /// Maximum length of a string value, in bytes
+    pub fn with_max_string_length(self, max_string_length: usize) -> Self {
+        Self {
+            max_string_length,
+            ..self
+        }
+    }
+
+    /// Counters for the sanitizations applied by this profile
+    pub fn stats(&self) -> Arc<HardeningStats> {
+        self.stats.clone()
+    }
+
+    pub(crate) fn sanitize(&self, value: Value) -> Value {
+        self.sanitize_value(value, 0)
+    }
+
+    /// The name of a top-level field, only allocating when it changes
+    pub(crate) fn sanitize_key(&self, key: Cow<'static, str>) -> Cow<'static, str> {
+        if key.contains(is_forbidden) || key.len() > self.max_string_length {
+            Cow::Owned(self.sanitize_string(key.into_owned()))
+        } else {
+            key
+        }
+    }
+
+    fn sanitize_value(&self, value: Value, depth: usize) -> Value {
+        match value {
+            Value::String(s) => Value::String(self.sanitize_string(s)),
+            Value::Array(_) | Value::Object(_) if depth >= self.max_depth => {
+                self.stats.depth_capped.fetch_add(1, Ordering::Relaxed);
+                Value::String(self.sanitize_string(value.to_string()))
+            }
+            Value::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .map(|v| self.sanitize_value(v, depth + 1))
+                    .collect(),
+            ),
+            Value::Object(entries) => Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (self.sanitize_string(k), self.sanitize_value(v, depth + 1)))
+                    .collect(),
+            ),
+            v => v,
+        }
+    }
+
+    fn sanitize_string(&self, mut s: String) -> String {
+        if s.contains(is_forbidden) {
+            self.stats
+                .control_characters_replaced
+                .fetch_add(1, Ordering::Relaxed);
+            s = s.replace(is_forbidden, " ");
+        }
+        if s.len() > self.max_string_length {
+            self.stats.strings_truncated.fetch_add(1, Ordering::Relaxed);
+            let mut end = self.max_string_length;
+            while !s.is_char_boundary(end) {
+                end -= 1;
+            }
+            s.truncate(end);
+        }
+        s
+    }
+}
+
+#[inline]
+fn is_forbidden(c: char) -> bool {
+    matches!(c, '\t' | '\r' | '\0')
+}
+
+/// Number of values affected by each kind of sanitization
+#[derive(Default, Debug)]
+pub struct HardeningStats {
+    control_characters_replaced: AtomicU64,
+    strings_truncated: AtomicU64,
+    depth_capped: AtomicU64,
+}
+
+impl HardeningStats {
+    /// Strings in which tab, carriage return or NUL characters were replaced
+    pub fn control_characters_replaced(&self) -> u64 {
+        self.control_characters_replaced.load(Ordering::Relaxed)
+    }
+
+    /// Strings that were truncated to the maximum string length
+    pub fn strings_truncated(&self) -> u64 {
+        self.strings_truncated.load(Ordering::Relaxed)
+    }
+
+    /// Nested values that were replaced with their JSON text
+    pub fn depth_capped(&self) -> u64 {
+        self.depth_capped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HardeningProfile;
+    use serde_json::json;
+
+    #[test]
+    fn test_sanitize() {
+        let profile = HardeningProfile::default()
+            .with_max_depth(1)
+            .with_max_string_length(6);
+        let sanitized = profile.sanitize(json!({
+            "a\tb": "x\ry\0",
+            "long": "åäöü",
+            "nested": { "b": 1 },
+        }));
+        assert_eq!(
+            sanitized,
+            json!({
+                "a b": "x y ",
+                "long": "åäö",
+                "nested": "{\"b\":1",
+            })
+        );
+        let stats = profile.stats();
+        assert_eq!(stats.control_characters_replaced(), 2);
+        assert_eq!(stats.strings_truncated(), 2);
+        assert_eq!(stats.depth_capped(), 1);
+    }
+}
@@ -0,0 +1,307 @@
+//! Delivery of records to a Splunk
+//! [HTTP Event Collector](https://docs.splunk.com/Documentation/Splunk/latest/Data/UsetheHTTPEventCollector)
+//!
+//! The records are the event envelopes written by [`SplunkHecFormat`](crate::splunk::SplunkHecFormat),
+//! and are posted to `/services/collector/event` in requests of up to `batch_size` records,
+//! authenticated with the HEC token, over plain HTTP/1.1; TLS is not supported. The requests can
+//! be gzip compressed. A request is resent, after reconnecting, until HEC answers it or the
+//! number of attempts runs out, so records may be indexed more than once. Requests answered with
+//! a server error, `429 Too Many Requests` or HEC's "server is busy" `code`, and requests that
+//! could not be sent, are kept and resent after the flush interval. Requests HEC rejects with
+//! another `code`, such as an invalid token or invalid data, are dropped and counted.
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records; beyond that the
+//! oldest records are dropped and counted, or the records chosen by another [`BackpressurePolicy`].
+//! They are sent from a background thread, started with the first record, when a batch is full or
+//! the oldest queued record is older than the flush interval. Call [`SplunkHecSink::flush`] before
+//! exiting to wait for the queued records to be sent. When the last clone of the sink is dropped,
+//! the queued records are sent once more and the thread is stopped.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::hec::SplunkHecSink;
+//! # use tracing_logstash::splunk::SplunkHecFormat;
+//! #
+//! let sink = SplunkHecSink::new("http://splunk:8088", "2b9cf8e0-1c62-4a1e-bb36-0a6e7f1b1e6d")
+//!     .unwrap()
+//!     .with_compression(true)
+//!     .with_batch_size(256);
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .event_format(SplunkHecFormat::default().with_index("main"))
+//!     .with_writer(sink.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! sink.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::compress::gzip;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::http::{Connection, Url};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use serde_json::Value;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The HEC status code for "Internal server error"
+const INTERNAL_ERROR: u64 = 8;
+/// The HEC status code for "Server is busy"
+const SERVER_BUSY: u64 = 9;
+
+/// A writer posting records to a Splunk HTTP Event Collector, see the [module](self)
+/// documentation
+///
+/// Clones share the same connection and queued records.
+#[derive(Clone)]
+pub struct SplunkHecSink {
+    config: Arc<Config>,
+    delivery: Delivery<Vec<u8>>,
+}
+
+#[derive(Clone)]
+struct Config {
+    url: Url,
+    /// The path of the event endpoint
+    path: String,
+    /// The `Authorization` header
+    authorization: String,
+    compression: bool,
+    batch_size: usize,
+    max_pending: usize,
+    max_attempts: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    policy: BackpressurePolicy,
+}
+
+impl Config {
+    fn batching(&self) -> Batching {
+        Batching {
+            batch_size: self.batch_size,
+            batch_bytes: usize::MAX,
+            max_pending: self.max_pending,
+            max_attempts: self.max_attempts,
+            flush_interval: self.flush_interval,
+            connections: 1,
+            policy: self.policy,
+        }
+    }
+}
+
+/// The connection to HEC, used from the background thread
+struct Collector {
+    config: Arc<Config>,
+    connection: Connection,
+}
+
+impl SplunkHecSink {
+    /// A sink for the collector at `url`, such as `http://localhost:8088`, which is resolved
+    /// once, authenticating with `token`. A path in the URL is prefixed to
+    /// `/services/collector/event`.
+    pub fn new(url: &str, token: &str) -> io::Result<Self> {
+        let url = Url::parse(url, 8088)?;
+        Ok(Self {
+            config: Arc::new(Config {
+                path: format!("{}/services/collector/event", url.path),
+                url,
+                authorization: format!("Splunk {}", token),
+                compression: false,
+                batch_size: 64,
+                max_pending: 10_000,
+                max_attempts: 3,
+                flush_interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                policy: BackpressurePolicy::DropOldest,
+            }),
+            delivery: Delivery::new(),
+        })
+    }
+
+    fn with_config(self, f: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Arc::unwrap_or_clone(self.config);
+        f(&mut config);
+        Self {
+            config: Arc::new(config),
+            ..self
+        }
+    }
+
+    /// Compress the requests with gzip, defaults to false
+    pub fn with_compression(self, compression: bool) -> Self {
+        self.with_config(|config| config.compression = compression)
+    }
+
+    /// Number of records per request, defaults to 64
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        self.with_config(|config| config.batch_size = batch_size.max(1))
+    }
+
+    /// Maximum number of records queued, such as while the collector is unreachable, defaults to
+    /// 10000
+    pub fn with_max_pending(self, max_pending: usize) -> Self {
+        self.with_config(|config| config.max_pending = max_pending.max(1))
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(self, policy: BackpressurePolicy) -> Self {
+        self.with_config(|config| config.policy = policy)
+    }
+
+    /// Number of connection attempts per request, defaults to 3
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        self.with_config(|config| config.max_attempts = max_attempts.max(1))
+    }
+
+    /// Send a partial batch when its oldest record is older than this, defaults to one second
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        self.with_config(|config| config.flush_interval = flush_interval)
+    }
+
+    /// Timeout for connecting, writing and waiting for responses, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_config(|config| config.timeout = timeout)
+    }
+
+    /// Number of records dropped because too many records were queued, because HEC rejected
+    /// them, or because they could not be sent when the sink was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of requests that
+    /// could not be sent in the number of attempts instead of keeping them, see
+    /// [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be sent, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+
+    fn push(&self, record: Vec<u8>, level: Level) -> io::Result<()> {
+        self.delivery
+            .push(record, level, self.config.batching(), || Collector {
+                connection: Connection::new(&self.config.url, self.config.timeout),
+                config: self.config.clone(),
+            })
+    }
+}
+
+impl Transport for Collector {
+    type Record = Vec<u8>;
+
+    fn send(&mut self, batch: &[Vec<u8>], _seq: u64) -> io::Result<u64> {
+        let mut body = Vec::new();
+        for record in batch {
+            body.extend_from_slice(trim_separator(record));
+            body.push(b'\n');
+        }
+        let mut headers = vec![
+            ("Content-Type", "application/json"),
+            ("Authorization", self.config.authorization.as_str()),
+        ];
+        if self.config.compression {
+            body = gzip(&body);
+            headers.push(("Content-Encoding", "gzip"));
+        }
+        let (status, body) = self.connection.post(&self.config.path, &headers, &body)?;
+        match response_code(&body) {
+            Some((0, _)) => Ok(0),
+            None if (200..=299).contains(&status) => Ok(0),
+            Some((code, text)) if retryable(status, Some(code)) => Err(io::Error::other(format!(
+                "splunk hec responded {}: {} (code {})",
+                status, text, code
+            ))),
+            None if retryable(status, None) => {
+                Err(io::Error::other(format!("splunk hec responded {}", status)))
+            }
+            _ => Ok(batch.len() as u64),
+        }
+    }
+
+    fn bytes(record: &Vec<u8>) -> &[u8] {
+        record
+    }
+}
+
+/// Whether a request answered with `status` and the HEC `code` should be resent
+fn retryable(status: u16, code: Option<u64>) -> bool {
+    matches!(status, 429 | 500..=599) || matches!(code, Some(INTERNAL_ERROR | SERVER_BUSY))
+}
+
+/// The `code` and `text` of a HEC response, such as `{"text":"Success","code":0}`
+fn response_code(body: &[u8]) -> Option<(u64, String)> {
+    let response = serde_json::from_slice::<Value>(body).ok()?;
+    let code = response["code"].as_u64()?;
+    let text = response["text"].as_str().unwrap_or_default().to_owned();
+    Some((code, text))
+}
+
+impl WriteRecord for SplunkHecSink {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if trim_separator(record).is_empty() {
+            return Ok(());
+        }
+        self.push(record.to_vec(), level)
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for SplunkHecSink {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{response_code, retryable};
+
+    #[test]
+    fn test_response_code() {
+        assert_eq!(
+            response_code(br#"{"text":"Success","code":0}"#),
+            Some((0, "Success".to_owned()))
+        );
+        assert_eq!(
+            response_code(br#"{"text":"Invalid token","code":4}"#),
+            Some((4, "Invalid token".to_owned()))
+        );
+        assert_eq!(response_code(b"<html></html>"), None);
+        assert!(retryable(503, Some(9)));
+        assert!(retryable(400, Some(9)));
+        assert!(!retryable(403, Some(4)));
+        assert!(!retryable(400, None));
+    }
+}
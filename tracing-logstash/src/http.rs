@@ -0,0 +1,224 @@
+//! A minimal HTTP/1.1 client for the sinks posting records, over plain TCP without TLS
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// An `http://` URL, resolved once
+#[derive(Clone)]
+pub(crate) struct Url {
+    pub(crate) addrs: Vec<SocketAddr>,
+    /// The `Host` header
+    pub(crate) host: String,
+    /// The path, without a trailing slash
+    pub(crate) path: String,
+}
+
+impl Url {
+    /// Parses `http://host[:port][/path]`, using `default_port` without a port
+    pub(crate) fn parse(url: &str, default_port: u16) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("only http:// URLs are supported"))?;
+        let (host, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if host.is_empty() {
+            return Err(invalid("URL without a host"));
+        }
+        let addrs = match host.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => host.to_socket_addrs()?,
+            _ => (host.trim_matches(['[', ']']), default_port).to_socket_addrs()?,
+        }
+        .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(invalid("address resolved to nothing"));
+        }
+        Ok(Self {
+            addrs,
+            host: host.to_owned(),
+            path: path.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+/// A connection kept open between requests, reconnecting after errors and when the server
+/// closes it
+pub(crate) struct Connection {
+    addrs: Vec<SocketAddr>,
+    host: String,
+    timeout: Duration,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl Connection {
+    /// A connection to the host of `url`, connected with the first request
+    pub(crate) fn new(url: &Url, timeout: Duration) -> Self {
+        Self {
+            addrs: url.addrs.clone(),
+            host: url.host.clone(),
+            timeout,
+            connection: None,
+        }
+    }
+
+    /// Posts `body` to `path`, returning the status and body of the response
+    pub(crate) fn post(
+        &mut self,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<(u16, Vec<u8>)> {
+        let result = self.try_post(path, headers, body);
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn try_post(
+        &mut self,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> io::Result<(u16, Vec<u8>)> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        let connection = self.connection.as_mut().expect("connected");
+
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+            path,
+            self.host,
+            body.len()
+        );
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let mut request = request.into_bytes();
+        request.extend_from_slice(body);
+        connection.get_mut().write_all(&request)?;
+        connection.get_mut().flush()?;
+
+        let (status, close, body) = read_response(connection)?;
+        if close {
+            self.connection = None;
+        }
+        Ok((status, body))
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let mut error = None;
+        for addr in &self.addrs {
+            match TcpStream::connect_timeout(addr, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(BufReader::new(stream));
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("at least one address"))
+    }
+}
+
+/// Reads a response, returning its status, whether the server closes the connection, and its
+/// body
+fn read_response<R: BufRead>(reader: &mut R) -> io::Result<(u16, bool, Vec<u8>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut close = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(invalid());
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<u64>().map_err(|_| invalid())?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("connection") {
+            close = value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size = line.trim_end().split(';').next().unwrap_or_default();
+            let size = u64::from_str_radix(size, 16).map_err(|_| invalid())?;
+            // The chunk and its CRLF
+            reader.take(size + 2).read_to_end(&mut body)?;
+            body.truncate(body.len().saturating_sub(2));
+            if size == 0 {
+                break;
+            }
+        }
+    } else if let Some(len) = content_length {
+        reader.take(len).read_to_end(&mut body)?;
+    } else {
+        reader.read_to_end(&mut body)?;
+        close = true;
+    }
+    Ok((status, close, body))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_response, Url};
+
+    #[test]
+    fn test_read_response() {
+        let mut response =
+            &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"[..];
+        assert_eq!(
+            read_response(&mut response).unwrap(),
+            (200, false, b"abcde".to_vec())
+        );
+        assert!(response.is_empty());
+
+        let mut response =
+            &b"HTTP/1.1 429 Too Many Requests\r\ncontent-length: 2\r\nConnection: close\r\n\r\n{}"
+                [..];
+        assert_eq!(
+            read_response(&mut response).unwrap(),
+            (429, true, b"{}".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_url() {
+        let url = Url::parse("http://127.0.0.1/prefix/", 9200).unwrap();
+        assert_eq!(url.host, "127.0.0.1");
+        assert_eq!(url.path, "/prefix");
+        assert_eq!(url.addrs[0].port(), 9200);
+        let url = Url::parse("http://[::1]:8088", 9200).unwrap();
+        assert_eq!(url.path, "");
+        assert_eq!(url.addrs[0].port(), 8088);
+        assert!(Url::parse("https://localhost", 9200).is_err());
+    }
+}
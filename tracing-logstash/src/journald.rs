@@ -0,0 +1,290 @@
+//! Delivery of records to systemd-journald, using its
+//! [native protocol](https://systemd.io/JOURNAL_NATIVE_PROTOCOL/)
+//!
+//! [`JournaldFormat`] writes the level as `PRIORITY`, the message as `MESSAGE`, and the
+//! constants, event fields and recorded span fields as journal fields with their names in upper
+//! case. [`JournaldWriter`] sends each record as a datagram to the journal socket, so the fields
+//! can be queried with `journalctl`.
+//!
+//! Records larger than the maximum datagram size of the socket are dropped and counted, as
+//! passing them in a memory file is not supported.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::journald::{JournaldFormat, JournaldWriter};
+//! #
+//! let logger = tracing_logstash::Layer::default()
+//!     .event_format(JournaldFormat::default().with_syslog_identifier("checkout"))
+//!     .with_writer(JournaldWriter::new().unwrap());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, MakeSerializer, RecordKeys};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::syslog::syslog_severity;
+use crate::text::TextFields;
+use serde::Serializer;
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing_core::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for the journald native protocol, see the [module](self) documentation
+///
+/// When used with a JSON serializer, as when wrapped by other formats, the journal fields are
+/// serialized as a map of strings.
+#[derive(Clone)]
+pub struct JournaldFormat {
+    syslog_identifier: String,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+}
+
+impl Default for JournaldFormat {
+    fn default() -> Self {
+        let syslog_identifier = std::env::current_exe()
+            .ok()
+            .and_then(|exe| {
+                exe.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        Self {
+            syslog_identifier,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+        }
+    }
+}
+
+impl JournaldFormat {
+    /// Defaults to the name of the executable
+    pub fn with_syslog_identifier(self, syslog_identifier: impl Into<String>) -> Self {
+        Self {
+            syslog_identifier: syslog_identifier.into(),
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+
+    fn fields<SS>(
+        &self,
+        event: &Event<'_>,
+        level: Level,
+        ctx: &Context<'_, SS>,
+    ) -> Vec<(String, String)>
+    where
+        SS: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let event_metadata = event.metadata();
+        let mut fields = TextFields::excluding(&self.excluded_fields);
+        for (key, value) in &self.constants {
+            fields.add(*key, value);
+        }
+        fields.add_event(event, ctx);
+
+        let mut journal_fields = Vec::with_capacity(fields.fields.len() + 6);
+        journal_fields.push(("PRIORITY".to_owned(), syslog_severity(&level).to_string()));
+        if let Some(message) = fields.message {
+            journal_fields.push(("MESSAGE".to_owned(), message));
+        }
+        if !self.syslog_identifier.is_empty() {
+            journal_fields.push((
+                "SYSLOG_IDENTIFIER".to_owned(),
+                self.syslog_identifier.clone(),
+            ));
+        }
+        journal_fields.push(("TARGET".to_owned(), event_metadata.target().to_owned()));
+        if let Some(file) = event_metadata.file() {
+            journal_fields.push(("CODE_FILE".to_owned(), file.to_owned()));
+        }
+        if let Some(line) = event_metadata.line() {
+            journal_fields.push(("CODE_LINE".to_owned(), line.to_string()));
+        }
+        for (name, value) in fields.fields {
+            if let Some(name) = field_name(&name) {
+                journal_fields.push((name, value));
+            }
+        }
+        journal_fields
+    }
+}
+
+/// Field names are up to 64 upper case letters, digits and underscores, and must not start with
+/// an underscore or a digit
+fn field_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .skip_while(|c| *c == '_' || c.is_ascii_digit())
+        .take(64)
+        .collect();
+    (!name.is_empty()).then_some(name)
+}
+
+fn write_field(buffer: &mut Vec<u8>, name: &str, value: &str) {
+    buffer.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buffer.push(b'\n');
+        buffer.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buffer.push(b'=');
+    }
+    buffer.extend_from_slice(value.as_bytes());
+    buffer.push(b'\n');
+}
+
+impl FormatEvent for JournaldFormat {
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let fields = self.fields(event, level, &ctx);
+        serializer.collect_map(fields.iter().map(|(name, value)| (name, value)))
+    }
+
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        _make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> io::Result<()> {
+        for (name, value) in self.fields(event, level, &ctx) {
+            write_field(buffer, &name, &value);
+        }
+        Ok(())
+    }
+}
+
+/// A writer sending records to the journal socket, see the [module](self) documentation
+///
+/// Clones share the same socket.
+#[derive(Clone)]
+pub struct JournaldWriter {
+    socket: Arc<UnixDatagram>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl JournaldWriter {
+    /// A writer for the journal socket at `/run/systemd/journal/socket`
+    pub fn new() -> io::Result<Self> {
+        Self::with_path("/run/systemd/journal/socket")
+    }
+
+    /// A writer for the journal socket at `path`
+    pub fn with_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            dropped: Default::default(),
+        })
+    }
+
+    /// Number of records dropped because they could not be sent
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl WriteRecord for JournaldWriter {
+    fn write_record(&self, record: &[u8], _level: Level) -> io::Result<()> {
+        self.socket.send(record).map(|_| ()).inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for JournaldWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{field_name, write_field};
+
+    #[test]
+    fn test_field_name() {
+        assert_eq!(field_name("http.status").as_deref(), Some("HTTP_STATUS"));
+        assert_eq!(field_name("_hidden").as_deref(), Some("HIDDEN"));
+        assert_eq!(field_name("2fa").as_deref(), Some("FA"));
+        assert_eq!(field_name("__").as_deref(), None);
+        assert_eq!(field_name(&"x".repeat(80)).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_write_field() {
+        let mut buffer = Vec::new();
+        write_field(&mut buffer, "MESSAGE", "single line");
+        write_field(&mut buffer, "STACK", "a\nb");
+        assert_eq!(
+            buffer,
+            b"MESSAGE=single line\nSTACK\n\x03\0\0\0\0\0\0\0a\nb\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Field name constants, and the [`logstash_fields!`](crate::logstash_fields) macro for
+//! configuring fields by key
+//!
+//! Using constants instead of string literals for field names turns typos into compile errors,
+//! instead of records with a misspelled field that ends up as a separate field in Elasticsearch.
+//! Applications declare their own keys as `&'static str` constants next to these.
+
+/// `@version`
+pub const VERSION: &str = "@version";
+/// `@timestamp`
+pub const TIMESTAMP: &str = "@timestamp";
+/// `message`
+pub const MESSAGE: &str = "message";
+/// `logger_name`
+pub const LOGGER_NAME: &str = "logger_name";
+/// `thread_name`
+pub const THREAD_NAME: &str = "thread_name";
+/// `level`
+pub const LEVEL: &str = "level";
+/// `level_value`
+pub const LEVEL_VALUE: &str = "level_value";
+/// `event.name`
+pub const EVENT_NAME: &str = "event.name";
+/// `stack_trace`
+pub const STACK_TRACE: &str = "stack_trace";
+/// `spans`
+pub const SPANS: &str = "spans";
+
+/// `service.name`
+pub const SERVICE_NAME: &str = "service.name";
+/// `service.version`
+pub const SERVICE_VERSION: &str = "service.version";
+/// `service.environment`
+pub const SERVICE_ENVIRONMENT: &str = "service.environment";
+/// `trace.id`
+pub const TRACE_ID: &str = "trace.id";
+/// `transaction.id`
+pub const TRANSACTION_ID: &str = "transaction.id";
+/// `span.id`
+pub const SPAN_ID: &str = "span.id";
+
+/// `http.request.method`
+pub const HTTP_REQUEST_METHOD: &str = "http.request.method";
+/// `http.response.status_code`
+pub const HTTP_STATUS_CODE: &str = "http.response.status_code";
+/// `url.path`
+pub const URL_PATH: &str = "url.path";
+/// `client.ip`
+pub const CLIENT_IP: &str = "client.ip";
+/// `user.id`
+pub const USER_ID: &str = "user.id";
+/// `error.message`
+pub const ERROR_MESSAGE: &str = "error.message";
+/// `event.duration`
+pub const EVENT_DURATION: &str = "event.duration";
+
+/// Span fields and constants configured by key, see [`logstash_fields!`](crate::logstash_fields)
+pub struct Fields {
+    pub span_fields: Vec<crate::fields::FieldSpec>,
+    pub constants: Vec<(&'static str, String)>,
+}
+
+/// Configures span fields and constants using only declared keys
+///
+/// Keys are paths to `&'static str` constants, such as the ones in [`keys`](crate::keys);
+/// string literals are rejected. `TO => FROM` records the span field `FROM` under the name `TO`.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// use tracing_logstash::keys;
+///
+/// const TENANT_ID: &str = "tenant.id";
+///
+/// let fields = tracing_logstash::logstash_fields! {
+///     span_fields: [keys::USER_ID, TENANT_ID],
+///     constants: [keys::SERVICE_NAME = "checkout"],
+/// };
+///
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default()
+///         .with_span_fields(fields.span_fields)
+///         .with_constants(fields.constants),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+///
+/// A misspelled key does not compile:
+/// ```compile_fail
+/// let fields = tracing_logstash::logstash_fields! {
+///     span_fields: [tracing_logstash::keys::USER_IDD],
+/// };
+/// ```
+#[macro_export]
+macro_rules! logstash_fields {
+    (@span_field $key:path) => {
+        ::std::convert::Into::into($crate::logstash_fields!(@key $key))
+    };
+    (@span_field $key:path, $source_key:path) => {
+        ::std::convert::Into::into((
+            $crate::logstash_fields!(@key $key),
+            $crate::logstash_fields!(@key $source_key),
+        ))
+    };
+    (@key $key:path) => {{
+        const KEY: &'static str = $key;
+        KEY
+    }};
+    (
+        $(span_fields: [$($span_key:path $(=> $source_key:path)?),* $(,)?] $(,)?)?
+        $(constants: [$($constant_key:path = $value:expr),* $(,)?] $(,)?)?
+    ) => {
+        $crate::keys::Fields {
+            span_fields: ::std::vec![
+                $($($crate::logstash_fields!(@span_field $span_key $(, $source_key)?)),*)?
+            ],
+            constants: ::std::vec![
+                $($(($crate::logstash_fields!(@key $constant_key), ::std::string::String::from($value))),*)?
+            ],
+        }
+    };
+}
@@ -1,51 +1,223 @@
+pub mod aggregate;
+pub mod append;
+pub mod background;
+pub mod batch;
+pub mod builder;
+#[cfg(all(unix, feature = "capture"))]
+pub mod capture;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod cef;
+mod checksum;
+pub mod clef;
+mod compress;
+pub mod context;
+pub mod contributors;
+pub mod cost;
+pub mod datadog;
+pub mod deadline;
+mod delivery;
+pub mod diagnostics;
+pub mod dropped;
+pub mod elastic;
+#[cfg(feature = "elasticsearch")]
+pub mod elasticsearch;
+#[cfg(unix)]
+pub mod emergency;
+pub mod emf;
 mod event_recorder;
+pub mod fallback;
 mod fields;
+#[cfg(feature = "fluentd")]
+pub mod fluentd;
 pub mod format;
+pub mod gcp;
+pub mod gelf;
+pub mod hardening;
+#[cfg(feature = "hec")]
+pub mod hec;
+mod host;
+#[cfg(any(feature = "elasticsearch", feature = "hec"))]
+mod http;
+#[cfg(unix)]
+pub mod journald;
+pub mod keys;
+pub mod logfmt;
 pub mod logstash;
+pub mod loki;
+#[cfg(feature = "lumberjack")]
+pub mod lumberjack;
+pub mod mirror;
+pub mod otel;
+mod oversize;
+pub mod prelude;
+pub mod quota;
+pub mod raw;
+pub mod record;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod replay;
+pub mod rolling;
+pub mod self_test;
 mod span_recorder;
+pub mod splunk;
+pub mod stack_trace;
+pub mod syslog;
+pub mod targeted_debug;
+pub mod template;
+mod text;
+pub mod thread;
+pub mod trace_context;
+pub mod udp;
 
+pub use fields::{FieldSpec, RecordedValue, Structured, Unit};
+
+use crate::aggregate::Aggregation;
+use crate::cost::CostAttribution;
+use crate::diagnostics::Diagnostics;
+use crate::dropped::DroppedSummary;
+use crate::fallback::FallbackWriter;
 use crate::logstash::LogstashFormat;
+use crate::oversize::Shrink;
+use crate::quota::{Admission, TenantQuotas};
+use crate::replay::ReplayBuffer;
+use crate::self_test::{SelfTest, SelfTestReport};
 use span_recorder::SpanRecorder;
+use std::borrow::Cow;
 use std::io::Write;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing_core::field::FieldSet;
+use tracing_core::metadata::Kind;
 use tracing_core::span::{Attributes, Id, Record};
-use tracing_core::{Event, Level, Subscriber};
+use tracing_core::{Dispatch, Event, Level, Metadata, Subscriber};
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::Context;
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+/// A builder installing a layer with the default format and writer as the global default
+/// subscriber, see [`builder`](mod@builder)
+pub fn builder() -> builder::Builder {
+    builder::Builder::default()
+}
 
-pub struct Layer<S, E = LogstashFormat, W = fn() -> std::io::StdoutLock<'static>> {
-    record_separator: Vec<u8>,
+/// The layer writing a record for each event
+///
+/// A configured layer can be cloned and installed in several subscribers. Clones share the
+/// [`Diagnostics`], the [`SelfTest`] handle, the tenant quota usage, the aggregation windows, the
+/// [`DroppedSummary`] and the [`ReplayBuffer`].
+///
+/// When several layers recording span fields of the same type are installed in one registry,
+/// the first one notified about a span records its fields, and the others use those.
+pub struct Layer<S, E = LogstashFormat, W = fn() -> std::io::StdoutLock<'static>, M = format::Json>
+{
+    record_separator: RecordSeparator,
     make_writer: W,
     event_format: E,
+    make_serializer: M,
+    tenant_quotas: Option<TenantQuotas>,
+    aggregation: Option<Arc<Aggregation>>,
+    dropped_summary: Option<Arc<DroppedSummary>>,
+    replay_buffer: Option<Arc<ReplayBuffer>>,
+    cost_attribution: Option<CostAttribution>,
+    record_checksum: bool,
+    max_level: Option<Level>,
+    severity_remap: Option<SeverityRemap>,
+    write_error_policy: WriteErrorPolicy,
+    max_record_bytes: Option<usize>,
+    oversize_strategy: OversizeStrategy,
+    strict: bool,
+    bare: bool,
+    diagnostics: Arc<Diagnostics>,
+    self_test: SelfTest,
+    instance: u64,
     _inner: PhantomData<S>,
 }
 
+/// Identifies a layer within the registry, so only the layer that recorded a span merges its
+/// later values
+fn next_instance() -> u64 {
+    static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(0);
+    NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// The layer that recorded the span fields of type `R`
+struct RecordedBy<R>(u64, PhantomData<fn() -> R>);
+
 impl<S> Default for Layer<S> {
     fn default() -> Self {
         Self {
-            record_separator: vec![b'\n'],
+            record_separator: RecordSeparator::NEWLINE,
             make_writer: || std::io::stdout().lock(),
             event_format: Default::default(),
+            make_serializer: format::Json,
+            tenant_quotas: None,
+            aggregation: None,
+            dropped_summary: None,
+            replay_buffer: None,
+            cost_attribution: None,
+            record_checksum: false,
+            max_level: None,
+            severity_remap: None,
+            write_error_policy: WriteErrorPolicy::Count,
+            max_record_bytes: None,
+            oversize_strategy: OversizeStrategy::TruncateMessage,
+            strict: false,
+            bare: false,
+            diagnostics: Default::default(),
+            self_test: Default::default(),
+            instance: next_instance(),
             _inner: Default::default(),
         }
     }
 }
 
-impl<S, E, W> Layer<S, E, W>
+impl<S, E: Clone, W: Clone, M: Clone> Clone for Layer<S, E, W, M> {
+    fn clone(&self) -> Self {
+        Self {
+            record_separator: self.record_separator.clone(),
+            make_writer: self.make_writer.clone(),
+            event_format: self.event_format.clone(),
+            make_serializer: self.make_serializer.clone(),
+            tenant_quotas: self.tenant_quotas.clone(),
+            aggregation: self.aggregation.clone(),
+            dropped_summary: self.dropped_summary.clone(),
+            replay_buffer: self.replay_buffer.clone(),
+            cost_attribution: self.cost_attribution.clone(),
+            record_checksum: self.record_checksum,
+            max_level: self.max_level,
+            severity_remap: self.severity_remap.clone(),
+            write_error_policy: self.write_error_policy,
+            max_record_bytes: self.max_record_bytes,
+            oversize_strategy: self.oversize_strategy,
+            strict: self.strict,
+            bare: self.bare,
+            diagnostics: self.diagnostics.clone(),
+            self_test: self.self_test.clone(),
+            // A clone may be installed in the same registry as the original
+            instance: next_instance(),
+            _inner: PhantomData,
+        }
+    }
+}
+
+impl<S, E, W, M> Layer<S, E, W, M>
 where
     E: format::FormatEvent + 'static,
     S: Subscriber + for<'a> LookupSpan<'a>,
     W: for<'writer> MakeWriter<'writer> + 'static,
+    M: format::MakeSerializer + 'static,
 {
-    pub fn record_separator(self, separator: impl Into<Vec<u8>>) -> Layer<S, E, W> {
+    pub fn record_separator(self, separator: impl Into<RecordSeparator>) -> Layer<S, E, W, M> {
         Layer {
             record_separator: separator.into(),
             ..self
         }
     }
 
-    pub fn event_format<E2>(self, event_format: E2) -> Layer<S, E2, W>
+    pub fn event_format<E2>(self, event_format: E2) -> Layer<S, E2, W, M>
     where
         E2: format::FormatEvent + 'static,
     {
@@ -53,11 +225,28 @@ where
             event_format,
             record_separator: self.record_separator,
             make_writer: self.make_writer,
+            make_serializer: self.make_serializer,
+            tenant_quotas: self.tenant_quotas,
+            aggregation: self.aggregation,
+            dropped_summary: self.dropped_summary,
+            replay_buffer: self.replay_buffer,
+            cost_attribution: self.cost_attribution,
+            record_checksum: self.record_checksum,
+            max_level: self.max_level,
+            severity_remap: self.severity_remap,
+            write_error_policy: self.write_error_policy,
+            max_record_bytes: self.max_record_bytes,
+            oversize_strategy: self.oversize_strategy,
+            strict: self.strict,
+            bare: self.bare,
+            diagnostics: self.diagnostics,
+            self_test: self.self_test,
+            instance: self.instance,
             _inner: self._inner,
         }
     }
 
-    pub fn with_writer<W2>(self, make_writer: W2) -> Layer<S, E, W2>
+    pub fn with_writer<W2>(self, make_writer: W2) -> Layer<S, E, W2, M>
     where
         W2: for<'writer> MakeWriter<'writer> + 'static,
     {
@@ -65,28 +254,413 @@ where
             make_writer,
             event_format: self.event_format,
             record_separator: self.record_separator,
+            make_serializer: self.make_serializer,
+            tenant_quotas: self.tenant_quotas,
+            aggregation: self.aggregation,
+            dropped_summary: self.dropped_summary,
+            replay_buffer: self.replay_buffer,
+            cost_attribution: self.cost_attribution,
+            record_checksum: self.record_checksum,
+            max_level: self.max_level,
+            severity_remap: self.severity_remap,
+            write_error_policy: self.write_error_policy,
+            max_record_bytes: self.max_record_bytes,
+            oversize_strategy: self.oversize_strategy,
+            strict: self.strict,
+            bare: self.bare,
+            diagnostics: self.diagnostics,
+            self_test: self.self_test,
+            instance: self.instance,
+            _inner: self._inner,
+        }
+    }
+
+    /// Write records the current writer fails to write to `fallback` instead, see [`fallback`]
+    pub fn with_fallback_writer<F>(self, fallback: F) -> Layer<S, E, FallbackWriter<W, F>, M>
+    where
+        F: for<'writer> MakeWriter<'writer> + 'static,
+    {
+        Layer {
+            make_writer: FallbackWriter::new(self.make_writer, fallback),
+            event_format: self.event_format,
+            record_separator: self.record_separator,
+            make_serializer: self.make_serializer,
+            tenant_quotas: self.tenant_quotas,
+            aggregation: self.aggregation,
+            dropped_summary: self.dropped_summary,
+            replay_buffer: self.replay_buffer,
+            cost_attribution: self.cost_attribution,
+            record_checksum: self.record_checksum,
+            max_level: self.max_level,
+            severity_remap: self.severity_remap,
+            write_error_policy: self.write_error_policy,
+            max_record_bytes: self.max_record_bytes,
+            oversize_strategy: self.oversize_strategy,
+            strict: self.strict,
+            bare: self.bare,
+            diagnostics: self.diagnostics,
+            self_test: self.self_test,
+            instance: self.instance,
+            _inner: self._inner,
+        }
+    }
+
+    /// Serialize records with another serde data format than JSON
+    pub fn with_serializer<M2>(self, make_serializer: M2) -> Layer<S, E, W, M2>
+    where
+        M2: format::MakeSerializer + 'static,
+    {
+        Layer {
+            make_serializer,
+            record_separator: self.record_separator,
+            make_writer: self.make_writer,
+            event_format: self.event_format,
+            tenant_quotas: self.tenant_quotas,
+            aggregation: self.aggregation,
+            dropped_summary: self.dropped_summary,
+            replay_buffer: self.replay_buffer,
+            cost_attribution: self.cost_attribution,
+            record_checksum: self.record_checksum,
+            max_level: self.max_level,
+            severity_remap: self.severity_remap,
+            write_error_policy: self.write_error_policy,
+            max_record_bytes: self.max_record_bytes,
+            oversize_strategy: self.oversize_strategy,
+            strict: self.strict,
+            bare: self.bare,
+            diagnostics: self.diagnostics,
+            self_test: self.self_test,
+            instance: self.instance,
             _inner: self._inner,
         }
     }
 
-    fn write_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        let mut serializer = serde_json::Serializer::new(self.make_writer.make_writer());
+    /// Enforce record rate and size quotas per tenant
+    pub fn with_tenant_quotas(self, tenant_quotas: Option<TenantQuotas>) -> Layer<S, E, W, M> {
+        Layer {
+            tenant_quotas,
+            ..self
+        }
+    }
+
+    /// Write summaries of the events selected by `aggregation` instead of the events
+    pub fn with_aggregation(self, aggregation: Aggregation) -> Layer<S, E, W, M> {
+        Layer {
+            aggregation: Some(Arc::new(aggregation)),
+            ..self
+        }
+    }
+
+    /// Write a record with the number of records dropped by the writers, see [`dropped`]
+    pub fn with_dropped_summary(self, dropped_summary: DroppedSummary) -> Layer<S, E, W, M> {
+        Layer {
+            dropped_summary: Some(Arc::new(dropped_summary)),
+            ..self
+        }
+    }
+
+    /// Keep records of verbose events and only write them when an error occurs, see [`replay`]
+    pub fn with_replay_buffer(self, replay_buffer: ReplayBuffer) -> Layer<S, E, W, M> {
+        Layer {
+            replay_buffer: Some(Arc::new(replay_buffer)),
+            ..self
+        }
+    }
+
+    /// Stamp records with their size and count the bytes written per target
+    pub fn with_cost_attribution(
+        self,
+        cost_attribution: Option<CostAttribution>,
+    ) -> Layer<S, E, W, M> {
+        Layer {
+            cost_attribution,
+            ..self
+        }
+    }
+
+    /// Write a CRC-32 checksum of each record serialized as a JSON object as `log.crc32`, 8 hex
+    /// digits. The checksum is computed over the record, without its separator, with the digits
+    /// of the field replaced by `00000000`; making the same replacement verifies the record.
+    pub fn with_record_checksum(self, record_checksum: bool) -> Layer<S, E, W, M> {
+        Layer {
+            record_checksum,
+            ..self
+        }
+    }
+
+    /// Only write events at this level or more severe, after their level has been changed by the
+    /// format or the [severity remap](Self::with_severity_remap). The events must still be
+    /// enabled by the filters of the subscriber at their original level.
+    pub fn with_max_level(self, max_level: Option<Level>) -> Layer<S, E, W, M> {
+        Layer { max_level, ..self }
+    }
+
+    /// Change the level of events by target and level, see [`SeverityRemap`]. The remapped level
+    /// is the one written by the format, compared with the
+    /// [maximum level](Self::with_max_level), and seen by the writer in
+    /// [`make_writer_for`](MakeWriter::make_writer_for).
+    pub fn with_severity_remap(self, severity_remap: Option<SeverityRemap>) -> Layer<S, E, W, M> {
+        Layer {
+            severity_remap,
+            ..self
+        }
+    }
+
+    /// What to do with records that could not be serialized or written, defaults to
+    /// [`WriteErrorPolicy::Count`]
+    pub fn with_write_error_policy(
+        self,
+        write_error_policy: WriteErrorPolicy,
+    ) -> Layer<S, E, W, M> {
+        Layer {
+            write_error_policy,
+            ..self
+        }
+    }
+
+    /// Shrink records serialized as JSON objects that are larger than this many bytes with the
+    /// [oversize strategy](Self::with_oversize_strategy), counting them in
+    /// [`Diagnostics::oversized_records`]. Fields added by the layer, such as the checksum, and
+    /// the record separator are not counted. Records the format can't shrink, such as text
+    /// records, are written as they are and counted in [`Diagnostics::unshrinkable_records`].
+    pub fn with_max_record_bytes(self, max_record_bytes: Option<usize>) -> Layer<S, E, W, M> {
+        Layer {
+            max_record_bytes,
+            ..self
+        }
+    }
+
+    /// Defaults to [`OversizeStrategy::TruncateMessage`]
+    pub fn with_oversize_strategy(self, oversize_strategy: OversizeStrategy) -> Layer<S, E, W, M> {
+        Layer {
+            oversize_strategy,
+            ..self
+        }
+    }
+
+    /// Panic when the registry doesn't know about a span the layer is notified about, instead of
+    /// counting it in the [`Diagnostics`]. Intended for development and tests.
+    pub fn strict(self, strict: bool) -> Layer<S, E, W, M> {
+        Layer { strict, ..self }
+    }
+
+    /// Skip recording span fields, for programs that don't use spans. Span fields are then never
+    /// written, and spans are listed without fields.
+    pub fn bare(self, bare: bool) -> Layer<S, E, W, M> {
+        Layer { bare, ..self }
+    }
+
+    /// Counters for unexpected conditions the layer recovered from
+    pub fn diagnostics(&self) -> Arc<Diagnostics> {
+        self.diagnostics.clone()
+    }
+
+    /// Handle for flushing the aggregation windows, see [`aggregate`]
+    pub fn aggregation(&self) -> Option<Arc<Aggregation>> {
+        self.aggregation.clone()
+    }
+
+    /// Handle for checking that records are delivered, see [`self_test`]
+    pub fn self_test(&self) -> SelfTest {
+        self.self_test.clone()
+    }
+
+    fn span<'a>(&self, id: &Id, ctx: &'a Context<'a, S>) -> Option<SpanRef<'a, S>> {
+        let span = ctx.span(id);
+        if span.is_none() {
+            if self.strict {
+                panic!("Span not found, this is a bug");
+            }
+            self.diagnostics.record_missing_span();
+        }
+        span
+    }
+
+    /// The level of the event as changed by the format and the severity remap
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        let level = self.event_format.event_level(event);
+        match &self.severity_remap {
+            Some(severity_remap) => severity_remap.remap(event.metadata().target(), level),
+            None => level,
+        }
+    }
+
+    /// Writes the record, returning whether it was admitted by the quotas
+    fn write_event(&self, event: &Event<'_>, ctx: Context<'_, S>) -> std::io::Result<bool> {
+        let tenant = self
+            .tenant_quotas
+            .as_ref()
+            .and_then(|quotas| quotas.tenant::<S, E::R>(event, &ctx));
+
+        let level = self.event_level(event);
+        let mut buffer = Vec::with_capacity(512);
         self.event_format
-            .format_event(&mut serializer, event, ctx)
-            .unwrap();
-        let mut inner = serializer.into_inner();
-        inner.write_all(&self.record_separator).unwrap();
+            .write_event(&self.make_serializer, &mut buffer, event, level, ctx)?;
+        self.write_record(buffer, tenant, event.metadata(), level)
+    }
+
+    /// Writes a formatted record of an event written at `level`, returning whether it was
+    /// admitted by the quotas
+    fn write_record(
+        &self,
+        mut buffer: Vec<u8>,
+        tenant: Option<String>,
+        metadata: &'static Metadata<'static>,
+        level: Level,
+    ) -> std::io::Result<bool> {
+        let separator = self.record_separator.as_bytes();
+
+        if let Some(max) = self.max_record_bytes {
+            let keys = self.event_format.record_keys();
+            match oversize::shrink(&mut buffer, max, self.oversize_strategy, keys) {
+                Shrink::Fits => {}
+                Shrink::Shrunk => self.diagnostics.record_oversized_record(),
+                Shrink::Unshrinkable => self.diagnostics.record_unshrinkable_record(),
+            }
+        }
+
+        if let (Some(quotas), Some(tenant)) = (&self.tenant_quotas, tenant) {
+            let bytes = (buffer.len() + separator.len()) as u64;
+            match quotas.admit(&tenant, bytes, Instant::now()) {
+                Admission::Within => {}
+                Admission::Sampled(rate) => {
+                    if quotas.sampling_fields() {
+                        if let Some(mut fields) = json_field_prefix(&buffer, "sampling.rate") {
+                            fields.extend_from_slice(
+                                format!("{},\"sampling.decision\":\"sampled\"", rate).as_bytes(),
+                            );
+                            insert_json_fields(&mut buffer, fields);
+                        }
+                    }
+                }
+                Admission::Dropped => return Ok(false),
+            }
+        }
+
+        // The placeholder is counted in the size, then filled over the final record
+        let checksum = self
+            .record_checksum
+            .then(|| checksum::insert_placeholder(&mut buffer))
+            .flatten();
+        if let Some(cost_attribution) = &self.cost_attribution {
+            cost_attribution.stamp(&mut buffer, separator.len());
+        }
+        buffer.extend_from_slice(separator);
+        if let Some(position) = checksum {
+            checksum::fill(&mut buffer, position, separator.len());
+        }
+
+        // Writers choosing by level see the level the record was written at
+        let remapped;
+        let writer_metadata = if level == *metadata.level() {
+            metadata
+        } else {
+            remapped = with_level(metadata, level);
+            &remapped
+        };
+        // Write the whole record at once, so writers see one write per record
+        let mut writer = self.make_writer.make_writer_for(writer_metadata);
+        writer.write_all(&buffer)?;
+        if self_test::is_writing() {
+            writer.flush()?;
+        }
+        if let Some(cost_attribution) = &self.cost_attribution {
+            cost_attribution.record(metadata.target(), buffer.len());
+        }
+        Ok(true)
+    }
+
+    /// Keeps the record of a verbose event, or writes the records kept before an error, returning
+    /// whether the event was kept
+    fn replay(
+        &self,
+        replay_buffer: &ReplayBuffer,
+        event: &Event<'_>,
+        ctx: &Context<'_, S>,
+    ) -> bool {
+        let level = self.event_level(event);
+        if level != Level::ERROR && !replay_buffer.keeps(level) {
+            return false;
+        }
+        let root = ctx
+            .event_scope(event)
+            .and_then(|scope| scope.from_root().next())
+            .map(|span| span.id());
+        if level != Level::ERROR {
+            let tenant = self
+                .tenant_quotas
+                .as_ref()
+                .and_then(|quotas| quotas.tenant::<S, E::R>(event, ctx));
+            let mut buffer = Vec::with_capacity(512);
+            let result = self
+                .event_format
+                .write_event(
+                    &self.make_serializer,
+                    &mut buffer,
+                    event,
+                    level,
+                    ctx.clone(),
+                )
+                .map(|_| {
+                    if let Some(mut replayed) = json_field_prefix(&buffer, "replayed") {
+                        replayed.extend_from_slice(b"true");
+                        insert_json_fields(&mut buffer, replayed);
+                    }
+                    replay_buffer.keep(root, (event.metadata(), level, tenant, buffer));
+                    true
+                });
+            self.handle_write_error(result);
+            return true;
+        }
+        for (metadata, level, tenant, record) in replay_buffer.take(&root) {
+            self.handle_write_error(self.write_record(record, tenant, metadata, level));
+        }
+        false
+    }
+
+    fn handle_write_error(&self, result: std::io::Result<bool>) {
+        if let Err(error) = result {
+            match self.write_error_policy {
+                WriteErrorPolicy::Ignore => {}
+                WriteErrorPolicy::Count => self.diagnostics.record_write_error(),
+                WriteErrorPolicy::Callback(callback) => callback(&error),
+            }
+        }
+    }
+
+    fn write_self_test_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let start = Instant::now();
+        let (result, writers) = self_test::writing(|| self.write_event(event, ctx));
+        let error = match result {
+            Ok(true) => None,
+            Ok(false) => Some("dropped by tenant quota".to_owned()),
+            Err(e) => Some(e.to_string()),
+        };
+        self.self_test.report(SelfTestReport {
+            format: std::any::type_name::<E>(),
+            writer: std::any::type_name::<W>(),
+            serializer: std::any::type_name::<M>(),
+            latency: start.elapsed(),
+            error,
+            writers,
+        });
     }
 }
 
-impl<S, E, W> tracing_subscriber::Layer<S> for Layer<S, E, W>
+impl<S, E, W, M> tracing_subscriber::Layer<S> for Layer<S, E, W, M>
 where
     S: Subscriber + for<'a> LookupSpan<'a>,
     E: format::FormatEvent + 'static,
     W: for<'writer> MakeWriter<'writer> + 'static,
+    M: format::MakeSerializer + 'static,
 {
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
+        if self.bare {
+            return;
+        }
+        let Some(span) = self.span(id, &ctx) else {
+            return;
+        };
 
         let mut extensions = span.extensions_mut();
 
@@ -95,29 +669,397 @@ where
             recorder.record_span(attrs);
 
             extensions.insert(recorder);
+            extensions.insert(RecordedBy::<E::R>(self.instance, PhantomData));
         }
     }
 
     fn on_record(&self, id: &Id, record: &Record<'_>, ctx: Context<'_, S>) {
-        let span = ctx.span(id).expect("Span not found, this is a bug");
+        if self.bare {
+            return;
+        }
+        let Some(span) = self.span(id, &ctx) else {
+            return;
+        };
         let mut extensions = span.extensions_mut();
 
+        let recorded_by = extensions.get_mut::<RecordedBy<E::R>>().map(|r| r.0);
+        if recorded_by != Some(self.instance) {
+            return;
+        }
         if let Some(fields) = extensions.get_mut::<E::R>() {
             fields.merge(record);
         }
     }
 
+    fn on_close(&self, id: Id, _ctx: Context<'_, S>) {
+        if let Some(replay_buffer) = &self.replay_buffer {
+            replay_buffer.discard(id);
+        }
+    }
+
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        if let Some(aggregation) = &self.aggregation {
+            aggregation.register(subscriber);
+        }
+    }
+
     fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
-        self.write_event(event, ctx);
+        if let Some(dropped) = self
+            .dropped_summary
+            .as_ref()
+            .and_then(|dropped_summary| dropped_summary.take_due(Instant::now()))
+        {
+            dropped::with_event(dropped, |summary| {
+                self.handle_write_error(self.write_event(summary, ctx.clone()))
+            });
+        }
+        if let Some(aggregation) = &self.aggregation {
+            // Written by the aggregation thread or `flush`
+            if aggregation.is_summary(event.metadata()) {
+                self.handle_write_error(self.write_event(event, ctx));
+                return;
+            }
+        }
+        if event.metadata().target() == self_test::TARGET {
+            // Whatever the level, and not aggregated or replayed, to check the delivery
+            self.write_self_test_event(event, ctx);
+            return;
+        }
+        if let Some(replay_buffer) = &self.replay_buffer {
+            if self.replay(replay_buffer, event, &ctx) {
+                return;
+            }
+        }
+        if let Some(max_level) = self.max_level {
+            // More verbose levels compare greater
+            if self.event_level(event) > max_level {
+                return;
+            }
+        }
+        if let Some(aggregation) = &self.aggregation {
+            let now = Instant::now();
+            for summary in aggregation.take_due(now) {
+                summary.with_event(|summary| {
+                    self.handle_write_error(self.write_event(summary, ctx.clone()))
+                });
+            }
+            let summary_tenants = self
+                .tenant_quotas
+                .as_ref()
+                .filter(|quotas| quotas.summary_records());
+            let tenant_field = summary_tenants.map(TenantQuotas::field);
+            let tenant =
+                || summary_tenants.and_then(|quotas| quotas.tenant::<S, E::R>(event, &ctx));
+            if aggregation.add(event, now, tenant_field, tenant) {
+                return;
+            }
+        }
+        self.handle_write_error(self.write_event(event, ctx));
+    }
+}
+
+/// Rules changing the level of events by target and level, to demote noisy dependencies
+/// without filtering them away
+///
+/// The first rule matching the target and level of an event applies; rules are not chained.
+///
+/// # Example
+/// ```
+/// # use tracing_core::Level;
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::SeverityRemap;
+/// #
+/// let logger = tracing_logstash::Layer::default()
+///     .with_severity_remap(Some(
+///         SeverityRemap::default().with_rule("h2", Level::WARN, Level::DEBUG),
+///     ))
+///     .with_max_level(Some(Level::INFO));
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone, Default)]
+pub struct SeverityRemap {
+    rules: Vec<(&'static str, Level, Level)>,
+}
+
+impl SeverityRemap {
+    /// Write events with `target` or its descendants at level `from` at level `to`
+    pub fn with_rule(mut self, target: &'static str, from: Level, to: Level) -> Self {
+        self.rules.push((target, from, to));
+        self
+    }
+
+    fn remap(&self, target: &str, level: Level) -> Level {
+        self.rules
+            .iter()
+            .find(|(prefix, from, _)| *from == level && target_matches(target, prefix))
+            .map_or(level, |(_, _, to)| *to)
+    }
+}
+
+/// The metadata of an event with another level, for writers choosing by level. The field set is
+/// empty, writers don't see the fields.
+fn with_level(metadata: &'static Metadata<'static>, level: Level) -> Metadata<'static> {
+    let kind = if metadata.is_span() {
+        Kind::SPAN
+    } else {
+        Kind::EVENT
+    };
+    Metadata::new(
+        metadata.name(),
+        metadata.target(),
+        level,
+        metadata.file(),
+        metadata.line(),
+        metadata.module_path(),
+        FieldSet::new(&[], metadata.callsite()),
+        kind,
+    )
+}
+
+/// What the layer does with a record that could not be serialized or written, such as when
+/// stdout is a closed pipe
+#[derive(Copy, Clone, Debug)]
+pub enum WriteErrorPolicy {
+    /// Drop the record
+    Ignore,
+    /// Drop the record and count it in [`Diagnostics::write_errors`]
+    Count,
+    /// Drop the record and call the function with the error. Logging from the function is
+    /// likely to fail again, write to stderr instead.
+    Callback(fn(&std::io::Error)),
+}
+
+/// What the layer does with a record larger than the
+/// [maximum record size](Layer::with_max_record_bytes)
+///
+/// The message and the fields kept are the [record keys](format::FormatEvent::record_keys) of
+/// the format. Records that can't be shrunk enough by the strategy are replaced with the stub.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OversizeStrategy {
+    /// Truncate the message, marking it with a `…[truncated]` suffix
+    TruncateMessage,
+    /// Drop fields other than the message and the kept fields, largest first, listing them in
+    /// `log.dropped_fields`
+    DropLargestFields,
+    /// Replace the record with one keeping the kept fields, such as the timestamp and level,
+    /// with a message noting the size of the record, and the size as `log.oversized_bytes`
+    Stub,
+}
+
+/// Bytes written after each record
+///
+/// Can be created from the strings and bytes a `Vec<u8>` can, which are copied, or without
+/// copying from static bytes with [`from_static`](Self::from_static).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordSeparator(Cow<'static, [u8]>);
+
+impl RecordSeparator {
+    pub const NEWLINE: RecordSeparator = RecordSeparator(Cow::Borrowed(b"\n"));
+    pub const NUL: RecordSeparator = RecordSeparator(Cow::Borrowed(b"\0"));
+    /// No separator, for self-delimiting or length-prefixed records
+    pub const NONE: RecordSeparator = RecordSeparator(Cow::Borrowed(b""));
+
+    pub const fn from_static(separator: &'static [u8]) -> Self {
+        Self(Cow::Borrowed(separator))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Default for RecordSeparator {
+    fn default() -> Self {
+        Self::NEWLINE
+    }
+}
+
+impl<'a> From<Cow<'a, [u8]>> for RecordSeparator {
+    fn from(separator: Cow<'a, [u8]>) -> Self {
+        Self(Cow::Owned(separator.into_owned()))
+    }
+}
+
+impl<'a> From<Cow<'a, str>> for RecordSeparator {
+    fn from(separator: Cow<'a, str>) -> Self {
+        Self(Cow::Owned(separator.into_owned().into_bytes()))
+    }
+}
+
+impl From<&[u8]> for RecordSeparator {
+    fn from(separator: &[u8]) -> Self {
+        Self(Cow::Owned(separator.to_vec()))
     }
 }
 
+impl From<&mut [u8]> for RecordSeparator {
+    fn from(separator: &mut [u8]) -> Self {
+        Self(Cow::Owned(separator.to_vec()))
+    }
+}
+
+impl<const N: usize> From<[u8; N]> for RecordSeparator {
+    fn from(separator: [u8; N]) -> Self {
+        Self(Cow::Owned(separator.to_vec()))
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for RecordSeparator {
+    fn from(separator: &[u8; N]) -> Self {
+        Self(Cow::Owned(separator.to_vec()))
+    }
+}
+
+impl From<Vec<u8>> for RecordSeparator {
+    fn from(separator: Vec<u8>) -> Self {
+        Self(Cow::Owned(separator))
+    }
+}
+
+impl From<Box<[u8]>> for RecordSeparator {
+    fn from(separator: Box<[u8]>) -> Self {
+        Self(Cow::Owned(separator.into_vec()))
+    }
+}
+
+impl From<&str> for RecordSeparator {
+    fn from(separator: &str) -> Self {
+        Self(Cow::Owned(separator.as_bytes().to_vec()))
+    }
+}
+
+impl From<&String> for RecordSeparator {
+    fn from(separator: &String) -> Self {
+        Self(Cow::Owned(separator.as_bytes().to_vec()))
+    }
+}
+
+impl From<String> for RecordSeparator {
+    fn from(separator: String) -> Self {
+        Self(Cow::Owned(separator.into_bytes()))
+    }
+}
+
+impl From<Box<str>> for RecordSeparator {
+    fn from(separator: Box<str>) -> Self {
+        Self(Cow::Owned(separator.into_string().into_bytes()))
+    }
+}
+
+impl From<std::ffi::CString> for RecordSeparator {
+    fn from(separator: std::ffi::CString) -> Self {
+        Self(Cow::Owned(separator.into_bytes()))
+    }
+}
+
+/// The start of a field appended to a record serialized as a JSON object, `,"key":`, or `None`
+/// if the record is not a JSON object
+pub(crate) fn json_field_prefix(record: &[u8], key: &str) -> Option<Vec<u8>> {
+    if record.first() != Some(&b'{') || record.last() != Some(&b'}') {
+        return None;
+    }
+    let mut prefix = if record.len() > 2 {
+        b",".to_vec()
+    } else {
+        Vec::new()
+    };
+    prefix.extend_from_slice(
+        serde_json::to_string(key)
+            .expect("strings serialize")
+            .as_bytes(),
+    );
+    prefix.push(b':');
+    Some(prefix)
+}
+
+/// Inserts fields, starting with a [`json_field_prefix`], before the end of a JSON object
+pub(crate) fn insert_json_fields(record: &mut Vec<u8>, fields: Vec<u8>) {
+    let end = record.len() - 1;
+    record.splice(end..end, fields);
+}
+
+/// The record without its trailing record separator, for transports framing records themselves
+pub(crate) fn trim_separator(record: &[u8]) -> &[u8] {
+    let end = record
+        .iter()
+        .rposition(|b| !matches!(b, b'\n' | b'\r' | b'\0'))
+        .map_or(0, |i| i + 1);
+    &record[..end]
+}
+
+/// Whether `target` is `prefix` or one of its descendants, e.g. `my_app` matches `my_app` and
+/// `my_app::db`, but not `my_app_macros`
+pub(crate) fn target_matches(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
 #[derive(Copy, Clone)]
 pub enum LoggerName {
     Event,
     Span,
 }
 
+/// Which event names to display as `event.name`
+#[derive(Copy, Clone)]
+pub enum EventName {
+    /// Display the names of all events, including generated names like `event src/main.rs:10`
+    All,
+    /// Only display names set explicitly, as in `event!(name: "payment.captured", ...)`
+    Explicit,
+}
+
+/// Classification of an event, used to decide whether to display the stack trace and span list
+///
+/// See [`LogstashFormat::with_error_classifier`](logstash::LogstashFormat::with_error_classifier).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Always display the stack trace and span list, including all spans
+    Unexpected,
+    /// Never display the stack trace and span list, e.g. for 4xx responses
+    ClientError,
+    /// Display the stack trace and span list according to the configured level filters
+    Unclassified,
+}
+
+/// Which levels of the spans in an event's scope to display
+#[derive(Copy, Clone)]
+pub enum SpanLevels {
+    /// The levels of all spans in the scope, innermost first, as `span_levels`
+    All,
+    /// The most severe level among the spans in the scope, as `span_level_max`
+    MostSevere,
+}
+
+/// Where a field written by the format comes from, see
+/// [`with_field_precedence`](logstash::LogstashFormat::with_field_precedence)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FieldOrigin {
+    /// Fields of the event
+    Event,
+    /// Recorded fields of the span the event is in
+    InnermostSpan,
+    /// Recorded fields of the parents of that span, inner spans first
+    OuterSpans,
+    /// Fields added by the field contributor
+    Dynamic,
+    /// The constants of the format
+    Constant,
+}
+
+impl FieldOrigin {
+    /// The order fields are written in by default, the first one written winning
+    pub const DEFAULT_PRECEDENCE: [FieldOrigin; 5] = [
+        FieldOrigin::Constant,
+        FieldOrigin::Dynamic,
+        FieldOrigin::Event,
+        FieldOrigin::InnermostSpan,
+        FieldOrigin::OuterSpans,
+    ];
+}
+
 #[derive(Copy, Clone)]
 pub enum DisplayLevelFilter {
     Off,
@@ -149,3 +1091,9 @@ impl DisplayLevelFilter {
         filter_level >= span_level
     }
 }
+
+impl From<Level> for DisplayLevelFilter {
+    fn from(level: Level) -> Self {
+        Self::from_level(level)
+    }
+}
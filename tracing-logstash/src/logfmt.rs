@@ -0,0 +1,377 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, MakeSerializer, RecordKeys};
+use crate::logstash::{LogFieldContributor, LogTimestamp};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::text::TextFields;
+use serde::Serializer;
+use std::io::Write as _;
+use std::sync::Arc;
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for [logfmt](https://brandur.org/logfmt) lines of `key=value` pairs
+///
+/// Records start with `ts`, `level`, `logger_name`, `thread_name` and `msg`, followed by
+/// constants, contributed fields, event fields and recorded span fields. Values with spaces,
+/// quotes, `=` or control characters are quoted.
+///
+/// When used with a JSON serializer, as when wrapped by other formats, the line is serialized
+/// as a string.
+///
+/// For reading records in a terminal during development, a [`Theme`] colors and emphasizes
+/// the fields with ANSI escape codes, keeping the same fields as the plain lines.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logfmt::LogfmtFormat::default()
+///         .with_constants(vec![("service", "checkout".to_owned())]),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct LogfmtFormat<FC = ()> {
+    display_timestamp: bool,
+    display_logger_name: bool,
+    display_thread_name: bool,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    theme: Option<Theme>,
+    field_contributor: FC,
+}
+
+impl Default for LogfmtFormat {
+    fn default() -> Self {
+        Self {
+            display_timestamp: true,
+            display_logger_name: true,
+            display_thread_name: true,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            theme: None,
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> LogfmtFormat<FC> {
+    pub fn with_timestamp(self, display_timestamp: bool) -> Self {
+        Self {
+            display_timestamp,
+            ..self
+        }
+    }
+    pub fn with_logger_name(self, display_logger_name: bool) -> Self {
+        Self {
+            display_logger_name,
+            ..self
+        }
+    }
+    pub fn with_thread_name(self, display_thread_name: bool) -> Self {
+        Self {
+            display_thread_name,
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    /// Color and emphasize fields for terminals, see [`Theme`]
+    pub fn with_theme(self, theme: Option<Theme>) -> Self {
+        Self { theme, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> LogfmtFormat<FC2> {
+        LogfmtFormat {
+            display_timestamp: self.display_timestamp,
+            display_logger_name: self.display_logger_name,
+            display_thread_name: self.display_thread_name,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            theme: self.theme,
+            field_contributor,
+        }
+    }
+}
+
+impl<FC: LogFieldContributor> LogfmtFormat<FC> {
+    fn format<SS>(&self, event: &Event<'_>, level: Level, ctx: &Context<'_, SS>) -> String
+    where
+        SS: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let event_metadata = event.metadata();
+        let mut fields = TextFields::excluding(&self.excluded_fields);
+        if self.display_timestamp {
+            if let Ok(serde_json::Value::String(ts)) = serde_json::to_value(LogTimestamp::default())
+            {
+                fields.add("ts", ts);
+            }
+        }
+        fields.add("level", level.as_str().to_ascii_lowercase());
+        if self.display_logger_name {
+            fields.add("logger_name", event_metadata.target());
+        }
+        if self.display_thread_name {
+            if let Some(name) = std::thread::current().name() {
+                fields.add("thread_name", name);
+            }
+        }
+        // Reserve the position of msg, which is only known once the event has been visited
+        fields.add("msg", String::new());
+        for (key, value) in &self.constants {
+            fields.add(*key, value);
+        }
+        self.field_contributor.add_fields(&mut fields);
+        fields.add_event(event, ctx);
+
+        let message = fields.message.take();
+        let mut line = String::with_capacity(256);
+        for (key, value) in &fields.fields {
+            let value = match (key.as_ref(), &message) {
+                ("msg", Some(message)) => message,
+                ("msg", None) => continue,
+                _ => value,
+            };
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            let style = self
+                .theme
+                .as_ref()
+                .and_then(|theme| theme.style(key, &level));
+            if let Some(style) = style {
+                line.push_str(&format!("\x1b[{}m", style));
+            }
+            push_key(&mut line, key);
+            line.push('=');
+            push_value(&mut line, value);
+            if style.is_some() {
+                line.push_str("\x1b[0m");
+            }
+        }
+        line
+    }
+}
+
+/// A terminal color, written as an ANSI escape code
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Color {
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    White,
+}
+
+impl Color {
+    fn code(self) -> u8 {
+        30 + self as u8
+    }
+}
+
+/// Colors and emphasis of [`LogfmtFormat`] lines
+///
+/// By default `level` is colored by level, `logger_name` is dimmed and no fields are
+/// highlighted.
+///
+/// # Example
+/// ```
+/// # use tracing_core::Level;
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::logfmt::{LogfmtFormat, Theme};
+/// #
+/// let theme = Theme::default()
+///     .with_level_color(Level::INFO, None)
+///     .with_highlighted_fields(vec!["order_id", "tenant_id"]);
+///
+/// let logger = tracing_logstash::Layer::default()
+///     .event_format(LogfmtFormat::default().with_theme(Some(theme)));
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Theme {
+    /// Colors of the levels, from `ERROR` to `TRACE`
+    level_colors: [Option<Color>; 5],
+    dimmed_logger_name: bool,
+    highlighted_fields: Vec<&'static str>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            level_colors: [
+                Some(Color::Red),
+                Some(Color::Yellow),
+                Some(Color::Green),
+                Some(Color::Blue),
+                Some(Color::Magenta),
+            ],
+            dimmed_logger_name: true,
+            highlighted_fields: Vec::new(),
+        }
+    }
+}
+
+impl Theme {
+    /// Color of `level` for events at `level`, or `None` for the default terminal color
+    pub fn with_level_color(mut self, level: Level, color: Option<Color>) -> Self {
+        self.level_colors[level_index(&level)] = color;
+        self
+    }
+
+    pub fn with_dimmed_logger_name(self, dimmed_logger_name: bool) -> Self {
+        Self {
+            dimmed_logger_name,
+            ..self
+        }
+    }
+
+    /// Fields written in bold
+    pub fn with_highlighted_fields(self, highlighted_fields: Vec<&'static str>) -> Self {
+        Self {
+            highlighted_fields,
+            ..self
+        }
+    }
+
+    /// The SGR parameter of a field
+    fn style(&self, key: &str, level: &Level) -> Option<u8> {
+        match key {
+            "level" => self.level_colors[level_index(level)].map(Color::code),
+            "logger_name" if self.dimmed_logger_name => Some(2),
+            key if self.highlighted_fields.contains(&key) => Some(1),
+            _ => None,
+        }
+    }
+}
+
+fn level_index(level: &Level) -> usize {
+    match *level {
+        Level::ERROR => 0,
+        Level::WARN => 1,
+        Level::INFO => 2,
+        Level::DEBUG => 3,
+        Level::TRACE => 4,
+    }
+}
+
+fn push_key(out: &mut String, key: &str) {
+    out.extend(key.chars().map(|c| {
+        if c.is_whitespace() || c.is_control() || matches!(c, '=' | '"') {
+            '_'
+        } else {
+            c
+        }
+    }));
+}
+
+fn push_value(out: &mut String, value: &str) {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '=' | '"'));
+    if !needs_quotes {
+        out.push_str(value);
+        return;
+    }
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{{{:04x}}}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+impl<FC: LogFieldContributor> FormatEvent for LogfmtFormat<FC> {
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.format(event, level, &ctx))
+    }
+
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        _make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> std::io::Result<()> {
+        buffer.write_all(self.format(event, level, &ctx).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{push_key, push_value};
+
+    #[test]
+    fn test_quoting() {
+        let mut line = String::new();
+        push_key(&mut line, "a b=c");
+        line.push('=');
+        push_value(&mut line, "plain");
+        line.push(' ');
+        push_value(&mut line, "");
+        line.push(' ');
+        push_value(&mut line, "say \"hi\"\n\\");
+        line.push(' ');
+        push_value(&mut line, "a=b");
+        assert_eq!(line, r#"a_b_c=plain "" "say \"hi\"\n\\" "a=b""#);
+    }
+}
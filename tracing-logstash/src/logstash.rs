@@ -1,14 +1,21 @@
-use crate::fields::{FieldConfig, FieldSpec};
-use crate::format::{
-    write_extension_fields, DefaultSpanFormat, FormatEvent, FormatSpan, SerializableSpanList,
-};
+use crate::emf::EmfMetrics;
+use crate::fields::{FieldConfig, FieldSpec, RecordedValue, TryForEachField};
+use crate::format::{DefaultSpanFormat, FormatEvent, FormatSpan, RecordKeys, SerializableSpanList};
+use crate::hardening::HardeningProfile;
 use crate::span_recorder::DefaultSpanRecorder;
-use crate::{DisplayLevelFilter, LoggerName};
-use serde::ser::{Error, SerializeMap};
+use crate::stack_trace::{StackTraceConfig, StackTraceHandle};
+use crate::trace_context::ApmCorrelation;
+use crate::{
+    target_matches, DisplayLevelFilter, ErrorClass, EventName, FieldOrigin, LoggerName, SpanLevels,
+};
+use serde::ser::{Error, SerializeMap, SerializeSeq};
 use serde::{Serialize, Serializer};
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as _;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 use tracing_core::field::{Field, Visit};
 use tracing_core::{Event, Level, Metadata, Subscriber};
 use tracing_subscriber::layer::Context;
@@ -27,23 +34,62 @@ use tracing_subscriber::registry::LookupSpan;
 /// #
 /// # let collector = tracing_subscriber::Registry::default().with(logger);
 /// ```
+#[derive(Clone)]
 pub struct LogstashFormat<FC = (), SF = DefaultSpanFormat> {
     display_version: bool,
     display_timestamp: bool,
+    timestamp_field: Option<&'static str>,
+    received_timestamp_key: &'static str,
     display_logger_name: Option<LoggerName>,
+    logger_root: Option<&'static str>,
+    logger_strip_prefix: Option<&'static str>,
     display_thread_name: bool,
+    display_event_name: Option<EventName>,
     display_level: bool,
     display_level_value: bool,
     display_span_list: Option<DisplayLevelFilter>,
-    display_stack_trace: Option<(DisplayLevelFilter, DisplayLevelFilter)>,
+    display_span_levels: Option<SpanLevels>,
+    display_stack_trace: Option<StackTraceConfig>,
+    stack_trace_handle: Option<StackTraceHandle>,
+    display_stack_frames: bool,
+    error_classifier: Option<fn(&Event<'_>) -> ErrorClass>,
     span_format: SF,
     span_fields: Arc<FieldConfig>,
     constants: Vec<(&'static str, String)>,
+    message_key: &'static str,
+    /// `message.template`, or the template key of the custom message key
+    message_template_key: &'static str,
+    expand_message_templates: bool,
+    apm_correlation: Option<ApmCorrelation>,
+    hardening: Option<HardeningProfile>,
+    level_override: Option<LevelOverride>,
+    emf_metrics: Option<EmfMetrics>,
+    retention: Option<Retention>,
+    value_labels: Option<ValueLabels>,
+    display_uptime: bool,
+    last_event: Option<Arc<AtomicU64>>,
+    float_digits: Option<u32>,
+    max_string_length: Option<usize>,
+    serialized_span_fields: bool,
+    span_list_cache: Option<u64>,
+    flatten_objects: bool,
+    excluded_fields: Arc<[&'static str]>,
+    allowed_event_fields: Option<Vec<&'static str>>,
+    field_precedence: Vec<FieldOrigin>,
     field_contributor: FC,
 }
 
+/// Appended to string values truncated to the maximum string length
+pub(crate) const TRUNCATED_SUFFIX: &str = "…[truncated]";
+
+/// Start of the monotonic clock for `process.uptime_ms` and `log.gap_ms`
+fn process_start() -> Instant {
+    static PROCESS_START: OnceLock<Instant> = OnceLock::new();
+    *PROCESS_START.get_or_init(Instant::now)
+}
+
 /// Converts a `Level` to a numeric value.
-const fn level_value(level: &Level) -> u64 {
+pub(crate) const fn level_value(level: &Level) -> u64 {
     match *level {
         Level::ERROR => 3,
         Level::WARN => 4,
@@ -60,6 +106,27 @@ impl<FC, SF> LogstashFormat<FC, SF> {
             ..self
         }
     }
+    /// Use the time in this event field as `@timestamp`, for events that carry the time they
+    /// occurred, such as replayed messages. The field holds an RFC 3339 string or the number of
+    /// milliseconds since the Unix epoch. Events without a valid time in the field use the
+    /// current time.
+    ///
+    /// Events using the time from the field also have the current time, when they were written,
+    /// under the [received timestamp key](Self::with_received_timestamp_key).
+    pub fn with_timestamp_field(self, timestamp_field: Option<&'static str>) -> Self {
+        Self {
+            timestamp_field,
+            ..self
+        }
+    }
+    /// Key of the time the event was written when `@timestamp` is taken from an event field,
+    /// defaults to `event.created`
+    pub fn with_received_timestamp_key(self, received_timestamp_key: &'static str) -> Self {
+        Self {
+            received_timestamp_key,
+            ..self
+        }
+    }
     pub fn with_version(self, display_version: bool) -> Self {
         Self {
             display_version,
@@ -72,12 +139,36 @@ impl<FC, SF> LogstashFormat<FC, SF> {
             ..self
         }
     }
+    /// Logger name of events with an empty target, or with nothing left of it after
+    /// [`with_logger_strip_prefix`](Self::with_logger_strip_prefix), such as
+    /// `env!("CARGO_PKG_NAME")`. Applies to [`LoggerName::Event`].
+    pub fn with_logger_root(self, logger_root: Option<&'static str>) -> Self {
+        Self {
+            logger_root,
+            ..self
+        }
+    }
+    /// Remove a common prefix from the logger names of events, so with `my_workspace`
+    /// the target `my_workspace::billing` is written as `billing`. Only whole path segments are
+    /// removed. Applies to [`LoggerName::Event`].
+    pub fn with_logger_strip_prefix(self, logger_strip_prefix: Option<&'static str>) -> Self {
+        Self {
+            logger_strip_prefix,
+            ..self
+        }
+    }
     pub fn with_thread_name(self, display_thread_name: bool) -> Self {
         Self {
             display_thread_name,
             ..self
         }
     }
+    pub fn with_event_name(self, display_event_name: Option<EventName>) -> Self {
+        Self {
+            display_event_name,
+            ..self
+        }
+    }
     pub fn with_level(self, display_level: bool) -> Self {
         Self {
             display_level,
@@ -90,21 +181,99 @@ impl<FC, SF> LogstashFormat<FC, SF> {
             ..self
         }
     }
+    /// Display `process.uptime_ms`, the time since the first format was created, from a
+    /// monotonic clock
+    pub fn with_uptime(self, display_uptime: bool) -> Self {
+        process_start();
+        Self {
+            display_uptime,
+            ..self
+        }
+    }
+    /// Display `log.gap_ms`, the time since this format last formatted an event, from a
+    /// monotonic clock
+    pub fn with_gap(self, display_gap: bool) -> Self {
+        process_start();
+        Self {
+            last_event: display_gap.then(Default::default),
+            ..self
+        }
+    }
     pub fn with_span_list(self, display_span_list: Option<DisplayLevelFilter>) -> Self {
         Self {
             display_span_list,
             ..self
         }
     }
+    pub fn with_span_levels(self, display_span_levels: Option<SpanLevels>) -> Self {
+        Self {
+            display_span_levels,
+            ..self
+        }
+    }
     pub fn with_stack_trace(
         self,
         display_stack_trace: Option<(DisplayLevelFilter, DisplayLevelFilter)>,
     ) -> Self {
+        self.with_stack_trace_config(display_stack_trace.map(|(event_filter, span_filter)| {
+            StackTraceConfig::from_filters(event_filter, span_filter)
+        }))
+    }
+    /// Like [`with_stack_trace`](Self::with_stack_trace), built with a [`StackTraceConfig`]
+    pub fn with_stack_trace_config(self, display_stack_trace: Option<StackTraceConfig>) -> Self {
         Self {
             display_stack_trace,
             ..self
         }
     }
+    /// Read the stack trace configuration from a handle for every event, so it can be adjusted at
+    /// runtime. Takes precedence over [`with_stack_trace`](Self::with_stack_trace).
+    pub fn with_stack_trace_handle(self, stack_trace_handle: Option<StackTraceHandle>) -> Self {
+        Self {
+            stack_trace_handle,
+            ..self
+        }
+    }
+
+    /// In addition to `stack_trace`, display the stack trace as `stack_frames`, an array of
+    /// `{target, file, line}` objects. Has no effect unless the stack trace is displayed.
+    pub fn with_stack_frames(self, display_stack_frames: bool) -> Self {
+        Self {
+            display_stack_frames,
+            ..self
+        }
+    }
+
+    /// Classify events to decide whether to display the stack trace and span list, instead of
+    /// relying only on the level filters.
+    ///
+    /// # Example
+    /// ```
+    /// # use tracing_subscriber::prelude::*;
+    /// # use tracing_logstash::{DisplayLevelFilter, ErrorClass};
+    /// #
+    /// fn classify(event: &tracing::Event<'_>) -> ErrorClass {
+    ///     if event.metadata().target().starts_with("my_app::api") {
+    ///         ErrorClass::ClientError
+    ///     } else {
+    ///         ErrorClass::Unclassified
+    ///     }
+    /// }
+    ///
+    /// let logger = tracing_logstash::Layer::default().event_format(
+    ///     tracing_logstash::logstash::LogstashFormat::default()
+    ///         .with_stack_trace(Some((DisplayLevelFilter::ERROR, DisplayLevelFilter::All)))
+    ///         .with_error_classifier(classify),
+    /// );
+    /// #
+    /// # let collector = tracing_subscriber::Registry::default().with(logger);
+    /// ```
+    pub fn with_error_classifier(self, error_classifier: fn(&Event<'_>) -> ErrorClass) -> Self {
+        Self {
+            error_classifier: Some(error_classifier),
+            ..self
+        }
+    }
 
     pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
         Self {
@@ -142,15 +311,43 @@ impl<FC, SF> LogstashFormat<FC, SF> {
         LogstashFormat {
             display_version: self.display_version,
             display_timestamp: self.display_timestamp,
+            timestamp_field: self.timestamp_field,
+            received_timestamp_key: self.received_timestamp_key,
             display_logger_name: self.display_logger_name,
+            logger_root: self.logger_root,
+            logger_strip_prefix: self.logger_strip_prefix,
             display_thread_name: self.display_thread_name,
+            display_event_name: self.display_event_name,
             display_level: self.display_level,
             display_stack_trace: self.display_stack_trace,
+            stack_trace_handle: self.stack_trace_handle,
+            display_stack_frames: self.display_stack_frames,
+            error_classifier: self.error_classifier,
             display_level_value: self.display_level_value,
             display_span_list: self.display_span_list,
+            display_span_levels: self.display_span_levels,
             span_format: self.span_format,
             span_fields: self.span_fields,
             constants: self.constants,
+            message_key: self.message_key,
+            message_template_key: self.message_template_key,
+            expand_message_templates: self.expand_message_templates,
+            apm_correlation: self.apm_correlation,
+            hardening: self.hardening,
+            level_override: self.level_override,
+            emf_metrics: self.emf_metrics,
+            retention: self.retention,
+            value_labels: self.value_labels,
+            display_uptime: self.display_uptime,
+            last_event: self.last_event,
+            float_digits: self.float_digits,
+            max_string_length: self.max_string_length,
+            serialized_span_fields: self.serialized_span_fields,
+            span_list_cache: self.span_list_cache,
+            flatten_objects: self.flatten_objects,
+            excluded_fields: self.excluded_fields,
+            allowed_event_fields: self.allowed_event_fields,
+            field_precedence: self.field_precedence,
             field_contributor,
         }
     }
@@ -173,22 +370,257 @@ impl<FC, SF> LogstashFormat<FC, SF> {
         Self { constants, ..self }
     }
 
+    /// Write the event message under a different key than `message`. The unexpanded message of
+    /// [message templates](Self::with_message_templates) is written under the key followed by
+    /// `.template`.
+    pub fn with_message_key(self, message_key: &'static str) -> Self {
+        let message_template_key = match message_key {
+            "message" => "message.template",
+            // Created once per format and kept for the lifetime of the program
+            key => String::leak(format!("{}.template", key)),
+        };
+        Self {
+            message_key,
+            message_template_key,
+            ..self
+        }
+    }
+
+    /// Expand `{field_name}` placeholders in the event message with the values of the event's
+    /// fields. The unexpanded message is kept in `message.template`, or under the
+    /// [message key](Self::with_message_key) followed by `.template`.
+    ///
+    /// # Example
+    /// ```
+    /// # use tracing_subscriber::prelude::*;
+    /// #
+    /// let logger = tracing_logstash::Layer::default().event_format(
+    ///     tracing_logstash::logstash::LogstashFormat::default().with_message_templates(true),
+    /// );
+    /// #
+    /// # let collector = tracing_subscriber::Registry::default().with(logger);
+    /// # let _guard = tracing::subscriber::set_default(collector);
+    /// // {"message": "user 42 logged in", "message.template": "user {user_id} logged in", ...}
+    /// tracing::info!(user_id = 42, "user {{user_id}} logged in");
+    /// ```
+    pub fn with_message_templates(self, expand_message_templates: bool) -> Self {
+        Self {
+            expand_message_templates,
+            ..self
+        }
+    }
+
+    /// Add the fields Elastic APM uses to correlate logs with traces and transactions.
+    pub fn with_apm_correlation(self, apm_correlation: Option<ApmCorrelation>) -> Self {
+        Self {
+            apm_correlation,
+            ..self
+        }
+    }
+
+    /// Sanitize all field values according to a [`HardeningProfile`] before writing them.
+    pub fn with_hardening(self, hardening: Option<HardeningProfile>) -> Self {
+        Self { hardening, ..self }
+    }
+
+    /// Round floating point event and span fields to this many significant digits, so
+    /// `0.1 + 0.2` is written as `0.3` rather than `0.30000000000000004`.
+    ///
+    /// Floats are always written in the shortest form that reads back as the same value, with
+    /// `.` as the decimal separator regardless of the locale, and in exponent notation when that
+    /// is shorter, as in `1e+16`. NaN and infinities are written as `null`.
+    pub fn with_float_digits(self, float_digits: Option<u32>) -> Self {
+        Self {
+            float_digits: float_digits.map(|digits| digits.clamp(1, 17)),
+            ..self
+        }
+    }
+
+    /// Truncate string values of event and span fields longer than this many bytes, including
+    /// the message and debug-formatted values, marking them with a `…[truncated]` suffix
+    pub fn with_max_string_length(self, max_string_length: Option<usize>) -> Self {
+        Self {
+            max_string_length,
+            ..self
+        }
+    }
+
+    /// Serialize span fields to JSON once when they are recorded, rather than for every event
+    /// logged in the span. This is faster when many events are logged in spans with long string
+    /// fields, at the cost of memory per span. Serialized span fields are not rounded by
+    /// [`with_float_digits`](Self::with_float_digits) or truncated by
+    /// [`with_max_string_length`](Self::with_max_string_length).
+    pub fn with_serialized_span_fields(self, serialized_span_fields: bool) -> Self {
+        Self {
+            serialized_span_fields,
+            ..self
+        }
+    }
+
+    /// Keep the serialized span list in the innermost span, so it is serialized once for all the
+    /// events logged in that span rather than for every event. The list is serialized again
+    /// when values are recorded on a span of the scope.
+    pub fn with_cached_span_list(self, cached_span_list: bool) -> Self {
+        static NEXT_CACHE: AtomicU64 = AtomicU64::new(0);
+        Self {
+            span_list_cache: cached_span_list.then(|| NEXT_CACHE.fetch_add(1, Ordering::Relaxed)),
+            ..self
+        }
+    }
+
+    /// Never write fields with these names, whether they are event fields, span fields or
+    /// contributed fields. Span fields with these names are not recorded, so they are not passed
+    /// to child processes by [`context::to_env`](crate::context::to_env) either.
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+
+    /// Only write the event fields with these names, and the message, to bound the fields of
+    /// events from third-party crates. Span fields and contributed fields are not affected.
+    pub fn with_allowed_event_fields(
+        self,
+        allowed_event_fields: Option<Vec<&'static str>>,
+    ) -> Self {
+        Self {
+            allowed_event_fields,
+            ..self
+        }
+    }
+
+    /// Order of precedence of fields with the same name from different origins, the first
+    /// origin winning. Origins left out follow in the order of
+    /// [`FieldOrigin::DEFAULT_PRECEDENCE`]. Fields written by the format itself, such as
+    /// `@timestamp`, always take precedence, except `spans`, which is written right after the
+    /// [`Dynamic`](FieldOrigin::Dynamic) fields.
+    pub fn with_field_precedence(self, field_precedence: Vec<FieldOrigin>) -> Self {
+        let mut precedence = Vec::new();
+        for origin in field_precedence
+            .into_iter()
+            .chain(FieldOrigin::DEFAULT_PRECEDENCE)
+        {
+            if !precedence.contains(&origin) {
+                precedence.push(origin);
+            }
+        }
+        Self {
+            field_precedence: precedence,
+            ..self
+        }
+    }
+
+    /// Write object values, and strings holding JSON objects, as one field per leaf with dotted
+    /// keys, so `request = {"method": "GET"}` is written as `"request.method": "GET"`. Arrays
+    /// are written as they are.
+    pub fn with_flattened_objects(self, flatten_objects: bool) -> Self {
+        Self {
+            flatten_objects,
+            ..self
+        }
+    }
+
+    /// Let an event field override `level` and `level_value`, for events bridged from systems
+    /// whose severity does not match the tracing level.
+    ///
+    /// # Example
+    /// ```
+    /// # use tracing_subscriber::prelude::*;
+    /// # use tracing_logstash::logstash::LevelOverride;
+    /// #
+    /// let logger = tracing_logstash::Layer::default().event_format(
+    ///     tracing_logstash::logstash::LogstashFormat::default()
+    ///         .with_level_override(Some(LevelOverride::new("log.level", vec!["legacy_bridge"]))),
+    /// );
+    /// #
+    /// # let collector = tracing_subscriber::Registry::default().with(logger);
+    /// ```
+    pub fn with_level_override(self, level_override: Option<LevelOverride>) -> Self {
+        Self {
+            level_override,
+            ..self
+        }
+    }
+
+    /// Write a `retention` hint chosen by target and level, see [`Retention`].
+    pub fn with_retention(self, retention: Option<Retention>) -> Self {
+        Self { retention, ..self }
+    }
+
+    /// Write the labels of coded field values next to the codes, see [`ValueLabels`].
+    pub fn with_value_labels(self, value_labels: Option<ValueLabels>) -> Self {
+        Self {
+            value_labels,
+            ..self
+        }
+    }
+
+    /// Promote numeric event fields to CloudWatch metrics, see [`EmfMetrics`].
+    pub fn with_emf_metrics(self, emf_metrics: Option<EmfMetrics>) -> Self {
+        Self {
+            emf_metrics,
+            ..self
+        }
+    }
+
     pub fn span_format<FS2>(self, span_format: FS2) -> LogstashFormat<FC, FS2> {
         LogstashFormat {
             display_version: self.display_version,
             display_timestamp: self.display_timestamp,
+            timestamp_field: self.timestamp_field,
+            received_timestamp_key: self.received_timestamp_key,
             display_logger_name: self.display_logger_name,
+            logger_root: self.logger_root,
+            logger_strip_prefix: self.logger_strip_prefix,
             display_thread_name: self.display_thread_name,
+            display_event_name: self.display_event_name,
             display_level: self.display_level,
             display_stack_trace: self.display_stack_trace,
+            stack_trace_handle: self.stack_trace_handle,
+            display_stack_frames: self.display_stack_frames,
+            error_classifier: self.error_classifier,
             display_level_value: self.display_level_value,
             display_span_list: self.display_span_list,
+            display_span_levels: self.display_span_levels,
             span_format,
             span_fields: self.span_fields,
             constants: self.constants,
+            message_key: self.message_key,
+            message_template_key: self.message_template_key,
+            expand_message_templates: self.expand_message_templates,
+            apm_correlation: self.apm_correlation,
+            hardening: self.hardening,
+            level_override: self.level_override,
+            emf_metrics: self.emf_metrics,
+            retention: self.retention,
+            value_labels: self.value_labels,
+            display_uptime: self.display_uptime,
+            last_event: self.last_event,
+            float_digits: self.float_digits,
+            max_string_length: self.max_string_length,
+            serialized_span_fields: self.serialized_span_fields,
+            span_list_cache: self.span_list_cache,
+            flatten_objects: self.flatten_objects,
+            excluded_fields: self.excluded_fields,
+            allowed_event_fields: self.allowed_event_fields,
+            field_precedence: self.field_precedence,
             field_contributor: self.field_contributor,
         }
     }
+
+    fn event_logger_name<'a>(&self, target: &'a str) -> &'a str {
+        let name = match self.logger_strip_prefix {
+            Some(prefix) if target_matches(target, prefix) => {
+                target[prefix.len()..].trim_start_matches("::")
+            }
+            _ => target,
+        };
+        match self.logger_root {
+            Some(root) if name.is_empty() => root,
+            _ => name,
+        }
+    }
 }
 
 impl Default for LogstashFormat {
@@ -196,61 +628,132 @@ impl Default for LogstashFormat {
         Self {
             display_version: true,
             display_timestamp: true,
+            timestamp_field: None,
+            received_timestamp_key: "event.created",
             display_logger_name: Some(LoggerName::Event),
+            logger_root: None,
+            logger_strip_prefix: None,
             display_thread_name: true,
+            display_event_name: None,
             display_level: true,
             display_level_value: true,
             display_stack_trace: None,
+            stack_trace_handle: None,
+            display_stack_frames: false,
+            error_classifier: None,
             display_span_list: None,
+            display_span_levels: None,
             span_format: Default::default(),
             span_fields: Default::default(),
             constants: Default::default(),
+            message_key: "message",
+            message_template_key: "message.template",
+            expand_message_templates: false,
+            apm_correlation: None,
+            hardening: None,
+            level_override: None,
+            emf_metrics: None,
+            retention: None,
+            value_labels: None,
+            display_uptime: false,
+            last_event: None,
+            float_digits: None,
+            max_string_length: None,
+            serialized_span_fields: false,
+            span_list_cache: None,
+            flatten_objects: false,
+            excluded_fields: Default::default(),
+            allowed_event_fields: None,
+            field_precedence: FieldOrigin::DEFAULT_PRECEDENCE.to_vec(),
             field_contributor: (),
         }
     }
 }
 
-fn format_stack_trace<SS>(
+/// Metadata of the spans in the event's scope, from the root, followed by the event's own
+fn stack_frames<SS>(
     event: &Event<'_>,
     ctx: &Context<'_, SS>,
-    event_filter: DisplayLevelFilter,
-    span_filter: DisplayLevelFilter,
-) -> Option<String>
+    config: &StackTraceConfig,
+) -> Option<Vec<&'static Metadata<'static>>>
 where
     SS: Subscriber + for<'a> LookupSpan<'a>,
 {
-    fn append_line(stack_trace: &mut String, metadata: &Metadata<'_>) {
-        writeln!(
-            stack_trace,
-            "  at {}({}:{})",
-            metadata.target(),
-            metadata.file().unwrap_or("<unknown>"),
-            metadata.line().unwrap_or(0)
-        )
-        .unwrap();
-    }
-
     let event_metadata = event.metadata();
-    if !event_filter.is_enabled(event, event_metadata.level()) {
+    if !config
+        .event_filter
+        .is_enabled(event, event_metadata.level())
+    {
         return None;
     }
 
-    let mut stack_trace = String::new();
+    let mut frames = Vec::new();
     if let Some(scope) = ctx.event_scope(event) {
         for span in scope.from_root() {
             let span_metadata = span.metadata();
-            if span_filter.is_enabled(event, span_metadata.level()) {
-                append_line(&mut stack_trace, span_metadata);
+            if config.span_filter.is_enabled(event, span_metadata.level()) {
+                frames.push(span_metadata);
             }
         }
     }
+    frames.push(event_metadata);
+    if let Some(max_frames) = config.max_frames {
+        frames.drain(..frames.len().saturating_sub(max_frames));
+    }
+
+    Some(frames)
+}
 
-    append_line(&mut stack_trace, event_metadata);
+fn format_stack_trace(frames: &[&Metadata<'_>]) -> String {
+    let mut stack_trace = String::new();
+    for metadata in frames {
+        writeln!(
+            stack_trace,
+            "  at {}({}:{})",
+            metadata.target(),
+            metadata.file().unwrap_or("<unknown>"),
+            metadata.line().unwrap_or(0)
+        )
+        .unwrap();
+    }
     if !stack_trace.is_empty() {
         stack_trace.truncate(stack_trace.len() - 1);
     }
+    stack_trace
+}
+
+struct SerializeStackFrames<'a>(&'a [&'a Metadata<'a>]);
 
-    Some(stack_trace)
+impl<'a> Serialize for SerializeStackFrames<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_seq(Some(self.0.len()))?;
+        for metadata in self.0 {
+            s.serialize_element(&SerializeStackFrame(metadata))?;
+        }
+        s.end()
+    }
+}
+
+struct SerializeStackFrame<'a>(&'a Metadata<'a>);
+
+impl<'a> Serialize for SerializeStackFrame<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("target", self.0.target())?;
+        if let Some(file) = self.0.file() {
+            s.serialize_entry("file", file)?;
+        }
+        if let Some(line) = self.0.line() {
+            s.serialize_entry("line", &line)?;
+        }
+        s.end()
+    }
 }
 
 struct SerializeSpanName<'c, SS>(&'c Event<'c>, &'c Context<'c, SS>);
@@ -263,19 +766,76 @@ where
     where
         S: Serializer,
     {
-        if let Some(span_metadata) = self.1.current_span().metadata() {
-            let name = format!("{}::{}", span_metadata.target(), span_metadata.name());
-            serializer.serialize_str(&name)
-        } else {
-            serializer.serialize_str(self.0.metadata().target())
+        match self.1.current_span().metadata() {
+            // Written without formatting the name into a string first
+            Some(span_metadata) => serializer.collect_str(&SpanName(span_metadata)),
+            None => serializer.serialize_str(self.0.metadata().target()),
+        }
+    }
+}
+
+/// Displays the logger name of a span, `<target>::<name>`
+struct SpanName<'a>(&'a Metadata<'a>);
+
+impl std::fmt::Display for SpanName<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}::{}", self.0.target(), self.0.name())
+    }
+}
+
+/// Whether the event has the name generated by the `tracing` macros, `event <file>:<line>`
+pub(crate) fn has_generated_name(metadata: &Metadata<'_>) -> bool {
+    let generated = metadata
+        .name()
+        .strip_prefix("event ")
+        .and_then(|location| location.rsplit_once(':'));
+    match (generated, metadata.file(), metadata.line()) {
+        (Some((name_file, name_line)), Some(file), Some(line)) => {
+            name_file == file && name_line.parse() == Ok(line)
         }
+        _ => false,
     }
 }
 
+struct SerializeSpanLevels<'c, SS>(&'c Event<'c>, &'c Context<'c, SS>);
+
+impl<'c, SS> Serialize for SerializeSpanLevels<'c, SS>
+where
+    SS: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_seq(None)?;
+        if let Some(scope) = self.1.event_scope(self.0) {
+            for span in scope {
+                s.serialize_element(span.metadata().level().as_str())?;
+            }
+        }
+        s.end()
+    }
+}
+
+fn most_severe_span_level<SS>(event: &Event<'_>, ctx: &Context<'_, SS>) -> Option<Level>
+where
+    SS: Subscriber + for<'a> LookupSpan<'a>,
+{
+    ctx.event_scope(event)?
+        .map(|span| *span.metadata().level())
+        .min()
+}
+
 pub trait LogFieldContributor {
     fn add_fields<F>(&self, serializer: &mut F)
     where
         F: LogFieldReceiver;
+
+    /// Names of the fields added by this contributor, used to detect conflicts between
+    /// [`Contributors`](crate::contributors::Contributors). Empty when not known in advance.
+    fn field_names(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
 }
 
 impl LogFieldContributor for () {
@@ -287,6 +847,80 @@ impl LogFieldContributor for () {
     }
 }
 
+impl<A: LogFieldContributor, B: LogFieldContributor> LogFieldContributor for (A, B) {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        self.0.add_fields(serializer);
+        self.1.add_fields(serializer);
+    }
+
+    fn field_names(&self) -> Vec<&'static str> {
+        let mut field_names = self.0.field_names();
+        field_names.extend(self.1.field_names());
+        field_names
+    }
+}
+
+/// Span lists serialized for events in a span, by format and level filter, with the number of
+/// records on the scope when serialized
+#[derive(Default)]
+struct SpanListCache(Vec<(u64, Level, u64, RecordedValue)>);
+
+impl<FC, SF: FormatSpan> LogstashFormat<FC, SF> {
+    fn cached_span_list<SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        event: &Event<'_>,
+        ctx: &Context<'_, SS>,
+        filter: DisplayLevelFilter,
+    ) -> Option<RecordedValue> {
+        let cache = self.span_list_cache?;
+        let level = match filter {
+            DisplayLevelFilter::Level(level) => level,
+            DisplayLevelFilter::Event => *event.metadata().level(),
+            DisplayLevelFilter::All => Level::TRACE,
+            DisplayLevelFilter::Off => return None,
+        };
+
+        let mut innermost = None;
+        let mut records = 0;
+        for span in ctx.event_scope(event)? {
+            if let Some(recorder) = span.extensions().get::<DefaultSpanRecorder>() {
+                records += recorder.records();
+            }
+            innermost.get_or_insert(span);
+        }
+        let innermost = innermost?;
+
+        if let Some(SpanListCache(entries)) = innermost.extensions().get::<SpanListCache>() {
+            if let Some((.., spans)) = entries
+                .iter()
+                .find(|entry| (entry.0, entry.1, entry.2) == (cache, level, records))
+            {
+                return Some(spans.clone());
+            }
+        }
+
+        let spans = serde_json::value::to_raw_value(&SerializableSpanList(
+            &self.span_format,
+            event,
+            ctx,
+            filter,
+        ))
+        .ok()
+        .map(RecordedValue::Serialized)?;
+        let mut extensions = innermost.extensions_mut();
+        if extensions.get_mut::<SpanListCache>().is_none() {
+            extensions.insert(SpanListCache::default());
+        }
+        let SpanListCache(entries) = extensions.get_mut::<SpanListCache>()?;
+        entries.retain(|entry| (entry.0, entry.1) != (cache, level));
+        entries.push((cache, level, records, spans.clone()));
+        Some(spans)
+    }
+}
+
 impl<DFN, FS> FormatEvent for LogstashFormat<DFN, FS>
 where
     FS: FormatSpan,
@@ -294,8 +928,24 @@ where
 {
     type R = DefaultSpanRecorder;
 
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        self.level_override
+            .as_ref()
+            .and_then(|level_override| level_override.level(event))
+            .unwrap_or(*event.metadata().level())
+    }
+
     fn span_recorder(&self) -> Self::R {
         DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_serialized_values(self.serialized_span_fields)
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: self.message_key,
+            ..RecordKeys::LOGSTASH
+        })
     }
 
     fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
@@ -303,17 +953,64 @@ where
         serializer: S,
         event: &Event<'_>,
         ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
     ) -> Result<S::Ok, S::Error> {
         let event_metadata = event.metadata();
-        let event_level = event_metadata.level();
+        let event_level = &level;
+
+        let error_class = self
+            .error_classifier
+            .map_or(ErrorClass::Unclassified, |classify| classify(event));
+        let (display_stack_trace, display_span_list) = match error_class {
+            ErrorClass::Unexpected => (Some(StackTraceConfig::ALL), Some(DisplayLevelFilter::All)),
+            ErrorClass::ClientError => (None, None),
+            ErrorClass::Unclassified => (
+                match &self.stack_trace_handle {
+                    Some(handle) => handle.get(),
+                    None => self.display_stack_trace,
+                },
+                self.display_span_list,
+            ),
+        };
 
         let mut s = serializer.serialize_map(None)?;
 
-        let mut seen = HashSet::new();
+        // Excluded fields are skipped as if already written
+        let mut seen = self
+            .excluded_fields
+            .iter()
+            .map(|field| Cow::Borrowed(*field))
+            .collect::<HashSet<_>>();
+
+        let template_fields = if self.expand_message_templates {
+            let mut template_fields = TemplateFields::default();
+            event.record(&mut template_fields);
+            Some(template_fields)
+        } else {
+            None
+        };
 
         let mut field_visitor = SerializingFieldVisitor {
             serializer: &mut s,
             field_name_filter: |name| seen.insert(name),
+            message_key: self.message_key,
+            message_template_key: self.message_template_key,
+            template_fields: template_fields.as_ref(),
+            hardening: self.hardening.as_ref(),
+            flatten_objects: self.flatten_objects,
+            allowed_event_fields: self.allowed_event_fields.as_deref(),
+            float_digits: self.float_digits,
+            max_string_length: self.max_string_length,
+            value_labels: self.value_labels.as_ref(),
             status: None,
         };
 
@@ -322,7 +1019,18 @@ where
         }
 
         if self.display_timestamp {
-            field_visitor.add_field("@timestamp", &LogTimestamp::default());
+            let occurred = self.timestamp_field.and_then(|field| {
+                let mut visitor = TimestampVisitor(field, None);
+                event.record(&mut visitor);
+                visitor.1
+            });
+            match occurred {
+                Some(occurred) => {
+                    field_visitor.add_field("@timestamp", &LogTimestamp(occurred));
+                    field_visitor.add_field(self.received_timestamp_key, &LogTimestamp::default());
+                }
+                None => field_visitor.add_field("@timestamp", &LogTimestamp::default()),
+            }
         }
 
         if self.display_thread_name {
@@ -334,15 +1042,24 @@ where
 
         if let Some(l) = self.display_logger_name {
             match l {
-                LoggerName::Event => {
-                    field_visitor.add_field("logger_name", event_metadata.target())
-                }
+                LoggerName::Event => field_visitor.add_field(
+                    "logger_name",
+                    self.event_logger_name(event_metadata.target()),
+                ),
                 LoggerName::Span => {
                     field_visitor.add_field("logger_name", &SerializeSpanName(event, &ctx))
                 }
             };
         }
 
+        match self.display_event_name {
+            Some(EventName::All) => field_visitor.add_field("event.name", event_metadata.name()),
+            Some(EventName::Explicit) if !has_generated_name(event_metadata) => {
+                field_visitor.add_field("event.name", event_metadata.name())
+            }
+            _ => {}
+        }
+
         if self.display_level {
             field_visitor.add_field("level", event_level.as_str());
         }
@@ -351,41 +1068,340 @@ where
             field_visitor.add_field("level_value", &level_value(event_level));
         }
 
-        if let Some((event_filter, span_filter)) = self.display_stack_trace {
-            if let Some(stack_trace) = format_stack_trace(event, &ctx, event_filter, span_filter) {
-                field_visitor.add_field("stack_trace", &stack_trace);
+        if let Some(retention) = self
+            .retention
+            .as_ref()
+            .and_then(|retention| retention.retention(event_metadata.target(), event_level))
+        {
+            field_visitor.add_field("retention", retention);
+        }
+
+        if self.display_uptime || self.last_event.is_some() {
+            let now = process_start().elapsed();
+            if self.display_uptime {
+                field_visitor.add_field("process.uptime_ms", &(now.as_millis() as u64));
+            }
+            if let Some(last_event) = &self.last_event {
+                // Stored as nanoseconds since the process start plus one, zero meaning none
+                let now_nanos = now.as_nanos() as u64 + 1;
+                let last = last_event.swap(now_nanos, Ordering::Relaxed);
+                if last != 0 {
+                    let gap_ms = now_nanos.saturating_sub(last) / 1_000_000;
+                    field_visitor.add_field("log.gap_ms", &gap_ms);
+                }
             }
         }
 
-        for (key, value) in &self.constants {
-            field_visitor.add_field(key, value);
+        if let Some(apm) = &self.apm_correlation {
+            if let Some(service_name) = &apm.service_name {
+                field_visitor.add_field("service.name", service_name);
+            }
+            if let Some(trace_context) = apm.provider.trace_context() {
+                field_visitor.add_field("trace.id", &trace_context.trace_id);
+                if let Some(transaction_id) = &trace_context.transaction_id {
+                    field_visitor.add_field("transaction.id", transaction_id);
+                }
+                if let Some(span_id) = &trace_context.span_id {
+                    field_visitor.add_field("span.id", span_id);
+                }
+            }
         }
 
-        self.field_contributor.add_fields(&mut field_visitor);
+        match self.display_span_levels {
+            Some(SpanLevels::All) if ctx.event_span(event).is_some() => {
+                field_visitor.add_field("span_levels", &SerializeSpanLevels(event, &ctx));
+            }
+            Some(SpanLevels::MostSevere) => {
+                if let Some(level) = most_severe_span_level(event, &ctx) {
+                    field_visitor.add_field("span_level_max", level.as_str());
+                }
+            }
+            _ => {}
+        }
 
-        if let Some(filter) = self.display_span_list {
-            field_visitor.add_field(
-                "spans",
-                &SerializableSpanList(&self.span_format, event, &ctx, filter),
-            );
+        if let Some(stack_trace) = display_stack_trace {
+            if let Some(frames) = stack_frames(event, &ctx, &stack_trace) {
+                field_visitor.add_field("stack_trace", &format_stack_trace(&frames));
+                if self.display_stack_frames {
+                    field_visitor.add_field("stack_frames", &SerializeStackFrames(&frames));
+                }
+            }
         }
 
-        event.record(&mut field_visitor);
-        if let Some(e) = field_visitor.status {
-            return Err(e);
+        if let Some(emf_metadata) = self
+            .emf_metrics
+            .as_ref()
+            .and_then(|emf| emf.metadata(event))
+        {
+            field_visitor.add_field("_aws", &emf_metadata);
         }
 
-        if let Some(scope) = ctx.event_scope(event) {
-            for span in scope {
-                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
-                    write_extension_fields(&mut seen, &mut s, span_fields)?;
+        for origin in &self.field_precedence {
+            match origin {
+                FieldOrigin::Constant => {
+                    for (key, value) in &self.constants {
+                        field_visitor.add_field(key, value);
+                    }
+                }
+                FieldOrigin::Dynamic => {
+                    self.field_contributor.add_fields(&mut field_visitor);
+                    if let Some(filter) = display_span_list {
+                        match self.cached_span_list(event, &ctx, filter) {
+                            Some(spans) => field_visitor.add_field("spans", &spans),
+                            None => field_visitor.add_field(
+                                "spans",
+                                &SerializableSpanList(&self.span_format, event, &ctx, filter),
+                            ),
+                        }
+                    }
+                }
+                FieldOrigin::Event => event.record(&mut field_visitor),
+                FieldOrigin::InnermostSpan => {
+                    if let Some(span) = ctx.event_span(event) {
+                        if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                            field_visitor.add_extension_fields(span_fields);
+                        }
+                    }
+                }
+                FieldOrigin::OuterSpans => {
+                    for span in ctx.event_scope(event).into_iter().flatten().skip(1) {
+                        if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                            field_visitor.add_extension_fields(span_fields);
+                        }
+                    }
                 }
             }
         }
+
+        if let Some(e) = field_visitor.status {
+            return Err(e);
+        }
         s.end()
     }
 }
 
+/// Retention hints written as `retention`, for driving index lifecycle policies downstream
+///
+/// The hint of the first target rule matching the event target or one of its ancestors is used,
+/// then the hint for the level, after any [`LevelOverride`], then the default. Events no rule
+/// matches have no hint.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::logstash::Retention;
+/// # use tracing_core::Level;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default().with_retention(Some(
+///         Retention::new()
+///             .with_default("90d")
+///             .with_level(Level::DEBUG, "30d")
+///             .with_target("audit", "365d"),
+///     )),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone, Default)]
+pub struct Retention {
+    default: Option<String>,
+    levels: Vec<(Level, String)>,
+    targets: Vec<(&'static str, String)>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hint for events no other rule matches
+    pub fn with_default(self, retention: impl Into<String>) -> Self {
+        Self {
+            default: Some(retention.into()),
+            ..self
+        }
+    }
+
+    /// Hint for events with `level`
+    pub fn with_level(mut self, level: Level, retention: impl Into<String>) -> Self {
+        self.levels.push((level, retention.into()));
+        self
+    }
+
+    /// Hint for events with `target` or one of its descendants
+    pub fn with_target(mut self, target: &'static str, retention: impl Into<String>) -> Self {
+        self.targets.push((target, retention.into()));
+        self
+    }
+
+    fn retention(&self, target: &str, level: &Level) -> Option<&str> {
+        self.targets
+            .iter()
+            .find(|(prefix, _)| target_matches(target, prefix))
+            .map(|(_, retention)| retention)
+            .or_else(|| {
+                self.levels
+                    .iter()
+                    .find(|(l, _)| l == level)
+                    .map(|(_, retention)| retention)
+            })
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// Labels of coded field values, written next to the codes, such as `grpc.status_text:
+/// "DEADLINE_EXCEEDED"` for `grpc.status: 4`
+///
+/// Codes are matched against the text of the value, so integer, string and `Debug` formatted
+/// enum codes can all be mapped. Values without a label are written without one.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::logstash::ValueLabels;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default().with_value_labels(Some(
+///         ValueLabels::new().with_field(
+///             "grpc.status",
+///             "grpc.status_text",
+///             [(0, "OK"), (4, "DEADLINE_EXCEEDED"), (5, "NOT_FOUND")],
+///         ),
+///     )),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone, Default)]
+pub struct ValueLabels {
+    fields: Vec<FieldLabels>,
+}
+
+#[derive(Clone)]
+struct FieldLabels {
+    field: &'static str,
+    label_field: &'static str,
+    labels: HashMap<String, &'static str>,
+}
+
+impl ValueLabels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write the label of each code of `field` as `label_field`
+    pub fn with_field<C: ToString>(
+        mut self,
+        field: &'static str,
+        label_field: &'static str,
+        labels: impl IntoIterator<Item = (C, &'static str)>,
+    ) -> Self {
+        self.fields.push(FieldLabels {
+            field,
+            label_field,
+            labels: labels
+                .into_iter()
+                .map(|(code, label)| (code.to_string(), label))
+                .collect(),
+        });
+        self
+    }
+
+    fn field(&self, field: &str) -> Option<&FieldLabels> {
+        self.fields.iter().find(|labels| labels.field == field)
+    }
+}
+
+/// An event field overriding the displayed level of events with some targets
+///
+/// The field value is a level name, such as `warn` or `WARNING`. Events without the field, or
+/// with a value that is not a level name, keep their level.
+#[derive(Clone)]
+pub struct LevelOverride {
+    field: &'static str,
+    targets: Vec<&'static str>,
+}
+
+impl LevelOverride {
+    /// Override the level of events with one of `targets` or their descendants
+    pub fn new(field: &'static str, targets: Vec<&'static str>) -> Self {
+        Self { field, targets }
+    }
+
+    pub(crate) fn level(&self, event: &Event<'_>) -> Option<Level> {
+        let target = event.metadata().target();
+        if !self
+            .targets
+            .iter()
+            .any(|prefix| target_matches(target, prefix))
+        {
+            return None;
+        }
+        let mut visitor = LevelVisitor(self.field, None);
+        event.record(&mut visitor);
+        visitor.1
+    }
+}
+
+struct LevelVisitor(&'static str, Option<Level>);
+
+impl LevelVisitor {
+    fn parse(value: &str) -> Option<Level> {
+        match value.to_ascii_lowercase().as_str() {
+            "warning" => Some(Level::WARN),
+            "fatal" | "critical" => Some(Level::ERROR),
+            value => value.parse().ok(),
+        }
+    }
+}
+
+impl Visit for LevelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.0 {
+            self.1 = Self::parse(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == self.0 {
+            self.1 = Self::parse(&format!("{:?}", value));
+        }
+    }
+}
+
+/// Takes the time an event occurred from the named field
+struct TimestampVisitor(&'static str, Option<time::OffsetDateTime>);
+
+impl Visit for TimestampVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        if field.name() == self.0 {
+            self.1 =
+                time::OffsetDateTime::from_unix_timestamp_nanos(value as i128 * 1_000_000).ok();
+        }
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if let Ok(value) = i64::try_from(value) {
+            self.record_i64(field, value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.0 {
+            self.1 =
+                time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339)
+                    .ok()
+                    .map(|time| time.to_offset(time::UtcOffset::UTC));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, format!("{:?}", value).trim_matches('"'));
+    }
+}
+
 pub trait LogFieldReceiver {
     fn add_field<V: ?Sized + Serialize>(&mut self, field: &'static str, value: &V);
 }
@@ -393,63 +1409,314 @@ pub trait LogFieldReceiver {
 pub struct SerializingFieldVisitor<'a, F, S, E> {
     field_name_filter: F,
     serializer: &'a mut S,
+    message_key: &'static str,
+    message_template_key: &'static str,
+    template_fields: Option<&'a TemplateFields>,
+    hardening: Option<&'a HardeningProfile>,
+    flatten_objects: bool,
+    allowed_event_fields: Option<&'a [&'static str]>,
+    float_digits: Option<u32>,
+    max_string_length: Option<usize>,
+    value_labels: Option<&'a ValueLabels>,
     status: Option<E>,
 }
 
-impl<'a, S: SerializeMap, F: FnMut(&'static str) -> bool>
+impl<'a, S: SerializeMap, F: FnMut(Cow<'static, str>) -> bool>
     SerializingFieldVisitor<'a, F, S, S::Error>
 {
+    /// A visitor writing the fields accepted by `field_name_filter` as they are
+    pub(crate) fn new(serializer: &'a mut S, field_name_filter: F) -> Self {
+        Self {
+            field_name_filter,
+            serializer,
+            message_key: "message",
+            message_template_key: "message.template",
+            template_fields: None,
+            hardening: None,
+            flatten_objects: false,
+            allowed_event_fields: None,
+            float_digits: None,
+            max_string_length: None,
+            value_labels: None,
+            status: None,
+        }
+    }
+
+    pub(crate) fn finish(self) -> Result<(), S::Error> {
+        self.status.map_or(Ok(()), Err)
+    }
+
+    /// Whether the event field is written, with the message always written
+    fn is_allowed(&self, field: &Field) -> bool {
+        field.name() == "message"
+            || self
+                .allowed_event_fields
+                .is_none_or(|allowed| allowed.contains(&field.name()))
+    }
+
+    /// The string, truncated to the maximum length
+    fn truncate<'s>(&self, value: &'s str) -> Cow<'s, str> {
+        match self.max_string_length {
+            Some(max) if value.len() > max => {
+                let mut end = max;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                Cow::Owned(format!("{}{}", &value[..end], TRUNCATED_SUFFIX))
+            }
+            _ => Cow::Borrowed(value),
+        }
+    }
+
     #[inline]
     fn record_field<V: ?Sized + Serialize>(&mut self, field: &Field, value: &V) {
         self.add_field(field.name(), value)
     }
+
+    /// Adds the label of a coded value after the value
+    fn add_value_label(&mut self, field: &str, code: &dyn std::fmt::Display) {
+        if let Some(labels) = self.value_labels.and_then(|labels| labels.field(field)) {
+            if let Some(label) = labels.labels.get(&code.to_string()) {
+                self.add_field(labels.label_field, label);
+            }
+        }
+    }
+
+    fn record_message(&mut self, message: &str) {
+        match self.template_fields.and_then(|t| t.expand(message)) {
+            Some(expanded) => {
+                self.add_field(self.message_key, &self.truncate(&expanded));
+                self.add_field(self.message_template_key, &self.truncate(message));
+            }
+            None => self.add_field(self.message_key, &self.truncate(message)),
+        }
+    }
+
+    /// Writes a field, like [`LogFieldReceiver::add_field`], with a name that may not be static
+    fn add_named_field<V: ?Sized + Serialize>(&mut self, field: Cow<'static, str>, value: &V) {
+        if self.status.is_some() {
+            return;
+        }
+        let key = match self.hardening {
+            Some(hardening) => hardening.sanitize_key(field),
+            None => field,
+        };
+        if (self.field_name_filter)(key.clone()) {
+            let flatten = self.flatten_objects && key != self.message_key;
+            let result = if flatten || self.hardening.is_some() {
+                match serde_json::to_value(value) {
+                    Ok(value) => {
+                        let value = match self.hardening {
+                            Some(hardening) => hardening.sanitize(value),
+                            None => value,
+                        };
+                        if flatten {
+                            serialize_flattened(
+                                self.serializer,
+                                &mut self.field_name_filter,
+                                key,
+                                value,
+                            )
+                        } else {
+                            self.serializer.serialize_entry(&key, &value)
+                        }
+                    }
+                    Err(e) => Err(S::Error::custom(e)),
+                }
+            } else {
+                self.serializer.serialize_entry(&key, &value)
+            };
+            if let Err(e) = result {
+                self.status = Some(e)
+            }
+        }
+    }
+
+    pub(crate) fn add_extension_fields<R: TryForEachField>(&mut self, recorded: &R) {
+        let _ = recorded.try_for_each::<(), _>(|name, value| {
+            match (value, self.float_digits) {
+                (RecordedValue::F64(v), Some(digits)) => {
+                    self.add_named_field(name.clone(), &round_significant(*v, digits))
+                }
+                (RecordedValue::String(v), _) if self.max_string_length.is_some() => {
+                    self.add_named_field(name.clone(), &self.truncate(v));
+                    self.add_value_label(name, v);
+                }
+                _ if !value.is_unset() => {
+                    self.add_named_field(name.clone(), value);
+                    if let Some(code) = self.value_labels.and_then(|_| value.to_text()) {
+                        self.add_value_label(name, &code);
+                    }
+                }
+                _ => {}
+            }
+            Ok(())
+        });
+    }
 }
 
-impl<'a, S: SerializeMap, F: FnMut(&'static str) -> bool> LogFieldReceiver
+/// Writes the leaves of objects, and of strings holding JSON objects, under dotted keys. The
+/// dotted keys are written once, unless already written or excluded, like any other field.
+fn serialize_flattened<S: SerializeMap, F: FnMut(Cow<'static, str>) -> bool>(
+    serializer: &mut S,
+    field_name_filter: &mut F,
+    key: Cow<'static, str>,
+    value: serde_json::Value,
+) -> Result<(), S::Error> {
+    match value {
+        serde_json::Value::Object(object) if !object.is_empty() => {
+            for (child, value) in object {
+                let key: Cow<str> = Cow::Owned(format!("{}.{}", key, child));
+                if field_name_filter(key.clone()) {
+                    serialize_flattened(serializer, field_name_filter, key, value)?;
+                }
+            }
+            Ok(())
+        }
+        serde_json::Value::String(string) if string.starts_with('{') => {
+            match serde_json::from_str(&string) {
+                Ok(object @ serde_json::Value::Object(_)) => {
+                    serialize_flattened(serializer, field_name_filter, key, object)
+                }
+                _ => serializer.serialize_entry(&key, &string),
+            }
+        }
+        value => serializer.serialize_entry(&key, &value),
+    }
+}
+
+/// Rounds `value` to `digits` significant digits
+fn round_significant(value: f64, digits: u32) -> f64 {
+    if !value.is_finite() {
+        return value;
+    }
+    format!("{:.*e}", digits as usize - 1, value)
+        .parse()
+        .unwrap_or(value)
+}
+
+impl<'a, S: SerializeMap, F: FnMut(Cow<'static, str>) -> bool> LogFieldReceiver
     for SerializingFieldVisitor<'a, F, S, S::Error>
 {
     fn add_field<V: ?Sized + Serialize>(&mut self, field: &'static str, value: &V) {
-        if self.status.is_none() && (self.field_name_filter)(field) {
-            if let Err(e) = self.serializer.serialize_entry(field, &value) {
-                self.status = Some(e)
-            }
-        }
+        self.add_named_field(Cow::Borrowed(field), value)
     }
 }
 
-impl<'a, F: FnMut(&'static str) -> bool, S: SerializeMap> Visit
+impl<'a, F: FnMut(Cow<'static, str>) -> bool, S: SerializeMap> Visit
     for SerializingFieldVisitor<'a, F, S, S::Error>
 {
     fn record_f64(&mut self, field: &Field, value: f64) {
+        if !self.is_allowed(field) {
+            return;
+        }
+        let value = self
+            .float_digits
+            .map_or(value, |digits| round_significant(value, digits));
         self.record_field(field, &value);
     }
 
     fn record_i64(&mut self, field: &Field, value: i64) {
+        if !self.is_allowed(field) {
+            return;
+        }
         self.record_field(field, &value);
+        self.add_value_label(field.name(), &value);
     }
 
     fn record_u64(&mut self, field: &Field, value: u64) {
+        if !self.is_allowed(field) {
+            return;
+        }
         self.record_field(field, &value);
+        self.add_value_label(field.name(), &value);
     }
 
     fn record_bool(&mut self, field: &Field, value: bool) {
+        if !self.is_allowed(field) {
+            return;
+        }
         self.record_field(field, &value);
     }
 
     fn record_str(&mut self, field: &Field, value: &str) {
-        self.record_field(field, value);
+        if !self.is_allowed(field) {
+            return;
+        }
+        if field.name() == "message" {
+            self.record_message(value);
+        } else {
+            self.record_field(field, &self.truncate(value));
+            self.add_value_label(field.name(), &value);
+        }
     }
 
     fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
-        self.record_field(field, &format!("{}", value));
+        if !self.is_allowed(field) {
+            return;
+        }
+        self.record_field(field, &self.truncate(&format!("{}", value)));
     }
 
     fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
-        self.record_field(field, &format!("{:?}", value));
+        if !self.is_allowed(field) {
+            return;
+        }
+        if field.name() == "message" {
+            self.record_message(&format!("{:?}", value));
+        } else {
+            let value = format!("{:?}", value);
+            self.record_field(field, &self.truncate(&value));
+            self.add_value_label(field.name(), &value);
+        }
     }
 }
 
-struct LogTimestamp(time::OffsetDateTime);
+/// String representations of the event fields, for expanding message templates
+#[derive(Default)]
+struct TemplateFields(Vec<(&'static str, String)>);
+
+impl TemplateFields {
+    /// Returns the expanded template, or `None` if no placeholder was replaced
+    fn expand(&self, template: &str) -> Option<String> {
+        let mut expanded = String::with_capacity(template.len());
+        let mut replaced = false;
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + len];
+            expanded.push_str(&rest[..start]);
+            match self.0.iter().find(|(n, _)| *n == name) {
+                Some((_, value)) => {
+                    expanded.push_str(value);
+                    replaced = true;
+                }
+                None => expanded.push_str(&rest[start..=start + len]),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        expanded.push_str(rest);
+        replaced.then_some(expanded)
+    }
+}
+
+impl Visit for TemplateFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), value.to_owned()));
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.0.push((field.name(), value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.push((field.name(), format!("{:?}", value)));
+    }
+}
+
+pub(crate) struct LogTimestamp(time::OffsetDateTime);
 
 impl Default for LogTimestamp {
     fn default() -> Self {
@@ -474,12 +1741,31 @@ impl Serialize for LogTimestamp {
 
 #[cfg(test)]
 mod test {
+    use super::{round_significant, TemplateFields};
     use time::macros::datetime;
 
+    #[test]
+    fn test_expand_template() {
+        let fields = TemplateFields(vec![("a", "1".to_owned()), ("b", "two".to_owned())]);
+        assert_eq!(
+            fields.expand("{a} and {b}, not {c} {").as_deref(),
+            Some("1 and two, not {c} {")
+        );
+        assert_eq!(fields.expand("no {c} placeholders"), None);
+    }
+
     #[test]
     fn test_serialize_log_timestamp() {
         let timestamp = super::LogTimestamp(datetime!(2020-01-01 00:00:00 +00:00));
         let serialized = serde_json::to_string(&timestamp).unwrap();
         assert_eq!(serialized, "\"2020-01-01T00:00:00Z\"");
     }
+
+    #[test]
+    fn test_round_significant() {
+        assert_eq!(round_significant(0.1 + 0.2, 6), 0.3);
+        assert_eq!(round_significant(123456.789, 3), 123000.0);
+        assert_eq!(round_significant(-0.000123456, 2), -0.00012);
+        assert!(round_significant(f64::NAN, 3).is_nan());
+    }
 }
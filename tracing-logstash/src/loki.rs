@@ -0,0 +1,235 @@
+use crate::format::{FormatEvent, Json, RecordKeys};
+use crate::logstash::LogstashFormat;
+use serde::ser::{Error as _, SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format wrapping the records of another format in a
+/// [Loki push API](https://grafana.com/docs/loki/latest/reference/loki-http-api/#ingest-logs)
+/// request, so they can be pushed to Loki without an agent
+///
+/// Each record is a request with a single `streams` entry, holding one `[timestamp, line]` value
+/// where the line is the record of the wrapped format. The stream labels are the configured
+/// labels, followed by the selected event fields that are present, unless a configured label has
+/// the same name. Label names are sanitized to the characters Loki accepts, so `http.method`
+/// becomes `http_method`.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::loki::LokiFormat::new(
+///         tracing_logstash::logstash::LogstashFormat::default(),
+///     )
+///     .with_labels(vec![("app", "checkout".to_owned())])
+///     .with_label_fields(vec!["tenant"]),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct LokiFormat<E = LogstashFormat> {
+    labels: Vec<(String, String)>,
+    label_fields: Vec<&'static str>,
+    event_format: E,
+}
+
+impl Default for LokiFormat {
+    fn default() -> Self {
+        Self::new(LogstashFormat::default())
+    }
+}
+
+impl<E> LokiFormat<E> {
+    pub fn new(event_format: E) -> Self {
+        Self {
+            labels: Default::default(),
+            label_fields: Default::default(),
+            event_format,
+        }
+    }
+
+    /// Labels of every stream
+    pub fn with_labels(self, labels: Vec<(&str, String)>) -> Self {
+        Self {
+            labels: labels
+                .into_iter()
+                .map(|(name, value)| (label_name(name), value))
+                .collect(),
+            ..self
+        }
+    }
+    /// Event fields written as stream labels. Keep these to fields with few distinct values, as
+    /// each label set is a separate stream in Loki.
+    pub fn with_label_fields(self, label_fields: Vec<&'static str>) -> Self {
+        Self {
+            label_fields,
+            ..self
+        }
+    }
+}
+
+/// Label names match `[a-zA-Z_][a-zA-Z0-9_]*`
+fn label_name(name: &str) -> String {
+    let mut label: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        label.insert(0, '_');
+    }
+    label
+}
+
+struct LabelFields<'a> {
+    names: &'a [&'static str],
+    values: Vec<(&'static str, String)>,
+}
+
+impl LabelFields<'_> {
+    fn add(&mut self, field: &Field, value: String) {
+        let name = field.name();
+        if self.names.contains(&name) && !self.values.iter().any(|(n, _)| *n == name) {
+            self.values.push((name, value));
+        }
+    }
+}
+
+impl Visit for LabelFields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.add(field, value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.add(field, format!("{:?}", value));
+    }
+}
+
+impl<E: FormatEvent> FormatEvent for LokiFormat<E> {
+    type R = E::R;
+
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        self.event_format.event_level(event)
+    }
+
+    fn span_recorder(&self) -> Self::R {
+        self.event_format.span_recorder()
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let timestamp = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+
+        let mut label_fields = LabelFields {
+            names: &self.label_fields,
+            values: Vec::new(),
+        };
+        if !self.label_fields.is_empty() {
+            event.record(&mut label_fields);
+        }
+
+        let mut line = Vec::with_capacity(512);
+        self.event_format
+            .write_event(&Json, &mut line, event, level, ctx)
+            .map_err(S::Error::custom)?;
+
+        let stream = Stream {
+            labels: &self.labels,
+            label_fields: &label_fields.values,
+            timestamp: timestamp.to_string(),
+            line: &String::from_utf8_lossy(&line),
+        };
+
+        let mut s = serializer.serialize_map(Some(1))?;
+        s.serialize_entry("streams", &[stream])?;
+        s.end()
+    }
+}
+
+struct Stream<'a> {
+    labels: &'a [(String, String)],
+    label_fields: &'a [(&'static str, String)],
+    timestamp: String,
+    line: &'a str,
+}
+
+impl Serialize for Stream<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(2))?;
+        s.serialize_entry("stream", &Labels(self))?;
+        s.serialize_entry("values", &[Value(self)])?;
+        s.end()
+    }
+}
+
+struct Labels<'a>(&'a Stream<'a>);
+
+impl Serialize for Labels<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(None)?;
+        for (name, value) in self.0.labels {
+            s.serialize_entry(name, value)?;
+        }
+        for (name, value) in self.0.label_fields {
+            let name = label_name(name);
+            if !self.0.labels.iter().any(|(label, _)| *label == name) {
+                s.serialize_entry(&name, value)?;
+            }
+        }
+        s.end()
+    }
+}
+
+struct Value<'a>(&'a Stream<'a>);
+
+impl Serialize for Value<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_seq(Some(2))?;
+        s.serialize_element(&self.0.timestamp)?;
+        s.serialize_element(self.0.line)?;
+        s.end()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::label_name;
+
+    #[test]
+    fn test_label_name() {
+        assert_eq!(label_name("http.method"), "http_method");
+        assert_eq!(label_name("2xx"), "_2xx");
+        assert_eq!(label_name("app"), "app");
+    }
+}
@@ -0,0 +1,329 @@
+//! Delivery of records to a Logstash `beats` input, using the Lumberjack v2 protocol
+//!
+//! Records are sent in windows of up to `window_size` JSON frames. A window is resent, after
+//! reconnecting, until the input acknowledges all of its frames or the number of attempts runs
+//! out. Windows that could not be delivered are kept and resent after the flush interval.
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records; beyond that the
+//! oldest records are dropped and counted, or the records chosen by another [`BackpressurePolicy`].
+//! They are sent from a background thread, started with the first record, when a window is full or
+//! the oldest queued record is older than the flush interval. Call [`LumberjackSink::flush`] before
+//! exiting to wait for the queued records to be sent. When the last clone of the sink is dropped,
+//! the queued records are sent once more and the thread is stopped.
+//!
+//! With compression enabled, the frames of each window are sent in a single compressed frame.
+//!
+//! A single connection waits for the acknowledgement of each window before sending the next. For
+//! higher throughput, the sink can keep a pool of connections, each with one window in flight, so
+//! up to `connections` windows are sent at once and windows may be received out of order.
+//!
+//! # Example
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::lumberjack::LumberjackSink;
+//! #
+//! let sink = LumberjackSink::new("logstash:5044")
+//!     .unwrap()
+//!     .with_window_size(64)
+//!     .with_flush_interval(Duration::from_secs(1));
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! sink.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::compress::zlib;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+const VERSION: u8 = b'2';
+const WINDOW: u8 = b'W';
+const JSON: u8 = b'J';
+const COMPRESSED: u8 = b'C';
+const ACK: u8 = b'A';
+
+/// A writer sending records to a Logstash `beats` input, see the [module](self) documentation
+///
+/// Clones share the same connections and queued records.
+#[derive(Clone)]
+pub struct LumberjackSink {
+    config: Arc<Config>,
+    delivery: Delivery<Vec<u8>>,
+}
+
+#[derive(Clone)]
+struct Config {
+    addrs: Vec<SocketAddr>,
+    window_size: usize,
+    compression: bool,
+    max_pending: usize,
+    max_attempts: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    policy: BackpressurePolicy,
+    connections: usize,
+}
+
+impl Config {
+    fn batching(&self) -> Batching {
+        Batching {
+            batch_size: self.window_size,
+            batch_bytes: usize::MAX,
+            max_pending: self.max_pending,
+            max_attempts: self.max_attempts,
+            flush_interval: self.flush_interval,
+            connections: self.connections,
+            policy: self.policy,
+        }
+    }
+}
+
+/// A connection to the input, used from its background thread
+struct Connection {
+    config: Arc<Config>,
+    stream: Option<TcpStream>,
+}
+
+impl LumberjackSink {
+    /// A sink for the input at `addr`, which is resolved once
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address resolved to nothing",
+            ));
+        }
+        Ok(Self {
+            config: Arc::new(Config {
+                addrs,
+                window_size: 1,
+                compression: false,
+                max_pending: 10_000,
+                max_attempts: 3,
+                flush_interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                policy: BackpressurePolicy::DropOldest,
+                connections: 1,
+            }),
+            delivery: Delivery::new(),
+        })
+    }
+
+    fn with_config(self, f: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Arc::unwrap_or_clone(self.config);
+        f(&mut config);
+        Self {
+            config: Arc::new(config),
+            ..self
+        }
+    }
+
+    /// Number of records per acknowledged window, defaults to 1
+    pub fn with_window_size(self, window_size: usize) -> Self {
+        self.with_config(|config| config.window_size = window_size.max(1))
+    }
+
+    /// Compress the frames of each window, defaults to false
+    pub fn with_compression(self, compression: bool) -> Self {
+        self.with_config(|config| config.compression = compression)
+    }
+
+    /// Maximum number of records queued, such as while the input is unreachable, defaults to
+    /// 10000
+    pub fn with_max_pending(self, max_pending: usize) -> Self {
+        self.with_config(|config| config.max_pending = max_pending.max(1))
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(self, policy: BackpressurePolicy) -> Self {
+        self.with_config(|config| config.policy = policy)
+    }
+
+    /// Number of connection attempts per window, defaults to 3
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        self.with_config(|config| config.max_attempts = max_attempts.max(1))
+    }
+
+    /// Send a partial window when its oldest record is older than this, defaults to one second
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        self.with_config(|config| config.flush_interval = flush_interval)
+    }
+
+    /// Timeout for connecting, writing and waiting for acknowledgements, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_config(|config| config.timeout = timeout)
+    }
+
+    /// Number of connections to the input, each with one window in flight, defaults to 1
+    pub fn with_connections(self, connections: usize) -> Self {
+        self.with_config(|config| config.connections = connections.max(1))
+    }
+
+    /// Number of records dropped because too many records were queued, or because they could
+    /// not be sent when the sink was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of windows that could
+    /// not be sent in the number of attempts instead of keeping them, see
+    /// [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be sent, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+
+    fn push(&self, record: Vec<u8>, level: Level) -> io::Result<()> {
+        self.delivery
+            .push(record, level, self.config.batching(), || Connection {
+                config: self.config.clone(),
+                stream: None,
+            })
+    }
+}
+
+impl Transport for Connection {
+    type Record = Vec<u8>;
+
+    fn send(&mut self, window: &[Vec<u8>], _seq: u64) -> io::Result<u64> {
+        let result = self.try_send_window(window);
+        if result.is_err() {
+            self.stream = None;
+        }
+        result.map(|()| 0)
+    }
+
+    fn bytes(record: &Vec<u8>) -> &[u8] {
+        record
+    }
+}
+
+impl Connection {
+    fn try_send_window(&mut self, records: &[Vec<u8>]) -> io::Result<()> {
+        if self.stream.is_none() {
+            self.stream = Some(self.connect()?);
+        }
+        let stream = self.stream.as_mut().expect("connected");
+        let window_size = records.len();
+
+        let mut frames = Vec::new();
+        for (seq, record) in records.iter().enumerate() {
+            let payload = trim_separator(record);
+            frames.extend_from_slice(&[VERSION, JSON]);
+            frames.extend_from_slice(&(seq as u32 + 1).to_be_bytes());
+            frames.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+            frames.extend_from_slice(payload);
+        }
+        if self.config.compression {
+            let compressed = zlib(&frames);
+            frames.clear();
+            frames.extend_from_slice(&[VERSION, COMPRESSED]);
+            frames.extend_from_slice(&(compressed.len() as u32).to_be_bytes());
+            frames.extend_from_slice(&compressed);
+        }
+        let mut window = Vec::with_capacity(6 + frames.len());
+        window.extend_from_slice(&[VERSION, WINDOW]);
+        window.extend_from_slice(&(window_size as u32).to_be_bytes());
+        window.extend_from_slice(&frames);
+        stream.write_all(&window)?;
+        stream.flush()?;
+
+        // The input may acknowledge part of the window before the whole of it
+        loop {
+            let mut ack = [0u8; 6];
+            stream.read_exact(&mut ack)?;
+            if ack[..2] != [VERSION, ACK] {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unexpected frame from lumberjack input",
+                ));
+            }
+            let seq = u32::from_be_bytes([ack[2], ack[3], ack[4], ack[5]]);
+            if seq as usize >= window_size {
+                return Ok(());
+            }
+        }
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let mut error = None;
+        for addr in &self.config.addrs {
+            match TcpStream::connect_timeout(addr, self.config.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.config.timeout))?;
+                    stream.set_write_timeout(Some(self.config.timeout))?;
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("at least one address"))
+    }
+}
+
+impl WriteRecord for LumberjackSink {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if trim_separator(record).is_empty() {
+            return Ok(());
+        }
+        self.push(record.to_vec(), level)
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for LumberjackSink {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::trim_separator;
+
+    #[test]
+    fn test_trim_separator() {
+        assert_eq!(trim_separator(b"{}\n"), b"{}");
+        assert_eq!(trim_separator(b"{}\r\n\0"), b"{}");
+        assert_eq!(trim_separator(b"\n"), b"");
+    }
+}
@@ -0,0 +1,283 @@
+//! Writing each record to several writers, for streams that must survive the outage of a
+//! single collector
+//!
+//! [`QuorumWriter`] writes each record to all of its writers, and considers it delivered when at
+//! least `quorum` of them wrote and flushed it without error. Records written by fewer writers
+//! are counted as undelivered and fail with an error, which the layer handles with its
+//! [`WriteErrorPolicy`](crate::WriteErrorPolicy), or a [`FallbackWriter`](crate::fallback)
+//! wrapped around the quorum writer by writing the record elsewhere. Records some writers failed
+//! to write are counted as diverged, so alerts can be raised before a second outage loses
+//! records. Writes are not retried.
+//!
+//! The writers are made for the event of each record, so writers choosing by level or target,
+//! such as a [`BackgroundWriter`](crate::background) dropping verbose records, work as they do
+//! without the quorum writer.
+//!
+//! [`TeeWriter`] writes each record to all of its writers without a quorum, for copying records
+//! to several destinations, such as a local file and a shipper, where a failing destination must
+//! not keep records from the others. Writers added with
+//! [`with_writer_separator`](TeeWriter::with_writer_separator) get records with their own
+//! separator in place of the one of the layer, for destinations framing records differently,
+//! such as `\n` for stdout and `\0` for the socket of an agent.
+//!
+//! # Example
+//! ```
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::mirror::QuorumWriter;
+//! #
+//! let writer = QuorumWriter::new(2)
+//!     .with_writer("stdout", std::io::stdout)
+//!     .with_writer("stderr", std::io::stderr)
+//!     .with_writer("null", std::io::sink);
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Periodically
+//! let stats = writer.stats();
+//! if stats.undelivered > 0 {
+//!     // Alert
+//! }
+//! ```
+
+use crate::RecordSeparator;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing_core::Metadata;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer writing each record to several writers, see the [module](self) documentation
+///
+/// Clones share the counters and the writers added before they were cloned.
+#[derive(Clone)]
+pub struct QuorumWriter {
+    quorum: usize,
+    record_separator: RecordSeparator,
+    writers: Vec<Mirror>,
+    delivered: Arc<AtomicU64>,
+    undelivered: Arc<AtomicU64>,
+    diverged: Arc<AtomicU64>,
+}
+
+#[derive(Clone)]
+struct Mirror {
+    name: String,
+    make_writer: Arc<BoxMakeWriter>,
+    /// Replaces the separator of the layer
+    separator: Option<RecordSeparator>,
+    failures: Arc<AtomicU64>,
+}
+
+/// Delivery counters of a [`QuorumWriter`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QuorumStats {
+    /// Records written by at least `quorum` writers
+    pub delivered: u64,
+    /// Records written by fewer than `quorum` writers
+    pub undelivered: u64,
+    /// Records that at least one writer failed to write
+    pub diverged: u64,
+    /// Number of records each writer failed to write, by name
+    pub failures: Vec<(String, u64)>,
+}
+
+impl QuorumWriter {
+    /// A writer considering records delivered when written by `quorum` writers
+    pub fn new(quorum: usize) -> Self {
+        Self {
+            quorum,
+            record_separator: RecordSeparator::NEWLINE,
+            writers: Vec::new(),
+            delivered: Default::default(),
+            undelivered: Default::default(),
+            diverged: Default::default(),
+        }
+    }
+
+    /// Adds a writer, named in the [`QuorumStats`]
+    pub fn with_writer<M>(self, name: impl Into<String>, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.push_writer(name.into(), make_writer, None)
+    }
+
+    fn push_writer<M>(
+        mut self,
+        name: String,
+        make_writer: M,
+        separator: Option<RecordSeparator>,
+    ) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.writers.push(Mirror {
+            name,
+            make_writer: Arc::new(BoxMakeWriter::new(make_writer)),
+            separator,
+            failures: Default::default(),
+        });
+        self
+    }
+
+    pub fn stats(&self) -> QuorumStats {
+        QuorumStats {
+            delivered: self.delivered.load(Ordering::Relaxed),
+            undelivered: self.undelivered.load(Ordering::Relaxed),
+            diverged: self.diverged.load(Ordering::Relaxed),
+            failures: self
+                .writers
+                .iter()
+                .map(|mirror| (mirror.name.clone(), mirror.failures.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
+
+/// A writer writing each record to several writers, see the [module](self) documentation
+///
+/// Clones share the counters and the writers added before they were cloned.
+#[derive(Clone)]
+pub struct TeeWriter(QuorumWriter);
+
+impl TeeWriter {
+    pub fn new() -> Self {
+        Self(QuorumWriter::new(0))
+    }
+
+    /// Adds a writer, named in the [`failures`](Self::failures)
+    pub fn with_writer<M>(self, name: impl Into<String>, make_writer: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self(self.0.with_writer(name, make_writer))
+    }
+
+    /// Adds a writer getting records with `separator` in place of the
+    /// [record separator](Self::with_record_separator) of the layer
+    pub fn with_writer_separator<M>(
+        self,
+        name: impl Into<String>,
+        make_writer: M,
+        separator: impl Into<RecordSeparator>,
+    ) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self(
+            self.0
+                .push_writer(name.into(), make_writer, Some(separator.into())),
+        )
+    }
+
+    /// The separator the layer writes after each record, removed for the writers added with
+    /// [`with_writer_separator`](Self::with_writer_separator), defaults to
+    /// [`RecordSeparator::NEWLINE`]
+    pub fn with_record_separator(self, separator: impl Into<RecordSeparator>) -> Self {
+        Self(QuorumWriter {
+            record_separator: separator.into(),
+            ..self.0
+        })
+    }
+
+    /// Number of records each writer failed to write, by name
+    pub fn failures(&self) -> Vec<(String, u64)> {
+        self.0.stats().failures
+    }
+}
+
+impl Default for TeeWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> MakeWriter<'a> for TeeWriter {
+    type Writer = QuorumRecord<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.0.make_writer()
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.0.make_writer_for(meta)
+    }
+}
+
+/// The writers made for a single event, writing each write to all of them as a whole record
+pub struct QuorumRecord<'a> {
+    writer: &'a QuorumWriter,
+    writers: Vec<Box<dyn Write + 'a>>,
+}
+
+impl Write for QuorumRecord<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let record = buf
+            .strip_suffix(self.writer.record_separator.as_bytes())
+            .unwrap_or(buf);
+        let mut written = 0;
+        for (writer, mirror) in self.writers.iter_mut().zip(&self.writer.writers) {
+            let result = match &mirror.separator {
+                Some(separator) => writer.write_all(&[record, separator.as_bytes()].concat()),
+                None => writer.write_all(buf),
+            };
+            let result = result.and_then(|_| writer.flush());
+            crate::self_test::report_writer(&mirror.name, &result);
+            match result {
+                Ok(()) => written += 1,
+                Err(_) => {
+                    mirror.failures.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if written < self.writers.len() {
+            self.writer.diverged.fetch_add(1, Ordering::Relaxed);
+        }
+        if written < self.writer.quorum {
+            self.writer.undelivered.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::other(format!(
+                "record written by {} of {} writers, below the quorum of {}",
+                written,
+                self.writers.len(),
+                self.writer.quorum
+            )));
+        }
+        self.writer.delivered.fetch_add(1, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for QuorumWriter {
+    type Writer = QuorumRecord<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        QuorumRecord {
+            writer: self,
+            writers: self
+                .writers
+                .iter()
+                .map(|mirror| mirror.make_writer.make_writer())
+                .collect(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        QuorumRecord {
+            writer: self,
+            writers: self
+                .writers
+                .iter()
+                .map(|mirror| mirror.make_writer.make_writer_for(meta))
+                .collect(),
+        }
+    }
+}
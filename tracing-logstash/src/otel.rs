@@ -0,0 +1,266 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, RecordKeys};
+use crate::logstash::{LogFieldContributor, LogFieldReceiver, SerializingFieldVisitor};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::trace_context::TraceContextProvider;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format for the [OpenTelemetry logs data model](https://opentelemetry.io/docs/specs/otel/logs/data-model/)
+///
+/// The message is written as `Body` and the event target as the `InstrumentationScope` name.
+/// Constants, contributed fields, event fields and recorded span fields are written as
+/// `Attributes`, and resource attributes as `Resource`. `Timestamp` is the number of nanoseconds
+/// since the Unix epoch, as a string, since it does not fit in a double.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::trace_context::TraceContext;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::otel::OtelFormat::default()
+///         .with_resource(vec![("service.name", "checkout".to_owned())])
+///         .with_trace_context(|| None::<TraceContext>),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct OtelFormat<FC = ()> {
+    resource: Vec<(&'static str, String)>,
+    trace_context: Option<Arc<dyn TraceContextProvider>>,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    field_contributor: FC,
+}
+
+impl Default for OtelFormat {
+    fn default() -> Self {
+        Self {
+            resource: Default::default(),
+            trace_context: None,
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            field_contributor: (),
+        }
+    }
+}
+
+impl<FC> OtelFormat<FC> {
+    /// Resource attributes, such as `service.name`
+    pub fn with_resource(self, resource: Vec<(&'static str, String)>) -> Self {
+        Self { resource, ..self }
+    }
+    /// Write `TraceId` and `SpanId`, using the ids from `provider`
+    pub fn with_trace_context(self, provider: impl TraceContextProvider + 'static) -> Self {
+        Self {
+            trace_context: Some(Arc::new(provider)),
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    pub fn with_field_contributor<FC2>(self, field_contributor: FC2) -> OtelFormat<FC2> {
+        OtelFormat {
+            resource: self.resource,
+            trace_context: self.trace_context,
+            span_fields: self.span_fields,
+            excluded_fields: self.excluded_fields,
+            constants: self.constants,
+            field_contributor,
+        }
+    }
+}
+
+const fn severity_number(level: &Level) -> u8 {
+    match *level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+impl<FC> FormatEvent for OtelFormat<FC>
+where
+    FC: LogFieldContributor,
+{
+    type R = DefaultSpanRecorder;
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        Some(RecordKeys {
+            message: "Body",
+            kept: &[
+                "Timestamp",
+                "SeverityText",
+                "SeverityNumber",
+                "TraceId",
+                "SpanId",
+                "InstrumentationScope",
+            ],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let event_metadata = event.metadata();
+        let timestamp = time::OffsetDateTime::now_utc().unix_timestamp_nanos();
+
+        let mut body = Body(None);
+        event.record(&mut body);
+
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("Timestamp", &timestamp.to_string())?;
+        s.serialize_entry("SeverityText", level.as_str())?;
+        s.serialize_entry("SeverityNumber", &severity_number(&level))?;
+        if let Some(body) = &body.0 {
+            s.serialize_entry("Body", body)?;
+        }
+        if let Some(trace_context) = self.trace_context.as_ref().and_then(|p| p.trace_context()) {
+            s.serialize_entry("TraceId", &trace_context.trace_id)?;
+            if let Some(span_id) = &trace_context.span_id {
+                s.serialize_entry("SpanId", span_id)?;
+            }
+        }
+        if !self.resource.is_empty() {
+            s.serialize_entry("Resource", &Resource(&self.resource))?;
+        }
+        s.serialize_entry(
+            "InstrumentationScope",
+            &InstrumentationScope(event_metadata.target()),
+        )?;
+        s.serialize_entry("Attributes", &Attributes(self, event, &ctx))?;
+        s.end()
+    }
+}
+
+struct Body(Option<String>);
+
+impl Visit for Body {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.0 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+struct Resource<'a>(&'a [(&'static str, String)]);
+
+impl Serialize for Resource<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(self.0.iter().map(|(k, v)| (k, v)))
+    }
+}
+
+struct InstrumentationScope<'a>(&'a str);
+
+impl Serialize for InstrumentationScope<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut s = serializer.serialize_map(Some(1))?;
+        s.serialize_entry("Name", self.0)?;
+        s.end()
+    }
+}
+
+struct Attributes<'a, FC, SS>(&'a OtelFormat<FC>, &'a Event<'a>, &'a Context<'a, SS>);
+
+impl<FC, SS> Serialize for Attributes<'_, FC, SS>
+where
+    FC: LogFieldContributor,
+    SS: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let Self(format, event, ctx) = self;
+
+        let mut s = serializer.serialize_map(None)?;
+
+        // The message is the body, not an attribute
+        let mut seen = HashSet::from([Cow::Borrowed("message")]);
+        seen.extend(
+            format
+                .excluded_fields
+                .iter()
+                .map(|field| Cow::Borrowed(*field)),
+        );
+        let mut field_visitor = SerializingFieldVisitor::new(&mut s, |name| seen.insert(name));
+
+        for (key, value) in &format.constants {
+            field_visitor.add_field(key, value);
+        }
+
+        format.field_contributor.add_fields(&mut field_visitor);
+
+        event.record(&mut field_visitor);
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    field_visitor.add_extension_fields(span_fields);
+                }
+            }
+        }
+
+        field_visitor.finish()?;
+        s.end()
+    }
+}
@@ -0,0 +1,326 @@
+//! Shrinking records larger than the maximum record size, see
+//! [`Layer::with_max_record_bytes`](crate::Layer::with_max_record_bytes)
+//!
+//! Records are parsed into their top-level fields with the values kept as JSON text, so fields
+//! keep their order and the values that are not changed are written back as they were.
+
+use crate::format::RecordKeys;
+use crate::logstash::TRUNCATED_SUFFIX;
+use crate::OversizeStrategy;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde_json::value::RawValue;
+use std::fmt;
+
+/// The top-level fields of a record, in the order they were written
+struct Fields(Vec<(String, Box<RawValue>)>);
+
+impl<'de> Deserialize<'de> for Fields {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct FieldsVisitor;
+
+        impl<'de> Visitor<'de> for FieldsVisitor {
+            type Value = Fields;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a JSON object")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields, A::Error> {
+                let mut fields = Vec::with_capacity(map.size_hint().unwrap_or(16));
+                while let Some(field) = map.next_entry()? {
+                    fields.push(field);
+                }
+                Ok(Fields(fields))
+            }
+        }
+
+        deserializer.deserialize_map(FieldsVisitor)
+    }
+}
+
+impl Fields {
+    fn get(&self, key: &str) -> Option<&RawValue> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| &**v)
+    }
+
+    fn set(&mut self, key: &str, value: &impl serde::Serialize) {
+        let Ok(value) = serde_json::value::to_raw_value(value) else {
+            return;
+        };
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key.to_owned(), value)),
+        }
+    }
+
+    fn to_vec(&self) -> Vec<u8> {
+        let mut record = Vec::with_capacity(256);
+        record.push(b'{');
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                record.push(b',');
+            }
+            let _ = serde_json::to_writer(&mut record, key);
+            record.push(b':');
+            record.extend_from_slice(value.get().as_bytes());
+        }
+        record.push(b'}');
+        record
+    }
+}
+
+/// What [`shrink`] did with a record
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Shrink {
+    /// The record was not larger than the maximum
+    Fits,
+    Shrunk,
+    /// The record is not a JSON object with the keys of the format, and was left as it was
+    Unshrinkable,
+}
+
+/// Shrinks a record serialized as a JSON object to at most `max` bytes, if it is larger.
+/// Records that can't be shrunk by the strategy are replaced with a stub.
+pub(crate) fn shrink(
+    record: &mut Vec<u8>,
+    max: usize,
+    strategy: OversizeStrategy,
+    keys: Option<RecordKeys>,
+) -> Shrink {
+    if record.len() <= max {
+        return Shrink::Fits;
+    }
+    let (Some(keys), Ok(mut fields)) = (keys, serde_json::from_slice::<Fields>(record)) else {
+        return Shrink::Unshrinkable;
+    };
+    let size = record.len();
+    let shrunk = match strategy {
+        OversizeStrategy::TruncateMessage => truncate_message(&mut fields, keys, size, max),
+        OversizeStrategy::DropLargestFields => drop_largest_fields(&mut fields, keys, size, max),
+        OversizeStrategy::Stub => None,
+    };
+    *record = shrunk.unwrap_or_else(|| stub(&fields, keys, size, max));
+    Shrink::Shrunk
+}
+
+fn truncate_message(
+    fields: &mut Fields,
+    keys: RecordKeys,
+    size: usize,
+    max: usize,
+) -> Option<Vec<u8>> {
+    let original: String = serde_json::from_str(fields.get(keys.message)?.get()).ok()?;
+    // Escaping may make the serialized message longer than the string, so shrink until it fits
+    let mut excess = size - max + TRUNCATED_SUFFIX.len();
+    loop {
+        let mut end = original.len().checked_sub(excess)?;
+        while !original.is_char_boundary(end) {
+            end -= 1;
+        }
+        fields.set(
+            keys.message,
+            &format!("{}{}", &original[..end], TRUNCATED_SUFFIX),
+        );
+        let record = fields.to_vec();
+        if record.len() <= max {
+            return Some(record);
+        }
+        excess += record.len() - max;
+    }
+}
+
+fn drop_largest_fields(
+    fields: &mut Fields,
+    keys: RecordKeys,
+    size: usize,
+    max: usize,
+) -> Option<Vec<u8>> {
+    let mut sizes = fields
+        .0
+        .iter()
+        .filter(|(key, _)| key.as_str() != keys.message && !keys.kept.contains(&key.as_str()))
+        .map(|(key, value)| (key.clone(), key.len() + value.get().len() + 4))
+        .collect::<Vec<_>>();
+    sizes.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+
+    let mut dropped = Vec::new();
+    let mut remaining = size;
+    for (key, field_size) in sizes {
+        if remaining <= max {
+            break;
+        }
+        remaining = remaining.saturating_sub(field_size);
+        dropped.push(key);
+    }
+    fields.0.retain(|(key, _)| !dropped.contains(key));
+    fields.set("log.dropped_fields", &dropped);
+    Some(fields.to_vec()).filter(|record| record.len() <= max)
+}
+
+fn stub(fields: &Fields, keys: RecordKeys, size: usize, max: usize) -> Vec<u8> {
+    let mut stub = Fields(
+        fields
+            .0
+            .iter()
+            .filter(|(key, _)| keys.kept.contains(&key.as_str()))
+            .cloned()
+            .collect(),
+    );
+    stub.set(
+        keys.message,
+        &format!(
+            "record of {} bytes exceeded the maximum of {} bytes",
+            size, max
+        ),
+    );
+    stub.set("log.oversized_bytes", &size);
+    stub.to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{shrink, Shrink};
+    use crate::format::RecordKeys;
+    use crate::OversizeStrategy;
+    use serde::Deserialize as _;
+    use serde_json::{json, Value};
+
+    fn shrunk(record: &str, max: usize, strategy: OversizeStrategy) -> String {
+        let mut record = record.as_bytes().to_vec();
+        assert_eq!(
+            shrink(&mut record, max, strategy, Some(RecordKeys::LOGSTASH)),
+            Shrink::Shrunk
+        );
+        assert!(record.len() <= max);
+        String::from_utf8(record).unwrap()
+    }
+
+    /// The keys of a record, in the order they were written
+    fn keys(record: &str) -> Vec<String> {
+        super::Fields::deserialize(&mut serde_json::Deserializer::from_str(record))
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn test_shrink() {
+        let record = serde_json::to_string(&json!({
+            "level": "INFO",
+            "message": "a \"quoted\" message ".repeat(10),
+            "body": "x".repeat(100),
+            "peer": "10.0.0.1",
+        }))
+        .unwrap();
+        let record_json: Value = serde_json::from_str(&record).unwrap();
+
+        let truncated: Value =
+            serde_json::from_str(&shrunk(&record, 250, OversizeStrategy::TruncateMessage)).unwrap();
+        let message = truncated["message"].as_str().unwrap();
+        assert!(message.starts_with("a \"quoted\""));
+        assert!(message.ends_with("…[truncated]"));
+        assert_eq!(truncated["body"], record_json["body"]);
+
+        let dropped: Value =
+            serde_json::from_str(&shrunk(&record, 300, OversizeStrategy::DropLargestFields))
+                .unwrap();
+        assert_eq!(dropped["message"], record_json["message"]);
+        assert_eq!(dropped["peer"], "10.0.0.1");
+        assert_eq!(dropped["log.dropped_fields"], json!(["body"]));
+
+        let stub: Value =
+            serde_json::from_str(&shrunk(&record, 150, OversizeStrategy::Stub)).unwrap();
+        assert_eq!(stub["level"], "INFO");
+        assert!(stub.get("body").is_none());
+        assert!(stub["log.oversized_bytes"].as_u64().unwrap() > 150);
+
+        // Falls back to the stub when the message can't absorb the excess
+        let stub: Value =
+            serde_json::from_str(&shrunk(&record, 120, OversizeStrategy::TruncateMessage)).unwrap();
+        assert!(stub.get("log.oversized_bytes").is_some());
+
+        let mut small = record.into_bytes();
+        let keys = Some(RecordKeys::LOGSTASH);
+        assert_eq!(
+            shrink(&mut small, 1000, OversizeStrategy::Stub, keys),
+            Shrink::Fits
+        );
+        let mut text = "x".repeat(200).into_bytes();
+        assert_eq!(
+            shrink(&mut text, 100, OversizeStrategy::Stub, keys),
+            Shrink::Unshrinkable
+        );
+        assert_eq!(text.len(), 200);
+    }
+
+    #[test]
+    fn test_shrink_with_message_key() {
+        let gelf = Some(RecordKeys {
+            message: "short_message",
+            kept: &["version", "host", "level"],
+        });
+        let record = format!(
+            r#"{{"version":"1.1","host":"web-1","short_message":"{}","level":6,"_body":"{}"}}"#,
+            "m".repeat(100),
+            "x".repeat(100)
+        );
+
+        let mut truncated = record.clone().into_bytes();
+        shrink(&mut truncated, 200, OversizeStrategy::TruncateMessage, gelf);
+        let truncated: Value = serde_json::from_slice(&truncated).unwrap();
+        assert!(truncated["short_message"]
+            .as_str()
+            .unwrap()
+            .ends_with("…[truncated]"));
+        assert!(truncated.get("log.oversized_bytes").is_none());
+
+        let mut stub = record.into_bytes();
+        shrink(&mut stub, 150, OversizeStrategy::Stub, gelf);
+        assert_eq!(
+            keys(std::str::from_utf8(&stub).unwrap()),
+            [
+                "version",
+                "host",
+                "level",
+                "short_message",
+                "log.oversized_bytes"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shrink_keeps_field_order() {
+        let record = format!(
+            r#"{{"@version":"1","level":"INFO","message":"{}","zone":"eu","body":"{}","app":"shop"}}"#,
+            "m".repeat(100),
+            "x".repeat(100)
+        );
+
+        let truncated = shrunk(&record, 200, OversizeStrategy::TruncateMessage);
+        assert_eq!(
+            keys(&truncated),
+            ["@version", "level", "message", "zone", "body", "app"]
+        );
+
+        let dropped = shrunk(&record, 200, OversizeStrategy::DropLargestFields);
+        assert_eq!(
+            keys(&dropped),
+            [
+                "@version",
+                "level",
+                "message",
+                "zone",
+                "app",
+                "log.dropped_fields"
+            ]
+        );
+
+        let stub = shrunk(&record, 200, OversizeStrategy::Stub);
+        assert_eq!(
+            keys(&stub),
+            ["@version", "level", "message", "log.oversized_bytes"]
+        );
+    }
+}
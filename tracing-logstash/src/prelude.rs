@@ -0,0 +1,14 @@
+//! The traits and types used to configure and install a layer
+//!
+//! # Example
+//! ```
+//! use tracing_logstash::prelude::*;
+//!
+//! let logger = tracing_logstash::Layer::default()
+//!     .event_format(LogstashFormat::default().with_span_fields(vec![FieldSpec::new("job_id")]));
+//! let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+pub use crate::logstash::{LogFieldContributor, LogFieldReceiver, LogstashFormat};
+pub use crate::{DisplayLevelFilter, FieldOrigin, FieldSpec, LoggerName, Unit};
+pub use tracing_subscriber::prelude::*;
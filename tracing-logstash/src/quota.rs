@@ -0,0 +1,359 @@
+use crate::fields::TryForEachField;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Record rate and size quotas per tenant, identified by the value of a designated field
+///
+/// The tenant is taken from the event's fields, or if the event doesn't have the field, from
+/// the recorded fields of the spans in its scope (see
+/// [`LogstashFormat::with_span_fields`](crate::logstash::LogstashFormat::with_span_fields)).
+/// Records without a tenant are not subject to quotas.
+///
+/// Records over quota are dropped, or if over-quota sampling is configured, all but one in
+/// every `n` are dropped. Clones share the same quotas and statistics.
+///
+/// Tenants without records for a minute, the longest quota window, are forgotten along with
+/// their statistics, so tenants seen once don't use memory for the lifetime of the process.
+///
+/// Only records are dropped: the recorded fields of spans are updated whether or not the
+/// records of their events are written. Summary records written by the layer, of
+/// [aggregated](crate::Layer::with_aggregation) events and of
+/// [dropped](crate::Layer::with_dropped_summary) records, are not in any span and have no
+/// tenant, so they account for every event of every tenant and are never dropped by quotas,
+/// unless [summary records](TenantQuotas::with_summary_records) are enabled.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::quota::TenantQuotas;
+/// #
+/// let quotas = TenantQuotas::new("tenant_id")
+///     .with_max_records_per_second(100)
+///     .with_max_bytes_per_minute(1024 * 1024);
+///
+/// let logger = tracing_logstash::Layer::default().with_tenant_quotas(Some(quotas.clone()));
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+///
+/// // Later
+/// for (tenant, stats) in quotas.stats() {
+///     println!("{}: {} dropped", tenant, stats.records_dropped);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct TenantQuotas {
+    field: &'static str,
+    max_records_per_second: Option<u64>,
+    max_bytes_per_minute: Option<u64>,
+    over_quota_sample_rate: u64,
+    sampling_fields: bool,
+    summary_records: bool,
+    tenants: Arc<Mutex<Tenants>>,
+}
+
+/// Tenants idle for this long are forgotten, as both quota windows would have restarted
+const IDLE: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Tenants {
+    states: HashMap<String, TenantState>,
+    /// When idle tenants were last removed
+    evicted_at: Option<Instant>,
+}
+
+/// Statistics for a single tenant
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TenantStats {
+    pub records_written: u64,
+    pub bytes_written: u64,
+    pub records_dropped: u64,
+}
+
+struct TenantState {
+    second_start: Instant,
+    records_this_second: u64,
+    minute_start: Instant,
+    bytes_this_minute: u64,
+    over_quota: u64,
+    last_seen: Instant,
+    stats: TenantStats,
+}
+
+impl TenantState {
+    fn new(now: Instant) -> Self {
+        Self {
+            second_start: now,
+            records_this_second: 0,
+            minute_start: now,
+            bytes_this_minute: 0,
+            over_quota: 0,
+            last_seen: now,
+            stats: Default::default(),
+        }
+    }
+}
+
+impl TenantQuotas {
+    pub fn new(field: &'static str) -> Self {
+        Self {
+            field,
+            max_records_per_second: None,
+            max_bytes_per_minute: None,
+            over_quota_sample_rate: 0,
+            sampling_fields: false,
+            summary_records: false,
+            tenants: Default::default(),
+        }
+    }
+
+    pub fn with_max_records_per_second(self, max_records_per_second: u64) -> Self {
+        Self {
+            max_records_per_second: Some(max_records_per_second),
+            ..self
+        }
+    }
+
+    pub fn with_max_bytes_per_minute(self, max_bytes_per_minute: u64) -> Self {
+        Self {
+            max_bytes_per_minute: Some(max_bytes_per_minute),
+            ..self
+        }
+    }
+
+    /// Write one in every `n` records over quota instead of dropping them all
+    pub fn with_over_quota_sampling(self, n: u64) -> Self {
+        Self {
+            over_quota_sample_rate: n,
+            ..self
+        }
+    }
+
+    /// Stamp the records written by over-quota sampling with `sampling.rate`, the number of
+    /// records each of them stands for, and `sampling.decision`, so counts can be re-weighted
+    /// downstream. Only records serialized as JSON objects are stamped.
+    pub fn with_sampling_fields(self, sampling_fields: bool) -> Self {
+        Self {
+            sampling_fields,
+            ..self
+        }
+    }
+
+    pub(crate) fn sampling_fields(&self) -> bool {
+        self.sampling_fields
+    }
+
+    /// Aggregate the events of each tenant separately, giving each summary record the tenant
+    /// field of the events it summarizes, so that summaries count towards the quota of the
+    /// tenant and are dropped or sampled with its records. Summaries of dropped records count
+    /// the records dropped by writers, which don't know their tenants, and keep having no tenant.
+    pub fn with_summary_records(self, summary_records: bool) -> Self {
+        Self {
+            summary_records,
+            ..self
+        }
+    }
+
+    pub(crate) fn summary_records(&self) -> bool {
+        self.summary_records
+    }
+
+    /// The field the tenant is taken from
+    pub(crate) fn field(&self) -> &'static str {
+        self.field
+    }
+
+    /// Statistics per tenant seen in the last minute
+    pub fn stats(&self) -> HashMap<String, TenantStats> {
+        let tenants = self.tenants.lock().unwrap_or_else(|e| e.into_inner());
+        tenants
+            .states
+            .iter()
+            .map(|(tenant, state)| (tenant.clone(), state.stats.clone()))
+            .collect()
+    }
+
+    pub(crate) fn tenant<S, R>(&self, event: &Event<'_>, ctx: &Context<'_, S>) -> Option<String>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+        R: TryForEachField + 'static,
+    {
+        let mut visitor = TenantVisitor(self.field, None);
+        event.record(&mut visitor);
+        if visitor.1.is_some() {
+            return visitor.1;
+        }
+        ctx.event_scope(event)?.find_map(|span| {
+            let extensions = span.extensions();
+            let mut tenant = None;
+            let _ = extensions.get::<R>()?.try_for_each(|name, value| {
+                if name == self.field && !value.is_unset() {
+                    tenant = Some(match serde_json::to_value(value) {
+                        Ok(serde_json::Value::String(s)) => s,
+                        Ok(value) => value.to_string(),
+                        Err(e) => return Err(e),
+                    });
+                }
+                Ok(())
+            });
+            tenant
+        })
+    }
+
+    /// Whether a record of `bytes` bytes for `tenant` is written, updating the statistics
+    pub(crate) fn admit(&self, tenant: &str, bytes: u64, now: Instant) -> Admission {
+        let mut tenants = self.tenants.lock().unwrap_or_else(|e| e.into_inner());
+        let evicted_at = *tenants.evicted_at.get_or_insert(now);
+        if now.duration_since(evicted_at) >= IDLE {
+            tenants
+                .states
+                .retain(|_, state| now.duration_since(state.last_seen) < IDLE);
+            tenants.evicted_at = Some(now);
+        }
+        if !tenants.states.contains_key(tenant) {
+            tenants
+                .states
+                .insert(tenant.to_owned(), TenantState::new(now));
+        }
+        let state = tenants
+            .states
+            .get_mut(tenant)
+            .expect("tenant was just inserted");
+        state.last_seen = now;
+
+        if now.duration_since(state.second_start) >= Duration::from_secs(1) {
+            state.second_start = now;
+            state.records_this_second = 0;
+        }
+        if now.duration_since(state.minute_start) >= Duration::from_secs(60) {
+            state.minute_start = now;
+            state.bytes_this_minute = 0;
+        }
+
+        let over_quota = self
+            .max_records_per_second
+            .is_some_and(|max| state.records_this_second >= max)
+            || self
+                .max_bytes_per_minute
+                .is_some_and(|max| state.bytes_this_minute + bytes > max);
+        let admission = if !over_quota {
+            Admission::Within
+        } else {
+            state.over_quota += 1;
+            if self.over_quota_sample_rate > 0
+                && (state.over_quota - 1).is_multiple_of(self.over_quota_sample_rate)
+            {
+                Admission::Sampled(self.over_quota_sample_rate)
+            } else {
+                Admission::Dropped
+            }
+        };
+
+        if admission != Admission::Dropped {
+            state.records_this_second += 1;
+            state.bytes_this_minute += bytes;
+            state.stats.records_written += 1;
+            state.stats.bytes_written += bytes;
+        } else {
+            state.stats.records_dropped += 1;
+        }
+        admission
+    }
+}
+
+/// Whether a record is written
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    Within,
+    /// Over quota and written by sampling, one in every `n`
+    Sampled(u64),
+    Dropped,
+}
+
+struct TenantVisitor(&'static str, Option<String>);
+
+impl Visit for TenantVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.0 {
+            self.1 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == self.0 {
+            self.1 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Admission, TenantQuotas, TenantStats};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_records_per_second() {
+        let quotas = TenantQuotas::new("tenant").with_max_records_per_second(2);
+        let now = Instant::now();
+        assert_eq!(quotas.admit("a", 10, now), Admission::Within);
+        assert_eq!(quotas.admit("a", 10, now), Admission::Within);
+        assert_eq!(quotas.admit("a", 10, now), Admission::Dropped);
+        assert_eq!(quotas.admit("b", 10, now), Admission::Within);
+        assert_eq!(
+            quotas.admit("a", 10, now + Duration::from_secs(1)),
+            Admission::Within
+        );
+        assert_eq!(
+            quotas.stats()["a"],
+            TenantStats {
+                records_written: 3,
+                bytes_written: 30,
+                records_dropped: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn test_bytes_per_minute_with_sampling() {
+        let quotas = TenantQuotas::new("tenant")
+            .with_max_bytes_per_minute(100)
+            .with_over_quota_sampling(2);
+        let now = Instant::now();
+        assert_eq!(quotas.admit("a", 100, now), Admission::Within);
+        assert_eq!(quotas.admit("a", 1, now), Admission::Sampled(2));
+        assert_eq!(quotas.admit("a", 1, now), Admission::Dropped);
+        assert_eq!(quotas.admit("a", 1, now), Admission::Sampled(2));
+        assert_eq!(
+            quotas.admit("a", 100, now + Duration::from_secs(60)),
+            Admission::Within
+        );
+    }
+
+    #[test]
+    fn test_idle_tenants_evicted() {
+        let quotas = TenantQuotas::new("tenant").with_max_records_per_second(1);
+        let now = Instant::now();
+        assert_eq!(quotas.admit("a", 10, now), Admission::Within);
+        assert_eq!(quotas.admit("b", 10, now), Admission::Within);
+        let later = now + Duration::from_secs(30);
+        assert_eq!(quotas.admit("b", 10, later), Admission::Within);
+        assert_eq!(quotas.stats().len(), 2);
+
+        // "a" has been idle for a minute, "b" for 30 seconds
+        let later = now + Duration::from_secs(60);
+        assert_eq!(quotas.admit("c", 10, later), Admission::Within);
+        let stats = quotas.stats();
+        assert!(!stats.contains_key("a"));
+        assert_eq!(stats["b"].records_written, 2);
+        assert_eq!(stats["c"].records_written, 1);
+        assert_eq!(quotas.tenants.lock().unwrap().states.len(), 2);
+
+        // A returning tenant starts over
+        assert_eq!(quotas.admit("a", 10, later), Admission::Within);
+        assert_eq!(quotas.stats()["a"].records_written, 1);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::logstash::{level_value, LogTimestamp};
+use crate::RecordSeparator;
+use serde::ser::SerializeMap;
+use serde::Serializer;
+use std::io::{self, BufRead, Write};
+use tracing_core::Level;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Writes arbitrary text, such as the captured output of a child process, as logstash records
+///
+/// Each record carries the name of the stream the text was captured from in `stream` and is
+/// marked with `raw: true`. Text longer than the maximum chunk length is split over several
+/// records, numbered by `chunk_index` and `chunk_count`.
+///
+/// # Example
+/// ```
+/// # use std::process::{Command, Stdio};
+/// # use tracing_logstash::raw::RawLogWriter;
+/// #
+/// let stdout = RawLogWriter::new(std::io::stdout).with_stream("stdout");
+///
+/// let mut child = Command::new("echo")
+///     .arg("hello")
+///     .stdout(Stdio::piped())
+///     .spawn()
+///     .unwrap();
+/// stdout
+///     .copy_lines(std::io::BufReader::new(child.stdout.take().unwrap()))
+///     .unwrap();
+/// # child.wait().unwrap();
+/// ```
+pub struct RawLogWriter<W> {
+    make_writer: W,
+    stream: String,
+    logger_name: String,
+    level: Level,
+    max_chunk_length: usize,
+    record_separator: RecordSeparator,
+    constants: Vec<(&'static str, String)>,
+}
+
+impl<W> RawLogWriter<W>
+where
+    W: for<'writer> MakeWriter<'writer>,
+{
+    pub fn new(make_writer: W) -> Self {
+        Self {
+            make_writer,
+            stream: "stdout".to_owned(),
+            logger_name: "raw".to_owned(),
+            level: Level::INFO,
+            max_chunk_length: 16 * 1024,
+            record_separator: RecordSeparator::NEWLINE,
+            constants: Vec::new(),
+        }
+    }
+
+    pub fn with_stream(self, stream: impl Into<String>) -> Self {
+        Self {
+            stream: stream.into(),
+            ..self
+        }
+    }
+
+    pub fn with_logger_name(self, logger_name: impl Into<String>) -> Self {
+        Self {
+            logger_name: logger_name.into(),
+            ..self
+        }
+    }
+
+    pub fn with_level(self, level: Level) -> Self {
+        Self { level, ..self }
+    }
+
+    /// Maximum length of the text in a single record, in bytes. At least 4, so that any
+    /// character fits in a chunk.
+    pub fn with_max_chunk_length(self, max_chunk_length: usize) -> Self {
+        Self {
+            max_chunk_length: max_chunk_length.max(4),
+            ..self
+        }
+    }
+
+    pub fn with_record_separator(self, separator: impl Into<RecordSeparator>) -> Self {
+        Self {
+            record_separator: separator.into(),
+            ..self
+        }
+    }
+
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+
+    /// Write `text`, which may span multiple lines, as a single record, or as several if it's
+    /// longer than the maximum chunk length.
+    pub fn write(&self, text: &str) -> io::Result<()> {
+        let chunks = chunks(text, self.max_chunk_length);
+        if let [text] = chunks.as_slice() {
+            return self.write_record(text, None);
+        }
+        let chunk_count = chunks.len();
+        for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+            self.write_record(chunk, Some((chunk_index, chunk_count)))?;
+        }
+        Ok(())
+    }
+
+    /// Write every line read from `reader` as a separate record, until end of file.
+    pub fn copy_lines<R: BufRead>(&self, reader: R) -> io::Result<()> {
+        for line in reader.lines() {
+            self.write(&line?)?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&self, text: &str, chunk: Option<(usize, usize)>) -> io::Result<()> {
+        let mut buffer = Vec::new();
+        let mut serializer = serde_json::Serializer::new(&mut buffer);
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("@version", "1")?;
+        s.serialize_entry("@timestamp", &LogTimestamp::default())?;
+        s.serialize_entry("logger_name", &self.logger_name)?;
+        s.serialize_entry("level", self.level.as_str())?;
+        s.serialize_entry("level_value", &level_value(&self.level))?;
+        for (key, value) in &self.constants {
+            s.serialize_entry(key, value)?;
+        }
+        s.serialize_entry("stream", &self.stream)?;
+        s.serialize_entry("raw", &true)?;
+        if let Some((chunk_index, chunk_count)) = chunk {
+            s.serialize_entry("chunk_index", &chunk_index)?;
+            s.serialize_entry("chunk_count", &chunk_count)?;
+        }
+        s.serialize_entry("message", text)?;
+        s.end()?;
+        buffer.extend_from_slice(self.record_separator.as_bytes());
+        self.make_writer.make_writer().write_all(&buffer)
+    }
+}
+
+/// Split `text` into pieces of at most `max_length` bytes, on character boundaries
+fn chunks(mut text: &str, max_length: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    while text.len() > max_length {
+        let mut end = max_length;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (chunk, rest) = text.split_at(end);
+        chunks.push(chunk);
+        text = rest;
+    }
+    chunks.push(text);
+    chunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::chunks;
+
+    #[test]
+    fn test_chunks() {
+        assert_eq!(chunks("", 4), vec![""]);
+        assert_eq!(chunks("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+        assert_eq!(chunks("åäö", 4), vec!["åä", "ö"]);
+    }
+}
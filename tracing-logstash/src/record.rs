@@ -0,0 +1,85 @@
+//! Writers taking each write as a whole record, for writers that frame, queue or send records
+//! themselves
+//!
+//! The layer writes each record, including its separator, with a single write. A
+//! [`RecordWriter`] passes each write to [`WriteRecord::write_record`] as one record, with the
+//! level of the event, and returns its error to the layer, where it is handled by the
+//! [`WriteErrorPolicy`](crate::WriteErrorPolicy) or a [`FallbackWriter`](crate::fallback).
+//! Writing a record with several writes, such as with `write!`, writes each part as a record.
+//!
+//! # Example
+//! ```
+//! # use std::io;
+//! # use tracing_core::{Level, Metadata};
+//! # use tracing_subscriber::fmt::MakeWriter;
+//! # use tracing_logstash::record::{RecordWriter, WriteRecord};
+//! #
+//! struct ErrorsToStderr;
+//!
+//! impl WriteRecord for ErrorsToStderr {
+//!     fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+//!         if level == Level::ERROR {
+//!             io::Write::write_all(&mut io::stderr(), record)?;
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! impl<'a> MakeWriter<'a> for ErrorsToStderr {
+//!     type Writer = RecordWriter<'a, Self>;
+//!
+//!     fn make_writer(&'a self) -> Self::Writer {
+//!         RecordWriter::new(self, Level::INFO)
+//!     }
+//!
+//!     fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+//!         RecordWriter::new(self, *meta.level())
+//!     }
+//! }
+//! ```
+
+use std::io::{self, Write};
+use tracing_core::Level;
+
+/// Writes whole records, see the [module](self) documentation
+pub trait WriteRecord {
+    /// Writes a record, including its separator, of an event at `level`
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()>;
+
+    /// Writes the record of the [self test](crate::self_test) and waits for it to be delivered,
+    /// for writers queueing records. The default writes the record.
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.write_record(record, level)
+    }
+}
+
+/// The writer made for a single event, see the [module](self) documentation
+pub struct RecordWriter<'a, W: ?Sized> {
+    writer: &'a W,
+    level: Level,
+}
+
+impl<'a, W: ?Sized> RecordWriter<'a, W> {
+    /// A writer for an event at `level`, `INFO` when the level of the event is unknown
+    pub fn new(writer: &'a W, level: Level) -> Self {
+        Self { writer, level }
+    }
+}
+
+impl<W: WriteRecord + ?Sized> Write for RecordWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if crate::self_test::is_writing() {
+            self.writer.write_acknowledged(buf, self.level)?;
+        } else {
+            self.writer.write_record(buf, self.level)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
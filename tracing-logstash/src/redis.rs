@@ -0,0 +1,361 @@
+//! Delivery of records to a Redis list or stream, for the Logstash `redis` input
+//!
+//! Records are pushed to a list with `RPUSH`, or added to a stream with `XADD` as the `message`
+//! entry field. Batches of up to `batch_size` records are pipelined, sending all commands before
+//! reading the replies. A batch is resent, after reconnecting, until all of its commands succeed
+//! or the number of attempts runs out, so records may be pushed more than once. Batches that
+//! could not be delivered are kept and resent after the flush interval. Records rejected by the
+//! server with an error reply, such as when the key holds another type, are dropped and counted.
+//!
+//! Records are queued by the threads writing them, up to `max_pending` records; beyond that the
+//! oldest records are dropped and counted, or the records chosen by another [`BackpressurePolicy`].
+//! They are sent from a background thread, started with the first record, when a batch is full or
+//! the oldest queued record is older than the flush interval. Call [`RedisSink::flush`] before
+//! exiting to wait for the queued records to be sent. When the last clone of the sink is dropped,
+//! the queued records are sent once more and the thread is stopped.
+//!
+//! # Example
+//! ```no_run
+//! # use std::time::Duration;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::redis::{RedisKey, RedisSink};
+//! #
+//! let sink = RedisSink::new("redis:6379", RedisKey::List("logstash".to_owned()))
+//!     .unwrap()
+//!     .with_batch_size(64)
+//!     .with_flush_interval(Duration::from_secs(1));
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // Before exiting
+//! sink.flush().unwrap();
+//! ```
+
+use crate::background::BackpressurePolicy;
+use crate::delivery::{Batching, Delivery, Transport};
+use crate::record::{RecordWriter, WriteRecord};
+use crate::trim_separator;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Where records are sent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RedisKey {
+    /// Pushed to the tail of the list, for the `list` data type of the Logstash input
+    List(String),
+    /// Added to the stream, with an id generated by Redis
+    Stream(String),
+}
+
+/// A writer sending records to Redis, see the [module](self) documentation
+///
+/// Clones share the same connection and queued records.
+#[derive(Clone)]
+pub struct RedisSink {
+    config: Arc<Config>,
+    delivery: Delivery<Vec<u8>>,
+}
+
+#[derive(Clone)]
+struct Config {
+    addrs: Vec<SocketAddr>,
+    key: RedisKey,
+    password: Option<String>,
+    batch_size: usize,
+    max_pending: usize,
+    max_attempts: usize,
+    flush_interval: Duration,
+    timeout: Duration,
+    policy: BackpressurePolicy,
+}
+
+impl Config {
+    fn batching(&self) -> Batching {
+        Batching {
+            batch_size: self.batch_size,
+            batch_bytes: usize::MAX,
+            max_pending: self.max_pending,
+            max_attempts: self.max_attempts,
+            flush_interval: self.flush_interval,
+            connections: 1,
+            policy: self.policy,
+        }
+    }
+}
+
+/// The connection to the server, used from the background thread
+struct Connection {
+    config: Arc<Config>,
+    connection: Option<BufReader<TcpStream>>,
+}
+
+impl RedisSink {
+    /// A sink for the server at `addr`, which is resolved once
+    pub fn new(addr: impl ToSocketAddrs, key: RedisKey) -> io::Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address resolved to nothing",
+            ));
+        }
+        Ok(Self {
+            config: Arc::new(Config {
+                addrs,
+                key,
+                password: None,
+                batch_size: 1,
+                max_pending: 10_000,
+                max_attempts: 3,
+                flush_interval: Duration::from_secs(1),
+                timeout: Duration::from_secs(10),
+                policy: BackpressurePolicy::DropOldest,
+            }),
+            delivery: Delivery::new(),
+        })
+    }
+
+    fn with_config(self, f: impl FnOnce(&mut Config)) -> Self {
+        let mut config = Arc::unwrap_or_clone(self.config);
+        f(&mut config);
+        Self {
+            config: Arc::new(config),
+            ..self
+        }
+    }
+
+    /// Password sent with `AUTH` when connecting
+    pub fn with_password(self, password: Option<String>) -> Self {
+        self.with_config(|config| config.password = password)
+    }
+
+    /// Number of records per pipelined batch, defaults to 1
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        self.with_config(|config| config.batch_size = batch_size.max(1))
+    }
+
+    /// Maximum number of records queued, such as while the server is unreachable, defaults to
+    /// 10000
+    pub fn with_max_pending(self, max_pending: usize) -> Self {
+        self.with_config(|config| config.max_pending = max_pending.max(1))
+    }
+
+    /// What to do with a record when `max_pending` records are queued, defaults to
+    /// [`BackpressurePolicy::DropOldest`]
+    pub fn with_backpressure_policy(self, policy: BackpressurePolicy) -> Self {
+        self.with_config(|config| config.policy = policy)
+    }
+
+    /// Number of connection attempts per batch, defaults to 3
+    pub fn with_max_attempts(self, max_attempts: usize) -> Self {
+        self.with_config(|config| config.max_attempts = max_attempts.max(1))
+    }
+
+    /// Send a partial batch when its oldest record is older than this, defaults to one second
+    pub fn with_flush_interval(self, flush_interval: Duration) -> Self {
+        self.with_config(|config| config.flush_interval = flush_interval)
+    }
+
+    /// Timeout for connecting, writing and waiting for replies, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.with_config(|config| config.timeout = timeout)
+    }
+
+    /// Number of records dropped because too many records were queued, because the server
+    /// rejected them, or because they could not be sent when the sink was dropped
+    pub fn dropped(&self) -> u64 {
+        self.delivery.dropped()
+    }
+
+    /// Write records to `fallback` instead of dropping them, and the records of batches that could
+    /// not be sent in the number of attempts instead of keeping them, see
+    /// [`fallback`](crate::fallback)
+    pub fn with_fallback_writer<M>(self, fallback: M) -> Self
+    where
+        M: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        Self {
+            delivery: self.delivery.with_fallback(fallback),
+            ..self
+        }
+    }
+
+    /// Number of records written to the fallback writer
+    pub fn fallbacks(&self) -> u64 {
+        self.delivery.fallbacks()
+    }
+
+    /// Wait for the queued records to be sent, failing if some could not be
+    pub fn flush(&self) -> io::Result<()> {
+        self.delivery.flush()
+    }
+
+    fn push(&self, record: Vec<u8>, level: Level) -> io::Result<()> {
+        self.delivery
+            .push(record, level, self.config.batching(), || Connection {
+                config: self.config.clone(),
+                connection: None,
+            })
+    }
+}
+
+impl Transport for Connection {
+    type Record = Vec<u8>;
+
+    fn send(&mut self, batch: &[Vec<u8>], _seq: u64) -> io::Result<u64> {
+        let result = self.try_send_batch(batch);
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    fn bytes(record: &Vec<u8>) -> &[u8] {
+        record
+    }
+}
+
+impl Connection {
+    /// Sends the batch, returning the number of records rejected by the server
+    fn try_send_batch(&mut self, records: &[Vec<u8>]) -> io::Result<u64> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect()?);
+        }
+        let connection = self.connection.as_mut().expect("connected");
+
+        let mut commands = Vec::new();
+        for record in records {
+            let record = trim_separator(record);
+            match &self.config.key {
+                RedisKey::List(key) => {
+                    push_command(&mut commands, &[b"RPUSH", key.as_bytes(), record])
+                }
+                RedisKey::Stream(key) => push_command(
+                    &mut commands,
+                    &[b"XADD", key.as_bytes(), b"*", b"message", record],
+                ),
+            }
+        }
+        connection.get_mut().write_all(&commands)?;
+        connection.get_mut().flush()?;
+
+        let mut rejected = 0;
+        for _ in records {
+            match read_reply(connection) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Other => rejected += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(rejected)
+    }
+
+    fn connect(&self) -> io::Result<BufReader<TcpStream>> {
+        let mut error = None;
+        for addr in &self.config.addrs {
+            match TcpStream::connect_timeout(addr, self.config.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.config.timeout))?;
+                    stream.set_write_timeout(Some(self.config.timeout))?;
+                    stream.set_nodelay(true)?;
+                    let mut connection = BufReader::new(stream);
+                    if let Some(password) = &self.config.password {
+                        let mut command = Vec::new();
+                        push_command(&mut command, &[b"AUTH", password.as_bytes()]);
+                        connection.get_mut().write_all(&command)?;
+                        read_reply(&mut connection)?;
+                    }
+                    return Ok(connection);
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+        Err(error.expect("at least one address"))
+    }
+}
+
+/// Appends a command as a RESP array of bulk strings
+fn push_command(buffer: &mut Vec<u8>, args: &[&[u8]]) {
+    buffer.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        buffer.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        buffer.extend_from_slice(arg);
+        buffer.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Reads a reply, failing with [`io::ErrorKind::Other`] for error replies
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let line = line.trim_end();
+    match line.as_bytes().first() {
+        Some(b'+' | b':') => Ok(()),
+        Some(b'-') => Err(io::Error::other(format!("redis replied {}", &line[1..]))),
+        Some(b'$') => {
+            let len: i64 = line[1..]
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid bulk length"))?;
+            if len >= 0 {
+                // The string and its CRLF
+                io::copy(&mut reader.take(len as u64 + 2), &mut io::sink())?;
+            }
+            Ok(())
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unexpected reply from redis",
+        )),
+    }
+}
+
+impl WriteRecord for RedisSink {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        if trim_separator(record).is_empty() {
+            return Ok(());
+        }
+        self.push(record.to_vec(), level)
+    }
+
+    fn write_acknowledged(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.delivery
+            .acknowledged(|| self.write_record(record, level))
+    }
+}
+
+impl<'a> MakeWriter<'a> for RedisSink {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{push_command, read_reply};
+
+    #[test]
+    fn test_resp() {
+        let mut command = Vec::new();
+        push_command(&mut command, &[b"RPUSH", b"logs", b"{}"]);
+        assert_eq!(command, b"*3\r\n$5\r\nRPUSH\r\n$4\r\nlogs\r\n$2\r\n{}\r\n");
+
+        let mut replies = &b":1\r\n$15\r\n1526919030474-0\r\n-WRONGTYPE no list\r\n"[..];
+        assert!(read_reply(&mut replies).is_ok());
+        assert!(read_reply(&mut replies).is_ok());
+        assert!(read_reply(&mut replies).is_err());
+        assert!(replies.is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+//! Keeping the most recent verbose records of each task in memory, and writing them only when an
+//! error occurs, for forensic context without the volume of writing them all
+//!
+//! Records of events at the replayed level or more verbose are kept, up to a capacity, for the
+//! root span of their scope, or for events outside spans, together. When an `ERROR` event is
+//! logged in the same root span, the kept records are written before it, marked with
+//! `"replayed":true` when serialized as JSON objects. Records still kept when the root span
+//! closes are discarded. Replayed records count against the quota of their tenant, see
+//! [`Layer::with_tenant_quotas`](crate::Layer::with_tenant_quotas).
+//!
+//! The events must still be enabled by the filters of the subscriber.
+//!
+//! # Example
+//! ```
+//! # use tracing_core::Level;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::replay::ReplayBuffer;
+//! #
+//! let logger = tracing_logstash::Layer::default()
+//!     .with_replay_buffer(ReplayBuffer::new(100).with_level(Level::DEBUG));
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use tracing_core::span::Id;
+use tracing_core::{Level, Metadata};
+
+/// Records kept by the layer until an error, see the [module](self) documentation
+pub struct ReplayBuffer {
+    capacity: usize,
+    level: Level,
+    records: Mutex<HashMap<Option<Id>, VecDeque<Kept>>>,
+}
+
+/// A serialized record, without its separator, with the tenant it is written for
+pub(crate) type Kept = (&'static Metadata<'static>, Level, Option<String>, Vec<u8>);
+
+impl ReplayBuffer {
+    /// Keep up to `capacity` records per root span
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            level: Level::DEBUG,
+            records: Default::default(),
+        }
+    }
+
+    /// Keep records of events at this level or more verbose, defaults to `DEBUG`
+    pub fn with_level(self, level: Level) -> Self {
+        Self { level, ..self }
+    }
+
+    /// Whether records of events at `level` are kept rather than written
+    pub(crate) fn keeps(&self, level: Level) -> bool {
+        // More verbose levels compare greater
+        level >= self.level
+    }
+
+    pub(crate) fn keep(&self, root: Option<Id>, record: Kept) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        let kept = records.entry(root).or_default();
+        if kept.len() >= self.capacity {
+            kept.pop_front();
+        }
+        kept.push_back(record);
+    }
+
+    /// The records kept for the root span, oldest first
+    pub(crate) fn take(&self, root: &Option<Id>) -> VecDeque<Kept> {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.remove(root).unwrap_or_default()
+    }
+
+    pub(crate) fn discard(&self, root: Id) {
+        let mut records = self.records.lock().unwrap_or_else(|e| e.into_inner());
+        records.remove(&Some(root));
+    }
+}
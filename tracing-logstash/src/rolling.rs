@@ -0,0 +1,338 @@
+//! Writing records to files that are rolled when they grow too large, or daily or weekly
+//!
+//! The path of the file is a [`Template`], so `{date:...}` placeholders start a new file when
+//! the rendered path changes, for example daily. When writing a record would make the file
+//! larger than the maximum size, or with a [`Rotation`] when the first record of a new day or
+//! week is written, the file is rolled: it is renamed by appending `.1`, earlier rolled files
+//! are shifted to `.2`, `.3` and so on, and the oldest one beyond the number of files to keep is
+//! deleted. Days start at midnight at the [UTC offset](RollingFileWriter::with_utc_offset) of
+//! the writer, which also renders the `{date:...}` placeholders, and weeks on Mondays.
+//!
+//! Rolled files can be [compressed](RollingFileWriter::with_compression) with gzip, as
+//! `.1.gz` and so on, and deleted oldest first to keep the files of the current path within a
+//! [total size](RollingFileWriter::with_max_total_size). Both happen on the thread writing the
+//! record that rolls the file. Files of earlier rendered paths are not counted or deleted.
+//!
+//! The file can be synced to disk after writing records at a
+//! [level](RollingFileWriter::with_sync_on), such as `ERROR`, after the first record written once
+//! an [interval](RollingFileWriter::with_sync_interval) has passed since the last sync, and
+//! [before it is rolled](RollingFileWriter::with_sync_on_roll).
+//!
+//! Records that could not be written or synced are dropped and counted.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::rolling::RollingFileWriter;
+//! #
+//! let writer = RollingFileWriter::new("/var/log/checkout/app-{date:%Y-%m-%d}.json")
+//!     .unwrap()
+//!     .with_max_size(Some(100 * 1024 * 1024))
+//!     .with_max_files(5)
+//!     .with_compression(true)
+//!     .with_max_total_size(Some(200 * 1024 * 1024));
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::append::FileSync;
+use crate::compress::gzip;
+use crate::record::{RecordWriter, WriteRecord};
+use crate::template::Template;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::{Date, OffsetDateTime, UtcOffset};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// A writer appending records to a rolled file, see the [module](self) documentation
+///
+/// Clones share the same file.
+#[derive(Clone)]
+pub struct RollingFileWriter {
+    template: Arc<Template>,
+    max_size: Option<u64>,
+    max_files: usize,
+    max_total_size: Option<u64>,
+    rotation: Rotation,
+    utc_offset: UtcOffset,
+    compression: bool,
+    sync: FileSync,
+    sync_on_roll: bool,
+    state: Arc<Mutex<State>>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// When the file is rolled regardless of its size
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Never,
+    /// When the first record of a day is written
+    Daily,
+    /// When the first record of a week, starting on Monday, is written
+    Weekly,
+}
+
+impl Rotation {
+    /// The first day of the period including `date`
+    fn period(self, date: Date) -> Option<Date> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Daily => Some(date),
+            Rotation::Weekly => {
+                let days = date.weekday().number_days_from_monday();
+                Some(date - time::Duration::days(days.into()))
+            }
+        }
+    }
+}
+
+struct State {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// The first day of the period the file was last written in
+    period: Option<Date>,
+}
+
+impl RollingFileWriter {
+    /// A writer appending to the file at the rendered `path` template, creating the file and its
+    /// directory if needed
+    pub fn new(path: &str) -> io::Result<Self> {
+        let template =
+            Template::parse(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let path = PathBuf::from(template.render(OffsetDateTime::now_utc(), &()));
+        let (file, size) = open(&path)?;
+        Ok(Self {
+            template: Arc::new(template),
+            max_size: None,
+            max_files: 5,
+            max_total_size: None,
+            rotation: Rotation::Never,
+            utc_offset: UtcOffset::UTC,
+            compression: false,
+            sync: FileSync::default(),
+            sync_on_roll: false,
+            state: Arc::new(Mutex::new(State {
+                path,
+                file,
+                size,
+                period: None,
+            })),
+            dropped: Default::default(),
+        })
+    }
+
+    /// Roll the file before it grows beyond this many bytes, defaults to never
+    pub fn with_max_size(self, max_size: Option<u64>) -> Self {
+        Self { max_size, ..self }
+    }
+
+    /// Number of rolled files to keep, defaults to 5
+    pub fn with_max_files(self, max_files: usize) -> Self {
+        Self { max_files, ..self }
+    }
+
+    /// Delete rolled files, oldest first, so that they and the file at its maximum size take at
+    /// most this many bytes, defaults to no limit
+    pub fn with_max_total_size(self, max_total_size: Option<u64>) -> Self {
+        Self {
+            max_total_size,
+            ..self
+        }
+    }
+
+    /// Defaults to [`Rotation::Never`]
+    pub fn with_rotation(self, rotation: Rotation) -> Self {
+        Self { rotation, ..self }
+    }
+
+    /// The offset of the local time used for rotation and `{date:...}` placeholders, defaults to
+    /// UTC. Local midnight is at the offset of the local time zone, which can be read with
+    /// `UtcOffset::current_local_offset` of the `local-offset` feature of the `time` crate; the
+    /// offset is fixed, so it does not follow daylight saving time changes.
+    pub fn with_utc_offset(self, utc_offset: UtcOffset) -> Self {
+        Self { utc_offset, ..self }
+    }
+
+    /// Compress rolled files with gzip, defaults to false
+    pub fn with_compression(self, compression: bool) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// Sync the file to disk after writing a record at this level or a more severe one, defaults
+    /// to never
+    pub fn with_sync_on(mut self, level: Level) -> Self {
+        self.sync.level = Some(level);
+        self
+    }
+
+    /// Sync the file to disk after the first record written once this long has passed since the
+    /// last sync, defaults to never
+    pub fn with_sync_interval(mut self, interval: Option<Duration>) -> Self {
+        self.sync.interval = interval;
+        self
+    }
+
+    /// Sync the file to disk before it is rolled, and the compressed file after it is written,
+    /// defaults to false
+    pub fn with_sync_on_roll(self, sync_on_roll: bool) -> Self {
+        Self {
+            sync_on_roll,
+            ..self
+        }
+    }
+
+    /// Number of records dropped because they could not be written or synced
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn append(&self, record: &[u8], level: Level) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| {
+            // The record being written when the lock was poisoned was lost
+            self.state.clear_poison();
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            e.into_inner()
+        });
+        let now = OffsetDateTime::now_utc().to_offset(self.utc_offset);
+        if self.template.uses_date() {
+            let path = PathBuf::from(self.template.render(now, &()));
+            if path != state.path {
+                let (file, size) = open(&path)?;
+                *state = State {
+                    path,
+                    file,
+                    size,
+                    period: None,
+                };
+            }
+        }
+        let period = self.rotation.period(now.date());
+        if period.is_some() && state.period.is_none() && state.size > 0 {
+            // The period the existing file was last written in
+            let modified = OffsetDateTime::from(state.file.metadata()?.modified()?);
+            state.period = self
+                .rotation
+                .period(modified.to_offset(self.utc_offset).date());
+        }
+        let rotate = state.size > 0 && state.period.is_some() && state.period != period;
+        let oversize = self
+            .max_size
+            .is_some_and(|max_size| state.size > 0 && state.size + record.len() as u64 > max_size);
+        if rotate || oversize {
+            if self.sync_on_roll {
+                state.file.sync_all()?;
+            }
+            self.roll(&state.path)?;
+            state.file = open(&state.path)?.0;
+            state.size = 0;
+        }
+        state.file.write_all(record)?;
+        state.size += record.len() as u64;
+        state.period = period;
+        self.sync.after_write(&state.file, level)
+    }
+
+    fn roll(&self, path: &Path) -> io::Result<()> {
+        if self.max_files == 0 {
+            return fs::remove_file(path);
+        }
+        for gz in [false, true] {
+            ignore_not_found(fs::remove_file(rolled_path(path, self.max_files, gz)))?;
+        }
+        for n in (1..self.max_files).rev() {
+            for gz in [false, true] {
+                let from = rolled_path(path, n, gz);
+                ignore_not_found(fs::rename(from, rolled_path(path, n + 1, gz)))?;
+            }
+        }
+        let rolled = rolled_path(path, 1, false);
+        fs::rename(path, &rolled)?;
+        if self.compression {
+            let data = fs::read(&rolled)?;
+            let mut file = File::create(rolled_path(path, 1, true))?;
+            file.write_all(&gzip(&data))?;
+            if self.sync_on_roll {
+                file.sync_all()?;
+            }
+            fs::remove_file(&rolled)?;
+        }
+        if let Some(max_total_size) = self.max_total_size {
+            let mut total = self.max_size.unwrap_or(0);
+            let mut files = Vec::new();
+            for n in 1..=self.max_files {
+                for gz in [false, true] {
+                    let rolled = rolled_path(path, n, gz);
+                    if let Ok(metadata) = fs::metadata(&rolled) {
+                        total += metadata.len();
+                        files.push((rolled, metadata.len()));
+                    }
+                }
+            }
+            while total > max_total_size {
+                let Some((oldest, size)) = files.pop() else {
+                    break;
+                };
+                ignore_not_found(fs::remove_file(oldest))?;
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+/// The path of the `n`th rolled file
+fn rolled_path(path: &Path, n: usize, gz: bool) -> PathBuf {
+    let mut rolled = OsString::from(path.as_os_str());
+    rolled.push(format!(".{}", n));
+    if gz {
+        rolled.push(".gz");
+    }
+    rolled.into()
+}
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+impl WriteRecord for RollingFileWriter {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        self.append(record, level).inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for RollingFileWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
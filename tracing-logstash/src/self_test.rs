@@ -0,0 +1,118 @@
+//! Startup check that records are delivered, so deployments with a misconfigured writer fail
+//! fast instead of silently losing logs
+//!
+//! The self test record is written whatever the [maximum level](crate::Layer::with_max_level) of
+//! the layer, and is not aggregated or held for replay. The writer is flushed after writing it,
+//! and the sinks and background writers of the crate wait for it to be sent, or acknowledged by
+//! the collectors acknowledging records, so the report covers the delivery rather than queueing.
+//! [`TeeWriter`](crate::mirror::TeeWriter) and [`QuorumWriter`](crate::mirror::QuorumWriter)
+//! report the result of each of their writers.
+//!
+//! # Example
+//! ```
+//! # use tracing_subscriber::prelude::*;
+//! let logger = tracing_logstash::Layer::default();
+//! let self_test = logger.self_test();
+//! let collector = tracing_subscriber::Registry::default().with(logger);
+//! tracing::subscriber::set_global_default(collector).unwrap();
+//!
+//! let report = self_test.run().expect("self test record did not reach the layer");
+//! assert!(report.is_ok(), "log delivery failed: {:?}", report);
+//! ```
+
+use std::cell::RefCell;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub(crate) const TARGET: &str = "tracing_logstash::self_test";
+
+/// The name of each writer, and why it failed to write the record, if it did
+type WriterResults = Vec<(String, Option<String>)>;
+
+thread_local! {
+    /// The results of the writers of tee and quorum writers, while the self test record is
+    /// written on this thread
+    static WRITERS: RefCell<Option<WriterResults>> = const { RefCell::new(None) };
+}
+
+/// Restores the previous results when dropped, also when unwinding
+struct Restore(Option<WriterResults>);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        WRITERS.set(self.0.take());
+    }
+}
+
+/// Runs `f` writing the self test record, returning its result and the results of the writers
+/// reported meanwhile
+pub(crate) fn writing<R>(f: impl FnOnce() -> R) -> (R, WriterResults) {
+    let _restore = Restore(WRITERS.replace(Some(Vec::new())));
+    let result = f();
+    let writers = WRITERS.with_borrow_mut(|writers| writers.take().unwrap_or_default());
+    (result, writers)
+}
+
+/// Whether the self test record is being written on this thread
+pub(crate) fn is_writing() -> bool {
+    WRITERS.with_borrow(Option::is_some)
+}
+
+/// Reports the result of a writer of a tee or quorum writer writing the self test record
+pub(crate) fn report_writer(name: &str, result: &io::Result<()>) {
+    WRITERS.with_borrow_mut(|writers| {
+        if let Some(writers) = writers {
+            let error = result.as_ref().err().map(ToString::to_string);
+            writers.push((name.to_owned(), error));
+        }
+    });
+}
+
+/// Outcome of writing the self test record
+#[derive(Clone, Debug)]
+pub struct SelfTestReport {
+    /// Type of the event format
+    pub format: &'static str,
+    /// Type of the writer
+    pub writer: &'static str,
+    /// Type of the serializer
+    pub serializer: &'static str,
+    /// Time taken to format, write and deliver the record
+    pub latency: Duration,
+    /// Why the record was not delivered, if it wasn't
+    pub error: Option<String>,
+    /// Why each writer of a tee or quorum writer failed to write the record, if it did, by name;
+    /// empty for other writers
+    pub writers: Vec<(String, Option<String>)>,
+}
+
+impl SelfTestReport {
+    /// Whether the record was delivered, by every writer of a tee or quorum writer
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.writers.iter().all(|(_, error)| error.is_none())
+    }
+}
+
+/// Handle for running the self test of a layer, obtained from
+/// [`Layer::self_test`](crate::Layer::self_test)
+#[derive(Clone, Default)]
+pub struct SelfTest {
+    report: Arc<Mutex<Option<SelfTestReport>>>,
+}
+
+impl SelfTest {
+    /// Logs an `INFO` record with the target `tracing_logstash::self_test` through the current
+    /// subscriber, and reports how the layer delivered it. Returns `None` when the record did not
+    /// reach the layer, because it is not part of the current subscriber or the record was
+    /// filtered out by another layer or a filter.
+    pub fn run(&self) -> Option<SelfTestReport> {
+        self.report.lock().unwrap_or_else(|e| e.into_inner()).take();
+        tracing::event!(target: TARGET, tracing::Level::INFO, "logging self test");
+        self.report.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+
+    pub(crate) fn report(&self, report: SelfTestReport) {
+        *self.report.lock().unwrap_or_else(|e| e.into_inner()) = Some(report);
+    }
+}
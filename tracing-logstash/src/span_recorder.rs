@@ -1,31 +1,45 @@
 use crate::fields::{FieldConfig, FieldRecorder, FieldVisitor, RecordedValue, TryForEachField};
+use std::borrow::Cow;
 use std::sync::Arc;
+use tracing_core::callsite::Identifier;
 use tracing_core::field::Field;
 use tracing_core::span::{Attributes, Record};
 
-pub trait SpanRecorder {
+pub trait SpanRecorder: TryForEachField {
     fn record_span(&mut self, attrs: &Attributes<'_>);
     fn merge(&mut self, record: &Record<'_>);
 }
 
 pub struct DefaultSpanRecorder {
     config: Arc<FieldConfig>,
+    /// The span's callsite and the span field indices of its fields, once the span is recorded
+    indices: Option<(Identifier, Arc<[Option<usize>]>)>,
     fields: Vec<RecordedValue>,
+    serialize_values: bool,
+    /// Names of the fields never recorded
+    excluded: Arc<[&'static str]>,
+    records: u64,
 }
 
 impl SpanRecorder for DefaultSpanRecorder {
     fn record_span(&mut self, attrs: &Attributes<'_>) {
+        let metadata = attrs.metadata();
+        self.indices = Some((
+            metadata.callsite(),
+            self.config.span_field_indices(metadata),
+        ));
         attrs.record(&mut FieldVisitor::new(self))
     }
 
     fn merge(&mut self, record: &Record<'_>) {
+        self.records += 1;
         record.record(&mut FieldVisitor::new(self))
     }
 }
 
 impl TryForEachField for DefaultSpanRecorder {
-    fn try_for_each<E, F: FnMut(&'static str, &RecordedValue) -> Result<(), E>>(
-        &self,
+    fn try_for_each<'a, E, F: FnMut(&'a Cow<'static, str>, &'a RecordedValue) -> Result<(), E>>(
+        &'a self,
         mut f: F,
     ) -> Result<(), E> {
         for (name, value) in self.config.span_field_names.iter().zip(self.fields.iter()) {
@@ -37,18 +51,63 @@ impl TryForEachField for DefaultSpanRecorder {
 
 impl FieldRecorder for DefaultSpanRecorder {
     fn record_field(&mut self, field: &Field, value: impl Into<RecordedValue>) {
-        if let Some(i) = self.config.field_index(field) {
-            self.fields[i] = value.into();
+        let index = match &self.indices {
+            Some((callsite, indices)) if *callsite == field.callsite() => {
+                indices.get(field.index()).copied().flatten()
+            }
+            _ => self.config.field_index(field),
+        };
+        if let Some(i) = index {
+            if self.excluded.contains(&field.name()) {
+                return;
+            }
+            let value = self.config.span_value(i, value.into());
+            if self.config.is_span_array(i) {
+                self.fields[i].push(value);
+                return;
+            }
+            self.fields[i] = if self.serialize_values {
+                value.into_serialized()
+            } else {
+                value
+            };
         }
     }
 }
 
 impl DefaultSpanRecorder {
     pub fn from_config(config: Arc<FieldConfig>) -> Self {
-        let n = config.span_field_index.len();
         Self {
+            fields: config.initial_span_values(),
             config,
-            fields: vec![RecordedValue::Unset; n],
+            indices: None,
+            serialize_values: false,
+            excluded: Default::default(),
+            records: 0,
         }
     }
+
+    /// Never record fields with these names, so they are neither written nor passed to child
+    /// processes by [`context::to_env`](crate::context::to_env)
+    pub fn with_excluded_fields(mut self, excluded: Arc<[&'static str]>) -> Self {
+        for name in excluded.iter() {
+            if let Some(&i) = self.config.span_field_index.get(*name) {
+                self.fields[i] = RecordedValue::Unset;
+            }
+        }
+        Self { excluded, ..self }
+    }
+
+    /// Keep the values serialized as JSON, so they don't have to be serialized for every event
+    pub fn with_serialized_values(self, serialize_values: bool) -> Self {
+        Self {
+            serialize_values,
+            ..self
+        }
+    }
+
+    /// Number of times values were recorded after the span was created
+    pub(crate) fn records(&self) -> u64 {
+        self.records
+    }
 }
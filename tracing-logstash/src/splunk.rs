@@ -0,0 +1,136 @@
+use crate::format::{FormatEvent, RecordKeys, SerializeEvent};
+use crate::logstash::LogstashFormat;
+use serde::ser::SerializeMap;
+use serde::Serializer;
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Output format wrapping the records of another format in the
+/// [Splunk HTTP Event Collector](https://docs.splunk.com/Documentation/Splunk/latest/Data/FormateventsforHTTPEventCollector)
+/// event envelope, so they can be posted to a HEC endpoint as they are, such as with the
+/// `hec::SplunkHecSink` of the `hec` feature
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::splunk::SplunkHecFormat::new(
+///         tracing_logstash::logstash::LogstashFormat::default(),
+///     )
+///     .with_source("checkout")
+///     .with_index("main"),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct SplunkHecFormat<E = LogstashFormat> {
+    host: String,
+    source: Option<String>,
+    sourcetype: String,
+    index: Option<String>,
+    event_format: E,
+}
+
+impl Default for SplunkHecFormat {
+    fn default() -> Self {
+        Self::new(LogstashFormat::default())
+    }
+}
+
+impl<E> SplunkHecFormat<E> {
+    pub fn new(event_format: E) -> Self {
+        Self {
+            host: crate::host::hostname(),
+            source: None,
+            sourcetype: "_json".to_owned(),
+            index: None,
+            event_format,
+        }
+    }
+
+    /// Name of the host sending the event, defaults to the name of this host
+    pub fn with_host(self, host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            ..self
+        }
+    }
+    pub fn with_source(self, source: impl Into<String>) -> Self {
+        Self {
+            source: Some(source.into()),
+            ..self
+        }
+    }
+    /// Source type of the event, defaults to `_json`
+    pub fn with_sourcetype(self, sourcetype: impl Into<String>) -> Self {
+        Self {
+            sourcetype: sourcetype.into(),
+            ..self
+        }
+    }
+    /// Index to store the event in, defaults to the index configured for the HEC token
+    pub fn with_index(self, index: impl Into<String>) -> Self {
+        Self {
+            index: Some(index.into()),
+            ..self
+        }
+    }
+}
+
+impl<E: FormatEvent> FormatEvent for SplunkHecFormat<E> {
+    type R = E::R;
+
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        self.event_format.event_level(event)
+    }
+
+    fn span_recorder(&self) -> Self::R {
+        self.event_format.span_recorder()
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        // The event is replaced as a whole
+        Some(RecordKeys {
+            message: "event",
+            kept: &["time", "host", "source", "sourcetype", "index"],
+        })
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        let time = time::OffsetDateTime::now_utc().unix_timestamp_nanos() / 1_000_000;
+
+        let mut s = serializer.serialize_map(None)?;
+        s.serialize_entry("time", &(time as f64 / 1000.0))?;
+        s.serialize_entry("host", &self.host)?;
+        if let Some(source) = &self.source {
+            s.serialize_entry("source", source)?;
+        }
+        s.serialize_entry("sourcetype", &self.sourcetype)?;
+        if let Some(index) = &self.index {
+            s.serialize_entry("index", index)?;
+        }
+        s.serialize_entry(
+            "event",
+            &SerializeEvent(&self.event_format, event, level, ctx),
+        )?;
+        s.end()
+    }
+}
@@ -0,0 +1,158 @@
+//! Configuration of the stack trace written by
+//! [`LogstashFormat`](crate::logstash::LogstashFormat), built with names rather than as a pair
+//! of filters, and adjustable at runtime through a [`StackTraceHandle`]
+//!
+//! # Example
+//! ```
+//! # use tracing_core::Level;
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::stack_trace::{StackTraceConfig, StackTraceHandle};
+//! #
+//! let config = StackTraceConfig::builder()
+//!     .when_event(Level::ERROR)
+//!     .include_spans(Level::DEBUG)
+//!     .max_frames(50)
+//!     .build()
+//!     .unwrap();
+//! let stack_trace = StackTraceHandle::new(Some(config));
+//!
+//! let logger = tracing_logstash::Layer::default().event_format(
+//!     tracing_logstash::logstash::LogstashFormat::default()
+//!         .with_stack_trace_handle(Some(stack_trace.clone())),
+//! );
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//!
+//! // During an incident
+//! stack_trace.set(Some(
+//!     StackTraceConfig::builder().when_event(Level::WARN).build().unwrap(),
+//! ));
+//! ```
+
+use crate::DisplayLevelFilter;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, RwLock};
+
+/// When to write the stack trace, and which spans it includes
+#[derive(Copy, Clone)]
+pub struct StackTraceConfig {
+    pub(crate) event_filter: DisplayLevelFilter,
+    pub(crate) span_filter: DisplayLevelFilter,
+    pub(crate) max_frames: Option<usize>,
+}
+
+impl StackTraceConfig {
+    /// The stack trace of every event, including all spans
+    pub const ALL: StackTraceConfig = StackTraceConfig {
+        event_filter: DisplayLevelFilter::All,
+        span_filter: DisplayLevelFilter::All,
+        max_frames: None,
+    };
+
+    pub fn builder() -> StackTraceConfigBuilder {
+        StackTraceConfigBuilder {
+            event_filter: None,
+            span_filter: DisplayLevelFilter::All,
+            max_frames: None,
+        }
+    }
+
+    /// The configuration equivalent to the `(event_filter, span_filter)` pair of
+    /// [`with_stack_trace`](crate::logstash::LogstashFormat::with_stack_trace)
+    pub fn from_filters(event_filter: DisplayLevelFilter, span_filter: DisplayLevelFilter) -> Self {
+        Self {
+            event_filter,
+            span_filter,
+            max_frames: None,
+        }
+    }
+}
+
+/// Builder for a [`StackTraceConfig`]
+pub struct StackTraceConfigBuilder {
+    event_filter: Option<DisplayLevelFilter>,
+    span_filter: DisplayLevelFilter,
+    max_frames: Option<usize>,
+}
+
+impl StackTraceConfigBuilder {
+    /// Write the stack trace of events at this level or more severe. Required.
+    pub fn when_event(self, event_filter: impl Into<DisplayLevelFilter>) -> Self {
+        Self {
+            event_filter: Some(event_filter.into()),
+            ..self
+        }
+    }
+
+    /// Include the spans at this level or more severe, defaults to all spans
+    pub fn include_spans(self, span_filter: impl Into<DisplayLevelFilter>) -> Self {
+        Self {
+            span_filter: span_filter.into(),
+            ..self
+        }
+    }
+
+    /// Keep only the innermost frames, the event and the spans closest to it
+    pub fn max_frames(self, max_frames: usize) -> Self {
+        Self {
+            max_frames: Some(max_frames),
+            ..self
+        }
+    }
+
+    pub fn build(self) -> Result<StackTraceConfig, InvalidStackTraceConfig> {
+        let event_filter = match self.event_filter {
+            None => return Err(InvalidStackTraceConfig("when_event is not set")),
+            Some(DisplayLevelFilter::Event) => {
+                return Err(InvalidStackTraceConfig(
+                    "when_event must not depend on the level of the event itself",
+                ))
+            }
+            Some(event_filter) => event_filter,
+        };
+        if self.max_frames == Some(0) {
+            return Err(InvalidStackTraceConfig("max_frames must be at least 1"));
+        }
+        Ok(StackTraceConfig {
+            event_filter,
+            span_filter: self.span_filter,
+            max_frames: self.max_frames,
+        })
+    }
+}
+
+/// A [`StackTraceConfigBuilder`] built with conflicting or missing settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidStackTraceConfig(&'static str);
+
+impl Display for InvalidStackTraceConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid stack trace config: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidStackTraceConfig {}
+
+/// Runtime handle for the stack trace configuration of a format
+///
+/// Cloned handles share the same configuration.
+#[derive(Clone, Default)]
+pub struct StackTraceHandle {
+    config: Arc<RwLock<Option<StackTraceConfig>>>,
+}
+
+impl StackTraceHandle {
+    pub fn new(config: Option<StackTraceConfig>) -> Self {
+        Self {
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Option<StackTraceConfig> {
+        *self.config.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Replaces the configuration, or disables stack traces with `None`
+    pub fn set(&self, config: Option<StackTraceConfig>) {
+        *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+    }
+}
@@ -0,0 +1,627 @@
+use crate::fields::{FieldConfig, FieldSpec};
+use crate::format::{FormatEvent, MakeSerializer, RecordKeys};
+use crate::logstash::LevelOverride;
+use crate::record::{RecordWriter, WriteRecord};
+use crate::span_recorder::DefaultSpanRecorder;
+use crate::text::TextFields;
+use crate::trim_separator;
+use serde::Serializer;
+use std::fmt::Write as _;
+use std::io::{self, Write as _};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing_core::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Syslog facility, as defined in RFC 5424
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Facility {
+    Kern = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Lpr = 6,
+    News = 7,
+    Uucp = 8,
+    Cron = 9,
+    AuthPriv = 10,
+    Ftp = 11,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Converts a `Level` to a syslog severity
+pub(crate) const fn syslog_severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+/// Output format for [RFC 5424](https://www.rfc-editor.org/rfc/rfc5424) syslog messages
+///
+/// Constants, event fields and recorded span fields are written as the parameters of a single
+/// structured data element, by default `fields@32473`.
+///
+/// When used with a JSON serializer, as when wrapped by other formats, the message is serialized
+/// as a string.
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::syslog::{Facility, SyslogFormat};
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     SyslogFormat::default()
+///         .with_facility(Facility::Local0)
+///         .with_app_name("checkout"),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct SyslogFormat {
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    sd_id: String,
+    span_fields: Arc<FieldConfig>,
+    excluded_fields: Arc<[&'static str]>,
+    constants: Vec<(&'static str, String)>,
+    level_override: Option<LevelOverride>,
+}
+
+impl Default for SyslogFormat {
+    fn default() -> Self {
+        Self {
+            facility: Facility::User,
+            hostname: crate::host::hostname(),
+            app_name: default_app_name(),
+            sd_id: "fields@32473".to_owned(),
+            span_fields: Default::default(),
+            excluded_fields: Default::default(),
+            constants: Default::default(),
+            level_override: None,
+        }
+    }
+}
+
+impl SyslogFormat {
+    /// Facility used for the PRI of all messages, defaults to [`Facility::User`]
+    pub fn with_facility(self, facility: Facility) -> Self {
+        Self { facility, ..self }
+    }
+    /// Defaults to the name of this host
+    pub fn with_hostname(self, hostname: &str) -> Self {
+        Self {
+            hostname: header_field(hostname, 255),
+            ..self
+        }
+    }
+    /// Defaults to the name of the executable
+    pub fn with_app_name(self, app_name: &str) -> Self {
+        Self {
+            app_name: header_field(app_name, 48),
+            ..self
+        }
+    }
+    /// Id of the structured data element holding the fields. Ids other than the IANA registered
+    /// ones must be of the form `name@<private enterprise number>`.
+    pub fn with_sd_id(self, sd_id: &str) -> Self {
+        Self {
+            sd_id: sd_name(sd_id),
+            ..self
+        }
+    }
+    pub fn with_span_fields(self, span_fields: Vec<FieldSpec>) -> Self {
+        Self {
+            span_fields: Arc::new(FieldConfig::new(span_fields)),
+            ..self
+        }
+    }
+    /// Never write or record fields with these names, see
+    /// [`LogstashFormat::with_excluded_fields`](crate::logstash::LogstashFormat::with_excluded_fields)
+    pub fn with_excluded_fields(self, excluded_fields: Vec<&'static str>) -> Self {
+        Self {
+            excluded_fields: excluded_fields.into(),
+            ..self
+        }
+    }
+    pub fn with_constants(self, constants: Vec<(&'static str, String)>) -> Self {
+        Self { constants, ..self }
+    }
+    /// Let an event field override the severity of the PRI, see [`LevelOverride`]
+    pub fn with_level_override(self, level_override: Option<LevelOverride>) -> Self {
+        Self {
+            level_override,
+            ..self
+        }
+    }
+
+    fn format<SS>(&self, event: &Event<'_>, level: Level, ctx: &Context<'_, SS>) -> String
+    where
+        SS: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut fields = TextFields::excluding(&self.excluded_fields);
+        for (key, value) in &self.constants {
+            fields.add(*key, value);
+        }
+        fields.add_event(event, ctx);
+
+        let pri = self.facility as u8 * 8 + syslog_severity(&level);
+        let timestamp = timestamp(time::OffsetDateTime::now_utc());
+
+        let mut message = String::with_capacity(256);
+        let _ = write!(
+            message,
+            "<{}>1 {} {} {} {} - ",
+            pri,
+            timestamp,
+            self.hostname,
+            self.app_name,
+            std::process::id()
+        );
+        if fields.fields.is_empty() {
+            message.push('-');
+        } else {
+            message.push('[');
+            message.push_str(&self.sd_id);
+            for (name, value) in &fields.fields {
+                let _ = write!(message, " {}=\"", sd_name(name));
+                escape_param_value(&mut message, value);
+                message.push('"');
+            }
+            message.push(']');
+        }
+        if let Some(msg) = fields.message {
+            // Receivers framing records by newlines would split multi-line messages
+            message.push(' ');
+            escape_newlines(&mut message, &msg);
+        }
+        message
+    }
+}
+
+fn default_app_name() -> String {
+    let app_name = std::env::current_exe()
+        .ok()
+        .and_then(|exe| {
+            exe.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| "-".to_owned());
+    header_field(&app_name, 48)
+}
+
+/// Header fields are printable US-ASCII, with `-` meaning no value
+/// The RFC 5424 TIMESTAMP of `now` in UTC, with microseconds; the RFC allows at most six
+/// fractional digits
+fn timestamp(now: time::OffsetDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.microsecond()
+    )
+}
+
+fn header_field(value: &str, max_length: usize) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_length)
+        .collect();
+    if value.is_empty() {
+        "-".to_owned()
+    } else {
+        value
+    }
+}
+
+/// SD-IDs and parameter names are up to 32 printable US-ASCII characters, except `=`, ` `, `]`
+/// and `"`
+fn sd_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(32)
+        .collect()
+}
+
+fn escape_param_value(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | ']' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Writes line breaks as `\n` and `\r`, keeping the record on one line
+fn escape_newlines(out: &mut String, value: &str) {
+    for c in value.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            c => out.push(c),
+        }
+    }
+}
+
+impl FormatEvent for SyslogFormat {
+    type R = DefaultSpanRecorder;
+
+    fn event_level(&self, event: &Event<'_>) -> Level {
+        self.level_override
+            .as_ref()
+            .and_then(|level_override| level_override.level(event))
+            .unwrap_or(*event.metadata().level())
+    }
+
+    fn span_recorder(&self) -> Self::R {
+        DefaultSpanRecorder::from_config(self.span_fields.clone())
+            .with_excluded_fields(self.excluded_fields.clone())
+    }
+
+    fn record_keys(&self) -> Option<RecordKeys> {
+        None
+    }
+
+    fn format_event<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        self.format_event_at(serializer, event, self.event_level(event), ctx)
+    }
+
+    fn format_event_at<S: Serializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        serializer: S,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.format(event, level, &ctx))
+    }
+
+    fn write_event<M: MakeSerializer, SS: Subscriber + for<'a> LookupSpan<'a>>(
+        &self,
+        _make_serializer: &M,
+        buffer: &mut Vec<u8>,
+        event: &Event<'_>,
+        level: Level,
+        ctx: Context<'_, SS>,
+    ) -> std::io::Result<()> {
+        buffer.write_all(self.format(event, level, &ctx).as_bytes())
+    }
+}
+
+/// The header a [`SyslogWriter`] writes before each record
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SyslogHeader {
+    /// `<PRI>1 TIMESTAMP HOSTNAME APP-NAME PROCID - -`, as received by daemons over the network
+    Rfc5424,
+    /// `<PRI>Mmm dd hh:mm:ss APP-NAME[PROCID]:`, as written to the local socket by the C library,
+    /// with the time in UTC
+    Rfc3164,
+    /// No header, for records already formatted as syslog messages, such as by
+    /// [`SyslogFormat`]
+    None,
+}
+
+/// A writer delivering records to a syslog daemon, over UDP, TCP or the local socket
+///
+/// Records are prefixed with a [`SyslogHeader`], with the PRI computed from the facility and the
+/// level of the event. Over UDP and the local socket each record is sent as one datagram; over
+/// TCP records are framed with their length, as described in RFC 6587, and the connection is
+/// reopened once when writing fails. Records that could not be sent are dropped and counted.
+///
+/// Clones share the same socket.
+///
+/// # Example
+/// ```no_run
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::syslog::{Facility, SyslogWriter};
+/// #
+/// let writer = SyslogWriter::local()
+///     .unwrap()
+///     .with_facility(Facility::Local0)
+///     .with_app_name("checkout");
+///
+/// let logger = tracing_logstash::Layer::default().with_writer(writer);
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct SyslogWriter {
+    transport: Arc<Transport>,
+    header: SyslogHeader,
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    dropped: Arc<AtomicU64>,
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp {
+        addrs: Vec<SocketAddr>,
+        timeout: Duration,
+        stream: Mutex<Option<TcpStream>>,
+    },
+    #[cfg(unix)]
+    Unix(UnixDatagram),
+}
+
+impl SyslogWriter {
+    fn new(transport: Transport, header: SyslogHeader) -> Self {
+        Self {
+            transport: Arc::new(transport),
+            header,
+            facility: Facility::User,
+            hostname: crate::host::hostname(),
+            app_name: default_app_name(),
+            dropped: Default::default(),
+        }
+    }
+
+    /// A writer sending datagrams to the daemon at `addr`, usually port 514, which is resolved
+    /// once
+    pub fn udp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+        })?;
+        let local_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(addr)?;
+        Ok(Self::new(Transport::Udp(socket), SyslogHeader::Rfc5424))
+    }
+
+    /// A writer connecting to the daemon at `addr`, which is resolved once. The connection is
+    /// opened when the first record is written.
+    pub fn tcp(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addrs = addr.to_socket_addrs()?.collect::<Vec<_>>();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "address resolved to nothing",
+            ));
+        }
+        Ok(Self::new(
+            Transport::Tcp {
+                addrs,
+                timeout: Duration::from_secs(10),
+                stream: Mutex::new(None),
+            },
+            SyslogHeader::Rfc5424,
+        ))
+    }
+
+    /// A writer sending datagrams to the local daemon, at `/dev/log` or on macOS
+    /// `/var/run/syslog`
+    #[cfg(unix)]
+    pub fn local() -> io::Result<Self> {
+        Self::unix("/dev/log").or_else(|e| Self::unix("/var/run/syslog").map_err(|_| e))
+    }
+
+    /// A writer sending datagrams to the daemon listening on the Unix socket at `path`
+    #[cfg(unix)]
+    pub fn unix(path: impl AsRef<Path>) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self::new(Transport::Unix(socket), SyslogHeader::Rfc3164))
+    }
+
+    /// Defaults to [`SyslogHeader::Rfc5424`] over the network, and [`SyslogHeader::Rfc3164`]
+    /// over the local socket
+    pub fn with_header(self, header: SyslogHeader) -> Self {
+        Self { header, ..self }
+    }
+
+    /// Facility used for the PRI of all records, defaults to [`Facility::User`]
+    pub fn with_facility(self, facility: Facility) -> Self {
+        Self { facility, ..self }
+    }
+
+    /// Defaults to the name of this host
+    pub fn with_hostname(self, hostname: &str) -> Self {
+        Self {
+            hostname: header_field(hostname, 255),
+            ..self
+        }
+    }
+
+    /// Defaults to the name of the executable
+    pub fn with_app_name(self, app_name: &str) -> Self {
+        Self {
+            app_name: header_field(app_name, 48),
+            ..self
+        }
+    }
+
+    /// Timeout for connecting and writing over TCP, defaults to 10 seconds
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        let Transport::Tcp { addrs, .. } = &*self.transport else {
+            return self;
+        };
+        let transport = Transport::Tcp {
+            addrs: addrs.clone(),
+            timeout,
+            stream: Mutex::new(None),
+        };
+        Self {
+            transport: Arc::new(transport),
+            ..self
+        }
+    }
+
+    /// Number of records dropped because they could not be sent
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn header(&self, level: &Level) -> String {
+        let pri = self.facility as u8 * 8 + syslog_severity(level);
+        let now = time::OffsetDateTime::now_utc();
+        match self.header {
+            SyslogHeader::Rfc5424 => format!(
+                "<{}>1 {} {} {} {} - - ",
+                pri,
+                timestamp(now),
+                self.hostname,
+                self.app_name,
+                std::process::id()
+            ),
+            SyslogHeader::Rfc3164 => {
+                const MONTHS: [&str; 12] = [
+                    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov",
+                    "Dec",
+                ];
+                format!(
+                    "<{}>{} {:2} {:02}:{:02}:{:02} {}[{}]: ",
+                    pri,
+                    MONTHS[now.month() as usize - 1],
+                    now.day(),
+                    now.hour(),
+                    now.minute(),
+                    now.second(),
+                    self.app_name,
+                    std::process::id()
+                )
+            }
+            SyslogHeader::None => String::new(),
+        }
+    }
+
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        match &*self.transport {
+            Transport::Udp(socket) => socket.send(message).map(|_| ()),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(message).map(|_| ()),
+            Transport::Tcp {
+                addrs,
+                timeout,
+                stream,
+            } => {
+                let mut frame = format!("{} ", message.len()).into_bytes();
+                frame.extend_from_slice(message);
+                let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(connected) = stream.as_mut() {
+                    if connected.write_all(&frame).is_ok() {
+                        return Ok(());
+                    }
+                }
+                *stream = None;
+                let mut connected = connect(addrs, *timeout)?;
+                connected.write_all(&frame)?;
+                *stream = Some(connected);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn connect(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TcpStream> {
+    let mut error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.expect("at least one address"))
+}
+
+impl WriteRecord for SyslogWriter {
+    fn write_record(&self, record: &[u8], level: Level) -> io::Result<()> {
+        let record = trim_separator(record);
+        if record.is_empty() {
+            return Ok(());
+        }
+        let mut message = self.header(&level).into_bytes();
+        message.extend_from_slice(record);
+        self.send(&message).inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    /// A writer with the severity of `INFO`, as the level of the event is unknown
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escape_newlines, escape_param_value, header_field, sd_name, timestamp};
+
+    #[test]
+    fn test_escaping() {
+        let mut escaped = String::new();
+        escape_param_value(&mut escaped, r#"a "b" [c] \d"#);
+        assert_eq!(escaped, r#"a \"b\" [c\] \\d"#);
+        let mut escaped = String::new();
+        escape_newlines(&mut escaped, "first\r\nsecond\n");
+        assert_eq!(escaped, r"first\r\nsecond\n");
+        assert_eq!(sd_name("a b=c\"d]"), "a_b_c_d_");
+        assert_eq!(sd_name(&"x".repeat(40)).len(), 32);
+        assert_eq!(header_field("my app", 48), "myapp");
+        assert_eq!(header_field("", 48), "-");
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let now =
+            time::OffsetDateTime::from_unix_timestamp_nanos(1_700_000_000_123_456_789).unwrap();
+        assert_eq!(timestamp(now), "2023-11-14T22:13:20.123456Z");
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Interest, LevelFilter, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Filter};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Runtime handle for the ids enabled in a [`TargetedDebugFilter`]
+///
+/// Cloned handles share the same set of ids.
+#[derive(Clone, Default)]
+pub struct DebugTargets {
+    ids: Arc<RwLock<HashMap<String, Instant>>>,
+}
+
+impl DebugTargets {
+    /// Capture all events in the scope of spans with this id, for the duration of `ttl`
+    pub fn enable(&self, id: impl Into<String>, ttl: Duration) {
+        let now = Instant::now();
+        let mut ids = self.ids.write().unwrap_or_else(|e| e.into_inner());
+        ids.retain(|_, expires| *expires > now);
+        ids.insert(id.into(), now + ttl);
+    }
+
+    pub fn disable(&self, id: &str) {
+        let mut ids = self.ids.write().unwrap_or_else(|e| e.into_inner());
+        ids.remove(id);
+    }
+
+    pub fn is_enabled(&self, id: &str) -> bool {
+        let ids = self.ids.read().unwrap_or_else(|e| e.into_inner());
+        ids.get(id).is_some_and(|expires| *expires > Instant::now())
+    }
+
+    fn is_empty(&self) -> bool {
+        let ids = self.ids.read().unwrap_or_else(|e| e.into_inner());
+        ids.is_empty()
+    }
+}
+
+/// A per-layer filter that enables everything its inner filter enables, plus all spans and
+/// events in the scope of a span whose id field holds an id enabled in [`DebugTargets`]
+///
+/// # Example
+/// ```
+/// # use std::time::Duration;
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_subscriber::filter::LevelFilter;
+/// # use tracing_logstash::targeted_debug::{DebugTargets, TargetedDebugFilter};
+/// #
+/// let targets = DebugTargets::default();
+/// let logger = tracing_logstash::Layer::default().with_filter(TargetedDebugFilter::new(
+///     LevelFilter::INFO,
+///     "correlation_id",
+///     targets.clone(),
+/// ));
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+///
+/// // Later, e.g. from an admin endpoint
+/// targets.enable("3f2c9a", Duration::from_secs(600));
+/// ```
+pub struct TargetedDebugFilter<F> {
+    inner: F,
+    id_field: &'static str,
+    targets: DebugTargets,
+}
+
+impl<F> TargetedDebugFilter<F> {
+    pub fn new(inner: F, id_field: &'static str, targets: DebugTargets) -> Self {
+        Self {
+            inner,
+            id_field,
+            targets,
+        }
+    }
+
+    fn in_targeted_scope<S>(&self, cx: &Context<'_, S>) -> bool
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if self.targets.is_empty() {
+            return false;
+        }
+        let Some(current) = cx.lookup_current() else {
+            return false;
+        };
+        current.scope().any(|span| {
+            span.extensions()
+                .get::<TargetedDebugId>()
+                .is_some_and(|id| self.targets.is_enabled(&id.0))
+        })
+    }
+}
+
+impl<S, F> Filter<S> for TargetedDebugFilter<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    F: Filter<S>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        self.inner.enabled(meta, cx) || self.in_targeted_scope(cx)
+    }
+
+    fn callsite_enabled(&self, meta: &'static Metadata<'static>) -> Interest {
+        let interest = self.inner.callsite_enabled(meta);
+        if interest.is_always() {
+            interest
+        } else {
+            Interest::sometimes()
+        }
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        None
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = IdVisitor(self.id_field, None);
+        attrs.record(&mut visitor);
+        if let (Some(span), Some(value)) = (ctx.span(id), visitor.1) {
+            span.extensions_mut().replace(TargetedDebugId(value));
+        }
+        self.inner.on_new_span(attrs, id, ctx)
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = IdVisitor(self.id_field, None);
+        values.record(&mut visitor);
+        if let (Some(span), Some(value)) = (ctx.span(id), visitor.1) {
+            span.extensions_mut().replace(TargetedDebugId(value));
+        }
+        self.inner.on_record(id, values, ctx)
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_enter(id, ctx)
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        self.inner.on_exit(id, ctx)
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        self.inner.on_close(id, ctx)
+    }
+}
+
+/// The value of the id field of a span, stored in the span's extensions
+struct TargetedDebugId(String);
+
+struct IdVisitor(&'static str, Option<String>);
+
+impl Visit for IdVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.0 {
+            self.1 = Some(value.to_owned());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == self.0 {
+            self.1 = Some(format!("{:?}", value));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::DebugTargets;
+    use std::time::Duration;
+
+    #[test]
+    fn test_debug_targets() {
+        let targets = DebugTargets::default();
+        targets.enable("a", Duration::from_secs(60));
+        targets.enable("b", Duration::ZERO);
+        assert!(targets.is_enabled("a"));
+        assert!(!targets.is_enabled("b"));
+        targets.disable("a");
+        assert!(!targets.is_enabled("a"));
+    }
+}
@@ -0,0 +1,277 @@
+//! Templates for naming log destinations, such as files and indices
+//!
+//! A template is literal text with placeholders in braces:
+//!
+//! * `{date:FORMAT}` formats the time using `strftime`-style conversions. Supported conversions
+//!   are `%Y` (year), `%m` (month), `%d` (day), `%H` (hour), `%M` (minute), `%S` (second),
+//!   `%j` (day of year), `%G` (ISO week-based year), `%V` (ISO week) and `%%`.
+//! * `{field:NAME}` inserts the value of a field. The value can be transformed by appending
+//!   filters: `|lowercase`, `|uppercase` and `|default:VALUE`, used when the field is missing.
+//!
+//! # Example
+//! ```
+//! # use tracing_logstash::template::Template;
+//! # use time::macros::datetime;
+//! #
+//! let template = Template::parse("logs-{field:service.name|lowercase}-{date:%Y.%m.%d}").unwrap();
+//! let fields = [("service.name", "Billing".to_owned())];
+//!
+//! let name = template.render(datetime!(2024-01-08 12:00 UTC), &fields[..]);
+//! assert_eq!(name, "logs-billing-2024.01.08");
+//! ```
+
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+use std::fmt::{self, Write as _};
+use time::OffsetDateTime;
+
+/// A parsed template
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Date(Vec<DatePart>),
+    Field(String, Vec<Filter>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum DatePart {
+    Literal(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    DayOfYear,
+    IsoYear,
+    IsoWeek,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Filter {
+    Lowercase,
+    Uppercase,
+    Default(String),
+}
+
+/// Error returned when a template can't be parsed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateError(String);
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid template: {}", self.0)
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Source of field values for `{field:NAME}` placeholders
+pub trait TemplateFields {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>>;
+}
+
+impl TemplateFields for () {
+    fn field(&self, _name: &str) -> Option<Cow<'_, str>> {
+        None
+    }
+}
+
+impl<K: AsRef<str>> TemplateFields for [(K, String)] {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>> {
+        self.iter()
+            .find(|(k, _)| k.as_ref() == name)
+            .map(|(_, v)| Cow::Borrowed(v.as_str()))
+    }
+}
+
+impl TemplateFields for Map<String, Value> {
+    fn field(&self, name: &str) -> Option<Cow<'_, str>> {
+        match self.get(name)? {
+            Value::String(s) => Some(Cow::Borrowed(s)),
+            Value::Null => None,
+            value => Some(Cow::Owned(value.to_string())),
+        }
+    }
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, TemplateError> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_owned()));
+            }
+            let len = rest[start..]
+                .find('}')
+                .ok_or_else(|| TemplateError(format!("unclosed placeholder in {:?}", template)))?;
+            parts.push(parse_placeholder(&rest[start + 1..start + len])?);
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_owned()));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Whether the rendered value depends on the time
+    pub fn uses_date(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Date(_)))
+    }
+
+    /// Whether the rendered value depends on fields
+    pub fn uses_fields(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Field(_, _)))
+    }
+
+    pub fn render<F: TemplateFields + ?Sized>(&self, time: OffsetDateTime, fields: &F) -> String {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => rendered.push_str(s),
+                Part::Date(date_parts) => render_date(&mut rendered, time, date_parts),
+                Part::Field(name, filters) => {
+                    let mut value = fields.field(name);
+                    for filter in filters {
+                        value = match (filter, value) {
+                            (Filter::Lowercase, Some(v)) => Some(Cow::Owned(v.to_lowercase())),
+                            (Filter::Uppercase, Some(v)) => Some(Cow::Owned(v.to_uppercase())),
+                            (Filter::Default(default), None) => Some(Cow::Borrowed(default)),
+                            (_, v) => v,
+                        }
+                    }
+                    if let Some(value) = value {
+                        rendered.push_str(&value);
+                    }
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, TemplateError> {
+    match placeholder.split_once(':') {
+        Some(("date", format)) => parse_date(format).map(Part::Date),
+        Some(("field", field)) => {
+            let mut filters = field.split('|');
+            let name = filters.next().unwrap_or_default();
+            if name.is_empty() {
+                return Err(TemplateError("missing field name".to_owned()));
+            }
+            let filters = filters
+                .map(|filter| match filter.split_once(':') {
+                    None if filter == "lowercase" => Ok(Filter::Lowercase),
+                    None if filter == "uppercase" => Ok(Filter::Uppercase),
+                    Some(("default", default)) => Ok(Filter::Default(default.to_owned())),
+                    _ => Err(TemplateError(format!("unknown filter {:?}", filter))),
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(Part::Field(name.to_owned(), filters))
+        }
+        _ => Err(TemplateError(format!(
+            "unknown placeholder {{{}}}",
+            placeholder
+        ))),
+    }
+}
+
+fn parse_date(format: &str) -> Result<Vec<DatePart>, TemplateError> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let part = match chars.next() {
+            Some('%') => {
+                literal.push('%');
+                continue;
+            }
+            Some('Y') => DatePart::Year,
+            Some('m') => DatePart::Month,
+            Some('d') => DatePart::Day,
+            Some('H') => DatePart::Hour,
+            Some('M') => DatePart::Minute,
+            Some('S') => DatePart::Second,
+            Some('j') => DatePart::DayOfYear,
+            Some('G') => DatePart::IsoYear,
+            Some('V') => DatePart::IsoWeek,
+            Some(c) => return Err(TemplateError(format!("unknown date conversion %{}", c))),
+            None => return Err(TemplateError("trailing % in date format".to_owned())),
+        };
+        if !literal.is_empty() {
+            parts.push(DatePart::Literal(std::mem::take(&mut literal)));
+        }
+        parts.push(part);
+    }
+    if !literal.is_empty() {
+        parts.push(DatePart::Literal(literal));
+    }
+    Ok(parts)
+}
+
+fn render_date(rendered: &mut String, time: OffsetDateTime, parts: &[DatePart]) {
+    for part in parts {
+        let _ = match part {
+            DatePart::Literal(s) => rendered.write_str(s),
+            DatePart::Year => write!(rendered, "{:04}", time.year()),
+            DatePart::Month => write!(rendered, "{:02}", time.month() as u8),
+            DatePart::Day => write!(rendered, "{:02}", time.day()),
+            DatePart::Hour => write!(rendered, "{:02}", time.hour()),
+            DatePart::Minute => write!(rendered, "{:02}", time.minute()),
+            DatePart::Second => write!(rendered, "{:02}", time.second()),
+            DatePart::DayOfYear => write!(rendered, "{:03}", time.ordinal()),
+            DatePart::IsoYear => write!(rendered, "{:04}", time.to_iso_week_date().0),
+            DatePart::IsoWeek => write!(rendered, "{:02}", time.iso_week()),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Template, TemplateError};
+    use serde_json::json;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_render_date() {
+        let template = Template::parse("app-{date:%G-W%V}-{date:%j %H:%M:%S %%}.log").unwrap();
+        assert!(template.uses_date());
+        assert_eq!(
+            template.render(datetime!(2021-01-03 04:05:06 UTC), &()),
+            "app-2020-W53-003 04:05:06 %.log"
+        );
+    }
+
+    #[test]
+    fn test_render_fields() {
+        let template =
+            Template::parse("{field:a|uppercase}/{field:b|default:none}/{field:c}").unwrap();
+        assert!(!template.uses_date());
+        let fields = json!({ "a": "x", "c": 42 });
+        assert_eq!(
+            template.render(
+                datetime!(2021-01-03 04:05:06 UTC),
+                fields.as_object().unwrap()
+            ),
+            "X/none/42"
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(Template::parse("{date:%Y"), Err(TemplateError(_))));
+        assert!(Template::parse("{date:%Q}").is_err());
+        assert!(Template::parse("{field:a|camelcase}").is_err());
+        assert!(Template::parse("{env:HOME}").is_err());
+    }
+}
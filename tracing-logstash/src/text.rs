@@ -0,0 +1,89 @@
+use crate::fields::TryForEachField;
+use crate::logstash::LogFieldReceiver;
+use crate::span_recorder::DefaultSpanRecorder;
+use serde::Serialize;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use tracing_core::field::{Field, Visit};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// The message and fields of an event as text, in the order they should be written. Only the
+/// first value of each field name is kept.
+#[derive(Default)]
+pub(crate) struct TextFields {
+    pub(crate) message: Option<String>,
+    pub(crate) fields: Vec<(Cow<'static, str>, String)>,
+    seen: HashSet<Cow<'static, str>>,
+}
+
+impl TextFields {
+    /// Fields skipping those with the `excluded` names, as if already added
+    pub(crate) fn excluding(excluded: &[&'static str]) -> Self {
+        Self {
+            seen: excluded.iter().map(|name| Cow::Borrowed(*name)).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn add(&mut self, name: impl Into<Cow<'static, str>>, value: impl Into<String>) {
+        let name = name.into();
+        if self.seen.insert(name.clone()) {
+            self.fields.push((name, value.into()));
+        }
+    }
+
+    /// Adds the fields of the event, followed by the recorded fields of the spans in its scope
+    pub(crate) fn add_event<SS>(&mut self, event: &Event<'_>, ctx: &Context<'_, SS>)
+    where
+        SS: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        event.record(self);
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope {
+                if let Some(span_fields) = span.extensions().get::<DefaultSpanRecorder>() {
+                    let _ = span_fields.try_for_each::<(), _>(|name, value| {
+                        if let Some(value) = value.to_text() {
+                            self.add(name.clone(), value);
+                        }
+                        Ok(())
+                    });
+                }
+            }
+        }
+    }
+}
+
+impl LogFieldReceiver for TextFields {
+    fn add_field<V: ?Sized + Serialize>(&mut self, field: &'static str, value: &V) {
+        match serde_json::to_value(value) {
+            Ok(Value::String(s)) => self.add(field, s),
+            Ok(Value::Null) | Err(_) => {}
+            Ok(value) => self.add(field, value.to_string()),
+        }
+    }
+}
+
+impl Visit for TextFields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message.get_or_insert_with(|| value.to_owned());
+        } else {
+            self.add(field.name(), value);
+        }
+    }
+
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.add(field.name(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message.get_or_insert_with(|| format!("{:?}", value));
+        } else {
+            self.add(field.name(), format!("{:?}", value));
+        }
+    }
+}
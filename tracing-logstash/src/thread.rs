@@ -0,0 +1,79 @@
+//! Spawning threads that log their panics
+//!
+//! A thread spawned with [`spawn_logged`] or [`spawn_scoped_logged`] emits an `ERROR` event
+//! with target `tracing_logstash::thread` when it panics, with the thread name in `thread_name`
+//! and the panic payload in `panic`. The panic is then resumed, so joining the thread returns
+//! the payload as usual.
+//!
+//! The thread runs with the dispatcher and in the span that were current when it was spawned.
+//!
+//! # Example
+//! ```
+//! let handle = tracing_logstash::thread::spawn_logged(|| {
+//!     panic!("worker failed");
+//! });
+//! assert!(handle.join().is_err());
+//! ```
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{JoinHandle, Scope, ScopedJoinHandle};
+
+/// Like [`std::thread::spawn`], but logs a structured event if the thread panics
+pub fn spawn_logged<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let f = logged(f);
+    std::thread::spawn(f)
+}
+
+/// Like [`Scope::spawn`], but logs a structured event if the thread panics
+pub fn spawn_scoped_logged<'scope, 'env, F, T>(
+    scope: &'scope Scope<'scope, 'env>,
+    f: F,
+) -> ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    let f = logged(f);
+    scope.spawn(f)
+}
+
+fn logged<F, T>(f: F) -> impl FnOnce() -> T
+where
+    F: FnOnce() -> T,
+{
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+    let span = tracing::Span::current();
+    move || {
+        tracing::dispatcher::with_default(&dispatch, || {
+            let _entered = span.enter();
+            match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(result) => result,
+                Err(payload) => {
+                    let thread = std::thread::current();
+                    tracing::error!(
+                        target: "tracing_logstash::thread",
+                        thread_name = thread.name().unwrap_or("<unnamed>"),
+                        panic = panic_message(&*payload),
+                        "thread panicked"
+                    );
+                    panic::resume_unwind(payload)
+                }
+            }
+        })
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&'static str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
@@ -0,0 +1,81 @@
+use std::sync::Arc;
+
+/// Ids of the distributed trace an event belongs to
+///
+/// Ids are hex encoded, as in W3C trace context: 32 digits for the trace id and 16 for span
+/// and transaction ids.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: Option<String>,
+    /// Id of the local root span, called a transaction by Elastic APM
+    pub transaction_id: Option<String>,
+}
+
+/// Provides the trace context of the currently executing code
+///
+/// Implemented for closures, which makes it easy to read the ids from e.g. OpenTelemetry:
+///
+/// ```
+/// # use tracing_logstash::trace_context::TraceContext;
+/// # struct Context { trace_id: u128, span_id: u64 }
+/// # fn current_otel_context() -> Option<Context> { None }
+/// let provider = || {
+///     current_otel_context().map(|cx| TraceContext {
+///         trace_id: format!("{:032x}", cx.trace_id),
+///         span_id: Some(format!("{:016x}", cx.span_id)),
+///         transaction_id: None,
+///     })
+/// };
+/// # let _ = tracing_logstash::trace_context::ApmCorrelation::new(provider);
+/// ```
+pub trait TraceContextProvider: Send + Sync {
+    fn trace_context(&self) -> Option<TraceContext>;
+}
+
+impl<F> TraceContextProvider for F
+where
+    F: Fn() -> Option<TraceContext> + Send + Sync,
+{
+    fn trace_context(&self) -> Option<TraceContext> {
+        self()
+    }
+}
+
+/// Configuration for the Elastic APM log correlation fields, `trace.id`, `transaction.id`,
+/// `span.id` and `service.name`
+///
+/// # Example
+/// ```
+/// # use tracing_subscriber::prelude::*;
+/// # use tracing_logstash::trace_context::{ApmCorrelation, TraceContext};
+/// #
+/// let logger = tracing_logstash::Layer::default().event_format(
+///     tracing_logstash::logstash::LogstashFormat::default().with_apm_correlation(Some(
+///         ApmCorrelation::new(|| None::<TraceContext>).with_service_name("checkout"),
+///     )),
+/// );
+/// #
+/// # let collector = tracing_subscriber::Registry::default().with(logger);
+/// ```
+#[derive(Clone)]
+pub struct ApmCorrelation {
+    pub(crate) service_name: Option<String>,
+    pub(crate) provider: Arc<dyn TraceContextProvider>,
+}
+
+impl ApmCorrelation {
+    pub fn new(provider: impl TraceContextProvider + 'static) -> Self {
+        Self {
+            service_name: None,
+            provider: Arc::new(provider),
+        }
+    }
+
+    pub fn with_service_name(self, service_name: impl Into<String>) -> Self {
+        Self {
+            service_name: Some(service_name.into()),
+            ..self
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Fire-and-forget delivery of records as UDP datagrams, for Logstash `udp` and Graylog GELF UDP
+//! inputs
+//!
+//! Each record is sent as one datagram from the thread writing it. Records that could not be
+//! sent, or that are larger than the maximum datagram size, are dropped and counted; nothing is
+//! retried.
+//!
+//! # Example
+//! ```no_run
+//! # use tracing_subscriber::prelude::*;
+//! # use tracing_logstash::udp::UdpWriter;
+//! #
+//! let writer = UdpWriter::new("logstash:5000").unwrap().with_max_datagram_size(8192);
+//!
+//! let logger = tracing_logstash::Layer::default().with_writer(writer);
+//! # let collector = tracing_subscriber::Registry::default().with(logger);
+//! ```
+
+use crate::record::{RecordWriter, WriteRecord};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Largest payload of a UDP datagram over IPv4
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// A writer sending records as UDP datagrams, see the [module](self) documentation
+///
+/// Clones share the same socket.
+#[derive(Clone)]
+pub struct UdpWriter {
+    socket: Arc<UdpSocket>,
+    max_datagram_size: usize,
+    dropped: Arc<AtomicU64>,
+}
+
+impl UdpWriter {
+    /// A writer sending to `addr`, which is resolved once
+    pub fn new(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+        })?;
+        let local_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local_addr)?;
+        socket.connect(addr)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            max_datagram_size: MAX_DATAGRAM_SIZE,
+            dropped: Default::default(),
+        })
+    }
+
+    /// Records larger than this many bytes are dropped, defaults to 65507. Set this to the
+    /// largest datagram the network delivers without fragmentation to avoid losing fragments.
+    pub fn with_max_datagram_size(self, max_datagram_size: usize) -> Self {
+        Self {
+            max_datagram_size: max_datagram_size.min(MAX_DATAGRAM_SIZE),
+            ..self
+        }
+    }
+
+    /// Number of records dropped because they were too large or could not be sent
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl WriteRecord for UdpWriter {
+    fn write_record(&self, record: &[u8], _level: Level) -> io::Result<()> {
+        let result = if record.len() > self.max_datagram_size {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "record larger than the maximum datagram size",
+            ))
+        } else {
+            self.socket.send(record).map(|_| ())
+        };
+        result.inspect_err(|_| {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        })
+    }
+}
+
+impl<'a> MakeWriter<'a> for UdpWriter {
+    type Writer = RecordWriter<'a, Self>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RecordWriter::new(self, Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        RecordWriter::new(self, *meta.level())
+    }
+}
@@ -0,0 +1,36 @@
+//! Installing the global default subscriber, in its own test binary as it can only be done once
+//! per process
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Default)]
+struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn try_init() {
+    let buffer = Buffer::default();
+    let cloned = buffer.clone();
+    let _guard = tracing_logstash::builder()
+        .with_writer(move || cloned.clone())
+        .try_init()
+        .unwrap();
+    tracing::info!("installed");
+
+    let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "installed");
+
+    // A second subscriber can't be installed, and reports it instead of panicking
+    assert!(tracing_logstash::builder().try_init().is_err());
+}
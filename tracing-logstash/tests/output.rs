@@ -4,7 +4,8 @@ use std::{
     sync::{Arc, RwLock},
 };
 use time::format_description::well_known::Rfc3339;
-use tracing_logstash::logstash::{LogFieldContributor, LogFieldReceiver};
+use tracing_logstash::format::FormatEvent;
+use tracing_logstash::logstash::{LogFieldContributor, LogFieldReceiver, LogstashFormat};
 use tracing_subscriber::{
     fmt::writer::BoxMakeWriter, prelude::__tracing_subscriber_SubscriberExt, Registry,
 };
@@ -40,6 +41,23 @@ impl Read for Buffer {
     }
 }
 
+/// Runs `f` with a layer using `event_format` installed and returns everything it wrote
+fn capture<E: FormatEvent + Send + Sync + 'static>(event_format: E, f: impl FnOnce()) -> String {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    let logger = tracing_logstash::Layer::default()
+        .event_format(event_format)
+        .with_writer(writer);
+
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, f);
+
+    let output = shared.read().unwrap().to_vec();
+    String::from_utf8(output).unwrap()
+}
+
 #[test]
 fn simple_log_format() {
     let shared = Arc::new(RwLock::new(Vec::new()));
@@ -145,3 +163,4227 @@ fn simple_log_format_with_dynamic_fields() {
     // assert that output_json["@timestamp"] is a valid timestamp
     time::OffsetDateTime::parse(output_json["@timestamp"].as_str().unwrap(), &Rfc3339).unwrap();
 }
+
+#[test]
+fn hardened_log_format() {
+    let profile = tracing_logstash::hardening::HardeningProfile::default()
+        .with_max_depth(1)
+        .with_max_string_length(40);
+    let stats = profile.stats();
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_hardening(Some(profile))
+            .with_constants(vec![("service\tname", "checkout".to_owned())]),
+        || tracing::info!(column = "a\tb", body = "0123456789".repeat(5), "line\r\n"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert_eq!(output_json["service name"], "checkout");
+    assert_eq!(output_json["column"], "a b");
+    assert_eq!(output_json["body"], "0123456789".repeat(4));
+    assert_eq!(output_json["message"], "line \n");
+    assert_eq!(stats.control_characters_replaced(), 3);
+    assert_eq!(stats.strings_truncated(), 1);
+}
+
+#[test]
+fn span_levels() {
+    let log = || {
+        let _outer = tracing::info_span!("outer").entered();
+        let _inner = tracing::debug_span!("inner").entered();
+        tracing::info!("test");
+    };
+
+    let output = capture(
+        LogstashFormat::default().with_span_levels(Some(tracing_logstash::SpanLevels::All)),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        output_json["span_levels"],
+        serde_json::json!(["DEBUG", "INFO"])
+    );
+
+    let output = capture(
+        LogstashFormat::default().with_span_levels(Some(tracing_logstash::SpanLevels::MostSevere)),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["span_level_max"], "INFO");
+}
+
+#[test]
+fn message_key_and_templates() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_message_key("msg")
+            .with_message_templates(true),
+        || tracing::info!(user_id = 42, "user {{user_id}} logged in"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert_eq!(output_json["msg"], "user 42 logged in");
+    assert_eq!(output_json["msg.template"], "user {user_id} logged in");
+    assert_eq!(output_json.get("message"), None);
+    assert_eq!(output_json.get("message.template"), None);
+}
+
+#[test]
+fn error_classifier() {
+    use tracing_logstash::{DisplayLevelFilter, ErrorClass};
+
+    fn classify(event: &tracing::Event<'_>) -> ErrorClass {
+        match event.metadata().level() {
+            &tracing::Level::WARN => ErrorClass::Unexpected,
+            _ => ErrorClass::ClientError,
+        }
+    }
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_stack_trace(Some((DisplayLevelFilter::ERROR, DisplayLevelFilter::All)))
+            .with_error_classifier(classify),
+        || {
+            tracing::error!("client error");
+            tracing::warn!("unexpected");
+        },
+    );
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(records[0].get("stack_trace"), None);
+    assert_eq!(records[0].get("spans"), None);
+    assert!(records[1]["stack_trace"].is_string());
+    assert_eq!(records[1]["spans"], serde_json::json!([]));
+}
+
+#[test]
+fn raw_log_writer() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = tracing_logstash::raw::RawLogWriter::new(move || Buffer::new(cloned.clone()))
+        .with_stream("stderr")
+        .with_max_chunk_length(8);
+
+    writer.write("line 1\nline 2").unwrap();
+    writer.copy_lines("a\nb\n".as_bytes()).unwrap();
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(records.len(), 4);
+    assert_eq!(records[0]["message"], "line 1\nl");
+    assert_eq!(records[0]["stream"], "stderr");
+    assert_eq!(records[0]["raw"], true);
+    assert_eq!(records[0]["chunk_index"], 0);
+    assert_eq!(records[1]["message"], "ine 2");
+    assert_eq!(records[1]["chunk_count"], 2);
+    assert_eq!(records[2]["message"], "a");
+    assert_eq!(records[2].get("chunk_index"), None);
+    assert_eq!(records[3]["message"], "b");
+}
+
+#[test]
+fn stack_frames() {
+    use tracing_logstash::DisplayLevelFilter;
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_stack_trace(Some((DisplayLevelFilter::All, DisplayLevelFilter::All)))
+            .with_stack_frames(true),
+        || {
+            let _span = tracing::info_span!("span").entered();
+            tracing::info!("test");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let frames = output_json["stack_frames"].as_array().unwrap();
+    assert_eq!(frames.len(), 2);
+    for (frame, line) in frames
+        .iter()
+        .zip(output_json["stack_trace"].as_str().unwrap().lines())
+    {
+        assert_eq!(frame["target"], "output");
+        assert_eq!(frame["file"], "tracing-logstash/tests/output.rs");
+        assert_eq!(
+            line,
+            format!(
+                "  at output(tracing-logstash/tests/output.rs:{})",
+                frame["line"]
+            )
+        );
+    }
+}
+
+#[test]
+fn targeted_debug() {
+    use std::time::Duration;
+    use tracing_logstash::targeted_debug::{DebugTargets, TargetedDebugFilter};
+    use tracing_subscriber::filter::LevelFilter;
+    use tracing_subscriber::Layer as _;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    let targets = DebugTargets::default();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(writer)
+        .with_filter(TargetedDebugFilter::new(
+            LevelFilter::INFO,
+            "correlation_id",
+            targets.clone(),
+        ));
+    let collector = Registry::default().with(logger);
+
+    tracing::subscriber::with_default(collector, || {
+        targets.enable("enabled", Duration::from_secs(60));
+        for id in ["enabled", "other"] {
+            let _span = tracing::info_span!("request", correlation_id = id).entered();
+            tracing::debug!(id, "debug");
+            tracing::info!(id, "info");
+        }
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| (record["id"].clone(), record["message"].clone()))
+        .collect::<Vec<_>>();
+
+    assert_eq!(
+        records,
+        vec![
+            ("enabled".into(), "debug".into()),
+            ("enabled".into(), "info".into()),
+            ("other".into(), "info".into()),
+        ]
+    );
+}
+
+#[test]
+fn event_name() {
+    use tracing_logstash::EventName;
+
+    let log = || {
+        tracing::info!(name: "payment.captured", "named");
+        tracing::info!("unnamed");
+    };
+
+    let output = capture(
+        LogstashFormat::default().with_event_name(Some(EventName::Explicit)),
+        log,
+    );
+    let names = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event.name"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        vec!["payment.captured".into(), serde_json::Value::Null]
+    );
+
+    let output = capture(
+        LogstashFormat::default().with_event_name(Some(EventName::All)),
+        log,
+    );
+    let names = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["event.name"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names[0], "payment.captured");
+    assert!(names[1]
+        .as_str()
+        .unwrap()
+        .starts_with("event tracing-logstash/tests/output.rs:"));
+}
+
+#[test]
+fn record_separator() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    // Not static, as accepted before `RecordSeparator`
+    let separator = String::from("\r\n");
+    let logger = tracing_logstash::Layer::default()
+        .record_separator(separator.as_str())
+        .with_writer(writer);
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        tracing::info!("first");
+        tracing::info!("second");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output.split_terminator("\r\n").collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert!(records.iter().all(|r| !r.contains('\n')));
+}
+
+#[test]
+fn apm_correlation() {
+    use tracing_logstash::trace_context::{ApmCorrelation, TraceContext};
+
+    let provider = || {
+        Some(TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_owned(),
+            span_id: Some("b7ad6b7169203331".to_owned()),
+            transaction_id: Some("00f067aa0ba902b7".to_owned()),
+        })
+    };
+    let output = capture(
+        LogstashFormat::default().with_apm_correlation(Some(
+            ApmCorrelation::new(provider).with_service_name("checkout"),
+        )),
+        || tracing::info!("test"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert_eq!(output_json["service.name"], "checkout");
+    assert_eq!(output_json["trace.id"], "0af7651916cd43dd8448eb211c80319c");
+    assert_eq!(output_json["transaction.id"], "00f067aa0ba902b7");
+    assert_eq!(output_json["span.id"], "b7ad6b7169203331");
+}
+
+#[test]
+fn missing_span_is_counted() {
+    use tracing::field::Value;
+    use tracing::span::{Id, Record};
+
+    let logger = tracing_logstash::Layer::default();
+    let diagnostics = logger.diagnostics();
+    let collector = Registry::default().with(logger);
+
+    tracing::subscriber::with_default(collector, || {
+        let span = tracing::info_span!("span", field = tracing::field::Empty);
+        let fields = span.metadata().unwrap().fields();
+        let field = fields.field("field").unwrap();
+        let values = [(&field, Some(&1 as &dyn Value))];
+        let values = fields.value_set(&values);
+
+        tracing::dispatcher::get_default(|dispatch| {
+            dispatch.record(&Id::from_u64(4711), &Record::new(&values))
+        });
+    });
+
+    assert_eq!(diagnostics.missing_spans(), 1);
+}
+
+#[test]
+fn tenant_quotas() {
+    use tracing_logstash::quota::TenantQuotas;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    let quotas = TenantQuotas::new("tenant_id").with_max_records_per_second(1);
+    let logger = tracing_logstash::Layer::default()
+        .event_format(LogstashFormat::default().with_span_fields(vec!["tenant_id".into()]))
+        .with_tenant_quotas(Some(quotas.clone()))
+        .with_writer(writer);
+    let collector = Registry::default().with(logger);
+
+    tracing::subscriber::with_default(collector, || {
+        tracing::info!(tenant_id = "noisy", "first");
+        {
+            let _span = tracing::info_span!("request", tenant_id = "noisy").entered();
+            tracing::info!("second");
+        }
+        tracing::info!(tenant_id = "quiet", "third");
+        tracing::info!("no tenant");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let messages = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["first", "third", "no tenant"]);
+
+    let stats = quotas.stats();
+    assert_eq!(stats["noisy"].records_written, 1);
+    assert_eq!(stats["noisy"].records_dropped, 1);
+    assert_eq!(stats["quiet"].records_written, 1);
+}
+
+#[test]
+fn sampling_fields() {
+    use tracing_logstash::quota::TenantQuotas;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let quotas = TenantQuotas::new("tenant_id")
+        .with_max_records_per_second(1)
+        .with_over_quota_sampling(2)
+        .with_sampling_fields(true);
+    let logger = tracing_logstash::Layer::default()
+        .with_tenant_quotas(Some(quotas))
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        for i in 0..4 {
+            tracing::info!(tenant_id = "noisy", i);
+        }
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["i"], 0);
+    assert!(records[0].get("sampling.rate").is_none());
+    for (record, i) in records[1..].iter().zip([1, 3]) {
+        assert_eq!(record["i"], i);
+        assert_eq!(record["sampling.rate"], 2);
+        assert_eq!(record["sampling.decision"], "sampled");
+    }
+}
+
+#[test]
+fn dropped_summary() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Duration;
+    use tracing_logstash::dropped::DroppedSummary;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let dropped = Arc::new(AtomicU64::new(0));
+    let summary = DroppedSummary::new(Duration::ZERO).with_source({
+        let dropped = dropped.clone();
+        move || dropped.load(Ordering::Relaxed)
+    });
+    let logger = tracing_logstash::Layer::default()
+        .with_dropped_summary(summary)
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("first");
+        dropped.store(132, Ordering::Relaxed);
+        tracing::info!("second");
+        tracing::info!("third");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let messages = records.iter().map(|r| &r["message"]).collect::<Vec<_>>();
+    assert_eq!(
+        messages,
+        ["first", "dropped 132 log events", "second", "third"]
+    );
+    assert_eq!(records[1]["logger_name"], "tracing_logstash");
+    assert_eq!(records[1]["level"], "WARN");
+    assert_eq!(records[1]["dropped"], 132);
+}
+
+#[test]
+fn write_errors() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tracing_logstash::WriteErrorPolicy;
+
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let logger = tracing_logstash::Layer::default().with_writer(|| FailingWriter);
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("first");
+        tracing::info!("second");
+    });
+    assert_eq!(diagnostics.write_errors(), 2);
+
+    static CALLBACKS: AtomicU64 = AtomicU64::new(0);
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(|| FailingWriter)
+        .with_write_error_policy(WriteErrorPolicy::Callback(|error| {
+            assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+            CALLBACKS.fetch_add(1, Ordering::Relaxed);
+        }));
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("first");
+    });
+    assert_eq!(CALLBACKS.load(Ordering::Relaxed), 1);
+    assert_eq!(diagnostics.write_errors(), 0);
+}
+
+#[test]
+fn max_record_bytes() {
+    use tracing_logstash::OversizeStrategy;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(move || Buffer::new(cloned.clone()))
+        .with_max_record_bytes(Some(400))
+        .with_oversize_strategy(OversizeStrategy::DropLargestFields);
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!(body = "x".repeat(1000), status = 200, "request received");
+        tracing::info!(status = 200, "small");
+    });
+    assert_eq!(diagnostics.oversized_records(), 1);
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert!(lines[0].len() <= 400);
+    let output_json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(output_json["message"], "request received");
+    assert_eq!(output_json["status"], 200);
+    assert_eq!(
+        output_json["log.dropped_fields"],
+        serde_json::json!(["body"])
+    );
+    let output_json: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+    assert!(output_json.get("log.dropped_fields").is_none());
+}
+
+#[test]
+fn max_record_bytes_format_keys() {
+    use tracing_logstash::gelf::GelfFormat;
+    use tracing_logstash::logfmt::LogfmtFormat;
+    use tracing_logstash::OversizeStrategy;
+
+    fn oversized<E: FormatEvent + Send + Sync + 'static>(
+        event_format: E,
+        strategy: OversizeStrategy,
+    ) -> (String, Arc<tracing_logstash::diagnostics::Diagnostics>) {
+        let shared = Arc::new(RwLock::new(Vec::new()));
+        let cloned = shared.clone();
+        let logger = tracing_logstash::Layer::default()
+            .event_format(event_format)
+            .with_writer(move || Buffer::new(cloned.clone()))
+            .with_max_record_bytes(Some(300))
+            .with_oversize_strategy(strategy);
+        let diagnostics = logger.diagnostics();
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            tracing::info!("{}", "m".repeat(1000));
+        });
+        let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+        (output, diagnostics)
+    }
+
+    let (output, diagnostics) = oversized(
+        LogstashFormat::default().with_message_key("msg"),
+        OversizeStrategy::TruncateMessage,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert!(output_json["msg"]
+        .as_str()
+        .unwrap()
+        .ends_with("…[truncated]"));
+    assert!(output_json.get("message").is_none());
+    assert_eq!(diagnostics.oversized_records(), 1);
+
+    let (output, _) = oversized(GelfFormat::default(), OversizeStrategy::Stub);
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert!(output_json["short_message"]
+        .as_str()
+        .unwrap()
+        .starts_with("record of"));
+    assert_eq!(output_json["version"], "1.1");
+    assert!(output_json.get("message").is_none());
+
+    // Text records are written as they are
+    let (output, diagnostics) = oversized(LogfmtFormat::default(), OversizeStrategy::Stub);
+    assert!(output.len() > 1000);
+    assert_eq!(diagnostics.oversized_records(), 0);
+    assert_eq!(diagnostics.unshrinkable_records(), 1);
+}
+
+#[test]
+fn fallback_writer() {
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf.windows(4).any(|w| w == b"down") {
+                Err(io::ErrorKind::ConnectionRefused.into())
+            } else {
+                Ok(buf.len())
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(|| FailingWriter)
+        .with_fallback_writer(move || Buffer::new(cloned.clone()));
+    let diagnostics = logger.diagnostics();
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("up");
+        tracing::info!("down");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["message"], "down");
+    assert_eq!(diagnostics.write_errors(), 0);
+}
+
+#[test]
+fn record_writer_errors() {
+    use tracing_logstash::udp::UdpWriter;
+
+    let writer = UdpWriter::new("127.0.0.1:9")
+        .unwrap()
+        .with_max_datagram_size(16);
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("larger than a datagram");
+    });
+    assert_eq!(diagnostics.write_errors(), 1);
+    assert_eq!(writer.dropped(), 1);
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(writer)
+        .with_fallback_writer(move || Buffer::new(cloned.clone()));
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("larger than a datagram");
+    });
+    let output_json: serde_json::Value = serde_json::from_slice(&shared.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "larger than a datagram");
+    assert_eq!(diagnostics.write_errors(), 0);
+}
+
+#[test]
+fn context_env() {
+    use tracing_logstash::context;
+
+    std::env::set_var(context::ENV_VAR, r#"{"parent_job":"j-1","attempt":2}"#);
+    assert_eq!(
+        context::from_env(),
+        [
+            ("attempt", "2".to_owned()),
+            ("parent_job", "j-1".to_owned())
+        ]
+    );
+
+    let logger = tracing_logstash::Layer::default().event_format(
+        LogstashFormat::default().with_span_fields(vec!["request_id".into(), "attempt".into()]),
+    );
+    let env = tracing::subscriber::with_default(Registry::default().with(logger), || {
+        let _outer = tracing::info_span!("outer", request_id = "r-1", attempt = 1).entered();
+        let _inner = tracing::info_span!("inner", request_id = 42).entered();
+        context::to_env()
+    });
+
+    let (name, value) = env.unwrap();
+    assert_eq!(name, "TRACING_LOGSTASH_CONTEXT");
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&value).unwrap(),
+        serde_json::json!({ "request_id": 42, "attempt": 1, "parent_job": "j-1" })
+    );
+}
+
+#[test]
+fn record_checksum() {
+    use tracing_logstash::cost::CostAttribution;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_record_checksum(true)
+        .with_cost_attribution(Some(CostAttribution::new()))
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!(order_id = 7, "checked");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let record = output.strip_suffix('\n').unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(record).unwrap();
+    assert_eq!(output_json["log.size_bytes"], output.len());
+
+    let checksum = output_json["log.crc32"].as_str().unwrap();
+    assert_eq!(checksum.len(), 8);
+    let placeholder = record.replace(
+        &format!(r#""log.crc32":"{}""#, checksum),
+        r#""log.crc32":"00000000""#,
+    );
+    let mut crc = !0u32;
+    for byte in placeholder.bytes() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    assert_eq!(format!("{:08x}", !crc), checksum);
+}
+
+#[test]
+fn replay_buffer() {
+    use tracing_logstash::replay::ReplayBuffer;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_replay_buffer(ReplayBuffer::new(2))
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        {
+            let _request = tracing::info_span!("request").entered();
+            let _inner = tracing::info_span!("inner").entered();
+            tracing::debug!("d1");
+            tracing::trace!("d2");
+            tracing::debug!("d3");
+            tracing::info!("i1");
+            tracing::error!("e1");
+        }
+        {
+            let _request = tracing::info_span!("request").entered();
+            tracing::debug!("closed");
+        }
+        {
+            let _request = tracing::info_span!("request").entered();
+            tracing::error!("e0");
+        }
+        tracing::debug!("o1");
+        tracing::error!("o2");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| (record["message"].clone(), record["replayed"].clone()))
+        .collect::<Vec<_>>();
+    let replayed = |message: &str| (message.into(), true.into());
+    let written = |message: &str| (message.into(), serde_json::Value::Null);
+    assert_eq!(
+        records,
+        [
+            written("i1"),
+            replayed("d2"),
+            replayed("d3"),
+            written("e1"),
+            written("e0"),
+            replayed("o1"),
+            written("o2"),
+        ]
+    );
+}
+
+#[test]
+fn replay_buffer_tenant_quotas() {
+    use tracing_logstash::quota::TenantQuotas;
+    use tracing_logstash::replay::ReplayBuffer;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let quotas = TenantQuotas::new("tenant_id").with_max_records_per_second(2);
+    let logger = tracing_logstash::Layer::default()
+        .event_format(LogstashFormat::default().with_span_fields(vec!["tenant_id".into()]))
+        .with_replay_buffer(ReplayBuffer::new(10))
+        .with_tenant_quotas(Some(quotas.clone()))
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        let _request = tracing::info_span!("request", tenant_id = "noisy").entered();
+        tracing::debug!("d1");
+        tracing::debug!("d2");
+        tracing::debug!("d3");
+        tracing::error!("e1");
+    });
+
+    // The replayed records use up the quota of the tenant
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let messages = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, vec!["d1", "d2"]);
+
+    let stats = quotas.stats();
+    assert_eq!(stats["noisy"].records_written, 2);
+    assert_eq!(stats["noisy"].records_dropped, 2);
+}
+
+#[test]
+fn span_logger_name() {
+    let output = capture(
+        LogstashFormat::default().with_logger_name(Some(tracing_logstash::LoggerName::Span)),
+        || {
+            tracing::info!("outside");
+            let _span = tracing::info_span!("request").entered();
+            tracing::info!("inside");
+        },
+    );
+    let names = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["logger_name"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["output", "output::request"]);
+}
+
+#[test]
+fn gelf_format() {
+    let output = capture(
+        tracing_logstash::gelf::GelfFormat::default()
+            .with_host("web-1")
+            .with_constants(vec![("service", "checkout".to_owned())]),
+        || tracing::warn!(status = 503, retry = true, "upstream failed\ndetails"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let expected_json = serde_json::json!({
+        "version": "1.1",
+        "host": "web-1",
+        "timestamp": output_json["timestamp"],
+        "level": 4,
+        "short_message": "upstream failed",
+        "full_message": "upstream failed\ndetails",
+        "_logger_name": "output",
+        "_thread_name": "gelf_format",
+        "_service": "checkout",
+        "_status": 503,
+        "_retry": "true",
+    });
+    assert_eq!(output_json, expected_json);
+    assert!(output_json["timestamp"].as_f64().unwrap() > 1_600_000_000.0);
+}
+
+#[test]
+fn gelf_colliding_field_names() {
+    let output = capture(
+        tracing_logstash::gelf::GelfFormat::default().with_constants(vec![
+            ("http status", "first".to_owned()),
+            ("http/status", "second".to_owned()),
+        ]),
+        || tracing::info!(id = 1, id_ = 2, "collides"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["_http_status"], "first");
+    assert_eq!(output_json["_id_"], 1);
+    assert_eq!(output.matches("\"_http_status\"").count(), 1);
+    assert_eq!(output.matches("\"_id_\"").count(), 1);
+}
+
+#[test]
+fn gcp_format() {
+    use tracing_logstash::trace_context::TraceContext;
+
+    let provider = || {
+        Some(TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_owned(),
+            span_id: Some("b7ad6b7169203331".to_owned()),
+            transaction_id: None,
+        })
+    };
+    let output = capture(
+        tracing_logstash::gcp::GcpFormat::default()
+            .with_thread_name(false)
+            .with_trace_context("my-project", provider)
+            .with_labels(vec![("service", "checkout".to_owned())]),
+        || tracing::warn!(status = 503, "upstream failed"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let expected_json = serde_json::json!({
+        "severity": "WARNING",
+        "timestamp": output_json["timestamp"],
+        "message": "upstream failed",
+        "logging.googleapis.com/sourceLocation": {
+            "file": output_json["logging.googleapis.com/sourceLocation"]["file"],
+            "line": output_json["logging.googleapis.com/sourceLocation"]["line"],
+            "function": "output",
+        },
+        "logging.googleapis.com/trace": "projects/my-project/traces/0af7651916cd43dd8448eb211c80319c",
+        "logging.googleapis.com/spanId": "b7ad6b7169203331",
+        "logging.googleapis.com/labels": { "service": "checkout" },
+        "logger_name": "output",
+        "status": 503,
+    });
+    assert_eq!(output_json, expected_json);
+    assert!(output_json["timestamp"].is_string());
+}
+
+#[test]
+fn thread_panic_is_logged() {
+    let output = capture(LogstashFormat::default(), || {
+        let handle = tracing_logstash::thread::spawn_logged(|| panic!("worker failed"));
+        assert!(handle.join().is_err());
+
+        std::thread::scope(|scope| {
+            let handle = tracing_logstash::thread::spawn_scoped_logged(scope, || {
+                panic!("{} failed", "scoped worker")
+            });
+            assert!(handle.join().is_err());
+        });
+    });
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["logger_name"], "tracing_logstash::thread");
+    assert_eq!(records[0]["level"], "ERROR");
+    assert_eq!(records[0]["message"], "thread panicked");
+    assert_eq!(records[0]["thread_name"], "<unnamed>");
+    assert_eq!(records[0]["panic"], "worker failed");
+    assert_eq!(records[1]["panic"], "scoped worker failed");
+}
+
+#[test]
+fn datadog_format() {
+    use tracing_logstash::trace_context::TraceContext;
+
+    let provider = || {
+        Some(TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_owned(),
+            span_id: Some("b7ad6b7169203331".to_owned()),
+            transaction_id: None,
+        })
+    };
+    let output = capture(
+        tracing_logstash::datadog::DatadogFormat::new("checkout")
+            .with_env("production")
+            .with_thread_name(false)
+            .with_trace_context(provider),
+        || tracing::warn!(status_code = 503, "upstream failed"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let expected_json = serde_json::json!({
+        "timestamp": output_json["timestamp"],
+        "status": "warning",
+        "service": "checkout",
+        "ddsource": "rust",
+        "env": "production",
+        "dd.trace_id": "9532127138774266268",
+        "dd.span_id": "13235353014750950193",
+        "logger.name": "output",
+        "message": "upstream failed",
+        "status_code": 503,
+    });
+    assert_eq!(output_json, expected_json);
+}
+
+#[test]
+fn span_location_targets() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_list(Some(tracing_logstash::DisplayLevelFilter::All))
+            .span_format(
+                tracing_logstash::format::DefaultSpanFormat::default()
+                    .with_location_targets(vec!["output"]),
+            ),
+        || {
+            let _outer = tracing::info_span!(target: "dependency::pool", "outer").entered();
+            let _inner = tracing::info_span!("inner").entered();
+            tracing::info!("test");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let spans = output_json["spans"].as_array().unwrap();
+    let span = |name: &str| spans.iter().find(|span| span["name"] == name).unwrap();
+
+    assert!(span("inner")["file"].is_string());
+    assert!(span("inner")["line"].is_u64());
+    assert!(span("outer").get("file").is_none());
+    assert!(span("outer").get("line").is_none());
+}
+
+#[test]
+fn cached_span_list() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_list(Some(tracing_logstash::DisplayLevelFilter::Event))
+            .with_cached_span_list(true)
+            .with_span_fields(vec!["attempt".into()])
+            .span_format(tracing_logstash::format::DefaultSpanFormat::default().with_fields(true)),
+        || {
+            let outer = tracing::info_span!("outer", attempt = 1).entered();
+            let _inner = tracing::debug_span!("inner").entered();
+            tracing::debug!("first");
+            tracing::debug!("second");
+            tracing::info!("info");
+            outer.record("attempt", 2);
+            tracing::debug!("third");
+        },
+    );
+    let spans = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| {
+            record["spans"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|span| (span["name"].clone(), span["attempt"].clone()))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let both = |attempt: i32| {
+        vec![
+            ("inner".into(), serde_json::Value::Null),
+            ("outer".into(), attempt.into()),
+        ]
+    };
+    assert_eq!(
+        spans,
+        [both(1), both(1), vec![("outer".into(), 1.into())], both(2)]
+    );
+}
+
+#[test]
+fn splunk_hec_format() {
+    let output = capture(
+        tracing_logstash::splunk::SplunkHecFormat::new(
+            LogstashFormat::default().with_timestamp(false),
+        )
+        .with_host("web-1")
+        .with_source("checkout")
+        .with_index("main"),
+        || tracing::info!(status = 200, "test"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert!(output_json["time"].as_f64().unwrap() > 1_600_000_000.0);
+    assert_eq!(output_json["host"], "web-1");
+    assert_eq!(output_json["source"], "checkout");
+    assert_eq!(output_json["sourcetype"], "_json");
+    assert_eq!(output_json["index"], "main");
+    assert_eq!(output_json["event"]["message"], "test");
+    assert_eq!(output_json["event"]["status"], 200);
+    assert_eq!(output_json["event"]["level"], "INFO");
+}
+
+#[test]
+fn level_override() {
+    use tracing_logstash::logstash::LevelOverride;
+
+    let format = || {
+        LogstashFormat::default()
+            .with_level_override(Some(LevelOverride::new("log.level", vec!["bridge"])))
+    };
+
+    let output = capture(
+        format(),
+        || tracing::event!(target: "bridge::syslog", tracing::Level::INFO, log.level = "WARNING", "test"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["level"], "WARN");
+    assert_eq!(output_json["level_value"], 4);
+
+    let output = capture(
+        format(),
+        || tracing::event!(target: "bridged", tracing::Level::INFO, log.level = "WARNING", "test"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["level"], "INFO");
+
+    let output = capture(
+        format(),
+        || tracing::event!(target: "bridge", tracing::Level::INFO, log.level = "loud", "test"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["level"], "INFO");
+}
+
+#[test]
+fn severity_remap() {
+    use tracing::Level;
+    use tracing_logstash::SeverityRemap;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let remap = SeverityRemap::default()
+        .with_rule("h2", Level::WARN, Level::DEBUG)
+        .with_rule("hyper", Level::ERROR, Level::WARN);
+    let logger = tracing_logstash::Layer::default()
+        .with_severity_remap(Some(remap))
+        .with_max_level(Some(Level::INFO))
+        .with_writer(move || Buffer::new(cloned.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::warn!(target: "h2::proto", "demoted");
+        tracing::warn!(target: "h2c", "kept");
+        tracing::error!(target: "h2", "error");
+        tracing::error!(target: "hyper::client", "warning");
+        tracing::debug!(target: "app", "debug");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| (record["message"].clone(), record["level"].clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        records,
+        [
+            ("kept".into(), "WARN".into()),
+            ("error".into(), "ERROR".into()),
+            ("warning".into(), "WARN".into()),
+        ]
+    );
+}
+
+#[test]
+fn severity_remap_other_format() {
+    use tracing::Level;
+    use tracing_logstash::gelf::GelfFormat;
+    use tracing_logstash::SeverityRemap;
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// Keeps the records and the levels the writers were made for
+    struct LevelWriter(Arc<RwLock<Vec<u8>>>, Arc<RwLock<Vec<Level>>>);
+
+    impl<'a> MakeWriter<'a> for LevelWriter {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            Buffer::new(self.0.clone())
+        }
+
+        fn make_writer_for(&'a self, meta: &tracing::Metadata<'_>) -> Self::Writer {
+            self.1.write().unwrap().push(*meta.level());
+            self.make_writer()
+        }
+    }
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let levels = Arc::new(RwLock::new(Vec::new()));
+    let logger = tracing_logstash::Layer::default()
+        .event_format(GelfFormat::default())
+        .with_severity_remap(Some(SeverityRemap::default().with_rule(
+            "h2",
+            Level::WARN,
+            Level::DEBUG,
+        )))
+        .with_writer(LevelWriter(shared.clone(), levels.clone()));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::warn!(target: "h2::proto", "demoted");
+        tracing::warn!(target: "app", "kept");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let severities = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["level"].clone())
+        .collect::<Vec<_>>();
+    // Debug and warning syslog severities
+    assert_eq!(severities, [7, 4]);
+    assert_eq!(*levels.read().unwrap(), [Level::DEBUG, Level::WARN]);
+}
+
+#[test]
+fn excluded_fields() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(vec!["session".into(), "tenant_id".into()])
+            .with_excluded_fields(vec!["password", "session"]),
+        || {
+            let _span = tracing::info_span!("login", session = "s-1", tenant_id = "t-1").entered();
+            tracing::info!(user = "alice", password = "hunter2", "logged in");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["user"], "alice");
+    assert_eq!(output_json["tenant_id"], "t-1");
+    assert!(output_json.get("password").is_none());
+    assert!(output_json.get("session").is_none());
+
+    // Excluded span fields are not recorded, so they are not passed to child processes
+    let logger = tracing_logstash::Layer::default().event_format(
+        LogstashFormat::default()
+            .with_span_fields(vec!["session".into(), "tenant_id".into()])
+            .with_excluded_fields(vec!["session"]),
+    );
+    let env = tracing::subscriber::with_default(Registry::default().with(logger), || {
+        let _span = tracing::info_span!("login", session = "s-1", tenant_id = "t-1").entered();
+        tracing_logstash::context::to_env()
+    });
+    let context: serde_json::Value = serde_json::from_str(&env.unwrap().1).unwrap();
+    assert_eq!(context["tenant_id"], "t-1");
+    assert!(context.get("session").is_none());
+}
+
+#[test]
+fn excluded_fields_in_other_formats() {
+    use tracing_logstash::gelf::GelfFormat;
+    use tracing_logstash::logfmt::LogfmtFormat;
+
+    let log = || {
+        let _span = tracing::info_span!("login", session = "s-1").entered();
+        tracing::info!(user = "alice", password = "hunter2", "logged in");
+    };
+    let excluded = || vec!["password", "session"];
+
+    let output = capture(
+        LogfmtFormat::default()
+            .with_span_fields(vec!["session".into()])
+            .with_excluded_fields(excluded()),
+        log,
+    );
+    assert!(output.contains("user=alice"));
+    assert!(!output.contains("hunter2"));
+    assert!(!output.contains("s-1"));
+
+    let output = capture(
+        GelfFormat::default()
+            .with_span_fields(vec!["session".into()])
+            .with_excluded_fields(excluded()),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["_user"], "alice");
+    assert!(output_json.get("_password").is_none());
+    assert!(output_json.get("_session").is_none());
+}
+
+#[test]
+fn allowed_event_fields() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(vec!["tenant_id".into()])
+            .with_allowed_event_fields(Some(vec!["status"])),
+        || {
+            let _span = tracing::info_span!("request", tenant_id = "t-1").entered();
+            tracing::info!(
+                status = 503,
+                retries = 3,
+                peer.addr = "10.0.0.1",
+                "upstream failed"
+            );
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "upstream failed");
+    assert_eq!(output_json["status"], 503);
+    assert_eq!(output_json["tenant_id"], "t-1");
+    assert_eq!(output_json["level"], "INFO");
+    assert!(output_json.get("retries").is_none());
+    assert!(output_json.get("peer.addr").is_none());
+}
+
+#[test]
+fn field_precedence() {
+    use tracing_logstash::FieldOrigin;
+
+    let log = || {
+        let _outer = tracing::info_span!("job", tenant = "outer", region = "eu").entered();
+        let _inner = tracing::info_span!("task", tenant = "inner", region = None::<&str>).entered();
+        tracing::info!(tenant = "event", "done");
+    };
+    let format = || {
+        LogstashFormat::default()
+            .with_constants(vec![("tenant", "constant".to_owned())])
+            .with_span_fields(vec!["tenant".into(), "region".into()])
+    };
+
+    let output = capture(format(), log);
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["tenant"], "constant");
+    assert_eq!(output_json["region"], "eu");
+
+    let output = capture(
+        format().with_field_precedence(vec![FieldOrigin::Event, FieldOrigin::InnermostSpan]),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["tenant"], "event");
+
+    let output = capture(
+        format().with_field_precedence(vec![FieldOrigin::OuterSpans, FieldOrigin::InnermostSpan]),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["tenant"], "outer");
+
+    let output = capture(
+        format().with_field_precedence(vec![FieldOrigin::InnermostSpan]),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["tenant"], "inner");
+    assert_eq!(output_json["region"], "eu");
+}
+
+#[test]
+fn span_list_field_order() {
+    use tracing_logstash::FieldOrigin;
+
+    let log = || {
+        let _span = tracing::info_span!("job").entered();
+        tracing::info!(tenant = "event", "done");
+    };
+    let format = || {
+        LogstashFormat::default()
+            .with_constants(vec![("service", "checkout".to_owned())])
+            .with_span_list(Some(tracing_logstash::DisplayLevelFilter::All))
+    };
+    let position = |output: &str, key: &str| output.find(&format!("\"{}\":", key)).unwrap();
+
+    // After the constants and dynamic fields, before the event fields
+    let output = capture(format(), log);
+    assert!(position(&output, "service") < position(&output, "spans"));
+    assert!(position(&output, "spans") < position(&output, "tenant"));
+
+    let output = capture(
+        format().with_field_precedence(vec![FieldOrigin::Event]),
+        log,
+    );
+    assert!(position(&output, "tenant") < position(&output, "service"));
+    assert!(position(&output, "service") < position(&output, "spans"));
+}
+
+#[test]
+fn max_string_length() {
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Body(&'static str);
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(vec!["path".into()])
+            .with_max_string_length(Some(8)),
+        || {
+            let _span = tracing::info_span!("request", path = "/orders/123/items").entered();
+            tracing::info!(body = ?Body("åäöåäö"), user = "alice", "request received");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "request …[truncated]");
+    assert_eq!(output_json["body"], "Body(\"å…[truncated]");
+    assert_eq!(output_json["path"], "/orders/…[truncated]");
+    assert_eq!(output_json["user"], "alice");
+}
+
+#[test]
+fn flattened_objects() {
+    #[derive(Serialize)]
+    struct Request {
+        method: &'static str,
+        headers: Headers,
+    }
+
+    #[derive(Serialize)]
+    struct Headers {
+        host: &'static str,
+    }
+
+    struct RequestContributor;
+    impl LogFieldContributor for RequestContributor {
+        fn add_fields<F>(&self, serializer: &mut F)
+        where
+            F: LogFieldReceiver,
+        {
+            serializer.add_field(
+                "request",
+                &Request {
+                    method: "GET",
+                    headers: Headers { host: "shop" },
+                },
+            );
+        }
+    }
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_version(false)
+            .with_timestamp(false)
+            .with_logger_name(None)
+            .with_thread_name(false)
+            .with_level(false)
+            .with_level_value(false)
+            .with_flattened_objects(true)
+            .with_field_contributor(RequestContributor),
+        || {
+            tracing::info!(
+                payload = r#"{"cart":{"items":2},"ids":[1,2]}"#,
+                note = "{not json}",
+                "{}",
+                r#"{"message":"kept"}"#
+            )
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        output_json,
+        serde_json::json!({
+            "request.method": "GET",
+            "request.headers.host": "shop",
+            "payload.cart.items": 2,
+            "payload.ids": [1, 2],
+            "note": "{not json}",
+            "message": "{\"message\":\"kept\"}",
+        })
+    );
+}
+
+#[test]
+fn flattened_key_collisions() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_version(false)
+            .with_timestamp(false)
+            .with_logger_name(None)
+            .with_thread_name(false)
+            .with_level(false)
+            .with_level_value(false)
+            .with_flattened_objects(true)
+            .with_excluded_fields(vec!["payload.secret"]),
+        || {
+            tracing::info!(
+                payload = r#"{"a":{"b":1},"secret":"hunter2"}"#,
+                payload.a.b = 2,
+                "collides"
+            )
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    // The first `payload.a.b` is kept, and the excluded flattened key is left out
+    assert_eq!(
+        output_json,
+        serde_json::json!({
+            "payload.a.b": 1,
+            "message": "collides",
+        })
+    );
+    assert_eq!(output.matches("payload.a.b").count(), 1);
+}
+
+#[test]
+fn emf_metrics() {
+    use tracing_logstash::emf::EmfMetrics;
+
+    let format = || {
+        LogstashFormat::default()
+            .with_constants(vec![("service", "checkout".to_owned())])
+            .with_emf_metrics(Some(
+                EmfMetrics::new("Checkout")
+                    .with_metric("latency_ms", "Milliseconds")
+                    .with_metric("items", "Count")
+                    .with_dimensions(vec!["service"]),
+            ))
+    };
+
+    let output = capture(format(), || {
+        tracing::info!(latency_ms = 12.5, items = "many", "checkout completed")
+    });
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert!(output_json["_aws"]["Timestamp"].as_i64().unwrap() > 1_600_000_000_000);
+    assert_eq!(
+        output_json["_aws"]["CloudWatchMetrics"],
+        serde_json::json!([{
+            "Namespace": "Checkout",
+            "Dimensions": [["service"]],
+            "Metrics": [{ "Name": "latency_ms", "Unit": "Milliseconds" }],
+        }])
+    );
+    assert_eq!(output_json["latency_ms"], 12.5);
+    assert_eq!(output_json["service"], "checkout");
+
+    let output = capture(format(), || tracing::info!("no metrics"));
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert!(output_json.get("_aws").is_none());
+}
+
+#[cfg(feature = "lumberjack")]
+#[test]
+fn lumberjack_sink() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tracing_logstash::lumberjack::LumberjackSink;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let read_u32 = |stream: &mut std::net::TcpStream| {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            u32::from_be_bytes(buf)
+        };
+        let mut payloads = Vec::new();
+        while payloads.len() < 3 {
+            let mut frame = [0u8; 2];
+            stream.read_exact(&mut frame).unwrap();
+            assert_eq!(&frame, b"2W");
+            let window_size = read_u32(&mut stream);
+            for expected_seq in 1..=window_size {
+                stream.read_exact(&mut frame).unwrap();
+                assert_eq!(&frame, b"2J");
+                assert_eq!(read_u32(&mut stream), expected_seq);
+                let mut payload = vec![0u8; read_u32(&mut stream) as usize];
+                stream.read_exact(&mut payload).unwrap();
+                payloads.push(String::from_utf8(payload).unwrap());
+            }
+            stream.write_all(b"2A").unwrap();
+            stream.write_all(&window_size.to_be_bytes()).unwrap();
+        }
+        payloads
+    });
+
+    let sink = LumberjackSink::new(addr).unwrap().with_window_size(2);
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        tracing::info!("three");
+    });
+    sink.flush().unwrap();
+
+    let payloads = server.join().unwrap();
+    let messages = payloads
+        .iter()
+        .map(|payload| {
+            serde_json::from_str::<serde_json::Value>(payload).unwrap()["message"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["one", "two", "three"]);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[cfg(feature = "lumberjack")]
+#[test]
+fn lumberjack_connections() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Barrier;
+    use tracing_logstash::lumberjack::LumberjackSink;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        // Each window is acknowledged once both connections have received one
+        let barrier = Arc::new(Barrier::new(2));
+        let connections = (0..2)
+            .map(|_| {
+                let (mut stream, _) = listener.accept().unwrap();
+                let barrier = barrier.clone();
+                std::thread::spawn(move || {
+                    let mut header = [0u8; 12];
+                    stream.read_exact(&mut header).unwrap();
+                    assert_eq!(&header[..8], b"2W\0\0\0\x012J");
+                    let mut len = [0u8; 4];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut payload = vec![0u8; u32::from_be_bytes(len) as usize];
+                    stream.read_exact(&mut payload).unwrap();
+                    barrier.wait();
+                    stream.write_all(b"2A\0\0\0\x01").unwrap();
+                    serde_json::from_slice::<serde_json::Value>(&payload).unwrap()["message"]
+                        .clone()
+                })
+            })
+            .collect::<Vec<_>>();
+        connections
+            .into_iter()
+            .map(|connection| connection.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let sink = LumberjackSink::new(addr).unwrap().with_connections(2);
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+    });
+    sink.flush().unwrap();
+
+    let mut messages = server.join().unwrap();
+    messages.sort_by_key(|message| message.to_string());
+    assert_eq!(messages, ["one", "two"]);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[cfg(feature = "lumberjack")]
+#[test]
+fn lumberjack_compression() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tracing_logstash::lumberjack::LumberjackSink;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut header = [0u8; 12];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(&header[..6], b"2W\0\0\0\x03");
+        assert_eq!(&header[6..8], b"2C");
+        let len = u32::from_be_bytes(header[8..].try_into().unwrap());
+        let mut compressed = vec![0u8; len as usize];
+        stream.read_exact(&mut compressed).unwrap();
+        stream.write_all(b"2A\0\0\0\x03").unwrap();
+        compressed
+    });
+
+    let sink = LumberjackSink::new(addr)
+        .unwrap()
+        .with_window_size(3)
+        .with_compression(true);
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        for _ in 0..3 {
+            tracing::info!("the same message, over and over again");
+        }
+    });
+    sink.flush().unwrap();
+
+    // A zlib stream, smaller than the records it holds
+    let compressed = server.join().unwrap();
+    assert_eq!(compressed[..2], [0x78, 0x9c]);
+    assert!(compressed.len() < 300);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[cfg(feature = "lumberjack")]
+#[test]
+fn lumberjack_unacknowledged() {
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+    use tracing_logstash::lumberjack::LumberjackSink;
+
+    // Connections are accepted by the backlog, but no window is ever acknowledged
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let sink = LumberjackSink::new(listener.local_addr().unwrap())
+        .unwrap()
+        .with_max_pending(2)
+        .with_max_attempts(1)
+        .with_timeout(Duration::from_millis(500));
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    let start = Instant::now();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        for i in 0..10 {
+            tracing::info!(i, "waiting");
+        }
+    });
+
+    // Logging does not wait for the input, and the queue keeps at most two records
+    assert!(start.elapsed() < Duration::from_millis(400));
+    assert!(sink.dropped() >= 8);
+    assert!(sink.flush().is_err());
+}
+
+#[cfg(feature = "lumberjack")]
+#[test]
+fn lumberjack_fallback_writer() {
+    use std::net::TcpListener;
+    use tracing_logstash::lumberjack::LumberjackSink;
+
+    // Nothing listens on the port once the listener is dropped
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let spool = Arc::new(RwLock::new(Vec::new()));
+    let cloned = spool.clone();
+    let sink = LumberjackSink::new(addr)
+        .unwrap()
+        .with_max_attempts(1)
+        .with_fallback_writer(move || Buffer::new(cloned.clone()));
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("collector down")
+    });
+    sink.flush().unwrap();
+
+    let output_json: serde_json::Value = serde_json::from_slice(&spool.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "collector down");
+    assert_eq!((sink.fallbacks(), sink.dropped()), (1, 0));
+}
+
+#[test]
+fn syslog_format() {
+    use tracing_logstash::syslog::{Facility, SyslogFormat};
+
+    let output = capture(
+        SyslogFormat::default()
+            .with_facility(Facility::Local0)
+            .with_hostname("web-1")
+            .with_app_name("checkout")
+            .with_constants(vec![("env", "prod".to_owned())]),
+        || tracing::warn!(path = "/cart [v2]", status = 503, "upstream failed"),
+    );
+
+    let (header, rest) = output.split_once(" - [").unwrap();
+    let mut header = header.split(' ');
+    assert_eq!(header.next(), Some("<132>1"));
+    // FULL-DATE "T" FULL-TIME, with at most six digits of TIME-SECFRAC
+    let timestamp = header.next().unwrap().as_bytes();
+    let pattern = b"dddd-dd-ddTdd:dd:dd";
+    assert!(pattern.iter().zip(timestamp).all(|(p, c)| if *p == b'd' {
+        c.is_ascii_digit()
+    } else {
+        p == c
+    }));
+    let time = &timestamp[pattern.len()..];
+    let (secfrac, offset) = time.split_at(time.len() - 1);
+    assert_eq!(offset, b"Z");
+    assert_eq!(secfrac[0], b'.');
+    assert!((2..=7).contains(&secfrac.len()));
+    assert!(secfrac[1..].iter().all(u8::is_ascii_digit));
+    assert_eq!(header.next(), Some("web-1"));
+    assert_eq!(header.next(), Some("checkout"));
+    assert_eq!(header.next(), Some(std::process::id().to_string().as_str()));
+    assert_eq!(
+        rest,
+        "fields@32473 env=\"prod\" path=\"/cart [v2\\]\" status=\"503\"] upstream failed\n"
+    );
+}
+
+#[test]
+fn syslog_format_multiline_and_level_override() {
+    use tracing_logstash::logstash::LevelOverride;
+    use tracing_logstash::syslog::{Facility, SyslogFormat};
+
+    let output = capture(
+        SyslogFormat::default()
+            .with_facility(Facility::Local0)
+            .with_level_override(Some(LevelOverride::new("log.level", vec!["bridge"]))),
+        || {
+            tracing::event!(
+                target: "bridge",
+                tracing::Level::INFO,
+                log.level = "ERROR",
+                "first line\nsecond line"
+            )
+        },
+    );
+
+    // Local0 and the overridden error severity
+    assert!(output.starts_with("<131>1 "));
+    assert_eq!(output.lines().count(), 1);
+    assert!(output.ends_with("] first line\\nsecond line\n"));
+}
+
+#[cfg(unix)]
+#[test]
+fn syslog_writer() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixDatagram;
+    use tracing_logstash::syslog::{Facility, SyslogWriter};
+
+    let path = std::env::temp_dir().join(format!("syslog-{}.socket", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let socket = UnixDatagram::bind(&path).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut len = Vec::new();
+        reader.read_until(b' ', &mut len).unwrap();
+        let len: usize = std::str::from_utf8(&len).unwrap().trim().parse().unwrap();
+        let mut message = vec![0u8; len];
+        reader.read_exact(&mut message).unwrap();
+        String::from_utf8(message).unwrap()
+    });
+
+    let local = SyslogWriter::unix(&path)
+        .unwrap()
+        .with_facility(Facility::Local0)
+        .with_app_name("checkout");
+    let tcp = SyslogWriter::tcp(addr)
+        .unwrap()
+        .with_hostname("web-1")
+        .with_app_name("checkout");
+    let collector = Registry::default()
+        .with(tracing_logstash::Layer::default().with_writer(local))
+        .with(tracing_logstash::Layer::default().with_writer(tcp));
+    tracing::subscriber::with_default(collector, || tracing::warn!("upstream failed"));
+
+    let mut datagram = [0u8; 4096];
+    let n = socket.recv(&mut datagram).unwrap();
+    let datagram = std::str::from_utf8(&datagram[..n]).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let (header, record) = datagram.split_once("]: ").unwrap();
+    assert!(header.starts_with("<132>"));
+    assert!(header.ends_with(&format!(" checkout[{}", std::process::id())));
+    let record: serde_json::Value = serde_json::from_str(record).unwrap();
+    assert_eq!(record["message"], "upstream failed");
+
+    let message = server.join().unwrap();
+    let (header, record) = message.split_once(" - - ").unwrap();
+    let header = header.split(' ').collect::<Vec<_>>();
+    assert_eq!(header[0], "<12>1");
+    assert_eq!(
+        header[2..],
+        ["web-1", "checkout", &std::process::id().to_string()]
+    );
+    let record: serde_json::Value = serde_json::from_str(record).unwrap();
+    assert_eq!(record["message"], "upstream failed");
+}
+
+#[test]
+fn cef_format() {
+    let output = capture(
+        tracing_logstash::cef::CefFormat::new("Acme", "Check|out", "1.0"),
+        || {
+            tracing::warn!(name: "login.failed", suser = "bob", reason = "a=b", "Login failed");
+            tracing::info!("test");
+        },
+    );
+    let mut lines = output.lines();
+
+    let (header, extensions) = lines.next().unwrap().rsplit_once('|').unwrap();
+    assert_eq!(
+        header,
+        "CEF:0|Acme|Check\\|out|1.0|login.failed|Login failed|6"
+    );
+    let mut extensions = extensions.split(' ');
+    assert!(extensions.next().unwrap().starts_with("rt="));
+    assert_eq!(
+        extensions.collect::<Vec<_>>(),
+        ["suser=bob", "reason=a\\=b"]
+    );
+
+    let (header, _) = lines.next().unwrap().rsplit_once('|').unwrap();
+    assert_eq!(header, "CEF:0|Acme|Check\\|out|1.0|output|test|3");
+}
+
+#[test]
+fn request_deadline() {
+    use std::time::{Duration, Instant};
+    use tracing_logstash::deadline::{self, RequestDeadline};
+
+    let output = capture(
+        LogstashFormat::default().with_field_contributor(RequestDeadline),
+        || {
+            deadline::scope(Instant::now() + Duration::from_secs(60), || {
+                tracing::info!("in time")
+            });
+            deadline::scope(Instant::now() - Duration::from_secs(1), || {
+                tracing::info!("late")
+            });
+            tracing::info!("no deadline");
+        },
+    );
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    let remaining = records[0]["request.deadline_ms_remaining"]
+        .as_i64()
+        .unwrap();
+    assert!(remaining > 50_000 && remaining <= 60_000);
+    assert!(
+        records[1]["request.deadline_ms_remaining"]
+            .as_i64()
+            .unwrap()
+            <= -1000
+    );
+    assert!(records[2].get("request.deadline_ms_remaining").is_none());
+}
+
+#[test]
+fn logstash_fields_macro() {
+    use tracing_logstash::keys;
+
+    const TENANT_ID: &str = "tenant.id";
+
+    let fields = tracing_logstash::logstash_fields! {
+        span_fields: [keys::USER_ID, TENANT_ID],
+        constants: [keys::SERVICE_NAME = "checkout"],
+    };
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(fields.span_fields)
+            .with_constants(fields.constants),
+        || {
+            let _span = tracing::info_span!("request", user.id = "u1", tenant.id = "t1").entered();
+            tracing::info!("test");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    assert_eq!(output_json[keys::SERVICE_NAME], "checkout");
+    assert_eq!(output_json[keys::USER_ID], "u1");
+    assert_eq!(output_json[TENANT_ID], "t1");
+}
+
+#[test]
+fn logfmt_format() {
+    let output = capture(
+        tracing_logstash::logfmt::LogfmtFormat::default()
+            .with_timestamp(false)
+            .with_constants(vec![("service", "checkout".to_owned())]),
+        || tracing::warn!(path = "/cart items", status = 503, "upstream failed"),
+    );
+
+    assert_eq!(
+        output,
+        "level=warn logger_name=output thread_name=logfmt_format msg=\"upstream failed\" \
+         service=checkout path=\"/cart items\" status=503\n"
+    );
+}
+
+#[test]
+fn logfmt_theme() {
+    use tracing::Level;
+    use tracing_logstash::logfmt::{Color, LogfmtFormat, Theme};
+
+    let format = LogfmtFormat::default()
+        .with_timestamp(false)
+        .with_thread_name(false)
+        .with_theme(Some(
+            Theme::default()
+                .with_level_color(Level::WARN, Some(Color::Cyan))
+                .with_highlighted_fields(vec!["order_id"]),
+        ));
+    let output = capture(format, || {
+        tracing::warn!(order_id = 7, status = 503, "upstream failed")
+    });
+
+    assert_eq!(
+        output,
+        "\x1b[36mlevel=warn\x1b[0m \x1b[2mlogger_name=output\x1b[0m msg=\"upstream failed\" \
+         \x1b[1morder_id=7\x1b[0m status=503\n"
+    );
+}
+
+#[test]
+fn otel_format() {
+    use tracing_logstash::trace_context::TraceContext;
+
+    let provider = || {
+        Some(TraceContext {
+            trace_id: "0af7651916cd43dd8448eb211c80319c".to_owned(),
+            span_id: Some("b7ad6b7169203331".to_owned()),
+            transaction_id: None,
+        })
+    };
+    let output = capture(
+        tracing_logstash::otel::OtelFormat::default()
+            .with_resource(vec![("service.name", "checkout".to_owned())])
+            .with_constants(vec![("deployment", "blue".to_owned())])
+            .with_trace_context(provider),
+        || tracing::warn!(status = 503, "upstream failed"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let expected_json = serde_json::json!({
+        "Timestamp": output_json["Timestamp"],
+        "SeverityText": "WARN",
+        "SeverityNumber": 13,
+        "Body": "upstream failed",
+        "TraceId": "0af7651916cd43dd8448eb211c80319c",
+        "SpanId": "b7ad6b7169203331",
+        "Resource": { "service.name": "checkout" },
+        "InstrumentationScope": { "Name": "output" },
+        "Attributes": { "deployment": "blue", "status": 503 },
+    });
+    assert_eq!(output_json, expected_json);
+    let timestamp: u128 = output_json["Timestamp"].as_str().unwrap().parse().unwrap();
+    assert!(timestamp > 1_600_000_000_000_000_000);
+}
+
+#[test]
+fn uptime_and_gap() {
+    let output = capture(
+        LogstashFormat::default().with_uptime(true).with_gap(true),
+        || {
+            tracing::info!("first");
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            tracing::info!("second");
+        },
+    );
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+
+    assert!(records[0]["process.uptime_ms"].is_u64());
+    assert!(records[0].get("log.gap_ms").is_none());
+    assert!(records[1]["log.gap_ms"].as_u64().unwrap() >= 20);
+    assert!(
+        records[1]["process.uptime_ms"].as_u64().unwrap()
+            >= records[0]["process.uptime_ms"].as_u64().unwrap() + 20
+    );
+}
+
+struct PrettyJson;
+
+impl tracing_logstash::format::MakeSerializer for PrettyJson {
+    fn serialize<T: Serialize + ?Sized>(&self, buffer: &mut Vec<u8>, value: &T) -> io::Result<()> {
+        value.serialize(&mut serde_json::Serializer::pretty(buffer))?;
+        Ok(())
+    }
+}
+
+#[test]
+fn custom_serializer() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    let logger = tracing_logstash::Layer::default()
+        .event_format(
+            LogstashFormat::default()
+                .with_span_fields(vec![tracing_logstash::FieldSpec::new("request").json()]),
+        )
+        .with_serializer(PrettyJson)
+        .with_writer(writer);
+
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        let _span = tracing::info_span!("handle", request = r#"{"id":42}"#).entered();
+        tracing::info!("pretty")
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    assert!(output.starts_with("{\n  \""));
+    // Recorded JSON is parsed rather than spliced, as PrettyJson doesn't declare SPLICES_JSON
+    assert!(output.contains("\"request\": {\n    \"id\": 42\n  }"));
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "pretty");
+}
+
+#[test]
+fn bare_layer() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = BoxMakeWriter::new(move || Buffer::new(cloned.clone()));
+
+    let logger = tracing_logstash::Layer::default()
+        .event_format(LogstashFormat::default().with_span_fields(vec!["tenant_id".into()]))
+        .bare(true)
+        .with_writer(writer);
+
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        let _span = tracing::info_span!("request", tenant_id = "acme").entered();
+        tracing::info!(status = 200, "done");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["status"], 200);
+    assert!(output_json.get("tenant_id").is_none());
+}
+
+#[test]
+fn loki_format() {
+    let output = capture(
+        tracing_logstash::loki::LokiFormat::new(LogstashFormat::default())
+            .with_labels(vec![("app", "checkout".to_owned())])
+            .with_label_fields(vec!["tenant.id"]),
+        || tracing::info!(tenant.id = "acme", status = 200, "done"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let stream = &output_json["streams"][0];
+    assert_eq!(
+        stream["stream"],
+        serde_json::json!({ "app": "checkout", "tenant_id": "acme" })
+    );
+    let value = stream["values"][0].as_array().unwrap();
+    let timestamp: u128 = value[0].as_str().unwrap().parse().unwrap();
+    assert!(timestamp > 1_600_000_000_000_000_000);
+    let line: serde_json::Value = serde_json::from_str(value[1].as_str().unwrap()).unwrap();
+    assert_eq!(line["message"], "done");
+    assert_eq!(line["status"], 200);
+}
+
+#[cfg(unix)]
+#[test]
+fn journald_writer() {
+    use std::os::unix::net::UnixDatagram;
+    use tracing_logstash::journald::{JournaldFormat, JournaldWriter};
+
+    let path = std::env::temp_dir().join(format!("journald-{}.socket", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let journal = UnixDatagram::bind(&path).unwrap();
+
+    let logger = tracing_logstash::Layer::default()
+        .event_format(JournaldFormat::default().with_syslog_identifier("checkout"))
+        .with_writer(JournaldWriter::with_path(&path).unwrap());
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        tracing::warn!(http.status = 503, "upstream\nfailed")
+    });
+
+    let mut datagram = vec![0; 4096];
+    let len = journal.recv(&mut datagram).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let datagram = &datagram[..len];
+
+    let text = String::from_utf8_lossy(datagram);
+    assert!(text.starts_with("PRIORITY=4\nMESSAGE\n"));
+    assert!(datagram
+        .windows(23)
+        .any(|w| w == b"\x0f\0\0\0\0\0\0\0upstream\nfailed"));
+    assert!(text.contains("\nSYSLOG_IDENTIFIER=checkout\nTARGET=output\n"));
+    assert!(text.contains("\nHTTP_STATUS=503\n"));
+}
+
+struct FailingWriter;
+
+impl Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "collector gone"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn self_test() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone())));
+    let self_test = logger.self_test();
+    let report =
+        tracing::subscriber::with_default(Registry::default().with(logger), || self_test.run());
+    let report = report.unwrap();
+    assert!(report.is_ok());
+    assert!(report.format.ends_with("LogstashFormat"));
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["logger_name"], "tracing_logstash::self_test");
+
+    let logger =
+        tracing_logstash::Layer::default().with_writer(BoxMakeWriter::new(|| FailingWriter));
+    let self_test = logger.self_test();
+    let report =
+        tracing::subscriber::with_default(Registry::default().with(logger), || self_test.run());
+    assert_eq!(report.unwrap().error.as_deref(), Some("collector gone"));
+
+    assert!(self_test.run().is_none());
+}
+
+#[test]
+fn self_test_delivery() {
+    use tracing::Level;
+    use tracing_logstash::background::BackgroundWriter;
+    use tracing_logstash::mirror::TeeWriter;
+
+    let run = |logger: tracing_logstash::Layer<Registry, _, _>| {
+        let self_test = logger.self_test();
+        tracing::subscriber::with_default(Registry::default().with(logger), || self_test.run())
+            .unwrap()
+    };
+
+    // Written whatever the maximum level
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let report = run(tracing_logstash::Layer::default()
+        .with_max_level(Some(Level::ERROR))
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone()))));
+    assert!(report.is_ok());
+    assert!(!shared.read().unwrap().is_empty());
+
+    // Queued records are waited for
+    let report = run(
+        tracing_logstash::Layer::default().with_writer(BoxMakeWriter::new(
+            BackgroundWriter::new(|| FailingWriter).unwrap(),
+        )),
+    );
+    assert_eq!(report.error.as_deref(), Some("record not delivered"));
+
+    // Each writer of a tee writer is reported
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = TeeWriter::new()
+        .with_writer("buffer", move || Buffer::new(cloned.clone()))
+        .with_writer("failing", || FailingWriter);
+    let report = run(tracing_logstash::Layer::default().with_writer(BoxMakeWriter::new(writer)));
+    assert_eq!(report.error, None);
+    assert_eq!(
+        report.writers,
+        [
+            ("buffer".to_owned(), None),
+            ("failing".to_owned(), Some("collector gone".to_owned()))
+        ]
+    );
+    assert!(!report.is_ok());
+}
+
+struct BuildInfo;
+
+impl LogFieldContributor for BuildInfo {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        serializer.add_field("version", "1.2.3");
+    }
+
+    fn field_names(&self) -> Vec<&'static str> {
+        vec!["version"]
+    }
+}
+
+#[test]
+fn namespaced_contributors() {
+    use tracing_logstash::contributors::{Contributors, FieldConflict};
+
+    let conflict = Contributors::new()
+        .with(BuildInfo)
+        .unwrap()
+        .with(BuildInfo)
+        .err();
+    assert_eq!(conflict, Some(FieldConflict { field: "version" }));
+
+    let contributors = Contributors::new()
+        .with(BuildInfo)
+        .unwrap()
+        .with_namespace("service", BuildInfo)
+        .unwrap();
+    let output = capture(
+        LogstashFormat::default().with_field_contributor(contributors),
+        || tracing::info!("started"),
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["version"], "1.2.3");
+    assert_eq!(output_json["service.version"], "1.2.3");
+}
+
+#[derive(Debug)]
+struct ShipmentError(io::Error);
+
+impl std::fmt::Display for ShipmentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("shipment failed")
+    }
+}
+
+impl std::error::Error for ShipmentError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.0)
+    }
+}
+
+#[test]
+fn clef_format() {
+    let output = capture(
+        tracing_logstash::clef::ClefFormat::default()
+            .with_message_templates(true)
+            .with_constants(vec![("Application", "checkout".to_owned())]),
+        || {
+            let error = ShipmentError(io::Error::other("carrier unavailable"));
+            tracing::error!(
+                order_id = 42,
+                error = &error as &dyn std::error::Error,
+                "order {{order_id}} not shipped"
+            );
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+
+    let expected_json = serde_json::json!({
+        "@t": output_json["@t"],
+        "@mt": "order {order_id} not shipped",
+        "@l": "Error",
+        "@x": "shipment failed\n ---> carrier unavailable",
+        "Application": "checkout",
+        "order_id": 42,
+        "error": "shipment failed",
+    });
+    assert_eq!(output_json, expected_json);
+    time::OffsetDateTime::parse(output_json["@t"].as_str().unwrap(), &Rfc3339).unwrap();
+}
+
+#[test]
+fn aggregation() {
+    use tracing_logstash::aggregate::Aggregation;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_aggregation(
+            Aggregation::new(std::time::Duration::from_millis(20))
+                .with_event("chunk.sent", &["bytes_sent", "ratio"]),
+        )
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone())));
+
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        let _span = tracing::info_span!("upload").entered();
+        for bytes_sent in [100, 200, 300] {
+            tracing::info!(name: "chunk.sent", bytes_sent, ratio = 0.5, "sent");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        tracing::info!("upload done");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"], "chunk.sent");
+    assert_eq!(records[0]["aggregate.count"], 3);
+    assert_eq!(records[0]["aggregate.window_ms"], 20);
+    assert_eq!(records[0]["bytes_sent"], 600);
+    assert_eq!(records[0]["ratio"], 1.5);
+    assert!(records[0].get("spans").is_none());
+    assert_eq!(records[1]["message"], "upload done");
+}
+
+#[test]
+fn aggregation_without_later_events() {
+    use tracing_logstash::aggregate::Aggregation;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .with_aggregation(
+            Aggregation::new(std::time::Duration::from_millis(20))
+                .with_event("chunk.sent", &["bytes_sent"])
+                .with_event("chunk.acked", &["bytes_acked"]),
+        )
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone())));
+    let aggregation = logger.aggregation().unwrap();
+
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        tracing::info!(name: "chunk.sent", bytes_sent = 100, "sent");
+        tracing::info!(name: "chunk.sent", bytes_sent = 200, "sent");
+        // No event follows the window, the thread writes its summary
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert_eq!(
+            shared
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|b| **b == b'\n')
+                .count(),
+            1
+        );
+
+        // Written before its window ends
+        tracing::info!(name: "chunk.acked", bytes_acked = 300, "acked");
+        aggregation.flush();
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"], "chunk.sent");
+    assert_eq!(records[0]["aggregate.count"], 2);
+    assert_eq!(records[0]["bytes_sent"], 300);
+    assert_eq!(records[1]["message"], "chunk.acked");
+    assert_eq!(records[1]["bytes_acked"], 300);
+}
+
+#[test]
+fn aggregation_over_quota() {
+    use tracing_logstash::aggregate::Aggregation;
+    use tracing_logstash::quota::TenantQuotas;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let logger = tracing_logstash::Layer::default()
+        .event_format(LogstashFormat::default().with_span_fields(vec!["tenant_id".into()]))
+        .with_aggregation(
+            Aggregation::new(std::time::Duration::from_millis(20))
+                .with_event("chunk.sent", &["bytes_sent"]),
+        )
+        .with_tenant_quotas(Some(
+            TenantQuotas::new("tenant_id")
+                .with_max_records_per_second(1)
+                .with_over_quota_sampling(10),
+        ))
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone())));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        let _span = tracing::info_span!("upload", tenant_id = "noisy").entered();
+        tracing::info!("upload started");
+        // Over quota, and the first of ten sampled records
+        tracing::info!("upload progress");
+        for bytes_sent in [100, 200] {
+            tracing::info!(name: "chunk.sent", bytes_sent, "sent");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        tracing::info!("upload done");
+    });
+
+    // The summary has no tenant and is written, while the records of the tenant are sampled
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["message"], "upload started");
+    assert_eq!(records[1]["message"], "upload progress");
+    assert_eq!(records[2]["message"], "chunk.sent");
+    assert_eq!(records[2]["bytes_sent"], 300);
+}
+
+#[test]
+fn aggregation_summary_records_over_quota() {
+    use tracing_logstash::aggregate::Aggregation;
+    use tracing_logstash::quota::TenantQuotas;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let quotas = TenantQuotas::new("tenant_id")
+        .with_max_records_per_second(1)
+        .with_summary_records(true);
+    let logger = tracing_logstash::Layer::default()
+        .event_format(LogstashFormat::default().with_span_fields(vec!["tenant_id".into()]))
+        .with_aggregation(
+            Aggregation::new(std::time::Duration::from_millis(20))
+                .with_event("chunk.sent", &["bytes_sent"]),
+        )
+        .with_tenant_quotas(Some(quotas.clone()))
+        .with_writer(BoxMakeWriter::new(move || Buffer::new(cloned.clone())));
+
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info_span!("upload", tenant_id = "noisy").in_scope(|| {
+            tracing::info!("upload started");
+            for bytes_sent in [100, 200] {
+                tracing::info!(name: "chunk.sent", bytes_sent, "sent");
+            }
+        });
+        tracing::info_span!("upload", tenant_id = "quiet").in_scope(|| {
+            tracing::info!(name: "chunk.sent", bytes_sent = 50, "sent");
+        });
+        std::thread::sleep(std::time::Duration::from_millis(30));
+        tracing::info!("uploads done");
+    });
+
+    // The summary of the tenant over quota is dropped
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0]["message"], "upload started");
+    assert_eq!(records[1]["message"], "chunk.sent");
+    assert_eq!(records[1]["tenant_id"], "quiet");
+    assert_eq!(records[1]["aggregate.count"], 1);
+    assert_eq!(records[1]["bytes_sent"], 50);
+    assert_eq!(records[2]["message"], "uploads done");
+    let stats = quotas.stats();
+    assert_eq!(stats["noisy"].records_dropped, 1);
+    assert_eq!(stats["quiet"].records_written, 1);
+}
+
+#[test]
+fn udp_writer() {
+    use tracing_logstash::udp::UdpWriter;
+
+    let input = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let writer = UdpWriter::new(input.local_addr().unwrap())
+        .unwrap()
+        .with_max_datagram_size(512);
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        tracing::info!("small");
+        tracing::info!(padding = "x".repeat(1024), "too large");
+    });
+
+    let mut datagram = vec![0; 4096];
+    let len = input.recv(&mut datagram).unwrap();
+    let output_json: serde_json::Value = serde_json::from_slice(&datagram[..len]).unwrap();
+    assert_eq!(output_json["message"], "small");
+    assert_eq!(writer.dropped(), 1);
+}
+
+#[test]
+fn float_formatting() {
+    let log = || {
+        let _span = tracing::info_span!("request", load = 2.0 / 3.0).entered();
+        tracing::info!(ratio = 0.1 + 0.2, big = 1e16, nan = f64::NAN, "measured");
+    };
+
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec!["load".into()]),
+        log,
+    );
+    assert!(output.contains(r#""ratio":0.30000000000000004"#));
+    assert!(output.contains(r#""big":1e+16"#));
+    assert!(output.contains(r#""nan":null"#));
+
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(vec!["load".into()])
+            .with_float_digits(Some(3)),
+        log,
+    );
+    assert!(output.contains(r#""ratio":0.3"#));
+    assert!(output.contains(r#""load":0.667"#));
+}
+
+#[test]
+fn quorum_writer() {
+    use tracing_logstash::mirror::QuorumWriter;
+
+    let primary = Arc::new(RwLock::new(Vec::new()));
+    let secondary = Arc::new(RwLock::new(Vec::new()));
+    let (cloned_primary, cloned_secondary) = (primary.clone(), secondary.clone());
+    let writer = QuorumWriter::new(2)
+        .with_writer("primary", move || Buffer::new(cloned_primary.clone()))
+        .with_writer("secondary", move || Buffer::new(cloned_secondary.clone()))
+        .with_writer("failing", || FailingWriter);
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("audited")
+    });
+
+    assert_eq!(*primary.read().unwrap(), *secondary.read().unwrap());
+    let output_json: serde_json::Value = serde_json::from_slice(&primary.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "audited");
+
+    let stats = writer.stats();
+    assert_eq!(stats.delivered, 1);
+    assert_eq!(stats.undelivered, 0);
+    assert_eq!(stats.diverged, 1);
+    assert_eq!(
+        stats.failures,
+        [
+            ("primary".to_owned(), 0),
+            ("secondary".to_owned(), 0),
+            ("failing".to_owned(), 1)
+        ]
+    );
+}
+
+#[test]
+fn quorum_writer_below_quorum() {
+    use tracing_logstash::mirror::QuorumWriter;
+    use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+    let errors = Arc::new(RwLock::new(Vec::new()));
+    let spool = Arc::new(RwLock::new(Vec::new()));
+    let (cloned_errors, cloned_spool) = (errors.clone(), spool.clone());
+    let writer = QuorumWriter::new(2)
+        .with_writer(
+            "errors",
+            (move || Buffer::new(cloned_errors.clone())).with_max_level(tracing::Level::ERROR),
+        )
+        .with_writer("failing", || FailingWriter);
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let diagnostics = logger.diagnostics();
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("not an error");
+        tracing::error!("audited");
+    });
+    assert_eq!(diagnostics.write_errors(), 2);
+
+    // The writers are made for the event
+    let output_json: serde_json::Value = serde_json::from_slice(&errors.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "audited");
+
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(writer.clone())
+        .with_fallback_writer(move || Buffer::new(cloned_spool.clone()));
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::error!("spooled")
+    });
+    let output_json: serde_json::Value = serde_json::from_slice(&spool.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "spooled");
+
+    let stats = writer.stats();
+    assert_eq!(
+        (stats.delivered, stats.undelivered, stats.diverged),
+        (0, 3, 3)
+    );
+}
+
+#[test]
+fn renamed_span_fields() {
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![("tenant", "tenant_id").into()]),
+        || {
+            for tenant_id in ["acme", "globex"] {
+                let _span = tracing::info_span!("request", tenant_id).entered();
+                tracing::info!("handled");
+            }
+        },
+    );
+    let tenants = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| record["tenant"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(tenants, ["acme", "globex"]);
+}
+
+#[test]
+fn renamed_span_fields_by_source_name() {
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![
+            ("tenant", "tenant_id").into(),
+            ("region", "region").into(),
+        ]),
+        || {
+            // `tenant` is not the source of any field and is not recorded
+            let _span = tracing::info_span!(
+                "request",
+                tenant = "ignored",
+                tenant_id = "acme",
+                region = "eu"
+            )
+            .entered();
+            tracing::info!("handled");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["tenant"], "acme");
+    assert_eq!(output_json["region"], "eu");
+    assert!(output_json.get("tenant_id").is_none());
+}
+
+#[test]
+fn span_fields_of_several_callsites() {
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![
+            ("tenant", "tenant_id").into(),
+            ("region", "region").into(),
+        ]),
+        || {
+            // The same fields at different indices of the field sets of two callsites, each
+            // recorded twice
+            for _ in 0..2 {
+                let span = tracing::info_span!(
+                    "request",
+                    region = "eu",
+                    tenant_id = tracing::field::Empty
+                );
+                span.record("tenant_id", "acme");
+                span.in_scope(|| tracing::info!("handled"));
+                let _span =
+                    tracing::info_span!("job", attempt = 1, tenant_id = "globex", region = "us")
+                        .entered();
+                tracing::info!("handled");
+            }
+        },
+    );
+    let fields = output
+        .lines()
+        .map(|line| {
+            let output_json: serde_json::Value = serde_json::from_str(line).unwrap();
+            (output_json["tenant"].clone(), output_json["region"].clone())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        fields,
+        [
+            ("acme", "eu"),
+            ("globex", "us"),
+            ("acme", "eu"),
+            ("globex", "us")
+        ]
+        .map(|(tenant, region)| (tenant.into(), region.into()))
+    );
+}
+
+#[test]
+fn rolling_file_writer() {
+    use tracing_logstash::rolling::RollingFileWriter;
+
+    let dir = std::env::temp_dir().join(format!("rolling-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("app.json");
+    let writer = RollingFileWriter::new(path.to_str().unwrap())
+        .unwrap()
+        .with_max_size(Some(100))
+        .with_max_files(2)
+        .with_sync_on(tracing::Level::INFO)
+        .with_sync_on_roll(true);
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        for n in 0..5 {
+            tracing::info!(n, "rolled");
+        }
+    });
+
+    let n = |name: &str| {
+        let content = std::fs::read_to_string(dir.join(name)).unwrap();
+        let output_json: serde_json::Value = serde_json::from_str(&content).unwrap();
+        output_json["n"].clone()
+    };
+    assert_eq!(n("app.json"), 4);
+    assert_eq!(n("app.json.1"), 3);
+    assert_eq!(n("app.json.2"), 2);
+    assert!(!dir.join("app.json.3").exists());
+    assert_eq!(writer.dropped(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rolling_file_writer_compression() {
+    use tracing_logstash::rolling::RollingFileWriter;
+
+    let dir = std::env::temp_dir().join(format!("rolling-gz-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("app.json");
+    let writer = RollingFileWriter::new(path.to_str().unwrap())
+        .unwrap()
+        .with_max_size(Some(100))
+        .with_max_files(5)
+        .with_compression(true)
+        .with_max_total_size(Some(500));
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        for n in 0..6 {
+            tracing::info!(n, "rolled");
+        }
+    });
+
+    let rolled = (1..=5)
+        .map(|n| std::fs::read(dir.join(format!("app.json.{}.gz", n))).ok())
+        .collect::<Vec<_>>();
+    let kept = rolled.iter().flatten().collect::<Vec<_>>();
+    // The oldest files were deleted to keep the total size
+    assert_eq!(kept.len(), 2);
+    assert!(rolled[..2].iter().all(Option::is_some));
+    assert!(100 + kept.iter().map(|gz| gz.len()).sum::<usize>() <= 500);
+    for gz in kept {
+        assert_eq!(gz[..3], [0x1f, 0x8b, 0x08]);
+        let size = u32::from_le_bytes(gz[gz.len() - 4..].try_into().unwrap());
+        assert!(size > 100);
+    }
+    assert!(!dir.join("app.json.1").exists());
+    assert_eq!(writer.dropped(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn rolling_file_writer_rotation() {
+    use std::time::{Duration, SystemTime};
+    use tracing_logstash::rolling::{RollingFileWriter, Rotation};
+
+    let dir = std::env::temp_dir().join(format!("rolling-daily-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("app.json");
+    std::fs::create_dir_all(&dir).unwrap();
+    // Last written two days ago
+    let file = std::fs::File::create(&path).unwrap();
+    std::io::Write::write_all(&mut &file, b"{\"n\":-1}\n").unwrap();
+    file.set_modified(SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60))
+        .unwrap();
+    drop(file);
+
+    let writer = RollingFileWriter::new(path.to_str().unwrap())
+        .unwrap()
+        .with_rotation(Rotation::Daily)
+        .with_utc_offset(time::UtcOffset::from_hms(2, 0, 0).unwrap());
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let collector = Registry::default().with(logger);
+    tracing::subscriber::with_default(collector, || {
+        tracing::info!(n = 0, "rotated");
+        tracing::info!(n = 1, "rotated");
+    });
+
+    let n = |name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["n"].clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(n("app.json.1"), [-1]);
+    assert_eq!(n("app.json"), [0, 1]);
+    assert_eq!(writer.dropped(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn builder() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+
+    let collector = tracing_logstash::builder()
+        .with_max_level(tracing::Level::INFO)
+        .event_format(
+            LogstashFormat::default().with_constants(vec![("service.name", "checkout".to_owned())]),
+        )
+        .with_writer(move || Buffer::new(cloned.clone()))
+        .finish();
+    tracing::subscriber::with_default(collector, || {
+        tracing::debug!("skipped");
+        tracing::info!("written");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "written");
+    assert_eq!(output_json["service.name"], "checkout");
+}
+
+#[cfg(unix)]
+#[test]
+fn emergency_writer() {
+    use tracing_logstash::emergency::EmergencyWriter;
+
+    let path = std::env::temp_dir().join(format!("emergency-{}.log", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    let writer = EmergencyWriter::new(file.into())
+        .with_constants(vec![("service.name", "checkout".to_owned())]);
+    writer.write("SIGSEGV in \"worker\"\n").unwrap();
+    writer.write(&"x".repeat(10_000)).unwrap();
+
+    let output = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+
+    let output_json: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(output_json["level"], "FATAL");
+    assert_eq!(output_json["reason"], "SIGSEGV in \"worker\"\n");
+    assert_eq!(output_json["pid"], std::process::id());
+    assert_eq!(output_json["service.name"], "checkout");
+    time::OffsetDateTime::parse(output_json["@timestamp"].as_str().unwrap(), &Rfc3339).unwrap();
+
+    assert!(lines[1].len() <= 4096);
+    let output_json: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+    assert!(output_json["reason"].as_str().unwrap().starts_with("xxx"));
+}
+
+#[test]
+fn field_units() {
+    use tracing_logstash::{FieldSpec, Unit};
+
+    let log = || {
+        let _span = tracing::info_span!("upload", payload_size = 12, rate = 1.5).entered();
+        tracing::info!("uploaded");
+    };
+
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![
+            FieldSpec::new("payload_size").unit(Unit::Kibibytes),
+            FieldSpec::new("rate").unit(Unit::MegabytesPerSecond),
+        ]),
+        log,
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["payload_size.bytes"], 12288);
+    assert_eq!(output_json["rate.bytes_per_second"], 1500000.0);
+    assert!(output_json.get("payload_size").is_none());
+}
+
+#[test]
+fn static_and_dynamic_span_fields() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use tracing_logstash::FieldSpec;
+
+    static ATTEMPTS: AtomicU64 = AtomicU64::new(0);
+
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![
+            "job_id".into(),
+            FieldSpec::static_value("deployment", "blue"),
+            FieldSpec::dynamic("attempt", || ATTEMPTS.fetch_add(1, Ordering::Relaxed) + 1),
+        ]),
+        || {
+            let _span = tracing::info_span!("job", job_id = "j-42").entered();
+            tracing::info!("started");
+            tracing::info!("finished");
+        },
+    );
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    for line in lines {
+        let output_json: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(output_json["job_id"], "j-42");
+        assert_eq!(output_json["deployment"], "blue");
+        assert_eq!(output_json["attempt"], 1);
+    }
+}
+
+#[test]
+fn translated_span_fields() {
+    use tracing_logstash::{FieldSpec, RecordedValue};
+
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![FieldSpec::translate(
+            "http.status_class",
+            "status",
+            |value| match value {
+                RecordedValue::I64(status) => RecordedValue::String(format!("{}xx", status / 100)),
+                value => value,
+            },
+        )]),
+        || {
+            let span = tracing::info_span!("request", status = tracing::field::Empty).entered();
+            span.record("status", 503);
+            tracing::info!("responded");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["http.status_class"], "5xx");
+    assert!(output_json.get("status").is_none());
+}
+
+#[test]
+fn array_span_fields() {
+    use tracing_logstash::FieldSpec;
+
+    let output = capture(
+        LogstashFormat::default().with_span_fields(vec![
+            FieldSpec::new("retry_reason").array(),
+            "attempt".into(),
+        ]),
+        || {
+            let span = tracing::info_span!(
+                "upload",
+                retry_reason = tracing::field::Empty,
+                attempt = tracing::field::Empty
+            )
+            .entered();
+            span.record("retry_reason", "timeout");
+            span.record("attempt", 1);
+            span.record("retry_reason", "reset");
+            span.record("attempt", 2);
+            tracing::info!("uploaded");
+        },
+    );
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(
+        output_json["retry_reason"],
+        serde_json::json!(["timeout", "reset"])
+    );
+    assert_eq!(output_json["attempt"], 2);
+}
+
+#[test]
+fn structured_span_fields() {
+    use tracing_logstash::{FieldSpec, Structured};
+
+    #[derive(Serialize)]
+    struct Customer {
+        id: u64,
+        tier: &'static str,
+    }
+
+    let log = || {
+        let customer = Customer {
+            id: 42,
+            tier: "gold",
+        };
+        let _span = tracing::info_span!(
+            "checkout",
+            customer = %Structured(&customer),
+            note = "not json"
+        )
+        .entered();
+        tracing::info!("checked out");
+    };
+
+    let format = || {
+        LogstashFormat::default().with_span_fields(vec![
+            FieldSpec::new("customer").json(),
+            FieldSpec::new("note").json(),
+        ])
+    };
+    for format in [format(), format().with_serialized_span_fields(true)] {
+        let output = capture(format, log);
+        let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(
+            output_json["customer"],
+            serde_json::json!({ "id": 42, "tier": "gold" })
+        );
+        assert_eq!(output_json["note"], "not json");
+    }
+}
+
+struct SlowBuildInfo;
+
+impl LogFieldContributor for SlowBuildInfo {
+    fn add_fields<F>(&self, serializer: &mut F)
+    where
+        F: LogFieldReceiver,
+    {
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        serializer.add_field("version", "1.2.3");
+    }
+}
+
+#[test]
+fn budgeted_contributor() {
+    use tracing_logstash::contributors::Budgeted;
+
+    let budgeted =
+        Budgeted::new(SlowBuildInfo, std::time::Duration::from_millis(1)).with_skip_events(2);
+    let counters = budgeted.counters();
+    let output = capture(
+        LogstashFormat::default().with_field_contributor(budgeted),
+        || {
+            for _ in 0..4 {
+                tracing::info!("started");
+            }
+        },
+    );
+    let versions = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| record.get("version").is_some())
+        .collect::<Vec<_>>();
+    assert_eq!(versions, [true, false, false, true]);
+    assert_eq!(counters.overruns(), 2);
+    assert_eq!(counters.skipped(), 2);
+}
+
+#[test]
+fn elastic_bulk_format() {
+    use tracing_logstash::elastic::{BulkAction, BulkFormat};
+    use tracing_logstash::template::Template;
+
+    let output = capture(
+        BulkFormat::new(
+            Template::parse("logs-{field:tenant|default:shared}").unwrap(),
+            LogstashFormat::default(),
+        )
+        .with_action(BulkAction::Create),
+        || {
+            tracing::info!(tenant = "acme", "done");
+            tracing::info!("done");
+        },
+    );
+    let lines = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines[0],
+        serde_json::json!({ "create": { "_index": "logs-acme" } })
+    );
+    assert_eq!(lines[1]["tenant"], "acme");
+    assert_eq!(
+        lines[2],
+        serde_json::json!({ "create": { "_index": "logs-shared" } })
+    );
+    assert_eq!(lines[3]["message"], "done");
+}
+
+#[test]
+fn serialized_span_fields() {
+    let log = || {
+        let span = tracing::info_span!(
+            "request",
+            user = "a \"quoted\" name",
+            attempt = 2,
+            ok = tracing::field::Empty
+        )
+        .entered();
+        span.record("ok", true);
+        tracing::info!("handled");
+    };
+    let format = || {
+        LogstashFormat::default()
+            .with_timestamp(false)
+            .with_thread_name(false)
+            .with_span_fields(vec!["user".into(), "attempt".into(), "ok".into()])
+    };
+
+    let expected = capture(format(), log);
+    let output = capture(format().with_serialized_span_fields(true), log);
+    assert_eq!(output, expected);
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["user"], "a \"quoted\" name");
+    assert_eq!(output_json["attempt"], 2);
+    assert_eq!(output_json["ok"], true);
+}
+
+#[test]
+fn timestamp_field() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_thread_name(false)
+            .with_timestamp_field(Some("occurred_at")),
+        || {
+            tracing::info!(occurred_at = "2024-03-01T12:00:00+02:00", "replayed");
+            tracing::info!(occurred_at = 1_700_000_000_123u64, "replayed");
+            tracing::info!(occurred_at = "yesterday", "replayed");
+            tracing::info!("live");
+        },
+    );
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records[0]["@timestamp"], "2024-03-01T10:00:00Z");
+    assert_eq!(records[1]["@timestamp"], "2023-11-14T22:13:20.123Z");
+    for record in &records[..2] {
+        let received = record["event.created"].as_str().unwrap();
+        assert!(time::OffsetDateTime::parse(received, &Rfc3339).is_ok());
+    }
+    for record in &records[2..] {
+        assert!(record.get("event.created").is_none());
+        let timestamp = record["@timestamp"].as_str().unwrap();
+        let timestamp = time::OffsetDateTime::parse(timestamp, &Rfc3339).unwrap();
+        assert!((time::OffsetDateTime::now_utc() - timestamp).whole_seconds() < 60);
+    }
+}
+
+#[test]
+fn append_file_writer() {
+    use tracing_logstash::append::AppendFileWriter;
+
+    let path = std::env::temp_dir().join(format!("append-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    // Separately opened writers, as in separate processes, with records larger than PIPE_BUF
+    let writers = (0..4)
+        .map(|_| AppendFileWriter::new(&path).unwrap())
+        .collect::<Vec<_>>();
+    let threads = writers
+        .iter()
+        .cloned()
+        .enumerate()
+        .map(|(worker, writer)| {
+            std::thread::spawn(move || {
+                let logger = tracing_logstash::Layer::default().with_writer(writer);
+                tracing::subscriber::with_default(Registry::default().with(logger), || {
+                    for i in 0..100 {
+                        tracing::info!(worker, i, padding = "x".repeat(8192), "appended");
+                    }
+                });
+            })
+        })
+        .collect::<Vec<_>>();
+    threads
+        .into_iter()
+        .for_each(|thread| thread.join().unwrap());
+
+    let output = std::fs::read_to_string(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let mut counts = [0; 4];
+    for line in output.lines() {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        counts[record["worker"].as_u64().unwrap() as usize] += 1;
+    }
+    assert_eq!(counts, [100; 4]);
+    for writer in &writers {
+        assert_eq!((writer.torn(), writer.dropped()), (0, 0));
+    }
+}
+
+#[test]
+fn background_writer() {
+    use std::sync::mpsc;
+    use tracing::Level;
+    use tracing_logstash::background::{BackgroundWriter, BackpressurePolicy};
+
+    /// The `i` of the events written and the number dropped, with the first event blocking the
+    /// writer until the others are queued
+    fn written(policy: BackpressurePolicy, level: Level) -> (Vec<u64>, u64) {
+        let shared = Arc::new(RwLock::new(Vec::new()));
+        let cloned = shared.clone();
+        let (started, started_rx) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let release_rx = std::sync::Mutex::new(release_rx);
+        let writer = BackgroundWriter::new(move || {
+            let _ = started.send(());
+            let _ = release_rx.lock().unwrap().recv();
+            Buffer::new(cloned.clone())
+        })
+        .unwrap()
+        .with_capacity(2)
+        .with_policy(policy);
+
+        let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            tracing::error!(i = 0);
+            started_rx.recv().unwrap();
+            for i in 1..4 {
+                match level {
+                    Level::ERROR => tracing::error!(i),
+                    _ => tracing::debug!(i),
+                }
+            }
+        });
+        drop(release);
+        writer.flush();
+
+        let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+        let written = output
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["i"].clone())
+            .map(|i| i.as_u64().unwrap())
+            .collect();
+        (written, writer.dropped())
+    }
+
+    assert_eq!(
+        written(BackpressurePolicy::DropNewest, Level::ERROR),
+        (vec![0, 1, 2], 1)
+    );
+    assert_eq!(
+        written(BackpressurePolicy::DropOldest, Level::ERROR),
+        (vec![0, 2, 3], 1)
+    );
+    assert_eq!(
+        written(BackpressurePolicy::DropBelow(Level::WARN), Level::DEBUG),
+        (vec![0, 1, 2], 1)
+    );
+}
+
+#[test]
+fn background_writer_inline_budget() {
+    use tracing_logstash::background::BackgroundWriter;
+
+    let threads = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let cloned = threads.clone();
+    let writer = BackgroundWriter::new(move || {
+        let thread = std::thread::current().name().map(str::to_owned);
+        cloned.lock().unwrap().push(thread);
+        io::sink()
+    })
+    .unwrap()
+    .with_inline_budget(2);
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    let test = std::thread::current().name().map(str::to_owned);
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        for i in 0..3 {
+            tracing::info!(i);
+        }
+        writer.flush();
+        for i in 3..5 {
+            tracing::info!(i);
+        }
+    });
+
+    let worker = Some("tracing-logstash-sink".to_owned());
+    assert_eq!(
+        *threads.lock().unwrap(),
+        [test.clone(), test.clone(), worker, test.clone(), test]
+    );
+    assert_eq!((writer.inline_records(), writer.handoffs()), (4, 1));
+    assert!(writer.inline_time() > std::time::Duration::ZERO);
+}
+
+#[test]
+fn batch_writer_backpressure_policy() {
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use tracing_logstash::background::BackpressurePolicy;
+    use tracing_logstash::batch::BatchWriter;
+
+    /// Blocks the first write until released
+    struct Blocking {
+        started: mpsc::Sender<()>,
+        release: mpsc::Receiver<()>,
+        written: Arc<RwLock<Vec<u8>>>,
+    }
+
+    impl io::Write for Blocking {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.started.send(());
+            let _ = self.release.recv();
+            self.written.write().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let (started, started_rx) = mpsc::channel();
+    let (release, release_rx) = mpsc::channel::<()>();
+    let writer = BatchWriter::new(Blocking {
+        started,
+        release: release_rx,
+        written: shared.clone(),
+    })
+    .with_max_records(1)
+    .with_flush_interval(Duration::ZERO)
+    .with_max_pending(2)
+    .with_backpressure_policy(BackpressurePolicy::DropBelow(tracing::Level::WARN));
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::error!(i = 0);
+        started_rx.recv().unwrap();
+        // One is queued behind the record being written, the others are dropped
+        for i in 1..4 {
+            tracing::debug!(i);
+        }
+    });
+    drop(release);
+    writer.flush().unwrap();
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let written = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["i"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(written, [0, 1]);
+    assert_eq!(writer.dropped(), 2);
+}
+
+#[test]
+fn batch_writer() {
+    use std::time::Duration;
+    use tracing_logstash::batch::BatchWriter;
+
+    /// Counts the writes reaching the underlying writer
+    #[derive(Clone, Default)]
+    struct Writes(Arc<RwLock<Vec<Vec<u8>>>>);
+
+    impl Write for Writes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let writes = Writes::default();
+    let writer = BatchWriter::new(writes.clone())
+        .with_max_records(2)
+        .with_flush_interval(Duration::from_secs(60));
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        tracing::info!("three");
+    });
+    writer.flush().unwrap();
+
+    let writes = writes.0.read().unwrap();
+    assert_eq!(writes.len(), 2);
+    let messages = writes
+        .iter()
+        .flat_map(|write| std::str::from_utf8(write).unwrap().lines())
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["one", "two", "three"]);
+    assert_eq!(writer.dropped(), 0);
+}
+
+#[test]
+fn batch_writer_flush_interval() {
+    use std::time::{Duration, Instant};
+    use tracing_logstash::batch::BatchWriter;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let writer = BatchWriter::new(Buffer::new(shared.clone()))
+        .with_flush_interval(Duration::from_millis(20));
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::error!("partial batch")
+    });
+
+    // Written by the background thread without further records or flushing
+    let start = Instant::now();
+    while shared.read().unwrap().is_empty() && start.elapsed() < Duration::from_secs(10) {
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let output_json: serde_json::Value = serde_json::from_slice(&shared.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "partial batch");
+}
+
+#[test]
+fn batch_writer_constants() {
+    use tracing_logstash::batch::{BatchFraming, BatchWriter};
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let writer = BatchWriter::new(Buffer::new(shared.clone()))
+        .with_max_records(2)
+        .with_batch_constants(vec![("service", "checkout".to_owned())]);
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        tracing::info!("three");
+    });
+    writer.flush().unwrap();
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let lines = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let batch = serde_json::json!({ "@batch": { "service": "checkout" } });
+    assert_eq!(lines.len(), 5);
+    assert_eq!(lines[0], batch);
+    assert_eq!(lines[1]["message"], "one");
+    assert_eq!(lines[3], batch);
+    assert_eq!(lines[4]["message"], "three");
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let writer = BatchWriter::new(Buffer::new(shared.clone()))
+        .with_framing(BatchFraming::JsonArray)
+        .with_batch_constants(vec![("service", "checkout".to_owned())]);
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+    });
+    writer.flush().unwrap();
+
+    let output: serde_json::Value = serde_json::from_slice(&shared.read().unwrap()).unwrap();
+    assert_eq!(output[0], batch);
+    assert_eq!(output[1]["message"], "one");
+}
+
+#[test]
+fn batch_writer_json_array() {
+    use tracing_logstash::batch::{BatchFraming, BatchWriter};
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let writer = BatchWriter::new(Buffer::new(shared.clone()))
+        .with_max_records(3)
+        .with_framing(BatchFraming::JsonArray);
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+    });
+    writer.flush().unwrap();
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    assert!(output.starts_with("[{") && output.ends_with("}]"));
+    let output_json: Vec<serde_json::Value> = serde_json::from_str(&output).unwrap();
+    let messages = output_json
+        .iter()
+        .map(|record| record["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["one", "two"]);
+}
+
+#[test]
+fn batch_writer_fallback_writer() {
+    use tracing_logstash::batch::BatchWriter;
+
+    let spool = Arc::new(RwLock::new(Vec::new()));
+    let cloned = spool.clone();
+    let writer =
+        BatchWriter::new(FailingWriter).with_fallback_writer(move || Buffer::new(cloned.clone()));
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+    });
+    writer.flush().unwrap();
+
+    let output = String::from_utf8(spool.read().unwrap().to_vec()).unwrap();
+    let messages = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["message"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["one", "two"]);
+    assert_eq!((writer.fallbacks(), writer.dropped()), (2, 0));
+}
+
+#[test]
+fn cloned_layers() {
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let format = LogstashFormat::default()
+        .with_timestamp(false)
+        .with_thread_name(false)
+        .with_span_fields(vec!["attempt".into()]);
+    let logger = tracing_logstash::Layer::default()
+        .event_format(format.clone())
+        .with_writer(move || Buffer::new(cloned.clone()));
+    let log = || {
+        let span = tracing::info_span!("request", attempt = tracing::field::Empty).entered();
+        span.record("attempt", 2);
+        tracing::info!("handled");
+    };
+
+    // The same configuration in two subscribers
+    tracing::subscriber::with_default(Registry::default().with(logger.clone()), log);
+    tracing::subscriber::with_default(Registry::default().with(logger.clone()), log);
+
+    // Two layers recording the same span fields in one registry
+    let other = Arc::new(RwLock::new(Vec::new()));
+    let cloned = other.clone();
+    let collector = Registry::default().with(logger).with(
+        tracing_logstash::Layer::default()
+            .event_format(format)
+            .with_writer(move || Buffer::new(cloned.clone())),
+    );
+    tracing::subscriber::with_default(collector, log);
+
+    let mut output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    output.push_str(std::str::from_utf8(&other.read().unwrap()).unwrap());
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records.len(), 4);
+    for record in &records {
+        assert_eq!(record["message"], "handled");
+        assert_eq!(record["attempt"], 2);
+    }
+}
+
+#[test]
+fn cost_attribution() {
+    use tracing_logstash::cost::CostAttribution;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let cost = CostAttribution::new();
+    let logger = tracing_logstash::Layer::default()
+        .with_writer(move || Buffer::new(cloned.clone()))
+        .with_cost_attribution(Some(cost.clone()));
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!(target: "app::db", "query");
+        tracing::info!(target: "app::db", "another query");
+        tracing::info!(target: "app::http", "request");
+    });
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let mut db_bytes = 0;
+    for line in output.lines() {
+        let record: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(record["log.size_bytes"], line.len() + 1);
+        if record["logger_name"] == "app::db" {
+            db_bytes += line.len() as u64 + 1;
+        }
+    }
+    let stats = cost.stats();
+    assert_eq!(stats["app::db"].records_written, 2);
+    assert_eq!(stats["app::db"].bytes_written, db_bytes);
+    assert_eq!(stats["app::http"].records_written, 1);
+}
+
+#[cfg(all(unix, feature = "capture"))]
+#[test]
+fn captured_stderr() {
+    use tracing_logstash::capture::Capture;
+    use tracing_logstash::raw::RawLogWriter;
+
+    let shared = Arc::new(RwLock::new(Vec::new()));
+    let cloned = shared.clone();
+    let writer = RawLogWriter::new(move || Buffer::new(cloned.clone())).with_stream("stderr");
+
+    let capture = Capture::stderr(writer).unwrap();
+    io::stderr().write_all(b"from a dependency\n").unwrap();
+    drop(capture);
+
+    let output = String::from_utf8(shared.read().unwrap().to_vec()).unwrap();
+    let output_json: serde_json::Value = serde_json::from_str(&output).unwrap();
+    assert_eq!(output_json["message"], "from a dependency");
+    assert_eq!(output_json["stream"], "stderr");
+}
+
+#[test]
+fn tee_writer() {
+    use tracing_logstash::mirror::TeeWriter;
+
+    let file = Arc::new(RwLock::new(Vec::new()));
+    let cloned = file.clone();
+    let writer = TeeWriter::new()
+        .with_writer("shipper", || FailingWriter)
+        .with_writer("file", move || Buffer::new(cloned.clone()));
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("copied")
+    });
+
+    let output_json: serde_json::Value = serde_json::from_slice(&file.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "copied");
+    assert_eq!(
+        writer.failures(),
+        [("shipper".to_owned(), 1), ("file".to_owned(), 0)]
+    );
+}
+
+#[test]
+fn tee_writer_separators() {
+    use tracing_logstash::mirror::TeeWriter;
+    use tracing_logstash::RecordSeparator;
+
+    let stdout = Arc::new(RwLock::new(Vec::new()));
+    let agent = Arc::new(RwLock::new(Vec::new()));
+    let (cloned_stdout, cloned_agent) = (stdout.clone(), agent.clone());
+    let writer = TeeWriter::new()
+        .with_record_separator("\r\n")
+        .with_writer_separator(
+            "stdout",
+            move || Buffer::new(cloned_stdout.clone()),
+            RecordSeparator::NEWLINE,
+        )
+        .with_writer_separator(
+            "agent",
+            move || Buffer::new(cloned_agent.clone()),
+            RecordSeparator::NUL,
+        );
+
+    let logger = tracing_logstash::Layer::default()
+        .record_separator("\r\n")
+        .with_writer(writer);
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("first");
+        tracing::info!("second");
+    });
+
+    let stdout = String::from_utf8(stdout.read().unwrap().to_vec()).unwrap();
+    let agent = String::from_utf8(agent.read().unwrap().to_vec()).unwrap();
+    assert_eq!(stdout.matches('\n').count(), 2);
+    assert!(!stdout.contains('\r') && !stdout.contains('\0'));
+    assert_eq!(
+        agent.split_terminator('\0').collect::<Vec<_>>(),
+        stdout.lines().collect::<Vec<_>>()
+    );
+    assert!(stdout.ends_with('\n') && agent.ends_with('\0'));
+}
+
+#[test]
+fn retention_hints() {
+    use tracing_logstash::logstash::Retention;
+
+    let output = capture(
+        LogstashFormat::default().with_retention(Some(
+            Retention::new()
+                .with_default("90d")
+                .with_level(tracing::Level::DEBUG, "30d")
+                .with_target("audit", "365d"),
+        )),
+        || {
+            tracing::info!("kept");
+            tracing::debug!("short");
+            tracing::debug!(target: "audit::login", "long");
+        },
+    );
+    let retention = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| record["retention"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(retention, ["90d", "30d", "365d"]);
+}
+
+#[test]
+fn logger_root() {
+    let output = capture(
+        LogstashFormat::default()
+            .with_logger_root(Some("checkout"))
+            .with_logger_strip_prefix(Some("my_workspace")),
+        || {
+            tracing::info!(target: "", "bridged");
+            tracing::info!(target: "my_workspace::billing", "billed");
+            tracing::info!(target: "my_workspace", "started");
+            tracing::info!(target: "my_workspace_tools", "unrelated");
+        },
+    );
+    let logger_names = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| record["logger_name"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        logger_names,
+        ["checkout", "billing", "checkout", "my_workspace_tools"]
+    );
+}
+
+#[test]
+fn writer_per_level() {
+    use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+    let warnings = Arc::new(RwLock::new(Vec::new()));
+    let others = Arc::new(RwLock::new(Vec::new()));
+    let (cloned_warnings, cloned_others) = (warnings.clone(), others.clone());
+    let writer = (move || Buffer::new(cloned_warnings.clone()))
+        .with_max_level(tracing::Level::WARN)
+        .or_else(move || Buffer::new(cloned_others.clone()));
+
+    let logger = tracing_logstash::Layer::default().with_writer(writer);
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::warn!("disk almost full");
+        tracing::info!("started");
+    });
+
+    let output_json: serde_json::Value = serde_json::from_slice(&warnings.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "disk almost full");
+    let output_json: serde_json::Value = serde_json::from_slice(&others.read().unwrap()).unwrap();
+    assert_eq!(output_json["message"], "started");
+}
+
+#[cfg(feature = "redis")]
+#[test]
+fn redis_sink() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use tracing_logstash::redis::{RedisKey, RedisSink};
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let read_len = |reader: &mut BufReader<std::net::TcpStream>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line.trim_end()[1..].parse::<usize>().unwrap()
+        };
+        let mut commands = Vec::new();
+        while commands.len() < 3 {
+            let args = read_len(&mut reader);
+            let mut command = Vec::new();
+            for _ in 0..args {
+                let len = read_len(&mut reader);
+                let mut arg = vec![0u8; len + 2];
+                reader.read_exact(&mut arg).unwrap();
+                command.push(String::from_utf8(arg[..len].to_vec()).unwrap());
+            }
+            commands.push(command);
+            writer.write_all(b":1\r\n").unwrap();
+        }
+        commands
+    });
+
+    let sink = RedisSink::new(addr, RedisKey::List("logstash".to_owned()))
+        .unwrap()
+        .with_batch_size(2);
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        tracing::info!("three");
+    });
+    sink.flush().unwrap();
+
+    let commands = server.join().unwrap();
+    let messages = commands
+        .iter()
+        .map(|command| {
+            assert_eq!(command[..2], ["RPUSH", "logstash"]);
+            serde_json::from_str::<serde_json::Value>(&command[2]).unwrap()["message"].clone()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(messages, ["one", "two", "three"]);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[cfg(feature = "elasticsearch")]
+#[test]
+fn elasticsearch_sink() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tracing_logstash::elastic::BulkFormat;
+    use tracing_logstash::elasticsearch::ElasticsearchSink;
+    use tracing_logstash::template::Template;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let rejected =
+            r#"{"errors":true,"items":[{"index":{"status":201}},{"index":{"status":400}}]}"#;
+        let mut requests = Vec::new();
+        for response in [
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_owned(),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                rejected.len(),
+                rejected
+            ),
+        ] {
+            let mut head = Vec::new();
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim_end().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+                head.push(line.trim_end().to_owned());
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            requests.push((head, String::from_utf8(body).unwrap()));
+            writer.write_all(response.as_bytes()).unwrap();
+        }
+        requests
+    });
+
+    let sink = ElasticsearchSink::new(&format!("http://{}/cluster/", addr))
+        .unwrap()
+        .with_header("Authorization", "ApiKey c2VjcmV0")
+        .with_batch_size(2)
+        .with_max_attempts(1)
+        .with_flush_interval(Duration::from_millis(10));
+    let logger = tracing_logstash::Layer::default()
+        .event_format(BulkFormat::new(
+            Template::parse("logs").unwrap(),
+            LogstashFormat::default(),
+        ))
+        .with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+    });
+    // Fails when it waits for the request answered with 503
+    let _ = sink.flush();
+    sink.flush().unwrap();
+
+    // The request is resent on the same connection
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0], requests[1]);
+    let (head, body) = &requests[1];
+    assert_eq!(head[0], "POST /cluster/_bulk HTTP/1.1");
+    assert!(head.contains(&"Content-Type: application/x-ndjson".to_owned()));
+    assert!(head.contains(&"Authorization: ApiKey c2VjcmV0".to_owned()));
+    let lines = body
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines[0],
+        serde_json::json!({ "index": { "_index": "logs" } })
+    );
+    assert_eq!(lines[1]["message"], "one");
+    assert_eq!(lines[3]["message"], "two");
+    // The second record was rejected
+    assert_eq!(sink.dropped(), 1);
+}
+
+#[cfg(feature = "elasticsearch")]
+#[test]
+fn elasticsearch_document_ids() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tracing_logstash::elastic::{BulkAction, BulkFormat};
+    use tracing_logstash::elasticsearch::ElasticsearchSink;
+    use tracing_logstash::template::Template;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        // The first record was indexed before the connection failed
+        let duplicate =
+            r#"{"errors":true,"items":[{"create":{"status":409}},{"create":{"status":201}}]}"#;
+        let mut bodies = Vec::new();
+        for response in [
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n".to_owned(),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                duplicate.len(),
+                duplicate
+            ),
+        ] {
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim_end().parse().unwrap();
+                }
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            bodies.push(String::from_utf8(body).unwrap());
+            writer.write_all(response.as_bytes()).unwrap();
+        }
+        bodies
+    });
+
+    let sink = ElasticsearchSink::new(&format!("http://{}", addr))
+        .unwrap()
+        .with_document_ids(true)
+        .with_batch_size(2)
+        .with_max_attempts(1)
+        .with_flush_interval(Duration::from_millis(10));
+    let logger = tracing_logstash::Layer::default()
+        .event_format(
+            BulkFormat::new(Template::parse("logs").unwrap(), LogstashFormat::default())
+                .with_action(BulkAction::Create),
+        )
+        .with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+    });
+    let _ = sink.flush();
+    sink.flush().unwrap();
+
+    // The resent request has the same ids
+    let bodies = server.join().unwrap();
+    assert_eq!(bodies[0], bodies[1]);
+    let ids = bodies[1]
+        .lines()
+        .step_by(2)
+        .map(|line| {
+            let action = serde_json::from_str::<serde_json::Value>(line).unwrap();
+            assert_eq!(action["create"]["_index"], "logs");
+            action["create"]["_id"].as_str().unwrap().to_owned()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(ids.len(), 2);
+    assert_ne!(ids[0], ids[1]);
+    assert_eq!(ids[0].split('-').next(), ids[1].split('-').next());
+    assert_eq!(sink.duplicates(), 1);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[cfg(feature = "hec")]
+#[test]
+fn splunk_hec_sink() {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use tracing_logstash::hec::SplunkHecSink;
+    use tracing_logstash::splunk::SplunkHecFormat;
+
+    // The head and body of each request
+    type Requests = Vec<(Vec<String>, Vec<u8>)>;
+
+    // Answers each request on a single connection with the next response
+    fn serve(
+        responses: &'static [&'static str],
+    ) -> (std::net::SocketAddr, std::thread::JoinHandle<Requests>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut requests = Vec::new();
+            for (status, body) in responses.iter().map(|r| r.split_once(' ').unwrap()) {
+                let mut head = Vec::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(len) = line.strip_prefix("Content-Length: ") {
+                        content_length = len.trim_end().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push(line.trim_end().to_owned());
+                }
+                let mut request = vec![0u8; content_length];
+                reader.read_exact(&mut request).unwrap();
+                requests.push((head, request));
+                let response = format!(
+                    "HTTP/1.1 {} -\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                writer.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (addr, server)
+    }
+
+    let (addr, server) = serve(&[
+        r#"503 {"text":"Server is busy","code":9}"#,
+        r#"200 {"text":"Success","code":0}"#,
+        r#"403 {"text":"Invalid token","code":4}"#,
+    ]);
+    let sink = SplunkHecSink::new(&format!("http://{}", addr), "secret")
+        .unwrap()
+        .with_batch_size(2)
+        .with_max_attempts(1)
+        .with_flush_interval(Duration::from_millis(10));
+    let logger = tracing_logstash::Layer::default()
+        .event_format(SplunkHecFormat::default().with_host("web-1"))
+        .with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        // Fails when it waits for the request answered with "Server is busy"
+        let _ = sink.flush();
+        sink.flush().unwrap();
+        tracing::info!("three");
+        sink.flush().unwrap();
+    });
+
+    // The busy request is resent, the rejected one is dropped
+    let requests = server.join().unwrap();
+    assert_eq!(requests[0], requests[1]);
+    let (head, body) = &requests[1];
+    assert_eq!(head[0], "POST /services/collector/event HTTP/1.1");
+    assert!(head.contains(&"Authorization: Splunk secret".to_owned()));
+    let events = String::from_utf8(body.clone())
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0]["host"], "web-1");
+    assert_eq!(events[1]["event"]["message"], "two");
+    assert_eq!(sink.dropped(), 1);
+
+    let (addr, server) = serve(&[r#"200 {"text":"Success","code":0}"#]);
+    let sink = SplunkHecSink::new(&format!("http://{}/splunk", addr), "secret")
+        .unwrap()
+        .with_compression(true);
+    let logger = tracing_logstash::Layer::default()
+        .event_format(SplunkHecFormat::default())
+        .with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+    });
+    sink.flush().unwrap();
+    let requests = server.join().unwrap();
+    let (head, body) = &requests[0];
+    assert_eq!(head[0], "POST /splunk/services/collector/event HTTP/1.1");
+    assert!(head.contains(&"Content-Encoding: gzip".to_owned()));
+    assert_eq!(body[..2], [0x1f, 0x8b]);
+}
+
+#[cfg(feature = "fluentd")]
+#[test]
+fn fluentd_sink() {
+    use serde_json::{json, Value};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use tracing_logstash::fluentd::FluentdSink;
+
+    // Decodes the subset of MessagePack written by the sink, None if incomplete
+    fn decode(buf: &[u8]) -> Option<(Value, &[u8])> {
+        fn take(rest: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+            (rest.len() >= n).then(|| rest.split_at(n))
+        }
+        fn items(mut rest: &[u8], n: usize) -> Option<(Vec<Value>, &[u8])> {
+            let mut values = Vec::new();
+            for _ in 0..n {
+                let (value, next) = decode(rest)?;
+                values.push(value);
+                rest = next;
+            }
+            Some((values, rest))
+        }
+
+        let (&marker, rest) = buf.split_first()?;
+        match marker {
+            0xc0 => Some((Value::Null, rest)),
+            0xc2 | 0xc3 => Some((Value::Bool(marker == 0xc3), rest)),
+            0xcf => {
+                let (n, rest) = take(rest, 8)?;
+                Some((json!(u64::from_be_bytes(n.try_into().unwrap())), rest))
+            }
+            0xcb => {
+                let (n, rest) = take(rest, 8)?;
+                Some((json!(f64::from_be_bytes(n.try_into().unwrap())), rest))
+            }
+            0xd7 => {
+                let (time, rest) = take(rest, 9)?;
+                Some((json!({ "type": time[0] }), rest))
+            }
+            0xa0..=0xbf | 0xd9 => {
+                let (len, rest) = match marker {
+                    0xd9 => (*rest.first()? as usize, &rest[1..]),
+                    _ => ((marker & 0x1f) as usize, rest),
+                };
+                let (s, rest) = take(rest, len)?;
+                Some((json!(std::str::from_utf8(s).unwrap()), rest))
+            }
+            0x90..=0x9f => {
+                let (values, rest) = items(rest, (marker & 0x0f) as usize)?;
+                Some((Value::Array(values), rest))
+            }
+            0x80..=0x8f => {
+                let (values, rest) = items(rest, 2 * (marker & 0x0f) as usize)?;
+                let map = values
+                    .chunks(2)
+                    .map(|kv| (kv[0].as_str().unwrap().to_owned(), kv[1].clone()))
+                    .collect();
+                Some((Value::Object(map), rest))
+            }
+            _ => panic!("unexpected marker {marker:#x}"),
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf = Vec::new();
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            let mut chunk = [0u8; 4096];
+            let n = stream.read(&mut chunk).unwrap();
+            assert_ne!(n, 0);
+            buf.extend_from_slice(&chunk[..n]);
+            while let Some((message, rest)) = decode(&buf) {
+                let chunk = message[2]["chunk"].as_str().unwrap();
+                // fixmap(1) "ack" chunk
+                let mut ack = vec![0x81, 0xa3, b'a', b'c', b'k', 0xa0 | chunk.len() as u8];
+                ack.extend_from_slice(chunk.as_bytes());
+                stream.write_all(&ack).unwrap();
+                buf = rest.to_vec();
+                messages.push(message);
+            }
+        }
+        messages
+    });
+
+    let sink = FluentdSink::new(addr, "app.test")
+        .unwrap()
+        .with_ack(true)
+        .with_batch_size(2);
+    let logger = tracing_logstash::Layer::default().with_writer(sink.clone());
+    tracing::subscriber::with_default(Registry::default().with(logger), || {
+        tracing::info!("one");
+        tracing::info!("two");
+        tracing::info!("three");
+    });
+    sink.flush().unwrap();
+
+    let messages = server.join().unwrap();
+    let mut records = Vec::new();
+    for message in &messages {
+        assert_eq!(message[0], "app.test");
+        for entry in message[1].as_array().unwrap() {
+            assert_eq!(entry[0], json!({ "type": 0 }));
+            assert_eq!(entry[1]["level"], "INFO");
+            records.push(entry[1]["message"].clone());
+        }
+    }
+    assert_eq!(records, ["one", "two", "three"]);
+    assert_ne!(messages[0][2]["chunk"], messages[1][2]["chunk"]);
+    assert_eq!(sink.dropped(), 0);
+}
+
+#[test]
+fn stack_trace_handle() {
+    use tracing::Level;
+    use tracing_logstash::stack_trace::{StackTraceConfig, StackTraceHandle};
+
+    assert!(StackTraceConfig::builder().max_frames(5).build().is_err());
+    assert!(StackTraceConfig::builder()
+        .when_event(Level::ERROR)
+        .max_frames(0)
+        .build()
+        .is_err());
+
+    let config = StackTraceConfig::builder()
+        .when_event(Level::ERROR)
+        .include_spans(Level::INFO)
+        .max_frames(2)
+        .build()
+        .unwrap();
+    let handle = StackTraceHandle::new(Some(config));
+    let output = capture(
+        LogstashFormat::default().with_stack_trace_handle(Some(handle.clone())),
+        || {
+            let _outer = tracing::info_span!("outer").entered();
+            let _inner = tracing::info_span!("inner").entered();
+            let _noise = tracing::debug_span!("noise").entered();
+            tracing::warn!("not traced");
+            tracing::error!("traced");
+            handle.set(Some(
+                StackTraceConfig::builder()
+                    .when_event(Level::WARN)
+                    .build()
+                    .unwrap(),
+            ));
+            tracing::warn!("traced at runtime");
+        },
+    );
+    let stack_traces = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .map(|record| {
+            record
+                .get("stack_trace")
+                .map(|stack_trace| stack_trace.as_str().unwrap().lines().count())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(stack_traces, [None, Some(2), Some(4)]);
+}
+
+#[test]
+fn value_labels() {
+    use tracing_logstash::logstash::ValueLabels;
+
+    let labels = ValueLabels::new()
+        .with_field(
+            "grpc.status",
+            "grpc.status_text",
+            [(0, "OK"), (4, "DEADLINE_EXCEEDED")],
+        )
+        .with_field("region", "region.name", [("eu-n1", "Stockholm")]);
+    let output = capture(
+        LogstashFormat::default()
+            .with_span_fields(vec!["region".into()])
+            .with_value_labels(Some(labels)),
+        || {
+            let _span = tracing::info_span!("call", region = "eu-n1").entered();
+            tracing::warn!(grpc.status = 4, "call failed");
+            tracing::warn!(grpc.status = 99, "unknown status");
+        },
+    );
+    let records = output
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(records[0]["grpc.status"], 4);
+    assert_eq!(records[0]["grpc.status_text"], "DEADLINE_EXCEEDED");
+    assert_eq!(records[0]["region.name"], "Stockholm");
+    assert_eq!(records[1]["grpc.status"], 99);
+    assert_eq!(records[1].get("grpc.status_text"), None);
+}